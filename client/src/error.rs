@@ -0,0 +1,16 @@
+/// Errors of the client calls.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The bot answered with an error status, `body` is its message.
+    #[error("request failed with status {status}: {body}")]
+    Status {
+        status: u16,
+        body: String,
+    },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,246 @@
+//! A typed async client for the HTTP API of the chatbot flow, for Rust services that talk to
+//! the bot. The flow itself is a WASM webhook and cannot be linked natively, so the client is a
+//! crate and workspace of its own; the event types are shared with the flow.
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+
+pub mod error;
+#[path = "../../src/events.rs"]
+pub mod events;
+
+pub use error::{ Error, Result };
+pub use events::ChatEvent;
+
+/// An answer asked for as JSON.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChatReply {
+    pub conversation_id: String,
+    /// What the answer is rated by, see [`Client::rate`].
+    pub message_id: String,
+    pub answer: String,
+    /// The answer split into the messages of a length-limited channel, the answer alone otherwise.
+    pub parts: Vec<String>,
+    /// The knowledge base, release notes, web search or API reference the answer drew on.
+    pub sources: Vec<String>,
+    /// Counted with the tokenizer of the chat model, the LLM service does not report usage.
+    pub tokens_used: u64,
+    /// The chunks used with their similarity and score after boosts, best first.
+    pub retrieval_scores: Vec<Value>,
+    /// The collection, knowledge base version and chunks the answer drew on.
+    pub provenance: Value,
+    /// The documents the answer drew on.
+    pub citations: Vec<Citation>,
+}
+
+/// A thumbs up or down on an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+/// A document an answer drew on, with the positions of the chunks of it used.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Citation {
+    pub source: Option<String>,
+    pub title: Option<String>,
+    pub chunk_indexes: Vec<u64>,
+}
+
+/// A document to ingest, `source`, `title` and `metadata` are stored with each of its chunks.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Document {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct KbVersion {
+    pub version: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IngestReport {
+    pub collection: String,
+    /// How many chunks were embedded and upserted.
+    pub written: usize,
+    pub kb_version: Option<KbVersion>,
+}
+
+/// A knowledge base chunk found by [`Client::search`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchHit {
+    /// The point id, as `/why` and `?action=quarantine` show it.
+    pub id: u64,
+    /// The similarity after boosts.
+    pub score: f32,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchReport {
+    pub collection: String,
+    /// Best first.
+    pub results: Vec<SearchHit>,
+}
+
+/// A client of one flow, e.g. `Client::new("https://code.flows.network/webhook/<id>")`.
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: String,
+    http: reqwest::Client,
+    conversation: Option<String>,
+    admin_token: Option<String>,
+    api_key: Option<String>,
+}
+
+impl Client {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            conversation: None,
+            admin_token: None,
+            api_key: None,
+        }
+    }
+
+    /// Keeps the questions in one conversation, sent as `x-conversation-name`.
+    pub fn with_conversation(mut self, name: &str) -> Self {
+        self.conversation = Some(name.to_string());
+        self
+    }
+
+    /// The `admin_token` of the flow, needed for the operator actions when it is set.
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// An API key of the flow, sent as `x-api-key`, its role deciding the actions allowed.
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    fn request(&self, action: Option<&str>, body: Vec<u8>) -> reqwest::RequestBuilder {
+        let mut req = self.http.post(&self.url).body(body);
+        if let Some(a) = action {
+            req = req.query(&[("action", a)]);
+        }
+        if let Some(c) = &self.conversation {
+            req = req.header("x-conversation-name", c);
+        }
+        if let Some(t) = &self.admin_token {
+            req = req.header("x-admin-token", t);
+        }
+        if let Some(k) = &self.api_key {
+            req = req.header("x-api-key", k);
+        }
+        req.header("x-platform", "rust-client").header("x-client-version", env!("CARGO_PKG_VERSION"))
+    }
+
+    async fn send(req: reqwest::RequestBuilder) -> Result<String> {
+        let res = req.send().await?;
+        let status = res.status();
+        let body = res.text().await?;
+        match status.is_success() {
+            true => Ok(body),
+            false => Err(Error::Status { status: status.as_u16(), body }),
+        }
+    }
+
+    /// Asks a question, returning the answer as the bot shows it, with any source footer.
+    pub async fn chat(&self, question: &str) -> Result<String> {
+        Self::send(self.request(None, question.as_bytes().to_vec())).await
+    }
+
+    /// Asks a question, returning the messages to send one by one when the channel limits their
+    /// length, see `x-max-message-chars`.
+    pub async fn chat_parts(&self, question: &str) -> Result<Vec<String>> {
+        let body = self.chat(question).await?;
+        Ok(body.split('\u{1e}').map(String::from).collect())
+    }
+
+    /// Asks a question, returning the answer with its provenance.
+    pub async fn chat_json(&self, question: &str) -> Result<ChatReply> {
+        let req = self.request(None, question.as_bytes().to_vec()).header("accept", "application/json");
+        Ok(serde_json::from_str(&Self::send(req).await?)?)
+    }
+
+    /// Asks a question, returning the events of the answer in order. They come together once the
+    /// answer is complete, the flow cannot stream them.
+    pub async fn stream(&self, question: &str) -> Result<Vec<ChatEvent>> {
+        let req = self.request(None, question.as_bytes().to_vec()).header("accept", "text/event-stream");
+        parse_event_stream(&Self::send(req).await?)
+    }
+
+    /// Marks the last answer of the conversation as helpful, which boosts the chunks it used.
+    pub async fn helpful(&self) -> Result<String> {
+        self.chat("/helpful").await
+    }
+
+    /// Rates an answer of the conversation by its `message_id`. An answer rated down is no longer
+    /// recalled from the conversation memory.
+    pub async fn rate(&self, message_id: &str, rating: Rating) -> Result<Value> {
+        let conversation = self.conversation.as_deref().unwrap_or_default();
+        let body = serde_json::json!({ "conversation": conversation, "message_id": message_id, "rating": rating });
+        let req = self.request(Some("feedback"), serde_json::to_vec(&body)?);
+        Ok(serde_json::from_str(&Self::send(req).await?)?)
+    }
+
+    /// Chunks, embeds and upserts the documents into `collection`, or the `collection_name` of
+    /// the flow.
+    pub async fn ingest(&self, collection: Option<&str>, documents: &[Document]) -> Result<IngestReport> {
+        let req = self.request(Some("ingest"), serde_json::to_vec(documents)?);
+        let req = match collection {
+            Some(c) => req.query(&[("collection", c)]),
+            None => req,
+        };
+        Ok(serde_json::from_str(&Self::send(req).await?)?)
+    }
+
+    /// Searches `collection`, or the `collection_name` of the flow, as a question would be,
+    /// returning up to `limit` chunks without asking the model. Needs the operator role.
+    pub async fn search(&self, collection: Option<&str>, query: &str, limit: Option<usize>) -> Result<SearchReport> {
+        let mut req = self.request(Some("search"), query.as_bytes().to_vec());
+        if let Some(c) = collection {
+            req = req.query(&[("collection", c)]);
+        }
+        if let Some(l) = limit {
+            req = req.query(&[("limit", l)]);
+        }
+        Ok(serde_json::from_str(&Self::send(req).await?)?)
+    }
+
+    /// Any operator action, for those without a typed call. An empty body reads.
+    pub async fn action(&self, action: &str, body: Option<&Value>) -> Result<Value> {
+        let body = match body {
+            Some(b) => serde_json::to_vec(b)?,
+            None => Vec::new(),
+        };
+        Ok(serde_json::from_str(&Self::send(self.request(Some(action), body)).await?)?)
+    }
+}
+
+/// Reads the `data` lines of a `text/event-stream` body.
+pub fn parse_event_stream(body: &str) -> Result<Vec<ChatEvent>> {
+    body.lines()
+        .filter_map(|l| l.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).map_err(Error::from))
+        .collect()
+}
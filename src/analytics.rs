@@ -0,0 +1,302 @@
+use std::{ collections::BTreeMap, time::{ SystemTime, UNIX_EPOCH } };
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{ guards, origin, sentiment };
+
+const ROLLUP_KEY: &str = "analytics:rollup";
+/// Raw day records are kept this long, the rollup keeps the series.
+const RAW_RETENTION_DAYS: i64 = 95;
+const MAX_ROLLUP_DAYS: u64 = 90;
+/// Unique chats are counted up to this many a day.
+const MAX_CHATS_PER_DAY: usize = 1000;
+const QUESTIONS_KEY: &str = "analytics:questions";
+/// The latest questions kept for the FAQ, without the chats that asked them.
+const MAX_QUESTIONS: usize = 300;
+/// Client versions are counted up to this many per channel a day.
+const MAX_VERSIONS_PER_CHANNEL: usize = 20;
+
+/// What a chat message turned into, the categories of the analytics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    KnowledgeBase,
+    ReleaseNotes,
+    WebSearch,
+    NoContext,
+    /// The knowledge base was skipped by a retrieval override.
+    MemoryOnly,
+    OffTopic,
+    Clarify,
+    Command,
+    Restart,
+}
+
+impl Category {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::KnowledgeBase => "knowledge_base",
+            Category::ReleaseNotes => "release_notes",
+            Category::WebSearch => "web_search",
+            Category::NoContext => "no_context",
+            Category::MemoryOnly => "memory_only",
+            Category::OffTopic => "off_topic",
+            Category::Clarify => "clarify",
+            Category::Command => "command",
+            Category::Restart => "restart",
+        }
+    }
+}
+
+/// The counters of one platform adapter on one day.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ChannelCounts {
+    questions: u64,
+    errors: u64,
+    confidence_sum: f64,
+    confidence_count: u64,
+    client_versions: BTreeMap<String, u64>,
+}
+
+/// The counters of one day, stored under `analytics:day:<date>` as messages come in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct DayRecord {
+    questions: u64,
+    errors: u64,
+    confidence_sum: f64,
+    confidence_count: u64,
+    categories: Map<String, Value>,
+    /// Short hashes of the chats seen, so chat names are not kept.
+    chats: Vec<String>,
+    /// The counters of each platform adapter the messages came through.
+    channels: BTreeMap<String, ChannelCounts>,
+    /// Conversations flagged as their user got frustrated.
+    frustrated: u64,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The `YYYY-MM-DD` date (UTC) of a Unix day number.
+pub fn date_of_day(days: i64) -> String {
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn today() -> i64 {
+    (now_secs() / 86400) as i64
+}
+
+fn day_key(days: i64) -> String {
+    format!("analytics:day:{}", date_of_day(days))
+}
+
+fn chat_hash(chat_id: &str) -> String {
+    let h = chat_id.bytes().fold(2166136261u32, |h, b| (h ^ (b as u32)).wrapping_mul(16777619));
+    format!("{h:08x}")
+}
+
+/// Counts one chat message. `confidence` is the best retrieval score of the answer, if any.
+pub fn record(chat_id: &str, category: Category, confidence: Option<f32>, error: bool) {
+    let key = day_key(today());
+    let mut day = get(&key)
+        .and_then(|v| serde_json::from_value::<DayRecord>(v).ok())
+        .unwrap_or_default();
+
+    day.questions += 1;
+    if error {
+        day.errors += 1;
+    }
+    if let Some(c) = confidence {
+        day.confidence_sum += c as f64;
+        day.confidence_count += 1;
+    }
+    let count = day.categories
+        .get(category.name())
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default();
+    day.categories.insert(category.name().to_string(), json!(count + 1));
+    let hash = chat_hash(chat_id);
+    if day.chats.len() < MAX_CHATS_PER_DAY && !day.chats.contains(&hash) {
+        day.chats.push(hash);
+    }
+    let origin = origin::current();
+    let channel = day.channels.entry(origin.platform).or_default();
+    channel.questions += 1;
+    if error {
+        channel.errors += 1;
+    }
+    if let Some(c) = confidence {
+        channel.confidence_sum += c as f64;
+        channel.confidence_count += 1;
+    }
+    if let Some(v) = origin.client_version {
+        if channel.client_versions.contains_key(&v) || channel.client_versions.len() < MAX_VERSIONS_PER_CHANNEL {
+            *channel.client_versions.entry(v).or_default() += 1;
+        }
+    }
+
+    guards::set_capped(
+        &key,
+        serde_json::to_value(&day).unwrap_or_default(),
+        Some(Expire {
+            kind: ExpireKind::Ex,
+            value: RAW_RETENTION_DAYS * 86400,
+        })
+    );
+}
+
+/// Counts a conversation flagged for review by [`crate::sentiment`].
+pub fn record_frustration() {
+    let key = day_key(today());
+    let mut day = get(&key)
+        .and_then(|v| serde_json::from_value::<DayRecord>(v).ok())
+        .unwrap_or_default();
+    day.frustrated += 1;
+    guards::set_capped(
+        &key,
+        serde_json::to_value(&day).unwrap_or_default(),
+        Some(Expire {
+            kind: ExpireKind::Ex,
+            value: RAW_RETENTION_DAYS * 86400,
+        })
+    );
+}
+
+/// Keeps the question among the latest [`MAX_QUESTIONS`] on-topic questions.
+pub fn record_question(question: &str) {
+    let mut questions = recent_questions();
+    questions.push(question.trim().to_string());
+    if questions.len() > MAX_QUESTIONS {
+        questions.drain(..questions.len() - MAX_QUESTIONS);
+    }
+    guards::set_capped(QUESTIONS_KEY, json!(questions), None);
+}
+
+/// The latest on-topic questions, oldest first.
+pub fn recent_questions() -> Vec<String> {
+    get(QUESTIONS_KEY)
+        .and_then(|v| serde_json::from_value::<Vec<String>>(v).ok())
+        .unwrap_or_default()
+}
+
+/// Aggregates the day records of the last [`MAX_ROLLUP_DAYS`] days into the daily series served by
+/// `?action=analytics`. Meant to be run by a scheduler hitting `?action=analytics_rollup`, and
+/// run on demand when the stored rollup is over an hour old.
+pub fn rollup() -> Value {
+    let today = today();
+    let series = (0..MAX_ROLLUP_DAYS as i64)
+        .rev()
+        .map(|ago| today - ago)
+        .filter_map(|days| {
+            let day = serde_json::from_value::<DayRecord>(get(&day_key(days))?).ok()?;
+            Some(
+                json!({
+                    "date": date_of_day(days),
+                    "questions": day.questions,
+                    "unique_chats": day.chats.len(),
+                    "avg_confidence": match day.confidence_count {
+                        0 => Value::Null,
+                        n => json!(day.confidence_sum / (n as f64)),
+                    },
+                    "error_rate": match day.questions {
+                        0 => 0.0,
+                        n => (day.errors as f64) / (n as f64),
+                    },
+                    "categories": day.categories,
+                    "frustrated": day.frustrated,
+                    "channels": day.channels
+                        .iter()
+                        .map(|(name, c)| {
+                            (
+                                name.clone(),
+                                json!({
+                                    "questions": c.questions,
+                                    "avg_confidence": match c.confidence_count {
+                                        0 => Value::Null,
+                                        n => json!(c.confidence_sum / (n as f64)),
+                                    },
+                                    "error_rate": match c.questions {
+                                        0 => 0.0,
+                                        n => (c.errors as f64) / (n as f64),
+                                    },
+                                    "client_versions": c.client_versions,
+                                }),
+                            )
+                        })
+                        .collect::<Map<String, Value>>(),
+                })
+            )
+        })
+        .collect::<Vec<Value>>();
+
+    let rollup = json!({ "updated_at": now_secs(), "days": series });
+    guards::set_capped(ROLLUP_KEY, rollup.clone(), None);
+    rollup
+}
+
+/// The daily series of the last `days` days, with the top categories over the range.
+pub fn report(days: u64) -> Value {
+    let rollup = match get(ROLLUP_KEY) {
+        Some(r) if now_secs().saturating_sub(r["updated_at"].as_u64().unwrap_or_default()) < 3600 => r,
+        _ => rollup(),
+    };
+
+    let since = date_of_day(today() - (days.min(MAX_ROLLUP_DAYS) as i64) + 1);
+    let series = rollup["days"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d["date"].as_str().unwrap_or_default() >= since.as_str())
+        .collect::<Vec<Value>>();
+
+    let mut totals: Vec<(String, u64)> = Vec::new();
+    for d in &series {
+        for (name, count) in d["categories"].as_object().into_iter().flatten() {
+            let count = count.as_u64().unwrap_or_default();
+            match totals.iter_mut().find(|(n, _)| n == name) {
+                Some(t) => {
+                    t.1 += count;
+                }
+                None => totals.push((name.clone(), count)),
+            }
+        }
+    }
+    totals.sort_by_key(|t| std::cmp::Reverse(t.1));
+
+    let mut channels: BTreeMap<String, u64> = BTreeMap::new();
+    for d in &series {
+        for (name, c) in d["channels"].as_object().into_iter().flatten() {
+            *channels.entry(name.clone()).or_default() += c["questions"].as_u64().unwrap_or_default();
+        }
+    }
+
+    json!({
+        "updated_at": rollup["updated_at"],
+        "days": series,
+        "top_categories": totals
+            .into_iter()
+            .map(|(name, count)| json!({ "category": name, "count": count }))
+            .collect::<Vec<Value>>(),
+        "channels": channels,
+        "flagged_for_review": sentiment::flagged(),
+    })
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use serde_json::Value;
+
+use crate::{ deterministic, error::{ Error, Result }, retry };
+
+/// A shape the answer is asked to take, checked once the model replies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnswerShape {
+    /// A JSON object or array, alone or in one fenced block.
+    Json,
+    /// A markdown table.
+    Table,
+    /// Numbered steps, `1.`, `2.` and so on.
+    Steps,
+}
+
+impl AnswerShape {
+    /// The shape requested with `?answer_shape=` or the `x-answer-shape` header (`json`, `table` or
+    /// `steps`), the query parameter taking precedence.
+    pub fn requested(qry: &HashMap<String, Value>, headers: &[(String, String)]) -> Result<Option<Self>> {
+        let shape = qry
+            .get("answer_shape")
+            .and_then(|v| v.as_str())
+            .or_else(|| {
+                headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case("x-answer-shape"))
+                    .map(|(_, v)| v.as_str())
+            })
+            .map(|s| s.trim().to_lowercase());
+        match shape.as_deref() {
+            None | Some("") => Ok(None),
+            Some("json") => Ok(Some(AnswerShape::Json)),
+            Some("table") => Ok(Some(AnswerShape::Table)),
+            Some("steps") => Ok(Some(AnswerShape::Steps)),
+            Some(s) => Err(Error::InvalidInput(format!("unknown answer shape `{s}`, use json, table or steps"))),
+        }
+    }
+
+    /// The instruction added to the post prompt.
+    pub fn instruction(&self) -> &'static str {
+        match self {
+            AnswerShape::Json => "Reply with valid JSON only, an object or an array, without any text around it.",
+            AnswerShape::Table =>
+                "Reply with a markdown table: a header row, a separator row of dashes, then one row per item, every row with the same number of columns.",
+            AnswerShape::Steps => "Reply with numbered steps, one per line, starting at `1.`.",
+        }
+    }
+
+    /// What is wrong with the answer for the shape, `None` when it fits.
+    pub fn violation(&self, answer: &str) -> Option<String> {
+        match self {
+            AnswerShape::Json =>
+                match serde_json::from_str::<Value>(unfenced(answer)) {
+                    Ok(Value::Object(_) | Value::Array(_)) => None,
+                    Ok(_) => Some("the JSON is not an object or an array".to_string()),
+                    Err(e) => Some(format!("the answer is not valid JSON: {e}")),
+                }
+            AnswerShape::Table => table_violation(answer),
+            AnswerShape::Steps => steps_violation(answer),
+        }
+    }
+}
+
+/// The answer without the code fence the model may wrap JSON in.
+fn unfenced(answer: &str) -> &str {
+    let trimmed = answer.trim();
+    match trimmed.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+            rest.strip_suffix("```").unwrap_or(rest).trim()
+        }
+        None => trimmed,
+    }
+}
+
+fn cells(line: &str) -> Vec<&str> {
+    line.trim().trim_start_matches('|').trim_end_matches('|').split('|').map(str::trim).collect()
+}
+
+fn table_violation(answer: &str) -> Option<String> {
+    let lines = answer
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| !l.contains('|'))
+        .take_while(|l| l.contains('|'))
+        .collect::<Vec<&str>>();
+    if lines.len() < 3 {
+        return Some("there is no markdown table with a header, a separator and at least one row".to_string());
+    }
+    let columns = cells(lines[0]).len();
+    let separator = cells(lines[1]);
+    let dashes = |c: &&str| {
+        let c = c.trim_matches(':');
+        c.len() >= 3 && c.chars().all(|ch| ch == '-')
+    };
+    if separator.len() != columns || !separator.iter().all(dashes) {
+        return Some(format!("the second line of the table is not a separator of {columns} `---` cells"));
+    }
+    lines[2..]
+        .iter()
+        .enumerate()
+        .find(|(_, l)| cells(l).len() != columns)
+        .map(|(i, l)| format!("row {} has {} cells instead of {columns}", i + 1, cells(l).len()))
+}
+
+fn steps_violation(answer: &str) -> Option<String> {
+    let numbers = answer
+        .lines()
+        .filter_map(|l| {
+            let l = l.trim_start();
+            let digits = l.find(|c: char| !c.is_ascii_digit())?;
+            match digits > 0 && (l[digits..].starts_with(". ") || l[digits..].starts_with(") ")) {
+                true => l[..digits].parse::<usize>().ok(),
+                false => None,
+            }
+        })
+        .collect::<Vec<usize>>();
+    if numbers.len() < 2 {
+        return Some("there are not at least two numbered steps".to_string());
+    }
+    numbers
+        .iter()
+        .enumerate()
+        .find(|(i, n)| **n != i + 1)
+        .map(|(i, n)| format!("step {} is numbered {n}, steps must be numbered 1, 2, 3 in order", i + 1))
+}
+
+/// Checks the answer against the shape and, when it does not fit, asks the model once to fix it
+/// with the violation. Falls back to the answer as it is when the repair fails or does not fit
+/// either.
+pub async fn enforce(shape: AnswerShape, answer: String, llm: &LLMServiceFlows<'_>, model: &str) -> String {
+    let Some(violation) = shape.violation(&answer) else {
+        return answer;
+    };
+    log::warn!("The answer does not fit the {:?} shape ({}), asking for a repair", shape, violation);
+
+    let sys_prompt = format!("You fix the format of answers without changing what they say. {}", shape.instruction());
+    let usr_prompt = format!("This answer is invalid because {violation}. Rewrite it in the required format:\n\n{answer}");
+    let co = ChatOptions {
+        model: Some(model),
+        restart: true,
+        system_prompt: Some(sys_prompt.as_str()),
+        token_limit: 4096,
+        max_tokens: Some(1024),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+    match retry::call("repair", || llm.chat_completion("answer-repair", &usr_prompt, &co)).await {
+        Ok(r) =>
+            match shape.violation(&r.choice) {
+                None => r.choice,
+                Some(v) => {
+                    log::warn!("The repaired answer does not fit the {:?} shape either ({}), sending it as it was", shape, v);
+                    answer
+                }
+            }
+        Err(e) => {
+            log::error!("Cannot repair the format of the answer: {}", e);
+            answer
+        }
+    }
+}
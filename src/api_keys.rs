@@ -0,0 +1,276 @@
+use rand::{ distributions::Alphanumeric, Rng };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{ analytics::now_secs, error::{ Error, Result }, guards, roles::Role };
+
+const STORED_KEY: &str = "api_keys";
+
+/// An API key bound to a role, from the `api_keys` setting or added with `?action=api_keys`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Who holds the key, logged in place of the key.
+    pub name: String,
+    /// The key itself, only in the setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The hex SHA-256 of the key, what the KV store keeps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_sha256: Option<String>,
+    pub role: Role,
+    /// Chat messages the key may send per UTC day, unlimited when unset.
+    #[serde(default)]
+    pub quota_per_day: Option<u64>,
+    /// A disabled key is refused like an unknown one.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl ApiKey {
+    fn matches(&self, key: &str) -> bool {
+        match (&self.key, &self.key_sha256) {
+            (Some(k), _) => same_secret(k, key),
+            (None, Some(h)) => constant_time_eq(h.as_bytes(), hash(key).as_bytes()),
+            (None, None) => false,
+        }
+    }
+}
+
+/// Whether two byte strings are equal, taking the same time wherever they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() &&
+        a
+            .iter()
+            .zip(b)
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether a secret sent by a caller is `expected`, comparing their SHA-256 digests in constant
+/// time so neither the content nor the length of the secret leaks through timing.
+pub fn same_secret(expected: &str, sent: &str) -> bool {
+    constant_time_eq(&Sha256::digest(expected.as_bytes()), &Sha256::digest(sent.as_bytes()))
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The keys of the `api_keys` JSON array, e.g.
+/// `[{"name": "support-team", "key": "...", "role": "operator", "quota_per_day": 500}]`.
+fn configured() -> Vec<ApiKey> {
+    let setting = std::env::var("api_keys").unwrap_or("".to_string());
+    if setting.trim().is_empty() {
+        return Vec::new();
+    }
+    serde_json::from_str(&setting).unwrap_or_else(|e| {
+        log::error!("Invalid api_keys: {}", e);
+        Vec::new()
+    })
+}
+
+fn stored() -> Vec<ApiKey> {
+    get(STORED_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Every key, those of the setting first. A stored key with the name of a configured one
+/// replaces it, which is how configured keys are disabled or given a quota at runtime.
+pub fn all() -> Vec<ApiKey> {
+    let stored = stored();
+    let mut keys = configured()
+        .into_iter()
+        .map(|k| {
+            match stored.iter().find(|s| s.name == k.name && s.key_sha256.is_none()) {
+                Some(s) => ApiKey { key: k.key, ..s.clone() },
+                None => k,
+            }
+        })
+        .collect::<Vec<ApiKey>>();
+    let names = keys
+        .iter()
+        .map(|k| k.name.clone())
+        .collect::<Vec<String>>();
+    keys.extend(stored.into_iter().filter(|s| !names.contains(&s.name)));
+    keys
+}
+
+/// The enabled key matching `key`.
+pub fn find(key: &str) -> Option<ApiKey> {
+    all()
+        .into_iter()
+        .find(|k| !k.disabled && k.matches(key))
+}
+
+/// What `?action=api_keys` takes: a key to add, or a change to the key of `name`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct KeyUpdate {
+    pub name: String,
+    pub role: Option<Role>,
+    /// `0` removes the quota.
+    pub quota_per_day: Option<u64>,
+    pub disabled: Option<bool>,
+}
+
+/// Adds a key, generating it, or updates the role, quota or state of an existing one. Returns
+/// the key with the generated secret, which is shown this once.
+pub fn upsert(update: &KeyUpdate) -> Result<Value> {
+    let name = update.name.trim();
+    if name.is_empty() {
+        return Err(Error::InvalidInput("the key needs a name".to_string()));
+    }
+    let mut stored = stored();
+    let existing = all()
+        .into_iter()
+        .find(|k| k.name == name);
+
+    let mut secret = None;
+    let mut key = match existing {
+        Some(k) => ApiKey { key: None, ..k },
+        None => {
+            let generated = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(40)
+                .map(char::from)
+                .collect::<String>();
+            let key = ApiKey {
+                name: name.to_string(),
+                key: None,
+                key_sha256: Some(hash(&generated)),
+                role: update.role.ok_or(Error::InvalidInput("a new key needs a role".to_string()))?,
+                quota_per_day: None,
+                disabled: false,
+            };
+            secret = Some(generated);
+            key
+        }
+    };
+    if let Some(role) = update.role {
+        key.role = role;
+    }
+    if let Some(quota) = update.quota_per_day {
+        key.quota_per_day = (quota > 0).then_some(quota);
+    }
+    if let Some(disabled) = update.disabled {
+        key.disabled = disabled;
+    }
+
+    stored.retain(|k| k.name != name);
+    stored.push(key.clone());
+    guards::set_capped(STORED_KEY, serde_json::to_value(&stored)?, None);
+    log::info!("API key {} updated", name);
+
+    let mut shown = describe(&key);
+    if let Some(secret) = secret {
+        shown["key"] = json!(secret);
+    }
+    Ok(shown)
+}
+
+/// A key without its secret, with today's usage.
+pub fn describe(key: &ApiKey) -> Value {
+    json!({
+        "name": key.name,
+        "role": key.role,
+        "quota_per_day": key.quota_per_day,
+        "used_today": used_today(&key.name),
+        "disabled": key.disabled,
+        "configured": key.key.is_some(),
+    })
+}
+
+/// The keys without their secrets, for `?action=api_keys`.
+pub fn list() -> Value {
+    json!(all().iter().map(describe).collect::<Vec<Value>>())
+}
+
+fn usage_key(name: &str) -> String {
+    format!("api_key_usage:{}:{}", name, now_secs() / 86400)
+}
+
+fn used_today(name: &str) -> u64 {
+    get(&usage_key(name))
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default()
+}
+
+/// Counts a chat message against the daily quota of the key, `Err` with the seconds until the
+/// quota resets once it is used up.
+pub fn charge(name: &str) -> std::result::Result<(), u64> {
+    let Some(quota) = all()
+        .into_iter()
+        .find(|k| k.name == name)
+        .and_then(|k| k.quota_per_day) else {
+        return Ok(());
+    };
+    let used = used_today(name);
+    if used >= quota {
+        return Err(86400 - (now_secs() % 86400));
+    }
+    guards::set_capped(&usage_key(name), json!(used + 1), Some(Expire { kind: ExpireKind::Ex, value: 2 * 86400 }));
+    Ok(())
+}
+
+/// Whether chat messages need a key, with `require_api_key` set to `true`.
+pub fn required() -> bool {
+    std::env::var("require_api_key").unwrap_or_default() == "true"
+}
+
+/// Refuses `require_api_key` without any enabled key, which would turn every chat message away.
+pub fn check_settings() -> Result<()> {
+    if required() && all().iter().all(|k| k.disabled) {
+        return Err(
+            Error::InvalidInput(
+                "`require_api_key` is on but no API key is enabled, add one to `api_keys` or with `?action=api_keys`".to_string()
+            )
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Option<&str>, key_sha256: Option<&str>) -> ApiKey {
+        ApiKey {
+            name: "support".to_string(),
+            key: key.map(String::from),
+            key_sha256: key_sha256.map(String::from),
+            role: Role::Operator,
+            quota_per_day: None,
+            disabled: false,
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_content_and_length() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn same_secret_needs_the_whole_secret() {
+        assert!(same_secret("s3cret", "s3cret"));
+        assert!(!same_secret("s3cret", "s3cre"));
+        assert!(!same_secret("s3cret", ""));
+    }
+
+    #[test]
+    fn keys_match_by_value_or_hash() {
+        assert_eq!(hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(key(Some("abc"), None).matches("abc"));
+        assert!(!key(Some("abc"), None).matches("abd"));
+        assert!(key(None, Some(&hash("abc"))).matches("abc"));
+        assert!(!key(None, Some(&hash("abc"))).matches("abd"));
+        assert!(!key(None, None).matches(""));
+    }
+}
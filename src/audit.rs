@@ -0,0 +1,164 @@
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ analytics::{ now_secs, Category }, chat_id, encryption, first_x_chars, guards, ingest, origin, roles::Role };
+
+const AUDIT_KEY: &str = "audit_log";
+/// The oldest entries are dropped once the log holds this many.
+const MAX_ENTRIES: usize = 200;
+
+fn load() -> Vec<Value> {
+    get(AUDIT_KEY)
+        .and_then(|v| v.as_array().cloned())
+        .unwrap_or_default()
+}
+
+/// Where an answer came from: the knowledge base version of the collection when answering and
+/// the chunks used with the version they were ingested in.
+pub fn provenance(collection_name: &str, chunks: Value) -> Value {
+    json!({
+        "collection": collection_name,
+        "kb_version": ingest::kb_version(collection_name),
+        "chunks": chunks,
+    })
+}
+
+/// Logs an answered (or failed) question with its provenance.
+pub fn record(chat_id: &str, question: &str, category: Category, confidence: Option<f32>, provenance: &Value, error: bool) {
+    let mut log = load();
+    log.push(
+        json!({
+            "at": now_secs(),
+            "chat_id": chat_id,
+            "question": encryption::seal(&first_x_chars(question, 200)),
+            "category": category.name(),
+            "confidence": confidence,
+            "error": error,
+            "provenance": provenance,
+            "origin": origin::current(),
+        })
+    );
+    if log.len() > MAX_ENTRIES {
+        log.drain(..log.len() - MAX_ENTRIES);
+    }
+    guards::set_capped(AUDIT_KEY, json!(log), None);
+}
+
+/// Sets `field` on the latest answer logged for the chat, e.g. the scores of the answer judge.
+pub fn attach(chat_id: &str, field: &str, value: Value) {
+    let mut log = load();
+    let latest = log
+        .iter_mut()
+        .rev()
+        .find(|e| e.get("denied").is_none() && e["chat_id"].as_str() == Some(chat_id));
+    if let Some(e) = latest {
+        e[field] = value;
+        guards::set_capped(AUDIT_KEY, json!(log), None);
+    }
+}
+
+/// Logs a command or an action refused for lack of a role, `chat_id` being empty for actions.
+pub fn denied(chat_id: &str, caller: &str, role: Role, what: &str, required: Role) {
+    let mut log = load();
+    log.push(
+        json!({
+            "at": now_secs(),
+            "chat_id": chat_id,
+            "caller": caller,
+            "role": role,
+            "denied": what,
+            "required": required,
+            "origin": origin::current(),
+        })
+    );
+    if log.len() > MAX_ENTRIES {
+        log.drain(..log.len() - MAX_ENTRIES);
+    }
+    guards::set_capped(AUDIT_KEY, json!(log), None);
+}
+
+/// Moves the entries of the chat `from` to the chat `into`, returning how many are (or with
+/// `dry_run` would be) moved.
+pub fn reassign(from: &str, into: &str, dry_run: bool) -> usize {
+    let mut log = load();
+    let mut moved = 0;
+    for e in log.iter_mut().filter(|e| e["chat_id"].as_str() == Some(from)) {
+        e["chat_id"] = json!(into);
+        moved += 1;
+    }
+    if !dry_run && moved > 0 {
+        guards::set_capped(AUDIT_KEY, json!(log), None);
+    }
+    moved
+}
+
+/// Deletes or anonymizes the entries logged before `cutoff`, returning how many are (or with
+/// `dry_run` would be) changed.
+pub fn apply_retention(cutoff: u64, anonymize: bool, dry_run: bool) -> usize {
+    let mut log = load();
+    let old = |e: &Value| e["at"].as_u64().unwrap_or_default() < cutoff;
+    let affected = log
+        .iter()
+        .filter(|e| old(e) && !(anonymize && e["anonymized"].as_bool().unwrap_or_default()))
+        .count();
+    if dry_run || affected == 0 {
+        return affected;
+    }
+
+    match anonymize {
+        true => {
+            for e in log.iter_mut().filter(|e| old(e)) {
+                e["chat_id"] = json!("");
+                e["question"] = Value::Null;
+                if let Some(origin) = e["origin"].as_object_mut() {
+                    origin.remove("user_agent");
+                }
+                e["anonymized"] = json!(true);
+            }
+        }
+        false => log.retain(|e| !old(e)),
+    }
+    guards::set_capped(AUDIT_KEY, json!(log), None);
+    affected
+}
+
+/// The latest answers, newest first, optionally of one chat only, with their question decrypted.
+pub fn entries(limit: usize, chat_id: Option<&str>) -> Vec<Value> {
+    load()
+        .into_iter()
+        .rev()
+        .filter(|e| e.get("denied").is_none())
+        .filter(|e| chat_id.is_none_or(|c| e["chat_id"].as_str() == Some(c)))
+        .take(limit)
+        .map(|mut e| {
+            if let Some(question) = e["question"].as_str() {
+                e["question"] = json!(encryption::open(question));
+            }
+            e
+        })
+        .collect()
+}
+
+/// The latest refusals, newest first.
+pub fn denials(limit: usize) -> Vec<Value> {
+    load()
+        .into_iter()
+        .rev()
+        .filter(|e| e.get("denied").is_some())
+        .take(limit)
+        .collect()
+}
+
+/// The latest entries as served by `?action=audit`, with the conversation name of their chat.
+pub fn recent(limit: usize, chat_id: Option<&str>) -> Value {
+    let named = |mut e: Value| {
+        if let Some(name) = e["chat_id"].as_str().and_then(chat_id::name_of) {
+            e["conversation_name"] = json!(name);
+        }
+        e
+    };
+    json!({
+        "entries": entries(limit, chat_id).into_iter().map(named).collect::<Vec<Value>>(),
+        "denied": denials(limit).into_iter().map(named).collect::<Vec<Value>>(),
+    })
+}
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use vector_store_flows::*;
+
+use crate::{
+    collection_config::CollectionConfig,
+    error::{ Error, Result },
+    ingest::{ self, Document, IngestOptions },
+};
+
+/// Whether a vector store error says the collection does not exist. The vector store only gives
+/// the message, e.g. "Collection `k8s` doesn't exist!" or "Not found".
+pub fn is_missing(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("doesn't exist") || message.contains("does not exist") || message.contains("not found")
+}
+
+/// Whether collections that are searched before any ingestion are created empty, with
+/// `auto_create_collections` set to `true`.
+fn auto_create() -> bool {
+    std::env::var("auto_create_collections").unwrap_or_default() == "true"
+}
+
+async fn create(collection_name: &str) -> Result<()> {
+    let p = CollectionCreateParams { vector_size: CollectionConfig::load(collection_name).vector_size };
+    create_collection(collection_name, &p).await.map_err(|e| Error::vector_store("create", collection_name, e))
+}
+
+/// Handles a search of a collection that does not exist: creates it empty when
+/// `auto_create_collections` is on, so the search finds nothing instead of failing, else fails
+/// with [`Error::MissingCollection`].
+pub async fn on_missing(collection_name: &str) -> Result<()> {
+    if !auto_create() {
+        log::error!(
+            "Collection {} does not exist. Ingest documents into it with `?action=ingest` or `/bootstrap`, or set auto_create_collections=true to start it empty.",
+            collection_name
+        );
+        return Err(Error::MissingCollection(collection_name.to_string()));
+    }
+    create(collection_name).await?;
+    log::warn!("Created the missing collection {} empty, answers have no knowledge base until it is ingested", collection_name);
+    Ok(())
+}
+
+/// The note shown to operators above answers given without the missing collection.
+pub fn note(collection_name: &str) -> String {
+    format!(
+        "_The knowledge base `{collection_name}` does not exist yet, so this answer does not use it. An admin can create and seed it with `/bootstrap`._"
+    )
+}
+
+/// An entry of `bootstrap_documents`: a URL to fetch, or a document.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Seed {
+    Url(String),
+    Document(Document),
+}
+
+/// The documents of `bootstrap_documents`, a JSON array of URLs and documents, e.g.
+/// `["https://kubernetes.io/docs/concepts/overview/", {"text": "...", "source": "runbook.md"}]`.
+fn configured_seeds() -> Result<Vec<Seed>> {
+    let setting = std::env::var("bootstrap_documents").unwrap_or("".to_string());
+    if setting.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(&setting).map_err(|e| Error::InvalidInput(format!("invalid bootstrap_documents: {e}")))
+}
+
+/// `/bootstrap`: creates the collection when it does not exist and seeds it from the URLs given,
+/// else from `bootstrap_documents`. A collection that already has points is left alone, it is
+/// ingested into with `?action=ingest`.
+pub async fn run(collection_name: &str, urls: &[String]) -> Result<String> {
+    let exists = match collection_info(collection_name).await {
+        Ok(info) if info.points_count > 0 => {
+            return Ok(
+                format!("`{collection_name}` already has {} points, add documents with `?action=ingest` instead.", info.points_count)
+            );
+        }
+        Ok(_) => true,
+        Err(e) if is_missing(&e.to_string()) => false,
+        Err(e) => {
+            return Err(Error::vector_store("info", collection_name, e));
+        }
+    };
+
+    let seeds = match urls.is_empty() {
+        true => configured_seeds()?,
+        false => urls.iter().cloned().map(Seed::Url).collect(),
+    };
+    let mut documents = Vec::new();
+    let mut failed = Vec::new();
+    for seed in seeds {
+        match seed {
+            Seed::Document(d) => documents.push(d),
+            Seed::Url(url) =>
+                match ingest::fetch_page(&url) {
+                    Ok(d) => documents.push(d),
+                    Err(e) => {
+                        log::error!("Cannot fetch {} to bootstrap {}: {}", url, collection_name, e);
+                        failed.push(url);
+                    }
+                }
+        }
+    }
+    documents.retain(|d| !d.text.trim().is_empty());
+
+    if !exists {
+        create(collection_name).await?;
+    }
+    let mut progress = |e: ingest::Progress| log::debug!("Bootstrap {}: {} {}", collection_name, e.name(), e.data());
+    let chunks = ingest::chunk_documents(documents, &IngestOptions::default(), &mut progress);
+    let written = ingest::embed_and_upsert(collection_name, chunks, &mut progress).await?;
+
+    let created = match exists {
+        true => format!("`{collection_name}` was empty"),
+        false => format!("Created `{collection_name}`"),
+    };
+    let mut reply = match written {
+        0 => format!("{created}, no documents to seed it with. Give URLs (`/bootstrap {collection_name} <url>...`), set `bootstrap_documents` or use `?action=ingest`."),
+        n => format!("{created} and seeded it with {n} chunks."),
+    };
+    if !failed.is_empty() {
+        reply.push_str(&format!(" Could not fetch: {}.", failed.join(", ")));
+    }
+    Ok(reply)
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use vector_store_flows::*;
+
+use crate::{ error::{ Error, Result }, fetch, ingest };
+
+/// Points read or deleted per vector store call.
+const BATCH: u64 = 100;
+/// Ids of matching points listed in the report.
+const MAX_LISTED: usize = 50;
+
+extern "C" {
+    fn get_flows_user(p: *mut u8) -> i32;
+    fn get_flow_id(p: *mut u8) -> i32;
+}
+
+/// A point read back by id, without its vector.
+#[derive(Deserialize)]
+struct StoredPoint {
+    id: PointId,
+    payload: Option<Map<String, Value>>,
+}
+
+fn host_string(read: unsafe extern "C" fn(*mut u8) -> i32) -> String {
+    let mut buf = Vec::<u8>::with_capacity(100);
+    unsafe {
+        let len = read(buf.as_mut_ptr());
+        buf.set_len(len.max(0) as usize);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+// vector-store-flows has no calls to read or delete points by id, so these call the vector store
+// the way it calls `upsertPoints` and `searchPoints`
+fn points_url(collection_name: &str, route: &str) -> String {
+    let prefix = option_env!("VECTOR_STORE_API_PREFIX").unwrap_or("https://vector-store.flows.network/api");
+    format!("{prefix}/{}/{}/{collection_name}/{route}", host_string(get_flows_user), host_string(get_flow_id))
+}
+
+fn get_points(collection_name: &str, ids: &[PointId]) -> Result<Vec<StoredPoint>> {
+    let body = fetch::post_json(&points_url(collection_name, "getPoints"), &[], &serde_json::to_vec(ids)?)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn delete_points(collection_name: &str, ids: &[PointId]) -> Result<()> {
+    fetch::post_json(&points_url(collection_name, "deletePoints"), &[], &serde_json::to_vec(ids)?)?;
+    Ok(())
+}
+
+/// The points to delete, those matching every field given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct DeleteFilter {
+    /// The `source` the points were ingested from.
+    pub source: Option<String>,
+    /// Points ingested before this time (Unix seconds).
+    pub ingested_before: Option<u64>,
+    /// Points of this knowledge base version.
+    pub kb_version: Option<u64>,
+    /// Other payload fields the points must have, with these values.
+    pub payload: Map<String, Value>,
+}
+
+impl DeleteFilter {
+    /// The filter of the JSON body, else of the `source`, `ingested_before` and `kb_version` query
+    /// parameters. A filter matching every point is refused.
+    pub fn from_request(qry: &HashMap<String, Value>, body: &[u8]) -> Result<Self> {
+        let filter = match body.is_empty() {
+            false => serde_json::from_slice::<DeleteFilter>(body)?,
+            true => {
+                let number = |name: &str| -> Result<Option<u64>> {
+                    match qry.get(name).and_then(|v| v.as_str()) {
+                        Some(v) => v.parse().map(Some).map_err(|_| Error::InvalidInput(format!("`{name}` must be a number"))),
+                        None => Ok(None),
+                    }
+                };
+                DeleteFilter {
+                    source: qry.get("source").and_then(|v| v.as_str()).map(String::from),
+                    ingested_before: number("ingested_before")?,
+                    kb_version: number("kb_version")?,
+                    payload: Map::new(),
+                }
+            }
+        };
+        match filter.source.is_none() && filter.ingested_before.is_none() && filter.kb_version.is_none() && filter.payload.is_empty() {
+            true => Err(Error::InvalidInput("give `source`, `ingested_before`, `kb_version` or `payload` to match points by".to_string())),
+            false => Ok(filter),
+        }
+    }
+
+    fn matches(&self, payload: &Map<String, Value>) -> bool {
+        self.source.as_ref().is_none_or(|s| payload.get("source").and_then(|v| v.as_str()) == Some(s)) &&
+            self.ingested_before.is_none_or(|t| payload.get("ingested_at").and_then(|v| v.as_u64()).is_some_and(|at| at < t)) &&
+            self.kb_version.is_none_or(|v| payload.get("kb_version").and_then(|k| k.as_u64()) == Some(v)) &&
+            self.payload.iter().all(|(k, v)| payload.get(k) == Some(v))
+    }
+}
+
+/// The ids of the points of the collection whose payload matches, and how many points were read.
+/// The vector store cannot filter, so every id ingestion handed out is read back in batches.
+async fn matching(collection_name: &str, matches: impl Fn(&Map<String, Value>) -> bool) -> Result<(Vec<u64>, u64)> {
+    let info = collection_info(collection_name).await.map_err(|e| Error::vector_store("info", collection_name, e))?;
+    let last_id = ingest::last_id(collection_name, info.points_count);
+
+    let mut ids = Vec::new();
+    let mut scanned = 0;
+    let mut from = 1;
+    while from <= last_id {
+        let batch = (from..=last_id.min(from + BATCH - 1)).map(PointId::Num).collect::<Vec<PointId>>();
+        let points = get_points(collection_name, &batch).map_err(|e| Error::vector_store("get", collection_name, e))?;
+        scanned += points.len() as u64;
+        for p in points {
+            if let (PointId::Num(id), Some(payload)) = (p.id, &p.payload) {
+                if matches(payload) {
+                    ids.push(id);
+                }
+            }
+        }
+        from += BATCH;
+    }
+    Ok((ids, scanned))
+}
+
+async fn delete(collection_name: &str, ids: &[u64]) -> Result<usize> {
+    let mut deleted = 0;
+    for batch in ids.chunks(BATCH as usize) {
+        let batch_ids = batch
+            .iter()
+            .map(|id| PointId::Num(*id))
+            .collect::<Vec<PointId>>();
+        delete_points(collection_name, &batch_ids).map_err(|e| Error::vector_store("delete", collection_name, e))?;
+        deleted += batch.len();
+    }
+    Ok(deleted)
+}
+
+/// Deletes the points of the collection matching the filter, or only counts them on a dry run.
+pub async fn run(collection_name: &str, filter: &DeleteFilter, dry_run: bool) -> Result<Value> {
+    let (ids, scanned) = matching(collection_name, |payload| filter.matches(payload)).await?;
+
+    let mut deleted = 0;
+    if !dry_run {
+        deleted = delete(collection_name, &ids).await?;
+        if deleted > 0 {
+            ingest::bump_version(collection_name);
+        }
+        log::info!("Deleted {} points of {} matching {:?}", deleted, collection_name, filter);
+    }
+
+    Ok(
+        json!({
+            "collection": collection_name,
+            "filter": filter,
+            "dry_run": dry_run,
+            "scanned": scanned,
+            "matched": ids.len(),
+            "deleted": deleted,
+            "ids": &ids[..ids.len().min(MAX_LISTED)],
+            "kb_version": ingest::kb_version(collection_name),
+        })
+    )
+}
+
+/// Deletes the points of the collection whose payload matches, returning how many were deleted.
+/// The knowledge base version is left to the caller, which usually just ingested a new one.
+pub async fn delete_where(collection_name: &str, matches: impl Fn(&Map<String, Value>) -> bool) -> Result<usize> {
+    let (ids, _) = matching(collection_name, matches).await?;
+    delete(collection_name, &ids).await
+}
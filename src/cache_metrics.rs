@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::guards;
+
+const METRICS_KEY: &str = "cache_metrics";
+
+/// The counters of one cache since they were last reset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries written.
+    pub stores: u64,
+    /// The bytes of the entries written, what the cache costs in the KV store.
+    pub bytes_stored: u64,
+    /// Entries not written, too large for the KV store.
+    pub rejected: u64,
+    /// Entries the cache dropped itself, e.g. unreadable ones. Expiries by the KV store are not seen.
+    pub evictions: u64,
+}
+
+/// The counters of every cache used since they were reset, by cache name.
+pub fn all() -> BTreeMap<String, CacheStats> {
+    get(METRICS_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn update(cache: &str, f: impl FnOnce(&mut CacheStats)) {
+    let mut metrics = all();
+    f(metrics.entry(cache.to_string()).or_default());
+    guards::set_capped(METRICS_KEY, serde_json::to_value(&metrics).unwrap_or_default(), None);
+}
+
+/// Counts the lookups of `cache`, `hits` of them found.
+pub fn lookups(cache: &str, hits: u64, misses: u64) {
+    if hits + misses > 0 {
+        update(cache, |s| {
+            s.hits += hits;
+            s.misses += misses;
+        });
+    }
+}
+
+/// Counts an entry of `bytes` written to `cache`, or rejected when it is over the KV store limit.
+pub fn stored(cache: &str, bytes: usize) {
+    let rejected = bytes > guards::max_store_value_bytes();
+    update(cache, |s| {
+        match rejected {
+            true => {
+                s.rejected += 1;
+            }
+            false => {
+                s.stores += 1;
+                s.bytes_stored += bytes as u64;
+            }
+        }
+    });
+}
+
+/// Counts the entries `cache` dropped.
+pub fn evicted(cache: &str, entries: u64) {
+    if entries > 0 {
+        update(cache, |s| {
+            s.evictions += entries;
+        });
+    }
+}
+
+pub fn stats(cache: &str) -> CacheStats {
+    all().remove(cache).unwrap_or_default()
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => (self.hits as f64) / (n as f64),
+        }
+    }
+
+    /// One line for the chat and `/diag`.
+    pub fn summary(&self) -> String {
+        format!(
+            "{:.0}% hit rate over {} lookups, {} entries stored ({} KB), {} rejected, {} evicted",
+            self.hit_rate() * 100.0,
+            self.hits + self.misses,
+            self.stores,
+            self.bytes_stored / 1024,
+            self.rejected,
+            self.evictions
+        )
+    }
+}
+
+/// Every cache with its counters and hit rate, for `?action=metrics`.
+pub fn report() -> Value {
+    all()
+        .into_iter()
+        .map(|(name, s)| {
+            let hit_rate = s.hit_rate();
+            let mut v = serde_json::to_value(s).unwrap_or_default();
+            v["hit_rate"] = json!(hit_rate);
+            (name, v)
+        })
+        .collect::<serde_json::Map<String, Value>>()
+        .into()
+}
+
+/// The counters of every cache, as markdown for `/metrics`.
+pub fn markdown() -> String {
+    let metrics = all();
+    match metrics.is_empty() {
+        true => "No cache has been used since the counters were reset.".to_string(),
+        false => metrics
+            .iter()
+            .map(|(name, s)| format!("- **{}**: {}", name, s.summary()))
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+/// Starts the counters of every cache over.
+pub fn reset() {
+    store_flows::del(METRICS_KEY);
+}
+
+/// Starts the counters of one cache over.
+pub fn reset_cache(cache: &str) {
+    let mut metrics = all();
+    if metrics.remove(cache).is_some() {
+        guards::set_capped(METRICS_KEY, serde_json::to_value(&metrics).unwrap_or_default(), None);
+    }
+}
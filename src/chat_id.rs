@@ -0,0 +1,269 @@
+use regex::Regex;
+use serde_json::json;
+use sha2::{ Digest, Sha256 };
+use store_flows::{ del, get };
+
+use crate::{ first_x_chars, guards, merge };
+
+/// Longest chat id of the `truncated` scheme.
+const MAX_CHARS: usize = 48;
+/// The sanitized name kept before the hash in the `hashed` scheme.
+const PREFIX_CHARS: usize = 32;
+
+/// How conversation names are turned into the chat ids their data is kept under, from the
+/// `chat_id_scheme` setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// The first 48 characters of the name with everything but letters and digits replaced by
+    /// `-`, the default. Long names sharing their start and names differing only in punctuation
+    /// share a chat id.
+    Truncated,
+    /// Names that are already safe and short are kept as they are, others become a sanitized
+    /// prefix and a hash of the whole name, e.g. `Slack-T024BE7LD-C-a1b2c3d4e5f60718`.
+    Hashed,
+}
+
+impl Scheme {
+    pub fn from_env() -> Self {
+        match std::env::var("chat_id_scheme").unwrap_or_default().as_str() {
+            "hashed" => Scheme::Hashed,
+            _ => Scheme::Truncated,
+        }
+    }
+
+    pub fn normalize(&self, name: &str) -> String {
+        match self {
+            Scheme::Truncated => first_x_chars(&sanitized(name), MAX_CHARS),
+            Scheme::Hashed => {
+                let safe = sanitized(name);
+                if safe == name && name.chars().count() <= MAX_CHARS {
+                    return safe;
+                }
+                // a name turning into another name is told apart by the hash
+                let hash = Sha256::digest(name.as_bytes())
+                    .iter()
+                    .take(8)
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                format!("{}-{hash}", first_x_chars(&safe, PREFIX_CHARS))
+            }
+        }
+    }
+}
+
+fn sanitized(name: &str) -> String {
+    let regex_pattern = Regex::new(r"[^a-zA-Z0-9]").unwrap();
+    regex_pattern.replace_all(name, "-").to_string()
+}
+
+/// The chat id of a conversation name with the configured scheme.
+pub fn normalize(name: &str) -> String {
+    Scheme::from_env().normalize(name)
+}
+
+/// Longest conversation name kept, in characters.
+const MAX_NAME_CHARS: usize = 200;
+
+fn name_key(chat_id: &str) -> String {
+    format!("conversation_name:{chat_id}")
+}
+
+fn shared_key(chat_id: &str) -> String {
+    format!("conversation_name_shared:{chat_id}")
+}
+
+/// Keeps the conversation name the chat id was made from, for listings and digests to show
+/// instead of the chat id. Only written when it changes, which means several names share the
+/// chat id, as is flagged for [`migrate`].
+pub fn remember_name(chat_id: &str, name: &str) {
+    let name = first_x_chars(name.trim(), MAX_NAME_CHARS);
+    if chat_id.is_empty() || name.is_empty() {
+        return;
+    }
+    match name_of(chat_id) {
+        Some(known) if known == name => {
+            return;
+        }
+        Some(_) if get(&shared_key(chat_id)).is_none() => guards::set_capped(&shared_key(chat_id), json!(true), None),
+        _ => {}
+    }
+    guards::set_capped(&name_key(chat_id), json!(name), None);
+}
+
+/// The conversation name of the chat id, `None` for chats not seen since names were kept.
+pub fn name_of(chat_id: &str) -> Option<String> {
+    get(&name_key(chat_id)).and_then(|v| v.as_str().map(String::from))
+}
+
+/// The conversation name of the chat id, else the chat id.
+pub fn display_name(chat_id: &str) -> String {
+    name_of(chat_id).unwrap_or(chat_id.to_string())
+}
+
+/// Preferences and state kept per chat under `<prefix>:<chat id>`, moved as they are. The memory
+/// and history mirror are merged instead, see [`merge::conversations`].
+const PREFERENCE_KEYS: &[&str] = &[
+    "units",
+    "model",
+    "collection",
+    "conversation_prompt",
+    "conversation_post_prompt",
+    "greeted",
+    "conversation_ttl",
+    "conversation_ttl_active",
+    "conversation_summary",
+    "last_active",
+    "reminders",
+    "sentiment",
+    "continue",
+    "trace",
+    "short_query",
+    "rate_limit:chat",
+];
+
+/// Moves the value of `old` to `new` unless `new` already has one.
+fn move_key(old: &str, new: &str) {
+    if let Some(v) = get(old) {
+        if get(new).is_none() {
+            guards::set_capped(new, v, None);
+        }
+        del(old);
+    }
+}
+
+fn migrated_key(chat_id: &str) -> String {
+    format!("chat_id_migrated:{chat_id}")
+}
+
+/// What becomes of the data under the `truncated` chat id of a name when it gets its `hashed` one.
+#[derive(Debug, PartialEq)]
+enum Migration {
+    /// The name already was its chat id.
+    Unchanged,
+    /// The old chat id was the name's alone, its data moves.
+    Move(String),
+    /// Other names shared the old chat id, or it is not known which name it was made from, so its
+    /// data is left where it is rather than handed to whichever name comes first.
+    Keep(String),
+}
+
+/// `remembered` is the last name seen under the old chat id and `shared` whether other names
+/// were seen under it too.
+fn plan(name: &str, chat_id: &str, remembered: Option<&str>, shared: bool) -> Migration {
+    let legacy = Scheme::Truncated.normalize(name);
+    if legacy == chat_id {
+        return Migration::Unchanged;
+    }
+    match remembered {
+        Some(n) if !shared && n == first_x_chars(name.trim(), MAX_NAME_CHARS) => Migration::Move(legacy),
+        _ => Migration::Keep(legacy),
+    }
+}
+
+/// Moves what was kept under the `truncated` chat id of the name to its `hashed` one, the first
+/// time the conversation is seen after switching schemes: the memory, history mirror and audit
+/// entries are merged into the new chat id, still empty, and the preferences moved. The
+/// conversation with the model starts over, the LLM service keeps it under the old id. An old
+/// chat id only moves when it is known to be the name's alone.
+pub fn migrate(name: &str, chat_id: &str) {
+    if Scheme::from_env() != Scheme::Hashed || get(&migrated_key(chat_id)).is_some() {
+        return;
+    }
+    let legacy = Scheme::Truncated.normalize(name);
+    match plan(name, chat_id, name_of(&legacy).as_deref(), get(&shared_key(&legacy)).is_some()) {
+        Migration::Unchanged => {}
+        Migration::Keep(legacy) => {
+            log::warn!("Not migrating chat id {} to {}, it may hold the data of other conversations", legacy, chat_id);
+        }
+        Migration::Move(legacy) => {
+            match merge::conversations(&legacy, chat_id, false) {
+                Ok(report) => log::info!("Migrated chat id {} to {}: {}", legacy, chat_id, report),
+                Err(e) => {
+                    log::error!("Cannot migrate chat id {} to {}: {}", legacy, chat_id, e);
+                    return;
+                }
+            }
+            for prefix in PREFERENCE_KEYS {
+                move_key(&format!("{prefix}:{legacy}"), &format!("{prefix}:{chat_id}"));
+            }
+            // the restart flag is kept under the bare chat id
+            move_key(&legacy, chat_id);
+        }
+    }
+    guards::set_capped(&migrated_key(chat_id), json!(true), None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_keeps_48_sanitized_characters() {
+        let name = format!("slack/{}", "a".repeat(60));
+        let id = Scheme::Truncated.normalize(&name);
+        assert_eq!(id.chars().count(), MAX_CHARS);
+        assert!(id.starts_with("slack-aaa"));
+        assert_eq!(Scheme::Truncated.normalize("team.alpha"), "team-alpha");
+    }
+
+    #[test]
+    fn hashed_keeps_safe_short_names() {
+        assert_eq!(Scheme::Hashed.normalize("team-alpha"), "team-alpha");
+        let long = "b".repeat(60);
+        let id = Scheme::Hashed.normalize(&long);
+        assert!(id.starts_with(&"b".repeat(PREFIX_CHARS)));
+        assert_eq!(id.len(), PREFIX_CHARS + 1 + 16);
+    }
+
+    #[test]
+    fn truncated_collisions_are_told_apart_by_hashed() {
+        let prefix = "c".repeat(MAX_CHARS);
+        let pairs = [
+            ("team.alpha".to_string(), "team/alpha".to_string()),
+            (format!("{prefix}-one"), format!("{prefix}-two")),
+        ];
+        for (a, b) in &pairs {
+            assert_eq!(Scheme::Truncated.normalize(a), Scheme::Truncated.normalize(b));
+            assert_ne!(Scheme::Hashed.normalize(a), Scheme::Hashed.normalize(b));
+        }
+    }
+
+    #[test]
+    fn migration_moves_only_an_old_id_of_the_name_alone() {
+        let name = "team.alpha";
+        let id = Scheme::Hashed.normalize(name);
+        let legacy = "team-alpha".to_string();
+        assert_eq!(plan(name, &id, Some(name), false), Migration::Move(legacy.clone()));
+        // another name was seen under the old id, or it is the other name's
+        assert_eq!(plan(name, &id, Some(name), true), Migration::Keep(legacy.clone()));
+        assert_eq!(plan(name, &id, Some("team/alpha"), false), Migration::Keep(legacy.clone()));
+        // seen before names were kept
+        assert_eq!(plan(name, &id, None, false), Migration::Keep(legacy));
+    }
+
+    #[test]
+    fn migration_leaves_names_that_were_their_id() {
+        assert_eq!(plan("team-alpha", "team-alpha", None, false), Migration::Unchanged);
+    }
+
+    #[test]
+    fn migration_covers_every_per_chat_key() {
+        let merged = ["memory", "history"];
+        let key = regex::Regex::new(r#"format!\("([a-z_:]+):\{chat_id\}"\)"#).unwrap();
+        let src = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        for entry in std::fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().is_some_and(|n| n == "chat_id.rs") {
+                continue;
+            }
+            let code = std::fs::read_to_string(&path).unwrap();
+            for prefix in key.captures_iter(&code).map(|c| c[1].to_string()) {
+                assert!(
+                    PREFERENCE_KEYS.contains(&prefix.as_str()) || merged.contains(&prefix.as_str()),
+                    "`{prefix}:{{chat_id}}` in {} is not migrated",
+                    path.display()
+                );
+            }
+        }
+    }
+}
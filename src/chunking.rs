@@ -0,0 +1,345 @@
+use serde_json::Value;
+
+use crate::{ error::{ Error, Result }, settings, tokenizer };
+
+/// The smallest chunk size in characters a request may ask for, smaller sizes cost an embedding
+/// for every few characters.
+pub const MIN_CHUNK_CHARS: usize = 100;
+
+/// The smallest chunk size in tokens a request may ask for with [`ChunkerKind::Tokens`].
+pub const MIN_CHUNK_TOKENS: usize = 25;
+
+/// Checks a requested chunk size against `min` and that the overlap is smaller than the size.
+pub fn check_size(size: usize, overlap: usize, min: usize) -> Result<()> {
+    if size < min {
+        return Err(Error::InvalidInput(format!("`chunk_size` must be at least {min}, got {size}")));
+    }
+    if overlap >= size {
+        return Err(Error::InvalidInput(format!("`chunk_overlap` must be smaller than `chunk_size`, got {overlap} and {size}")));
+    }
+    Ok(())
+}
+
+/// Splits text into the pieces that are embedded one by one.
+pub trait Chunker {
+    fn chunk(&self, text: &str) -> Vec<String>;
+}
+
+/// Cuts every `size` characters, repeating `overlap` characters between chunks.
+pub struct FixedSize {
+    pub size: usize,
+    pub overlap: usize,
+}
+
+/// Chunks of at most `size` characters, breaking on paragraph and then sentence boundaries where
+/// possible, with `overlap` characters repeated between chunks.
+pub struct SentenceBoundary {
+    pub size: usize,
+    pub overlap: usize,
+}
+
+/// Chunks of at most `tokens` tokens that only break between words, with `overlap` tokens
+/// repeated between chunks. Words are counted one by one, which can count a token more than the
+/// chunk encodes to at a word boundary.
+pub struct TokenCount {
+    pub tokens: usize,
+    pub overlap: usize,
+}
+
+/// Walks the text in windows of `size`, letting `cut` move the end of a window back, and starts
+/// the next window `overlap` characters before the end of the last one.
+fn split_chars(text: &str, size: usize, overlap: usize, cut: impl Fn(&str) -> Option<usize>) -> Vec<String> {
+    let chars = text.trim().chars().collect::<Vec<char>>();
+    let size = size.max(1);
+    let overlap = overlap.min(size / 2);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        let mut end = (start + size).min(chars.len());
+        if end < chars.len() {
+            let window = chars[start..end].iter().collect::<String>();
+            if let Some(i) = cut(&window).filter(|i| *i > size / 2) {
+                end = start + window[..i].chars().count() + 1;
+            }
+        }
+
+        let chunk = chars[start..end].iter().collect::<String>().trim().to_string();
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        if end == chars.len() {
+            break;
+        }
+        start = (end - overlap).max(start + 1);
+    }
+
+    chunks
+}
+
+impl Chunker for FixedSize {
+    fn chunk(&self, text: &str) -> Vec<String> {
+        split_chars(text, self.size, self.overlap, |_| None)
+    }
+}
+
+impl Chunker for SentenceBoundary {
+    fn chunk(&self, text: &str) -> Vec<String> {
+        split_chars(text, self.size, self.overlap, |window| {
+            window.rfind("\n\n").or_else(|| window.rfind(". "))
+        })
+    }
+}
+
+impl Chunker for TokenCount {
+    fn chunk(&self, text: &str) -> Vec<String> {
+        let words = text.split_inclusive(char::is_whitespace).collect::<Vec<&str>>();
+        // every word counted once, with the encoding resolved once
+        let counts = tokenizer::counter().count_each(&words);
+        let max = self.tokens.max(1);
+        let overlap = self.overlap.min(max / 2);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+
+        while start < words.len() {
+            // a word longer than the limit still makes a chunk of its own
+            let mut end = start + 1;
+            let mut tokens = counts[start];
+            while end < words.len() && tokens + counts[end] <= max {
+                tokens += counts[end];
+                end += 1;
+            }
+
+            let chunk = words[start..end].concat().trim().to_string();
+            if !chunk.is_empty() {
+                chunks.push(chunk);
+            }
+            if end == words.len() {
+                break;
+            }
+
+            let mut next = end;
+            let mut repeated = 0;
+            while next > start + 1 && repeated + counts[next - 1] <= overlap {
+                repeated += counts[next - 1];
+                next -= 1;
+            }
+            start = next;
+        }
+
+        chunks
+    }
+}
+
+/// The chunkers selectable with the `chunker` query parameter or setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkerKind {
+    Fixed,
+    Sentence,
+    Tokens,
+}
+
+impl ChunkerKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "fixed" => Some(Self::Fixed),
+            "sentence" => Some(Self::Sentence),
+            "tokens" => Some(Self::Tokens),
+            _ => None,
+        }
+    }
+
+    /// From the `chunker` query parameter, else the `chunker` setting, else sentence boundaries.
+    pub fn from_query(value: Option<&Value>) -> Self {
+        value
+            .and_then(|v| v.as_str())
+            .and_then(Self::parse)
+            .or_else(|| {
+                std::env
+                    ::var("chunker")
+                    .ok()
+                    .and_then(|s| Self::parse(&s))
+            })
+            .unwrap_or(Self::Sentence)
+    }
+
+    /// The default size and overlap, in characters or for [`ChunkerKind::Tokens`] in tokens.
+    pub fn default_size(&self) -> (usize, usize) {
+        match self {
+            Self::Tokens => (375, 40),
+            _ => (1500, 150),
+        }
+    }
+
+    /// The smallest size a request may ask for, in the unit of [`ChunkerKind::default_size`].
+    pub fn min_size(&self) -> usize {
+        match self {
+            Self::Tokens => MIN_CHUNK_TOKENS,
+            _ => MIN_CHUNK_CHARS,
+        }
+    }
+
+    pub fn build(&self, size: usize, overlap: usize) -> Box<dyn Chunker> {
+        match self {
+            Self::Fixed => Box::new(FixedSize { size, overlap }),
+            Self::Sentence => Box::new(SentenceBoundary { size, overlap }),
+            Self::Tokens => Box::new(TokenCount { tokens: size, overlap }),
+        }
+    }
+}
+
+/// The text under one Markdown heading, with the headings above it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    /// The headings from the top level down to this section's, e.g. `["Chapter 3", "Networking",
+    /// "Services"]`. Empty for the text before the first heading.
+    pub path: Vec<String>,
+    pub text: String,
+}
+
+impl Section {
+    /// The heading path as stored in the payload, e.g. `Chapter 3 > Networking > Services`.
+    pub fn heading_path(&self) -> Option<String> {
+        match self.path.is_empty() {
+            true => None,
+            false => Some(self.path.join(" > ")),
+        }
+    }
+}
+
+/// The level and title of an ATX heading line, `## Services` being `(2, "Services")`.
+fn heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    match (1..=6).contains(&level) && rest.starts_with([' ', '\t']) {
+        true => Some((level, rest.trim().trim_end_matches('#').trim().to_string())),
+        false => None,
+    }
+}
+
+/// The lines of the text outside fenced code blocks, where a `#` starts a heading and not a
+/// shell comment.
+fn heading_lines(text: &str) -> impl Iterator<Item = (usize, Option<(usize, String)>)> + '_ {
+    let mut fenced = false;
+    text.lines()
+        .enumerate()
+        .map(move |(i, line)| {
+            if line.trim_start().starts_with("```") {
+                fenced = !fenced;
+                return (i, None);
+            }
+            (i, (!fenced).then(|| heading(line)).flatten())
+        })
+}
+
+/// Whether the text reads as Markdown with sections: at least two headings outside code blocks.
+pub fn is_markdown(text: &str) -> bool {
+    heading_lines(text)
+        .filter(|(_, h)| h.is_some())
+        .nth(1)
+        .is_some()
+}
+
+/// Splits Markdown on its headings, each section keeping the path of headings above it. Empty
+/// sections, a heading directly followed by another, are left out.
+pub fn markdown_sections(text: &str) -> Vec<Section> {
+    let lines = text.lines().collect::<Vec<&str>>();
+    let mut sections = Vec::new();
+    let mut path: Vec<(usize, String)> = Vec::new();
+    let mut start = 0;
+    let mut push = |path: &[(usize, String)], from: usize, to: usize| {
+        let text = lines[from..to].join("\n").trim().to_string();
+        if !text.is_empty() {
+            sections.push(Section {
+                path: path
+                    .iter()
+                    .map(|(_, t)| t.clone())
+                    .collect(),
+                text,
+            });
+        }
+    };
+    for (i, h) in heading_lines(text).collect::<Vec<_>>() {
+        if let Some((level, title)) = h {
+            push(&path, start, i);
+            path.retain(|(l, _)| *l < level);
+            path.push((level, title));
+            start = i + 1;
+        }
+    }
+    push(&path, start, lines.len());
+    sections
+}
+
+/// Splits a QA pair stored in the conversation memory, `memory_chunk_tokens` (default 375)
+/// tokens per chunk with 40 tokens of overlap.
+pub fn memory_chunker() -> TokenCount {
+    let tokens = settings::setting("memory_chunk_tokens", 375);
+    TokenCount { tokens, overlap: 40 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_size_needs_the_minimum_and_a_smaller_overlap() {
+        assert!(check_size(50, 0, MIN_CHUNK_CHARS).is_err());
+        assert!(check_size(200, 200, MIN_CHUNK_CHARS).is_err());
+        assert!(check_size(200, 50, MIN_CHUNK_CHARS).is_ok());
+        assert!(check_size(MIN_CHUNK_TOKENS, 0, ChunkerKind::Tokens.min_size()).is_ok());
+    }
+
+    #[test]
+    fn fixed_size_repeats_the_overlap() {
+        let chunks = (FixedSize { size: 10, overlap: 2 }).chunk("abcdefghijklmnopqrst");
+        assert_eq!(chunks, vec!["abcdefghij", "ijklmnopqr", "qrst"]);
+        let chunks = (FixedSize { size: 10, overlap: 0 }).chunk("abcdefghijklmnopqrst");
+        assert_eq!(chunks, vec!["abcdefghij", "klmnopqrst"]);
+    }
+
+    #[test]
+    fn sentence_boundary_breaks_after_a_sentence() {
+        let chunks = (SentenceBoundary { size: 30, overlap: 0 }).chunk("First sentence here. Second one is longer.");
+        assert_eq!(chunks, vec!["First sentence here.", "Second one is longer."]);
+    }
+
+    #[test]
+    fn token_count_stays_under_the_limit() {
+        let text = "replicas ".repeat(200);
+        let chunks = (TokenCount { tokens: 20, overlap: 5 }).chunk(&text);
+        assert!(chunks.len() > 1);
+        let counts = tokenizer::counter().count_each(&chunks);
+        assert!(counts.iter().all(|n| *n <= 20), "{counts:?}");
+    }
+
+    #[test]
+    fn chunker_kinds_parse_and_size() {
+        assert_eq!(ChunkerKind::parse(" Tokens "), Some(ChunkerKind::Tokens));
+        assert_eq!(ChunkerKind::parse("words"), None);
+        assert_eq!(ChunkerKind::Tokens.min_size(), MIN_CHUNK_TOKENS);
+        assert_eq!(ChunkerKind::Fixed.min_size(), MIN_CHUNK_CHARS);
+    }
+
+    #[test]
+    fn markdown_needs_two_headings_outside_code() {
+        assert!(is_markdown("# A\ntext\n## B\nmore"));
+        assert!(!is_markdown("# A\n```sh\n# a comment\n```\ntext"));
+        assert!(!is_markdown("#hashtag\n#another"));
+    }
+
+    #[test]
+    fn sections_keep_their_heading_path() {
+        let sections = markdown_sections("intro\n# A\na text\n## B\nb text\n# C\n# D\nd text");
+        let paths = sections
+            .iter()
+            .map(|s| (s.heading_path(), s.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![
+            (None, "intro"),
+            (Some("A".to_string()), "a text"),
+            (Some("A > B".to_string()), "b text"),
+            (Some("D".to_string()), "d text"),
+        ]);
+    }
+}
@@ -0,0 +1,76 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A document an answer drew on, with the positions of the chunks of it used.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Citation {
+    pub source: Option<String>,
+    pub title: Option<String>,
+    pub chunk_indexes: Vec<u64>,
+}
+
+/// Whether answers get a "Sources" section, on unless the `citations` setting is `off`.
+pub fn enabled() -> bool {
+    std::env::var("citations").unwrap_or_default() != "off"
+}
+
+/// The documents of the knowledge base chunks in the provenance of an answer, in the order they
+/// were first used. Chunks ingested without a source or title cannot be cited.
+pub fn from_provenance(provenance: &Value) -> Vec<Citation> {
+    let mut citations: Vec<Citation> = Vec::new();
+    for c in provenance["chunks"].as_array().into_iter().flatten() {
+        let source = c["source"].as_str().map(String::from);
+        let title = c["title"].as_str().map(String::from);
+        if source.is_none() && title.is_none() {
+            continue;
+        }
+        let citation = match citations.iter_mut().find(|d| d.source == source && d.title == title) {
+            Some(d) => d,
+            None => {
+                citations.push(Citation { source, title, chunk_indexes: Vec::new() });
+                citations.last_mut().expect("just pushed")
+            }
+        };
+        if let Some(i) = c["chunk_index"].as_u64() {
+            if !citation.chunk_indexes.contains(&i) {
+                citation.chunk_indexes.push(i);
+            }
+        }
+    }
+    citations
+}
+
+/// The "Sources" section appended to the answer, `None` without citable documents.
+pub fn section(citations: &[Citation]) -> Option<String> {
+    if citations.is_empty() {
+        return None;
+    }
+
+    let mut lines = vec!["**Sources**".to_string()];
+    for (n, c) in citations.iter().enumerate() {
+        let name = match (&c.title, &c.source) {
+            (Some(t), Some(s)) if t != s => format!("{t} ({s})"),
+            (Some(t), _) => t.clone(),
+            (None, Some(s)) => s.clone(),
+            (None, None) => continue,
+        };
+        let mut indexes = c.chunk_indexes.clone();
+        indexes.sort();
+        let chunks = match indexes.len() {
+            0 => String::new(),
+            1 => format!(", part {}", indexes[0] + 1),
+            _ =>
+                format!(
+                    ", parts {}",
+                    indexes
+                        .iter()
+                        .map(|i| (i + 1).to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+        };
+        lines.push(format!("{}. {name}{chunks}", n + 1));
+    }
+    Some(lines.join("\n"))
+}
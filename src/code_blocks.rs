@@ -0,0 +1,101 @@
+use regex::Regex;
+
+use crate::settings;
+
+/// A code block or manifest lifted out of the source material.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    /// `yaml`, `shell` or the info string of a fenced block, empty when unknown.
+    pub language: String,
+    pub code: String,
+}
+
+/// Separates code from prose: fenced blocks, YAML manifests and paragraphs of shell commands are
+/// returned as [`CodeBlock`]s and removed from the returned prose.
+pub fn extract_code(text: &str) -> (String, Vec<CodeBlock>) {
+    let fenced = Regex::new(r"(?s)```([\w+-]*)[^\n]*\n(.*?)```").unwrap();
+
+    let mut blocks = Vec::new();
+    for c in fenced.captures_iter(text) {
+        let code = c[2].trim().to_string();
+        if !code.is_empty() {
+            let language = match c[1].is_empty() {
+                true => guess_language(&code),
+                false => c[1].to_lowercase(),
+            };
+            blocks.push(CodeBlock { language, code });
+        }
+    }
+    let unfenced = fenced.replace_all(text, "\n\n");
+
+    let mut prose = Vec::new();
+    for paragraph in unfenced.split("\n\n") {
+        let language = guess_language(paragraph);
+        match language.is_empty() {
+            true => prose.push(paragraph),
+            false => blocks.push(CodeBlock { language, code: paragraph.trim().to_string() }),
+        }
+    }
+
+    (prose.join("\n\n"), blocks)
+}
+
+/// Tells YAML manifests and shell commands apart from prose, returning an empty string for prose.
+fn guess_language(paragraph: &str) -> String {
+    let lines = paragraph
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .collect::<Vec<&str>>();
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    let key_value = Regex::new(r"^\s*(- )?[\w./-]+:(\s|$)").unwrap();
+    let list_item = Regex::new(r"^\s*- \S").unwrap();
+    let is_manifest = lines
+        .iter()
+        .any(|l| l.starts_with("apiVersion:") || l.starts_with("kind:"));
+    let yaml_lines = lines
+        .iter()
+        .filter(|l| key_value.is_match(l) || list_item.is_match(l))
+        .count();
+    if is_manifest && yaml_lines * 2 >= lines.len() {
+        return "yaml".to_string();
+    }
+
+    let command = Regex::new(r"^\s*(\$ |kubectl |helm |docker |minikube |kubeadm |curl )").unwrap();
+    if lines.iter().all(|l| command.is_match(l) || l.starts_with("  ")) && command.is_match(lines[0]) {
+        return "shell".to_string();
+    }
+
+    String::new()
+}
+
+/// Whether the question asks for a manifest or a command rather than an explanation.
+pub fn is_code_request(question: &str) -> bool {
+    let re = Regex::new(
+        r"(?i)\b(manifest|yaml|yml|kubectl|helm|command|cli|snippet|example\s+(file|config)|give\s+me\s+(a|an|the)\s+\w+\s+(for|to)|how\s+do\s+i\s+(create|apply|deploy|run|expose|scale))\b"
+    ).unwrap();
+    re.is_match(question)
+}
+
+/// How retrieval treats code points for questions that ask for code, from `code_retrieval`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodeRetrieval {
+    /// Add `code_boost` (default 0.1) to the score of code points.
+    Boost(f32),
+    /// Only use code points.
+    Exclusive,
+}
+
+impl CodeRetrieval {
+    pub fn from_env() -> Self {
+        match std::env::var("code_retrieval").unwrap_or_default().as_str() {
+            "exclusive" => CodeRetrieval::Exclusive,
+            _ =>
+                CodeRetrieval::Boost(
+                    settings::setting::<f32>("code_boost", 0.1)
+                ),
+        }
+    }
+}
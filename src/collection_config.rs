@@ -0,0 +1,196 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use store_flows::{ del, get };
+
+use crate::{
+    embedder::{ self, Embedder, LlmServiceEmbedder, LocalEmbedder, OpenAIEmbedder },
+    embedding_cache,
+    error::{ Error, Result },
+    guards,
+    loadtest,
+    retrieval,
+};
+
+/// Where the vectors of a collection come from. Vectors of different providers are not
+/// comparable, so a collection must be searched with the provider it was ingested with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingProvider {
+    /// OpenAI through flows.network. The model is fixed by the platform (text-embedding-ada-002).
+    OpenAI,
+    /// An OpenAI compatible endpoint through the LLM service, `llm_endpoint` by default.
+    LlmService,
+    /// An OpenAI style `/embeddings` endpoint called directly, `embedding_endpoint` by default.
+    Local,
+}
+
+/// Embedding and retrieval settings of one collection, stored in the KV store under
+/// `collection_config:<name>`. Collections without a record use the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct CollectionConfig {
+    pub provider: EmbeddingProvider,
+    /// Embedding model name passed to the LLM service or local endpoint.
+    pub model: Option<String>,
+    /// Overrides `llm_endpoint` for the LLM service provider, `embedding_endpoint` for the local
+    /// one.
+    pub endpoint: Option<String>,
+    /// Minimum similarity score of a point to be used as context.
+    pub score_threshold: f32,
+    pub vector_size: u64,
+    /// Disclosure appended to answers from this collection, `disclosure_footer` when unset.
+    pub disclosure_footer: Option<String>,
+    /// Whether the vector search results are also ranked by keyword matches with the question.
+    pub keyword_search: bool,
+    /// Answers sampled and voted on per question, `consensus_samples` when unset.
+    pub consensus_samples: Option<u32>,
+    /// Weight of the hypothetical answer search against the question search, see
+    /// [`retrieval::search_factors`]. `0.5` when unset.
+    pub hyde_weight: Option<f32>,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            provider: embedder::default_provider(),
+            model: None,
+            endpoint: None,
+            score_threshold: retrieval::default_score_threshold(),
+            vector_size: embedder::default_vector_size(),
+            disclosure_footer: None,
+            keyword_search: true,
+            consensus_samples: None,
+            hyde_weight: None,
+        }
+    }
+}
+
+impl CollectionConfig {
+    fn key(collection_name: &str) -> String {
+        format!("collection_config:{collection_name}")
+    }
+
+    pub fn load(collection_name: &str) -> Self {
+        match get(&Self::key(collection_name)) {
+            Some(v) =>
+                serde_json::from_value(v).unwrap_or_else(|e| {
+                    log::error!("Invalid config for collection {}: {}", collection_name, e);
+                    Self::default()
+                }),
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, collection_name: &str) {
+        guards::set_capped(&Self::key(collection_name), serde_json::to_value(self).unwrap_or_default(), None);
+    }
+
+    /// Embeds the texts with the provider of the collection, one vector per text. Texts embedded
+    /// before by the same provider, model and endpoint come from the embedding cache.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if let Some(vectors) = loadtest::embed(texts) {
+            return Ok(vectors);
+        }
+        if !embedding_cache::enabled() {
+            return self.embed_uncached(texts).await;
+        }
+        let embedder = format!(
+            "{:?}|{}|{}",
+            self.provider,
+            self.model.as_deref().unwrap_or_default(),
+            self.endpoint.as_deref().unwrap_or_default()
+        );
+        let mut vectors = embedding_cache::lookup(&embedder, texts);
+        let missing = texts
+            .iter()
+            .zip(&vectors)
+            .filter(|(_, v)| v.is_none())
+            .map(|(t, _)| t.clone())
+            .collect::<Vec<String>>();
+        if !missing.is_empty() {
+            let mut embedded = self.embed_uncached(&missing).await?.into_iter();
+            for (text, vector) in texts.iter().zip(vectors.iter_mut()) {
+                if vector.is_none() {
+                    let v = embedded.next().unwrap_or_default();
+                    embedding_cache::store(&embedder, text, &v);
+                    *vector = Some(v);
+                }
+            }
+        }
+        Ok(vectors.into_iter().flatten().collect())
+    }
+
+    /// Embeds the texts with the provider of the collection, bypassing the cache.
+    pub async fn embed_uncached(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let (endpoint, model) = (self.endpoint.as_deref(), self.model.as_deref());
+        match self.provider {
+            EmbeddingProvider::OpenAI => OpenAIEmbedder.embed(texts).await,
+            EmbeddingProvider::LlmService => LlmServiceEmbedder::new(endpoint, model).embed(texts).await,
+            EmbeddingProvider::Local => LocalEmbedder::new(endpoint, model).embed(texts).await,
+        }
+    }
+
+    pub async fn embed_one(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed(&[text.to_string()]).await?
+            .pop()
+            .ok_or(Error::Embedding("no embedding returned".to_string()))
+    }
+}
+
+/// The knowledge base collections clients may select, from the comma separated
+/// `allowed_collections` setting. `default` is always allowed.
+pub fn allowed(default: &str) -> Vec<String> {
+    let mut allowed = std::env
+        ::var("allowed_collections")
+        .unwrap_or("".to_string())
+        .split(',')
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<String>>();
+    if !allowed.iter().any(|c| c == default) {
+        allowed.push(default.to_string());
+    }
+    allowed
+}
+
+fn chat_key(chat_id: &str) -> String {
+    format!("collection:{chat_id}")
+}
+
+/// The collection the chat switched to with `/collection`, unless it is no longer allowed.
+pub fn for_chat(chat_id: &str, default: &str) -> Option<String> {
+    let collection = get(&chat_key(chat_id)).and_then(|v| v.as_str().map(String::from))?;
+    allowed(default).contains(&collection).then_some(collection)
+}
+
+/// Switches the chat to an allowed collection, `default` switching it back.
+pub fn save_for_chat(chat_id: &str, collection: &str, default: &str) -> Result<()> {
+    if collection == "default" || collection == default {
+        del(&chat_key(chat_id));
+        return Ok(());
+    }
+    match allowed(default).iter().any(|c| c == collection) {
+        true => {
+            guards::set_capped(&chat_key(chat_id), json!(collection), None);
+            Ok(())
+        }
+        false => Err(Error::InvalidInput(format!("collection `{collection}` is not allowed"))),
+    }
+}
+
+/// The collection selected with the `x-collection-name` header, else the one of the chat, else
+/// `default`. Collections missing from `allowed_collections` are an error rather than silently
+/// replaced.
+pub fn selected(headers: &[(String, String)], default: &str, chat_id: &str) -> Result<String> {
+    let requested = match headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("x-collection-name")) {
+        Some((_, v)) if !v.trim().is_empty() => v.trim(),
+        _ => {
+            return Ok(for_chat(chat_id, default).unwrap_or(default.to_string()));
+        }
+    };
+    match allowed(default).iter().any(|c| c == requested) {
+        true => Ok(requested.to_string()),
+        false => Err(Error::InvalidInput(format!("collection `{requested}` is not allowed"))),
+    }
+}
@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use serde_json::{ json, Value };
+use store_flows::set;
+use vector_store_flows::*;
+
+use crate::{
+    analytics,
+    bootstrap,
+    cache_metrics,
+    citations,
+    collection_config::{ self, CollectionConfig },
+    continuation,
+    conversation_ttl,
+    deterministic,
+    diagnostics,
+    feedback,
+    first_x_chars,
+    guards,
+    judge,
+    models,
+    overrides,
+    quarantine,
+    reminders,
+    retry,
+    roles::Role,
+    trace,
+    units::UnitStyle,
+    ContentSettings,
+    CHAT_MODEL,
+};
+
+/// Commands typed into the chat box in place of a question.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `/compare <sourceA> <sourceB>`
+    Compare {
+        source_a: String,
+        source_b: String,
+    },
+    /// `/why`, explains the previous answer.
+    Why,
+    /// `/helpful`, the previous answer was helpful.
+    Helpful,
+    /// `/continue`, the rest of an answer cut off by the token limit.
+    Continue,
+    /// `/units <kubernetes|decimal|binary|off>`
+    Units {
+        style: String,
+    },
+    /// `/model [name|default]`
+    Model {
+        name: Option<String>,
+    },
+    /// `/help [command]`
+    Help {
+        command: Option<String>,
+    },
+    /// `/sources`, the documents the previous answer drew on.
+    Sources,
+    /// `/forget`, erases what is stored about the conversation.
+    Forget,
+    /// `/set <setting> <value>`
+    Set {
+        name: String,
+        value: String,
+    },
+    /// `/stats`, the questions of the last week.
+    Stats,
+    /// `/metrics`, the counters of the caches and the averages of the answer judge.
+    Metrics,
+    /// `/collection [name|default]`
+    Collection {
+        name: Option<String>,
+    },
+    /// `/remind [<duration> <note>|cancel <id>]`, the pending reminders without arguments.
+    Remind {
+        args: String,
+    },
+    /// `/diag`, probes the stores and model endpoints.
+    Diag,
+    /// `/bootstrap [collection] [url...]`
+    Bootstrap {
+        collection: Option<String>,
+        urls: Vec<String>,
+    },
+}
+
+/// A command as registered for parsing, help and the greeting.
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub summary: &'static str,
+    /// The role needed to run it.
+    pub role: Role,
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        match self.args.is_empty() {
+            true => self.name.to_string(),
+            false => format!("{} {}", self.name, self.args),
+        }
+    }
+}
+
+/// Every command, in the order they are listed.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "/new", args: "", summary: "start the conversation over", role: Role::User },
+    CommandSpec {
+        name: "/compare",
+        args: "<sourceA> <sourceB>",
+        summary: "compare two ingested sources",
+        role: Role::User,
+    },
+    CommandSpec {
+        name: "/why",
+        args: "",
+        summary: "explain how the last answer was put together",
+        role: Role::User,
+    },
+    CommandSpec { name: "/helpful", args: "", summary: "mark the last answer as helpful", role: Role::User },
+    CommandSpec { name: "/continue", args: "", summary: "continue an answer that was cut off", role: Role::User },
+    CommandSpec {
+        name: "/units",
+        args: "<kubernetes|decimal|binary|off>",
+        summary: "choose how quantities are written",
+        role: Role::User,
+    },
+    CommandSpec {
+        name: "/model",
+        args: "[name|default]",
+        summary: "pin the conversation to a model, or show the allowed ones",
+        role: Role::User,
+    },
+    CommandSpec { name: "/help", args: "[command]", summary: "list the commands, or explain one", role: Role::User },
+    CommandSpec { name: "/sources", args: "", summary: "list the documents the last answer drew on", role: Role::User },
+    CommandSpec {
+        name: "/forget",
+        args: "",
+        summary: "erase the memory, history and trace of the conversation",
+        role: Role::User,
+    },
+    CommandSpec {
+        name: "/collection",
+        args: "[name|default]",
+        summary: "answer from another allowed knowledge base",
+        role: Role::User,
+    },
+    CommandSpec {
+        name: "/remind",
+        args: "[<duration> <note>|cancel <id>]",
+        summary: "bring a note back into the conversation later, or list the pending reminders",
+        role: Role::User,
+    },
+    CommandSpec { name: "/stats", args: "", summary: "questions, confidence and errors of the last week", role: Role::Operator },
+    CommandSpec { name: "/metrics", args: "", summary: "hits, misses and storage of the caches, and the answer judge's averages", role: Role::Operator },
+    CommandSpec {
+        name: "/set",
+        args: "<setting> <value|default>",
+        summary: "change a prompt or message setting without redeploying",
+        role: Role::Admin,
+    },
+    CommandSpec {
+        name: "/diag",
+        args: "",
+        summary: "check the KV store, the vector store and the model endpoints",
+        role: Role::Admin,
+    },
+    CommandSpec {
+        name: "/bootstrap",
+        args: "[collection] [url...]",
+        summary: "create a missing knowledge base and seed it from the URLs or `bootstrap_documents`",
+        role: Role::Admin,
+    },
+];
+
+/// The registered command of a name, with or without its slash.
+pub fn spec(name: &str) -> Option<&'static CommandSpec> {
+    let name = format!("/{}", name.trim_start_matches('/').to_ascii_lowercase());
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// The commands available to the role.
+pub fn available(role: Role) -> impl Iterator<Item = &'static CommandSpec> {
+    COMMANDS.iter().filter(move |c| c.role <= role)
+}
+
+/// The prefixes commands are typed with, from the comma separated `command_prefixes` (default
+/// `/`), e.g. `/,!` for `!new` too. Longest first, so `!!` wins over `!`.
+fn prefixes() -> Vec<String> {
+    let mut prefixes = std::env
+        ::var("command_prefixes")
+        .unwrap_or("/".to_string())
+        .split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<String>>();
+    if prefixes.is_empty() {
+        prefixes.push("/".to_string());
+    }
+    prefixes.sort_by_key(|p| std::cmp::Reverse(p.len()));
+    prefixes
+}
+
+/// Other names of commands, from the `command_aliases` JSON object, e.g.
+/// `{"reset": "new", "h": "help"}`.
+fn aliases() -> HashMap<String, String> {
+    let setting = std::env::var("command_aliases").unwrap_or("".to_string());
+    if setting.trim().is_empty() {
+        return HashMap::new();
+    }
+    match serde_json::from_str::<HashMap<String, String>>(&setting) {
+        Ok(aliases) =>
+            aliases
+                .into_iter()
+                .map(|(alias, name)| (alias.trim_start_matches('/').to_ascii_lowercase(), name))
+                .collect(),
+        Err(e) => {
+            log::error!("Invalid command_aliases: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// The text of a chat body, trimmed, or the `text`, `message` or `content` field of a JSON body.
+fn body_text(body: &str) -> String {
+    let body = body.trim();
+    if !body.starts_with('{') {
+        return body.to_string();
+    }
+    serde_json
+        ::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| {
+            ["text", "message", "content"]
+                .iter()
+                .find_map(|f| v.get(f).and_then(|t| t.as_str()).map(|t| t.trim().to_string()))
+        })
+        .unwrap_or(body.to_string())
+}
+
+/// The body as the registered command it types, e.g. `!reset` as `/new` with the prefix `!` and
+/// the alias `reset`, with its arguments. `None` when the body is not a command.
+pub fn normalize(body: &str) -> Option<String> {
+    let text = body_text(body);
+    let rest = prefixes()
+        .iter()
+        .find_map(|p| text.strip_prefix(p.as_str()))?
+        .to_string();
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest.as_str(), ""));
+    let name = name.to_ascii_lowercase();
+    let name = aliases().get(&name).cloned().unwrap_or(name);
+    let spec = spec(&name)?;
+    Some(format!("{} {}", spec.name, args.trim()).trim_end().to_string())
+}
+
+/// Whether the body is `/new` or one of its aliases, which restarts the conversation.
+pub fn is_restart(body: &str) -> bool {
+    normalize(body).as_deref() == Some("/new")
+}
+
+pub fn parse(body: &str) -> Option<Command> {
+    let text = normalize(body)?;
+    let text = text.as_str();
+    let mut parts = text.split_whitespace();
+
+    match spec(parts.next()?.strip_prefix('/')?)?.name {
+        "/compare" => {
+            let source_a = parts.next()?.to_string();
+            let source_b = parts.next()?.to_string();
+            Some(Command::Compare { source_a, source_b })
+        }
+        "/why" => Some(Command::Why),
+        "/helpful" => Some(Command::Helpful),
+        "/continue" => Some(Command::Continue),
+        "/units" => {
+            let style = parts.next().unwrap_or_default().to_string();
+            Some(Command::Units { style })
+        }
+        "/model" => Some(Command::Model { name: parts.next().map(String::from) }),
+        "/help" => Some(Command::Help { command: parts.next().map(String::from) }),
+        "/sources" => Some(Command::Sources),
+        "/forget" => Some(Command::Forget),
+        "/set" => {
+            // the value is the rest of the text, spaces and all
+            let mut rest = text.trim().splitn(3, char::is_whitespace).skip(1);
+            let name = rest.next()?.to_string();
+            let value = rest.next().unwrap_or_default().trim().to_string();
+            Some(Command::Set { name, value })
+        }
+        "/stats" => Some(Command::Stats),
+        "/metrics" => Some(Command::Metrics),
+        "/collection" => Some(Command::Collection { name: parts.next().map(String::from) }),
+        "/remind" => {
+            let args = text.trim().split_once(char::is_whitespace).map(|(_, rest)| rest.trim()).unwrap_or_default();
+            Some(Command::Remind { args: args.to_string() })
+        }
+        "/diag" => Some(Command::Diag),
+        "/bootstrap" => {
+            let mut args = parts.map(String::from).peekable();
+            let collection = args.next_if(|a| !a.starts_with("http://") && !a.starts_with("https://"));
+            Some(Command::Bootstrap { collection, urls: args.collect() })
+        }
+        _ => None,
+    }
+}
+
+impl Command {
+    pub fn spec(&self) -> &'static CommandSpec {
+        let name = match self {
+            Command::Compare { .. } => "/compare",
+            Command::Why => "/why",
+            Command::Helpful => "/helpful",
+            Command::Continue => "/continue",
+            Command::Units { .. } => "/units",
+            Command::Model { .. } => "/model",
+            Command::Help { .. } => "/help",
+            Command::Sources => "/sources",
+            Command::Forget => "/forget",
+            Command::Set { .. } => "/set",
+            Command::Stats => "/stats",
+            Command::Metrics => "/metrics",
+            Command::Collection { .. } => "/collection",
+            Command::Remind { .. } => "/remind",
+            Command::Diag => "/diag",
+            Command::Bootstrap { .. } => "/bootstrap",
+        };
+        spec(name).expect("every command is registered")
+    }
+}
+
+/// `/help`: the commands available to the caller, or the usage of one of them.
+fn help(command: Option<&str>, role: Role) -> String {
+    match command {
+        Some(name) =>
+            match spec(name).filter(|c| c.role <= role) {
+                Some(c) => format!("`{}`: {}.", c.usage(), c.summary),
+                None => format!("There is no `{}` command. Type `/help` to list them.", name),
+            }
+        None => {
+            let lines = available(role)
+                .map(|c| format!("- `{}`: {}", c.usage(), c.summary))
+                .collect::<Vec<String>>();
+            format!("Commands:\n{}", lines.join("\n"))
+        }
+    }
+}
+
+/// Runs the command, the caller having checked the role it needs with [`crate::roles::Caller::authorize`].
+pub async fn run(
+    cmd: Command,
+    chat_id: &str,
+    cs: &ContentSettings,
+    llm: &LLMServiceFlows<'_>,
+    role: Role
+) -> String {
+    match cmd {
+        Command::Compare { source_a, source_b } =>
+            compare_sources(&source_a, &source_b, chat_id, cs, llm).await,
+        Command::Why => trace::explain(chat_id),
+        Command::Helpful => feedback::helpful(chat_id, cs.collection_name()),
+        Command::Continue => continuation::resume(chat_id, cs, llm).await,
+        Command::Units { style } => set_unit_style(&style, chat_id),
+        Command::Model { name } => pin_model(name.as_deref(), chat_id),
+        Command::Help { command } => help(command.as_deref(), role),
+        Command::Sources => sources(chat_id),
+        Command::Forget => forget(chat_id),
+        Command::Set { name, value } => set_setting(&name, &value),
+        Command::Stats => stats(),
+        Command::Metrics => format!("{}\n{}", cache_metrics::markdown(), judge::summary()),
+        Command::Collection { name } => switch_collection(name.as_deref(), chat_id, cs),
+        Command::Remind { args } => remind(&args, chat_id),
+        Command::Diag => diagnostics::run(cs.collection_name()).await.markdown(),
+        Command::Bootstrap { collection, urls } => seed_collection(collection.as_deref(), &urls, cs).await,
+    }
+}
+
+fn sources(chat_id: &str) -> String {
+    let provenance = match trace::load(chat_id) {
+        Some(t) => json!({ "chunks": t.provenance() }),
+        None => {
+            return "There is no previous answer.".to_string();
+        }
+    };
+    match citations::section(&citations::from_provenance(&provenance)) {
+        Some(section) => section,
+        None => "The last answer did not draw on any document of the knowledge base.".to_string(),
+    }
+}
+
+/// Erases the conversation memory, the history mirror, the trace and any cut off answer of the
+/// chat now, and has the model start over on the next question. Settings chosen with commands
+/// are kept.
+fn forget(chat_id: &str) -> String {
+    conversation_ttl::clear(chat_id);
+    set(chat_id, json!(true), conversation_ttl::expire(chat_id));
+    log::info!("Forgot the conversation {}", chat_id);
+    "This conversation has been forgotten.".to_string()
+}
+
+fn set_setting(name: &str, value: &str) -> String {
+    match overrides::set(name, value) {
+        Ok(()) if value.is_empty() || value == "default" => format!("`{name}` is back to its configured value."),
+        Ok(()) => format!("`{name}` is set, from the next question on."),
+        Err(e) => format!("Cannot set it, {e}. Use `/set <setting> <value>`, or `default` to go back to the configured value."),
+    }
+}
+
+/// `/bootstrap`: seeds the collection of the conversation, or one of the allowed collections.
+async fn seed_collection(collection: Option<&str>, urls: &[String], cs: &ContentSettings) -> String {
+    let collection = collection.unwrap_or(cs.collection_name());
+    if !collection_config::allowed(cs.collection_name()).iter().any(|c| c == collection) {
+        return format!("`{collection}` is not one of the allowed collections.");
+    }
+    match bootstrap::run(collection, urls).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            log::error!("Cannot bootstrap {}: {}", collection, e);
+            format!("Cannot bootstrap `{collection}`: {e}")
+        }
+    }
+}
+
+/// `/stats`: the analytics of the last 7 days in a few lines.
+fn stats() -> String {
+    let report = analytics::report(7);
+    let days = report["days"].as_array().cloned().unwrap_or_default();
+    let questions = days
+        .iter()
+        .map(|d| d["questions"].as_u64().unwrap_or_default())
+        .sum::<u64>();
+    if questions == 0 {
+        return "No questions in the last 7 days.".to_string();
+    }
+    // the daily averages weighted by the questions of the day
+    let weighted = |field: &str| {
+        let (sum, n) = days
+            .iter()
+            .filter_map(|d| Some((d[field].as_f64()?, d["questions"].as_u64().unwrap_or_default() as f64)))
+            .fold((0.0, 0.0), |(sum, n), (v, q)| (sum + v * q, n + q));
+        (n > 0.0).then(|| sum / n)
+    };
+
+    let mut lines = vec![format!("**Last 7 days:** {questions} questions")];
+    if let Some(c) = weighted("avg_confidence") {
+        lines.push(format!("**Average confidence:** {c:.2}"));
+    }
+    lines.push(format!("**Error rate:** {:.1}%", weighted("error_rate").unwrap_or_default() * 100.0));
+    let categories = report["top_categories"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .take(3)
+        .map(|c| format!("{} ({})", c["category"].as_str().unwrap_or_default().replace('_', " "), c["count"]))
+        .collect::<Vec<String>>();
+    if !categories.is_empty() {
+        lines.push(format!("**Top categories:** {}", categories.join(", ")));
+    }
+    lines.join("\n")
+}
+
+/// `/remind`: schedules a reminder, cancels one, or lists the pending ones.
+fn remind(args: &str, chat_id: &str) -> String {
+    let (first, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    if first.is_empty() {
+        let pending = reminders::pending(chat_id);
+        if pending.is_empty() {
+            return "There are no pending reminders. Use `/remind <duration> <note>`, e.g. `/remind 2h check the rollout`.".to_string();
+        }
+        let now = analytics::now_secs();
+        let lines = pending
+            .iter()
+            .map(|r| format!("- {}: in {}, {}", r.id, reminders::in_words(r.due_at.saturating_sub(now)), r.note))
+            .collect::<Vec<String>>();
+        return format!("Pending reminders:\n{}\n\nCancel one with `/remind cancel <id>`.", lines.join("\n"));
+    }
+    if first.eq_ignore_ascii_case("cancel") {
+        return match rest.trim().parse::<u64>() {
+            Ok(id) if reminders::cancel(chat_id, id) => format!("Reminder {id} cancelled."),
+            Ok(id) => format!("There is no pending reminder {id}."),
+            Err(_) => "Usage: `/remind cancel <id>`.".to_string(),
+        };
+    }
+    let Some(delay) = reminders::parse_duration(first) else {
+        return format!("`{first}` is not a duration, use e.g. `30m`, `2h`, `1h30m` or `3d`.");
+    };
+    match reminders::schedule(chat_id, delay, rest) {
+        Ok(r) => format!("I'll remind you in {}: {} (reminder {}).", reminders::in_words(delay), r.note, r.id),
+        Err(e) => format!("Cannot set the reminder, {e}."),
+    }
+}
+
+fn switch_collection(name: Option<&str>, chat_id: &str, cs: &ContentSettings) -> String {
+    let default = std::env::var("collection_name").unwrap_or("".to_string());
+    let listed = collection_config
+        ::allowed(&default)
+        .iter()
+        .map(|c| format!("`{c}`"))
+        .collect::<Vec<String>>()
+        .join(", ");
+    match name {
+        None =>
+            format!(
+                "Answering from `{}`. Use `/collection <name>` to switch to one of {listed}, or `/collection default`.",
+                cs.collection_name()
+            ),
+        Some(name) =>
+            match collection_config::save_for_chat(chat_id, name, &default) {
+                Ok(()) if name == "default" => format!("Answering from `{default}` again."),
+                Ok(()) => format!("The rest of this conversation is answered from `{name}`."),
+                Err(e) => format!("Cannot switch, {e}. Choose one of {listed}."),
+            },
+    }
+}
+
+fn pin_model(name: Option<&str>, chat_id: &str) -> String {
+    let allowed = models::allowed();
+    let listed = allowed
+        .iter()
+        .map(|m| format!("`{m}`"))
+        .collect::<Vec<String>>()
+        .join(", ");
+    match name {
+        None => {
+            let current = match models::pinned(chat_id) {
+                Some(m) => format!("This conversation is pinned to `{m}`."),
+                None => "This conversation uses the default model.".to_string(),
+            };
+            format!("{current} Use `/model <name>` to pin one of {listed}, or `/model default` to unpin.")
+        }
+        Some("default") => {
+            models::unpin(chat_id);
+            "This conversation uses the default model again.".to_string()
+        }
+        Some(name) =>
+            match allowed.iter().find(|m| m.eq_ignore_ascii_case(name)) {
+                Some(m) => {
+                    models::pin(chat_id, m);
+                    format!("The rest of this conversation is answered by `{m}`.")
+                }
+                None => format!("`{name}` is not an allowed model. Choose one of {listed}."),
+            }
+    }
+}
+
+fn set_unit_style(style: &str, chat_id: &str) -> String {
+    match UnitStyle::parse(style) {
+        Some(style) => {
+            style.save_for_chat(chat_id);
+            format!("Quantities will be written in the {} style.", style.name())
+        }
+        None =>
+            format!(
+                "Quantities are written in the {} style. Use `/units kubernetes`, `/units decimal`, `/units binary` or `/units off` to change it.",
+                UnitStyle::for_chat(chat_id).name()
+            ),
+    }
+}
+
+async fn compare_sources(
+    source_a: &str,
+    source_b: &str,
+    chat_id: &str,
+    cs: &ContentSettings,
+    llm: &LLMServiceFlows<'_>
+) -> String {
+    let chunks_a = representative_chunks(source_a, cs.collection_name()).await;
+    let chunks_b = representative_chunks(source_b, cs.collection_name()).await;
+
+    if chunks_a.is_empty() || chunks_b.is_empty() {
+        log::info!(
+            "Compare found {} chunks for {} and {} chunks for {}",
+            chunks_a.len(),
+            source_a,
+            chunks_b.len(),
+            source_b
+        );
+        return cs.no_answer_mesg().to_string();
+    }
+
+    let sys_prompt =
+        "You're an assistant comparing two versions of technical documentation. Only use the excerpts provided, and say so when one side does not cover a topic.";
+    let usr_prompt = format!(
+        "Excerpts from `{source_a}`:\n{}\n\nExcerpts from `{source_b}`:\n{}\n\nCompare the two sources. Reply with a markdown table of the topics both cover and how they differ, followed by a short list of what only `{source_a}` covers and what only `{source_b}` covers.",
+        chunks_a.join("\n"),
+        chunks_b.join("\n")
+    );
+
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 2048,
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let conversation = format!("compare-{chat_id}");
+    match retry::call("compare", || llm.chat_completion(&conversation, &usr_prompt, &co)).await {
+        Ok(r) => r.choice,
+        Err(e) => {
+            log::error!("LLM returns error when comparing sources: {}", e);
+            cs.error_mesg().to_string()
+        }
+    }
+}
+
+/// Finds up to 4 chunks of the collection that belong to `source`. Chunks whose payload carries
+/// a `source` or `title` field are matched on it; when the collection has no such metadata the
+/// closest chunks to the source name are used instead.
+async fn representative_chunks(source: &str, collection_name: &str) -> Vec<String> {
+    let vector = match CollectionConfig::load(collection_name).embed_one(source).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Cannot embed {}: {}", source, e);
+            return Vec::new();
+        }
+    };
+
+    let p = PointsSearchParams {
+        vector,
+        limit: 20,
+    };
+    let points = match search_points(collection_name, &p).await {
+        Ok(sp) => sp,
+        Err(e) => {
+            log::error!("Vector search returns error: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let quarantined = quarantine::ids(collection_name);
+    let points = points
+        .into_iter()
+        .filter(|p| !matches!(p.id, PointId::Num(id) if quarantined.contains(&id)))
+        .collect::<Vec<ScoredPoint>>();
+
+    let needle = source.to_lowercase();
+    let field_matches = |p: &ScoredPoint, field: &str| {
+        p.payload
+            .as_ref()
+            .and_then(|m| m.get(field))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_lowercase().contains(&needle))
+    };
+    let has_metadata = points
+        .iter()
+        .any(|p| field_matches(p, "source").is_some() || field_matches(p, "title").is_some());
+
+    points
+        .iter()
+        .filter(|p| {
+            !has_metadata ||
+                field_matches(p, "source").unwrap_or(false) ||
+                field_matches(p, "title").unwrap_or(false)
+        })
+        .filter_map(|p| p.payload.as_ref()?.get("text")?.as_str())
+        .take(4)
+        .filter_map(|t| guards::take_payload(&first_x_chars(t, 1000)))
+        .collect()
+}
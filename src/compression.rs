@@ -0,0 +1,233 @@
+use llmservice_flows::chat::ChatOptions;
+use regex::Regex;
+
+use crate::{ deterministic, error::{ Error, Result }, retry, settings, short_query, CHAT_MODEL };
+
+/// Sentences kept from a chunk that shares no word with the question, so it is not emptied.
+const FALLBACK_SENTENCES: usize = 2;
+
+/// How retrieved chunks are shrunk before they go into the prompt, from the
+/// `context_compression` setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// Chunks are used whole, the default.
+    Off,
+    /// Keeps the sentences sharing a content word with the question, without a model call.
+    Extractive,
+    /// The chat model copies out the sentences that help answer the question and drops the
+    /// chunks it finds nothing in, falling back to extractive compression for the chunks it
+    /// leaves out and when it fails.
+    Llm,
+}
+
+impl Compression {
+    pub fn from_env() -> Self {
+        match std::env::var("context_compression").unwrap_or_default().as_str() {
+            "extractive" => Compression::Extractive,
+            "llm" => Compression::Llm,
+            _ => Compression::Off,
+        }
+    }
+}
+
+/// Chunks shorter than `compression_min_chars` (default 400) are used whole.
+fn min_chars() -> usize {
+    settings::setting("compression_min_chars", 400)
+}
+
+/// Shrinks the chunks to what is relevant to the question, in the order given. Chunks with code
+/// blocks are kept whole, a command cut in half is worse than a longer prompt. A chunk the model
+/// found nothing relevant in comes back empty, for the caller to drop.
+pub async fn compress(question: &str, chunks: Vec<String>) -> Vec<String> {
+    let mode = Compression::from_env();
+    if mode == Compression::Off {
+        return chunks;
+    }
+    let min_chars = min_chars();
+    let compressible = |c: &String| c.chars().count() >= min_chars && !c.contains("```");
+    let before = chunks
+        .iter()
+        .map(|c| c.chars().count())
+        .sum::<usize>();
+
+    let compressed = match mode {
+        Compression::Llm => {
+            let long = chunks
+                .iter()
+                .filter(|c| compressible(c))
+                .cloned()
+                .collect::<Vec<String>>();
+            match llm_extracts(question, &long).await {
+                Ok(mut extracts) => {
+                    extracts.reverse();
+                    chunks
+                        .into_iter()
+                        .map(|c| {
+                            match compressible(&c) {
+                                true =>
+                                    match extracts.pop().unwrap_or(Extract::Missing) {
+                                        Extract::Kept(text) => text,
+                                        Extract::Nothing => String::new(),
+                                        Extract::Missing => extractive(question, &c),
+                                    }
+                                false => c,
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                }
+                Err(e) => {
+                    log::error!("Compressing the context extractively, the model failed: {}", e);
+                    chunks
+                        .into_iter()
+                        .map(|c| if compressible(&c) { extractive(question, &c) } else { c })
+                        .collect()
+                }
+            }
+        }
+        _ =>
+            chunks
+                .into_iter()
+                .map(|c| if compressible(&c) { extractive(question, &c) } else { c })
+                .collect(),
+    };
+
+    let after = compressed
+        .iter()
+        .map(|c| c.chars().count())
+        .sum::<usize>();
+    log::debug!("Compressed the context from {} to {} characters", before, after);
+    compressed
+}
+
+fn sentences(text: &str) -> Vec<&str> {
+    let boundary = Regex::new(r"[.!?](\s+|$)|\n\s*\n").unwrap();
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for m in boundary.find_iter(text) {
+        sentences.push(text[start..m.end()].trim());
+        start = m.end();
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+/// The sentences of the chunk sharing a content word with the question, else its first
+/// [`FALLBACK_SENTENCES`].
+pub fn extractive(question: &str, chunk: &str) -> String {
+    let words = short_query::content_words(question);
+    let sentences = sentences(chunk);
+    let kept = sentences
+        .iter()
+        .filter(|s| {
+            let sentence = s.to_lowercase();
+            words.iter().any(|w| sentence.contains(w.as_str()))
+        })
+        .copied()
+        .collect::<Vec<&str>>();
+    match kept.is_empty() {
+        true => sentences.into_iter().take(FALLBACK_SENTENCES).collect::<Vec<&str>>().join(" "),
+        false => kept.join(" "),
+    }
+}
+
+/// What the model made of one passage.
+#[derive(Debug, Clone, PartialEq)]
+enum Extract {
+    /// The sentences it copied out.
+    Kept(String),
+    /// It answered `NONE`: nothing in the passage helps.
+    Nothing,
+    /// It left the passage out of its reply, or gave nothing after the number.
+    Missing,
+}
+
+/// Asks the model for the relevant sentences of every chunk at once.
+async fn llm_extracts(question: &str, chunks: &[String]) -> Result<Vec<Extract>> {
+    if chunks.is_empty() {
+        return Ok(Vec::new());
+    }
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+
+    let sys_prompt =
+        "You shorten search results. For each numbered passage, copy out word for word only the sentences that help answer the question. Reply with one line per passage starting with its number in brackets, e.g. `[2] ...`, and `NONE` after the number when nothing helps.";
+    let passages = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("[{}] {}", i + 1, c.replace('\n', " ")))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let usr_prompt = format!("Question: `{question}`\n\nPassages:\n{passages}");
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 8192,
+        max_tokens: Some(1024),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let reply = retry
+        ::call("compress", || llm.chat_completion("compress", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+    Ok(parse_extracts(&reply, chunks.len()))
+}
+
+/// The extract of each of the `count` passages from the `[n] ...` lines of the reply.
+fn parse_extracts(reply: &str, count: usize) -> Vec<Extract> {
+    let line = Regex::new(r"^\s*\[(\d+)\]\s*(.*)$").unwrap();
+    let mut extracts = vec![Extract::Missing; count];
+    for l in reply.lines() {
+        let Some(caps) = line.captures(l) else {
+            continue;
+        };
+        let n = caps[1].parse::<usize>().unwrap_or_default();
+        let text = caps[2].trim();
+        if let Some(slot) = n.checked_sub(1).and_then(|i| extracts.get_mut(i)) {
+            *slot = match text {
+                "" => Extract::Missing,
+                t if t.trim_end_matches('.').eq_ignore_ascii_case("none") => Extract::Nothing,
+                t => Extract::Kept(t.to_string()),
+            };
+        }
+    }
+    extracts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: &str = "Pods run containers. To restart a deployment, roll it out again. Services route traffic.";
+
+    #[test]
+    fn extractive_keeps_the_sentences_sharing_a_word() {
+        assert_eq!(extractive("How do I restart a deployment?", CHUNK), "To restart a deployment, roll it out again.");
+    }
+
+    #[test]
+    fn extractive_falls_back_to_the_first_sentences() {
+        assert_eq!(extractive("zebra", CHUNK), "Pods run containers. To restart a deployment, roll it out again.");
+    }
+
+    #[test]
+    fn none_is_told_apart_from_a_left_out_passage() {
+        let reply = "[1] Keep this.\n[2] NONE\n[4] none.\n[9] out of range\nnot a passage line";
+        assert_eq!(parse_extracts(reply, 5), vec![
+            Extract::Kept("Keep this.".to_string()),
+            Extract::Nothing,
+            Extract::Missing,
+            Extract::Nothing,
+            Extract::Missing,
+        ]);
+    }
+
+    #[test]
+    fn an_empty_extract_is_left_out() {
+        assert_eq!(parse_extracts("[1]", 1), vec![Extract::Missing]);
+    }
+}
@@ -0,0 +1,140 @@
+use async_graphql::SimpleObject;
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ collection_config::CollectionConfig, drift::cosine, retry, settings };
+
+/// Most answers sampled for one question.
+const MAX_SAMPLES: u32 = 7;
+
+/// How the answer was voted on, shown by `/why`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema, SimpleObject)]
+#[serde(default)]
+pub struct Consensus {
+    /// The answers generated, the regular one included.
+    pub samples: u32,
+    /// The answers in the largest group of answers saying the same thing.
+    pub agreeing: u32,
+    /// `agreeing` over `samples`.
+    pub agreement: f32,
+    /// The sampling temperature of the answer returned, `None` for the regular one.
+    pub temperature: Option<f32>,
+}
+
+/// The answers sampled per question of the collection, its `consensus_samples` else the
+/// `consensus_samples` setting. Below 2, the default, there is no voting.
+pub fn samples(collection_name: &str) -> u32 {
+    CollectionConfig::load(collection_name).consensus_samples
+        .or_else(|| {
+            settings::optional("consensus_samples")
+        })
+        .unwrap_or(0)
+        .min(MAX_SAMPLES)
+}
+
+/// Answers with a similarity at or above `consensus_similarity` (default `0.9`) say the same
+/// thing.
+fn similarity() -> f32 {
+    settings::setting("consensus_similarity", 0.9)
+}
+
+/// The temperatures of the extra samples, spread from 0.3 to 1.0.
+fn temperatures(extra: u32) -> Vec<f32> {
+    (0..extra)
+        .map(|i| match extra {
+            1 => 0.7,
+            n => 0.3 + (0.7 * (i as f32)) / ((n - 1) as f32),
+        })
+        .collect()
+}
+
+/// Samples `samples - 1` more answers to the prompts of the regular answer at varied
+/// temperatures, groups the answers by the similarity of their embeddings and returns the answer
+/// closest to the others of the largest group, with how much the answers agree. Falls back to the
+/// regular answer when too few samples come back or they cannot be embedded.
+pub async fn vote(
+    answer: String,
+    samples: u32,
+    user_prompt: &str,
+    co: &ChatOptions<'_>,
+    llm: &LLMServiceFlows<'_>,
+    collection_name: &str
+) -> (String, Option<Consensus>) {
+    let mut answers = vec![(answer, None)];
+    for (i, temperature) in temperatures(samples.saturating_sub(1)).into_iter().enumerate() {
+        // side calls, the conversation kept by the LLM service is the regular answer's
+        let options = ChatOptions {
+            model: co.model,
+            restart: true,
+            system_prompt: co.system_prompt,
+            post_prompt: co.post_prompt,
+            max_tokens: co.max_tokens,
+            token_limit: co.token_limit,
+            temperature: Some(temperature),
+            ..Default::default()
+        };
+        let id = format!("consensus-{i}");
+        match retry::call("consensus", || llm.chat_completion(&id, user_prompt, &options)).await {
+            Ok(r) if !r.choice.trim().is_empty() => answers.push((r.choice, Some(temperature))),
+            Ok(_) => {}
+            Err(e) => log::warn!("Cannot sample a consensus answer: {}", e),
+        }
+    }
+    if answers.len() < 2 {
+        return (answers.swap_remove(0).0, None);
+    }
+
+    let texts = answers
+        .iter()
+        .map(|(a, _)| a.clone())
+        .collect::<Vec<String>>();
+    let vectors = match CollectionConfig::load(collection_name).embed(&texts).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Cannot embed the consensus answers, keeping the regular one: {}", e);
+            return (answers.swap_remove(0).0, None);
+        }
+    };
+
+    let n = vectors.len();
+    let threshold = similarity();
+    let sims = (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| cosine(&vectors[i], &vectors[j]))
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<Vec<f32>>>();
+    // every answer's group is the answers close to it, the largest group wins, ties going to the
+    // answer closest to its group, then to the earlier answer
+    let group = |i: usize| {
+        (0..n)
+            .filter(|j| sims[i][*j] >= threshold)
+            .collect::<Vec<usize>>()
+    };
+    let closeness = |i: usize, members: &[usize]| {
+        members
+            .iter()
+            .map(|j| sims[i][*j])
+            .sum::<f32>() / (members.len() as f32)
+    };
+    let (best, agreeing, _) = (0..n)
+        .map(|i| {
+            let members = group(i);
+            (i, members.len(), closeness(i, &members))
+        })
+        .fold((0, 0, f32::MIN), |acc, (i, size, close)| match size > acc.1 || (size == acc.1 && close > acc.2) {
+            true => (i, size, close),
+            false => acc,
+        });
+
+    let consensus = Consensus {
+        samples: n as u32,
+        agreeing: agreeing as u32,
+        agreement: (agreeing as f32) / (n as f32),
+        temperature: answers[best].1,
+    };
+    log::info!("Consensus of {} answers: {} agree, answer {} returned", n, agreeing, best);
+    (answers.swap_remove(best).0, Some(consensus))
+}
@@ -0,0 +1,115 @@
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use serde::{ Deserialize, Serialize };
+use store_flows::{ del, get };
+
+use crate::{ deterministic, encryption, guards, models, priority::Lane, remember, retry, settings, tokenizer, units, ContentSettings };
+
+/// An answer is not continued more than this many times.
+const MAX_PARTS: usize = 5;
+
+pub const HINT: &str = "_The answer was cut off, type `/continue` for the rest._";
+
+/// An answer cut off by the output token limit, waiting for `/continue`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pending {
+    pub question: String,
+    /// The parts given so far, stitched together.
+    pub answer: String,
+    pub parts: usize,
+    /// The id of the first part, the whole answer is rated by it.
+    pub message_id: String,
+    /// The knowledge base collection the answer was given from.
+    pub collection: String,
+}
+
+fn key(chat_id: &str) -> String {
+    format!("continue:{chat_id}")
+}
+
+/// The output token limit of an answer, from `answer_max_tokens`.
+pub fn max_tokens() -> u16 {
+    settings::setting("answer_max_tokens", 1024)
+}
+
+/// Whether the answer looks cut off by the token limit. The LLM service does not report why
+/// generation stopped, so an answer close to the limit that stops mid-sentence or inside a code
+/// block is taken as truncated.
+pub fn is_truncated(answer: &str) -> bool {
+    let near_limit = tokenizer::count(answer) * 100 >= (max_tokens() as usize) * 85;
+    let open_code_block = answer.matches("```").count() % 2 == 1;
+    let ends_cleanly = answer
+        .trim_end()
+        .ends_with(['.', '!', '?', ')', ':', '`', '*', '|']);
+    near_limit && (open_code_block || !ends_cleanly)
+}
+
+/// Keeps the cut off answer until `/continue`, it reaches the memory once complete.
+pub fn save(chat_id: &str, pending: &Pending) {
+    let sealed = Pending {
+        question: encryption::seal(&pending.question),
+        answer: encryption::seal(&pending.answer),
+        parts: pending.parts,
+        message_id: pending.message_id.clone(),
+        collection: pending.collection.clone(),
+    };
+    guards::set_capped(&key(chat_id), serde_json::to_value(sealed).unwrap_or_default(), None);
+}
+
+pub fn take(chat_id: &str) -> Option<Pending> {
+    let pending = get(&key(chat_id)).and_then(|v| serde_json::from_value::<Pending>(v).ok())?;
+    del(&key(chat_id));
+    Some(Pending {
+        question: encryption::open(&pending.question)?,
+        answer: encryption::open(&pending.answer)?,
+        parts: pending.parts,
+        message_id: pending.message_id,
+        collection: pending.collection,
+    })
+}
+
+/// Asks the LLM for the rest of the cut off answer, in the same conversation so it sees where
+/// it stopped. Replies with the new part only.
+pub async fn resume(chat_id: &str, cs: &ContentSettings, llm: &LLMServiceFlows<'_>) -> String {
+    let mut pending = match take(chat_id) {
+        Some(p) => p,
+        None => {
+            return "There is no cut off answer to continue.".to_string();
+        }
+    };
+
+    let chat_model = models::for_chat(chat_id, Lane::Normal, &pending.question);
+    let co = ChatOptions {
+        model: Some(&chat_model),
+        restart: false,
+        system_prompt: Some(cs.system_prompt.as_str()),
+        max_tokens: Some(max_tokens()),
+        token_limit: 2048,
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+    let prompt =
+        "Your previous answer was cut off. Continue it exactly where it stopped, without repeating anything or adding an introduction.";
+    let part = match retry::call("continue", || llm.chat_completion(chat_id, prompt, &co)).await {
+        Ok(r) => units::format(&r.choice, units::UnitStyle::for_chat(chat_id)),
+        Err(e) => {
+            log::error!("LLM returns error when continuing: {}", e);
+            // keep the answer for another try
+            save(chat_id, &pending);
+            return cs.error_mesg().to_string();
+        }
+    };
+
+    pending.answer.push_str(&part);
+    pending.parts += 1;
+    match is_truncated(&part) && pending.parts < MAX_PARTS {
+        true => {
+            save(chat_id, &pending);
+            format!("{part}\n\n{HINT}")
+        }
+        false => {
+            remember(chat_id, &pending.question, &pending.answer, &pending.message_id, &pending.collection).await;
+            part
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use serde_json::{ json, Value };
+use store_flows::{ del, get };
+
+use crate::{ error::{ Error, Result }, guards };
+
+/// Longest addendum accepted, in characters.
+const MAX_CHARS: usize = 2000;
+
+fn key(chat_id: &str) -> String {
+    format!("conversation_prompt:{chat_id}")
+}
+
+fn post_key(chat_id: &str) -> String {
+    format!("conversation_post_prompt:{chat_id}")
+}
+
+/// The system prompt addendum of the conversation, merged after the configured system prompt.
+pub fn load(chat_id: &str) -> Option<String> {
+    get(&key(chat_id)).and_then(|v| v.as_str().map(String::from))
+}
+
+/// The post prompt addendum of the conversation, merged after the configured post prompt.
+pub fn load_post(chat_id: &str) -> Option<String> {
+    get(&post_key(chat_id)).and_then(|v| v.as_str().map(String::from))
+}
+
+fn checked(name: &str, prompt: &str) -> Result<()> {
+    match prompt.chars().count() > MAX_CHARS {
+        true => Err(Error::InvalidInput(format!("`{name}` is longer than {MAX_CHARS} characters"))),
+        false => Ok(()),
+    }
+}
+
+/// Sets the addenda from a `{"prompt": "...", "post_prompt": "..."}` body, either of them can be
+/// left out and an empty one removes it.
+pub fn save(chat_id: &str, body: &[u8]) -> Result<()> {
+    let req: Value = serde_json::from_slice(body)?;
+    let fields = [("prompt", key(chat_id)), ("post_prompt", post_key(chat_id))]
+        .into_iter()
+        .filter_map(|(name, key)| req.get(name).map(|p| (name, key, p)))
+        .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return Err(Error::InvalidInput("`prompt` or `post_prompt` is required".to_string()));
+    }
+    for (name, _, prompt) in &fields {
+        let prompt = prompt.as_str().ok_or(Error::InvalidInput(format!("`{name}` must be a string")))?;
+        checked(name, prompt.trim())?;
+    }
+
+    for (_, key, prompt) in fields {
+        match prompt.as_str().unwrap_or_default().trim() {
+            "" => {
+                del(&key);
+            }
+            prompt => guards::set_capped(&key, json!(prompt), None),
+        }
+    }
+    Ok(())
+}
+
+/// The post prompt addendum of the request, from the `post_prompt` query parameter or the
+/// `x-post-prompt` header, else the one of the conversation.
+pub fn post_prompt(chat_id: &str, qry: &HashMap<String, Value>, headers: &[(String, String)]) -> Result<Option<String>> {
+    let requested = qry
+        .get("post_prompt")
+        .and_then(|v| v.as_str().map(String::from))
+        .or(
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("x-post-prompt"))
+                .map(|(_, v)| v.clone())
+        );
+    match requested.as_deref().map(str::trim) {
+        Some(p) if !p.is_empty() => {
+            checked("post_prompt", p)?;
+            Ok(Some(p.to_string()))
+        }
+        _ => Ok(load_post(chat_id)),
+    }
+}
@@ -0,0 +1,165 @@
+use serde_json::{ json, Value };
+use store_flows::{ del, get, Expire, ExpireKind };
+
+use crate::{
+    analytics::now_secs,
+    continuation,
+    error::{ Error, Result },
+    guards,
+    history,
+    memory,
+    reminders,
+    resumption,
+    sentiment,
+    settings,
+    trace,
+};
+
+/// The conversations with a TTL, least recently active first, for the sweep: the KV store cannot
+/// list keys. Kept under the size limit of the KV store by dropping the least recently active,
+/// those still expire when they come back.
+const ACTIVE_KEY: &str = "conversation_ttl:active";
+
+fn key(chat_id: &str) -> String {
+    format!("conversation_ttl:{chat_id}")
+}
+
+/// When the conversation was last answered.
+fn last_active_key(chat_id: &str) -> String {
+    format!("conversation_ttl_active:{chat_id}")
+}
+
+/// Days a conversation is kept after its last message, from `conversation_ttl_days`. `0`, the
+/// default, keeps conversations until they are forgotten or their data expires by retention rule.
+fn default_days() -> u64 {
+    settings::setting("conversation_ttl_days", 0)
+}
+
+/// The TTL of the conversation in days, its own when set with `?action=conversation_ttl`, `0`
+/// meaning it does not expire.
+pub fn days(chat_id: &str) -> u64 {
+    get(&key(chat_id))
+        .and_then(|v| v.as_u64())
+        .unwrap_or_else(default_days)
+}
+
+/// Sets the TTL of the conversation, `None` going back to `conversation_ttl_days`.
+pub fn set_days(chat_id: &str, days: Option<u64>) -> Result<()> {
+    match days {
+        None => {
+            del(&key(chat_id));
+        }
+        Some(d) if d > 3650 => {
+            return Err(Error::InvalidInput("`days` must be at most 3650".to_string()));
+        }
+        Some(d) => guards::set_capped(&key(chat_id), json!(d), None),
+    }
+    Ok(())
+}
+
+/// The expiry of the per-chat flags written for the conversation, e.g. the restart flag, so they
+/// do not outlive it.
+pub fn expire(chat_id: &str) -> Option<Expire> {
+    match days(chat_id) {
+        0 => None,
+        d => Some(Expire { kind: ExpireKind::Ex, value: (d * 86400) as i64 }),
+    }
+}
+
+fn active() -> Vec<String> {
+    get(ACTIVE_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_active(active: &[String]) {
+    guards::set_capped(ACTIVE_KEY, json!(active), None);
+}
+
+fn last_active(chat_id: &str) -> Option<u64> {
+    get(&last_active_key(chat_id)).and_then(|v| v.as_u64())
+}
+
+/// Records that the conversation was answered now. Conversations that never expire are not
+/// tracked.
+pub fn touch(chat_id: &str) {
+    if days(chat_id) == 0 {
+        return;
+    }
+    guards::set_capped(&last_active_key(chat_id), json!(now_secs()), None);
+    let mut active = active();
+    active.retain(|c| c != chat_id);
+    active.push(chat_id.to_string());
+    save_active(&active);
+}
+
+/// Deletes what is stored about the conversation: its memory, history mirror, trace, summary,
+/// reminders, unfinished answer, mood, restart flag and last activity. Its settings, such as the
+/// model or the units, are kept.
+pub fn clear(chat_id: &str) {
+    memory::clear(chat_id);
+    history::clear(chat_id);
+    trace::clear(chat_id);
+    reminders::clear(chat_id);
+    resumption::clear(chat_id);
+    continuation::take(chat_id);
+    sentiment::clear(chat_id);
+    del(&format!("short_query:{chat_id}"));
+    del(chat_id);
+    del(&last_active_key(chat_id));
+}
+
+fn expired(chat_id: &str, last_active: u64) -> bool {
+    match days(chat_id) {
+        0 => false,
+        d => last_active + d * 86400 < now_secs(),
+    }
+}
+
+/// Clears the conversation when it comes back after being idle past its TTL, returning whether it
+/// did, so the conversation with the model starts over too.
+pub fn check(chat_id: &str) -> bool {
+    let Some(last_active) = last_active(chat_id) else {
+        return false;
+    };
+    if !expired(chat_id, last_active) {
+        return false;
+    }
+    // the sweep drops it from the list
+    clear(chat_id);
+    log::info!("Conversation {} expired after being idle since {}", chat_id, last_active);
+    true
+}
+
+/// Clears every conversation idle past its TTL, or with `dry_run` only counts them. Run with the
+/// retention sweep.
+pub fn sweep(dry_run: bool) -> Value {
+    // conversations cleared since they were listed have no time left
+    let tracked = active()
+        .into_iter()
+        .filter_map(|c| last_active(&c).map(|at| (c, at)))
+        .collect::<Vec<(String, u64)>>();
+    let stale = tracked
+        .iter()
+        .filter(|(c, at)| expired(c, *at))
+        .map(|(c, _)| c.clone())
+        .collect::<Vec<String>>();
+    if !dry_run {
+        for chat_id in &stale {
+            clear(chat_id);
+        }
+        let kept = tracked
+            .iter()
+            .map(|(c, _)| c.clone())
+            .filter(|c| !stale.contains(c))
+            .collect::<Vec<String>>();
+        save_active(&kept);
+        if !stale.is_empty() {
+            log::info!("Cleared {} conversations idle past their TTL", stale.len());
+        }
+    }
+    json!({
+        "tracked": tracked.len() - stale.len(),
+        "expired": stale.len(),
+    })
+}
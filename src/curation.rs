@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use sha2::{ Digest, Sha256 };
+use store_flows::get;
+
+use crate::{
+    analytics::now_secs,
+    error::{ Error, Result },
+    feedback::{ self, Rating },
+    guards,
+    ingest::{ self, Document, IngestOptions, Progress },
+    settings,
+};
+
+/// The `source` of the chunks of promoted answers, which citations show.
+const SOURCE: &str = "verified answer";
+/// Ratings read when looking for candidates, all that are kept.
+const MAX_RATINGS: usize = 1000;
+
+/// Body of `?action=curation`, the message ids of the candidates to promote and to reject.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct CurationRequest {
+    pub approve: Vec<String>,
+    pub reject: Vec<String>,
+}
+
+/// An upvoted answer that may be promoted into the knowledge base.
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    pub message_id: String,
+    pub question: String,
+    pub answer: String,
+    /// Net votes, up minus down, on the answers to the same question.
+    pub votes: i64,
+    pub rated_at: u64,
+}
+
+/// What an admin decided about a candidate.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Decision {
+    message_id: String,
+    promoted: bool,
+    question: String,
+    by: String,
+    at: u64,
+}
+
+#[derive(Deserialize)]
+struct Rated {
+    message_id: String,
+    rating: Rating,
+    /// `None` when it cannot be decrypted, e.g. with a retired key.
+    question: Option<String>,
+    answer: Option<String>,
+    at: u64,
+    #[serde(default)]
+    collection: String,
+}
+
+// one key per decision, there are too many for a single value of the KV store
+fn decision_key(collection_name: &str, message_id: &str) -> String {
+    format!("curation:{collection_name}:{message_id}")
+}
+
+/// Decided questions are no longer candidates, whichever of their answers is upvoted next.
+fn question_key(collection_name: &str, question: &str) -> String {
+    let hash = Sha256::digest(question.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("curation:{collection_name}:question:{hash}")
+}
+
+/// The latest decisions, for the report only, the oldest dropped when they no longer fit.
+fn recent_key(collection_name: &str) -> String {
+    format!("curation:{collection_name}:recent")
+}
+
+fn decided(collection_name: &str, message_id: &str, question: &str) -> bool {
+    get(&decision_key(collection_name, message_id)).is_some() || get(&question_key(collection_name, question)).is_some()
+}
+
+fn recent(collection_name: &str) -> Vec<Decision> {
+    get(&recent_key(collection_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Net upvotes an answer needs to become a candidate, `curation_min_votes` (default `2`), so a
+/// single thumbs up is not enough.
+fn min_votes() -> i64 {
+    settings::setting("curation_min_votes", 2)
+}
+
+/// Questions asked the same way count as one, whatever their case, spacing and punctuation.
+fn normalize(question: &str) -> String {
+    question
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// The upvoted answers of the collection not decided on yet, strongest first. The votes on every
+/// answer to the same question add up, the newest upvoted answer standing for them. Ratings from
+/// before the collection was recorded with them are left out.
+pub fn candidates(collection_name: &str) -> Vec<Candidate> {
+    let rated: Vec<Rated> = match serde_json::from_value(feedback::ratings(None, MAX_RATINGS)) {
+        Ok(rated) => rated,
+        Err(e) => {
+            log::error!("Cannot read the ratings: {}", e);
+            Vec::new()
+        }
+    };
+
+    // newest first, so the first upvoted answer of a question is its newest
+    let mut by_question: BTreeMap<String, Candidate> = BTreeMap::new();
+    let mut votes: BTreeMap<String, i64> = BTreeMap::new();
+    for r in rated {
+        if r.collection != collection_name {
+            continue;
+        }
+        // anonymized by retention, or sealed with a retired key
+        let (Some(asked), Some(answer)) = (r.question, r.answer) else {
+            continue;
+        };
+        if asked.is_empty() || answer.is_empty() {
+            continue;
+        }
+        let question = normalize(&asked);
+        *votes.entry(question.clone()).or_default() += match r.rating {
+            Rating::Up => 1,
+            Rating::Down => -1,
+        };
+        if r.rating == Rating::Up && !by_question.contains_key(&question) {
+            by_question.insert(question, Candidate {
+                message_id: r.message_id,
+                question: asked,
+                answer,
+                votes: 0,
+                rated_at: r.at,
+            });
+        }
+    }
+
+    let min = min_votes();
+    let mut candidates = by_question
+        .into_iter()
+        .filter(|(q, c)| !decided(collection_name, &c.message_id, q))
+        .filter_map(|(q, mut c)| {
+            c.votes = votes.get(&q).copied().unwrap_or_default();
+            (c.votes >= min).then_some(c)
+        })
+        .collect::<Vec<Candidate>>();
+    candidates.sort_by(|a, b| b.votes.cmp(&a.votes).then(b.rated_at.cmp(&a.rated_at)));
+    candidates
+}
+
+/// The candidates and the decisions taken, for `?action=curation` with an empty body.
+pub fn report(collection_name: &str) -> Value {
+    json!({
+        "collection": collection_name,
+        "min_votes": min_votes(),
+        "candidates": candidates(collection_name),
+        "decided": recent(collection_name),
+    })
+}
+
+/// Promotes the approved candidates into the collection as chunks of `verified answer`, carrying
+/// where they came from, and sets the rejected ones aside. Ids that are not candidates are
+/// returned as `unknown`.
+pub async fn decide(collection_name: &str, body: &[u8], approver: &str) -> Result<Value> {
+    let req: CurationRequest = serde_json::from_slice(body)?;
+    if req.approve.iter().any(|id| req.reject.contains(id)) {
+        return Err(Error::InvalidInput("a message id cannot be both approved and rejected".to_string()));
+    }
+
+    let candidates = candidates(collection_name);
+    let find = |id: &String| candidates.iter().find(|c| &c.message_id == id);
+    let unknown = req.approve
+        .iter()
+        .chain(req.reject.iter())
+        .filter(|id| find(id).is_none())
+        .cloned()
+        .collect::<Vec<String>>();
+
+    let approved = req.approve
+        .iter()
+        .filter_map(find)
+        .collect::<Vec<&Candidate>>();
+    let now = now_secs();
+    let documents = approved
+        .iter()
+        .map(|c| {
+            let mut metadata = Map::new();
+            metadata.insert("kind".to_string(), json!("verified_answer"));
+            metadata.insert("question".to_string(), json!(c.question));
+            metadata.insert("message_id".to_string(), json!(c.message_id));
+            metadata.insert("votes".to_string(), json!(c.votes));
+            metadata.insert("rated_at".to_string(), json!(c.rated_at));
+            metadata.insert("approved_by".to_string(), json!(approver));
+            metadata.insert("approved_at".to_string(), json!(now));
+            Document {
+                text: format!("Question: {}\n\nAnswer: {}", c.question, c.answer),
+                source: Some(SOURCE.to_string()),
+                title: Some(c.question.clone()),
+                metadata,
+            }
+        })
+        .collect::<Vec<Document>>();
+    let mut progress = |e: Progress| log::info!("Curation progress {}: {}", e.name(), e.data());
+    let chunks = ingest::chunk_documents(documents, &IngestOptions::default(), &mut progress);
+    let written = ingest::embed_and_upsert(collection_name, chunks, &mut progress).await?;
+
+    // recorded only once the chunks are written, so a failed promotion can be approved again
+    let mut recent = recent(collection_name);
+    let decided = approved
+        .iter()
+        .map(|c| (*c, true))
+        .chain(
+            req.reject
+                .iter()
+                .filter_map(find)
+                .map(|c| (c, false))
+        );
+    for (c, promoted) in decided {
+        let decision = Decision {
+            message_id: c.message_id.clone(),
+            promoted,
+            question: c.question.clone(),
+            by: approver.to_string(),
+            at: now,
+        };
+        let value = serde_json::to_value(&decision)?;
+        guards::set_capped(&decision_key(collection_name, &c.message_id), value, None);
+        guards::set_capped(&question_key(collection_name, &normalize(&c.question)), json!(c.message_id), None);
+        recent.push(decision);
+    }
+    guards::set_capped(&recent_key(collection_name), serde_json::to_value(&recent)?, None);
+    log::info!(
+        "{} promoted {} verified answers into {} chunks of {}, rejected {}",
+        approver,
+        approved.len(),
+        written,
+        collection_name,
+        req.reject.len() - unknown.iter().filter(|id| req.reject.contains(id)).count()
+    );
+
+    Ok(
+        json!({
+            "collection": collection_name,
+            "promoted": approved.iter().map(|c| &c.message_id).collect::<Vec<&String>>(),
+            "written": written,
+            "unknown": unknown,
+            "kb_version": ingest::kb_version(collection_name),
+        })
+    )
+}
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use crate::settings;
+
+/// Words per shingle, enough that common phrases do not make unrelated chunks look alike.
+const SHINGLE_WORDS: usize = 5;
+
+/// How much of the shorter of two chunks has to be in the longer one for it to be dropped, from
+/// `dedup_threshold` (`0.8` by default, `1` or more turns deduplication off).
+pub fn threshold() -> f32 {
+    settings::setting::<f32>("dedup_threshold", 0.8)
+}
+
+/// The overlapping runs of words of the text, lowercased and without punctuation.
+fn shingles(text: &str) -> HashSet<String> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<String>>();
+    match words.len() < SHINGLE_WORDS {
+        true => HashSet::from([words.join(" ")]),
+        false => words
+            .windows(SHINGLE_WORDS)
+            .map(|w| w.join(" "))
+            .collect(),
+    }
+}
+
+/// The share of the shingles of the smaller set found in the larger one, so a chunk repeated
+/// inside a longer one counts as a duplicate.
+fn containment(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let (small, large) = match a.len() <= b.len() {
+        true => (a, b),
+        false => (b, a),
+    };
+    if small.is_empty() {
+        return 0.0;
+    }
+    (small.intersection(large).count() as f32) / (small.len() as f32)
+}
+
+/// The indexes of the chunks repeating the text of an earlier one, e.g. overlapping chunks of the
+/// same page ingested twice under different ids. The chunks come best first, so the best scored
+/// of near-identical chunks is kept.
+pub fn near_duplicates(chunks: &[&str]) -> Vec<usize> {
+    let threshold = threshold();
+    if threshold >= 1.0 {
+        return Vec::new();
+    }
+    let mut kept: Vec<HashSet<String>> = Vec::new();
+    let mut duplicates = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let s = shingles(chunk);
+        match kept.iter().any(|k| containment(&s, k) >= threshold) {
+            true => duplicates.push(i),
+            false => kept.push(s),
+        }
+    }
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_chunks_after_the_first_are_duplicates() {
+        let page = "Pods are the smallest deployable units of computing in Kubernetes.";
+        let chunks = [page, "Services expose a set of pods behind one stable address.", page];
+        assert_eq!(near_duplicates(&chunks), vec![2]);
+    }
+
+    #[test]
+    fn a_chunk_inside_a_longer_one_is_a_duplicate() {
+        let chunks = [
+            "Some introduction. Pods are the smallest deployable units of computing in Kubernetes. More about services.",
+            "Pods are the smallest deployable units of computing in Kubernetes.",
+        ];
+        assert_eq!(near_duplicates(&chunks), vec![1]);
+    }
+
+    #[test]
+    fn punctuation_and_case_do_not_tell_chunks_apart() {
+        let chunks = ["Restart the pod, then check its logs again.", "restart the POD then check its logs again"];
+        assert_eq!(near_duplicates(&chunks), vec![1]);
+    }
+
+    #[test]
+    fn unrelated_chunks_are_kept() {
+        let chunks = [
+            "Pods are the smallest deployable units of computing in Kubernetes.",
+            "A Deployment manages a replicated application on your cluster.",
+        ];
+        assert!(near_duplicates(&chunks).is_empty());
+    }
+}
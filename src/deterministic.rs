@@ -0,0 +1,37 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+use crate::settings;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns deterministic mode on for the request when the `deterministic` setting is `true` or the
+/// request sends `x-deterministic: true`, as the eval harness and snapshot tests do.
+pub fn begin(headers: &[(String, String)]) {
+    let header = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("x-deterministic"))
+        .map(|(_, v)| v.trim().eq_ignore_ascii_case("true"));
+    let enabled = header.unwrap_or(std::env::var("deterministic").unwrap_or_default() == "true");
+    if enabled {
+        log::debug!("Serving the request in deterministic mode");
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The sampling temperature of every model call, 0 in deterministic mode and the model's default
+/// otherwise.
+pub fn temperature() -> Option<f32> {
+    enabled().then_some(0.0)
+}
+
+/// The seed of anything sampled at random, `deterministic_seed` (default 42) in deterministic
+/// mode. The LLM service takes no seed, temperature 0 is what makes its answers repeatable.
+pub fn seed() -> Option<u64> {
+    enabled().then(|| {
+        settings::setting("deterministic_seed", 42)
+    })
+}
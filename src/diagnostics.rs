@@ -0,0 +1,195 @@
+use std::{ future::Future, time::Instant };
+
+use llmservice_flows::chat::ChatOptions;
+use serde::Serialize;
+use serde_json::json;
+use store_flows::{ del, get, Expire, ExpireKind };
+use vector_store_flows::*;
+
+use crate::{
+    cache_metrics,
+    collection_config::{ self, CollectionConfig },
+    error::{ Error, Result },
+    first_x_chars,
+    guards,
+    retry,
+    CHAT_MODEL,
+};
+
+const PROBE_KEY: &str = "diagnostics:probe";
+const PROBE_TEXT: &str = "How do I restart a pod?";
+
+/// The outcome of probing one dependency.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    /// What was found, or why the probe failed.
+    pub detail: String,
+}
+
+/// Every probe of `/diag` and `?action=diag`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Runs a probe, timing it. Probes call the dependencies directly, without the retries or the
+/// embedding cache of regular requests, so a failure shows on the first try.
+async fn probe<F, Fut>(name: &str, f: F) -> Check where F: FnOnce() -> Fut, Fut: Future<Output = Result<String>> {
+    let started = Instant::now();
+    let result = f().await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(detail) => Check { name: name.to_string(), ok: true, latency_ms, detail },
+        Err(e) => {
+            log::error!("Diagnostics: {} failed: {}", name, e);
+            Check { name: name.to_string(), ok: false, latency_ms, detail: e.to_string() }
+        }
+    }
+}
+
+/// Writes, reads back and deletes a probe value.
+async fn kv_store() -> Result<String> {
+    let value = format!("{}", rand::random::<u64>());
+    guards::set_capped(PROBE_KEY, json!(value), Some(Expire { kind: ExpireKind::Ex, value: 60 }));
+    let read = get(PROBE_KEY);
+    del(PROBE_KEY);
+    match read.as_ref().and_then(|v| v.as_str()) == Some(value.as_str()) {
+        true => Ok("write, read and delete round-trip".to_string()),
+        false => Err(Error::KvStore(format!("read back {:?} instead of the value written", read))),
+    }
+}
+
+async fn collection(name: &str) -> Result<String> {
+    let info = collection_info(name).await.map_err(|e| Error::vector_store("info", name, e))?;
+    let config = CollectionConfig::load(name);
+    Ok(format!("{} points, {} dimensions configured", info.points_count, config.vector_size))
+}
+
+/// Embeds a sample question, checking the vector size against the collection's.
+async fn embedding(config: &CollectionConfig) -> Result<Vec<f32>> {
+    let vector = config
+        .embed_uncached(&[PROBE_TEXT.to_string()]).await?
+        .pop()
+        .ok_or(Error::Embedding("no embedding returned".to_string()))?;
+    if (vector.len() as u64) != config.vector_size {
+        return Err(
+            Error::Embedding(format!("{} dimensions returned, the collection is configured for {}", vector.len(), config.vector_size))
+        );
+    }
+    Ok(vector)
+}
+
+async fn search(name: &str, vector: Vec<f32>) -> Result<String> {
+    let p = PointsSearchParams { vector, limit: 1 };
+    let points = search_points(name, &p).await.map_err(|e| Error::vector_store("search", name, e))?;
+    Ok(match points.first() {
+        Some(p) => format!("best score {:.3}", p.score),
+        None => "no points returned".to_string(),
+    })
+}
+
+async fn llm_service() -> Result<String> {
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    if endpoint.is_empty() {
+        return Err(Error::InvalidInput("`llm_endpoint` is not set".to_string()));
+    }
+    let llm = retry::llm_client(&endpoint, &api_key);
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some("Reply with the single word OK."),
+        max_tokens: Some(5),
+        ..Default::default()
+    };
+    let reply = llm.chat_completion("diagnostics", "Are you there?", &co).await.map_err(Error::Llm)?;
+    Ok(format!("{} at {} replied `{}`", CHAT_MODEL, endpoint, first_x_chars(reply.choice.trim(), 40)))
+}
+
+async fn openai() -> Result<String> {
+    let openai = retry::openai_client();
+    let co = openai_flows::chat::ChatOptions {
+        model: openai_flows::chat::ChatModel::GPT4Turbo,
+        restart: true,
+        system_prompt: Some("Reply with the single word OK."),
+        max_tokens: Some(5),
+        ..Default::default()
+    };
+    let reply = openai.chat_completion("diagnostics", "Are you there?", &co).await.map_err(Error::Llm)?;
+    Ok(format!("replied `{}`", first_x_chars(reply.choice.trim(), 40)))
+}
+
+/// Probes the KV store, every allowed collection, an embedding and search round-trip on
+/// `collection_name`, and both model endpoints.
+pub async fn run(collection_name: &str) -> Report {
+    let mut checks = vec![probe("kv store", kv_store).await];
+
+    for name in &collection_config::allowed(collection_name) {
+        checks.push(probe(&format!("collection {name}"), || collection(name)).await);
+    }
+
+    let config = CollectionConfig::load(collection_name);
+    let started = Instant::now();
+    let embedded = embedding(&config).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let embedding_check = format!("embedding ({:?})", config.provider);
+    match embedded {
+        Ok(vector) => {
+            checks.push(Check {
+                name: embedding_check,
+                ok: true,
+                latency_ms,
+                detail: format!("{} dimensions", vector.len()),
+            });
+            checks.push(probe(&format!("search {collection_name}"), || search(collection_name, vector)).await);
+        }
+        Err(e) => {
+            log::error!("Diagnostics: {} failed: {}", embedding_check, e);
+            checks.push(Check { name: embedding_check, ok: false, latency_ms, detail: e.to_string() });
+        }
+    }
+
+    checks.push(probe("llm service", llm_service).await);
+    checks.push(probe("openai", openai).await);
+
+    // the counters are shown for judging the caches, they do not fail the report
+    for (cache, stats) in cache_metrics::all() {
+        checks.push(Check {
+            name: format!("{cache} cache"),
+            ok: true,
+            latency_ms: 0,
+            detail: stats.summary(),
+        });
+    }
+
+    Report { ok: checks.iter().all(|c| c.ok), checks }
+}
+
+impl Report {
+    /// The report in a few lines for the chat.
+    pub fn markdown(&self) -> String {
+        let lines = self.checks
+            .iter()
+            .map(|c| {
+                let status = match c.ok {
+                    true => "ok",
+                    false => "FAILED",
+                };
+                format!("- **{}**: {} in {} ms, {}", c.name, status, c.latency_ms, c.detail)
+            })
+            .collect::<Vec<String>>();
+        let failed = self.checks
+            .iter()
+            .filter(|c| !c.ok)
+            .count();
+        let summary = match failed {
+            0 => "**All checks passed.**".to_string(),
+            n => format!("**{n} of {} checks failed.**", self.checks.len()),
+        };
+        format!("{}\n{}", summary, lines.join("\n"))
+    }
+}
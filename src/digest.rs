@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{ json, Value };
+
+use crate::{ analytics::now_secs, audit, chat_id, error::{ Error, Result }, fetch, settings::setting, short_query };
+
+const TOP_TOPICS: usize = 5;
+/// A conversation is flagged once this many of its answers were poor.
+const FLAG_AFTER: usize = 2;
+const LOW_CONFIDENCE_LISTED: usize = 10;
+
+/// How often each topic word, or each conversation's poor answer, came up.
+type Counts = HashMap<String, usize>;
+
+/// What one tenant's conversations looked like over the digest period.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Digest {
+    /// The knowledge base collection of the tenant.
+    pub collection: String,
+    pub since: u64,
+    pub questions: usize,
+    pub conversations: usize,
+    pub top_topics: Vec<(String, usize)>,
+    /// Chats with at least [`FLAG_AFTER`] failed, unanswered or low confidence answers, by their
+    /// conversation name when known.
+    pub flagged_conversations: Vec<String>,
+    /// Questions answered below `digest_low_confidence`, lowest first.
+    pub low_confidence: Vec<(String, f32)>,
+}
+
+impl Digest {
+    fn subject(&self) -> String {
+        format!("Chatbot digest for {}: {} questions", self.collection, self.questions)
+    }
+
+    fn text(&self) -> String {
+        let mut lines = vec![
+            format!("{} questions in {} conversations.", self.questions, self.conversations),
+            String::new(),
+            "Top topics:".to_string()
+        ];
+        lines.extend(self.top_topics.iter().map(|(t, n)| format!("- {t} ({n})")));
+        lines.push(String::new());
+        match self.flagged_conversations.is_empty() {
+            true => lines.push("No flagged conversations.".to_string()),
+            false => {
+                lines.push("Flagged conversations:".to_string());
+                lines.extend(self.flagged_conversations.iter().map(|c| format!("- {c}")));
+            }
+        }
+        lines.push(String::new());
+        match self.low_confidence.is_empty() {
+            true => lines.push("No low confidence answers.".to_string()),
+            false => {
+                lines.push("Low confidence answers:".to_string());
+                lines.extend(self.low_confidence.iter().map(|(q, c)| format!("- {c:.2}: {q}")));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// The recipients of each tenant's digest, from the `digest_recipients` JSON object of
+/// collection to email addresses.
+fn recipients() -> HashMap<String, Vec<String>> {
+    let setting = std::env::var("digest_recipients").unwrap_or("".to_string());
+    if setting.trim().is_empty() {
+        return HashMap::new();
+    }
+    serde_json::from_str(&setting).unwrap_or_else(|e| {
+        log::error!("Invalid digest_recipients: {}", e);
+        HashMap::new()
+    })
+}
+
+/// Compiles the digests of the last `days` from the audit log, one per collection answered from.
+pub fn compile(days: u64) -> Vec<Digest> {
+    let since = now_secs().saturating_sub(days * 86400);
+    let low_mark = setting("digest_low_confidence", 0.8f32);
+
+    let mut digests: HashMap<String, (Digest, Counts, Counts)> = HashMap::new();
+    for e in audit::entries(usize::MAX, None) {
+        if e["at"].as_u64().unwrap_or_default() < since {
+            continue;
+        }
+        let collection = e["provenance"]["collection"].as_str().unwrap_or_default().to_string();
+        let (digest, topics, poor) = digests.entry(collection.clone()).or_insert_with(|| {
+            (Digest { collection, since, ..Default::default() }, HashMap::new(), HashMap::new())
+        });
+
+        digest.questions += 1;
+        let question = e["question"].as_str().unwrap_or_default();
+        for word in short_query::content_words(question) {
+            *topics.entry(word).or_default() += 1;
+        }
+
+        let chat_id = e["chat_id"].as_str().unwrap_or_default().to_string();
+        let confidence = e["confidence"].as_f64().map(|c| c as f32);
+        let low = confidence.is_some_and(|c| c < low_mark);
+        if low && !question.is_empty() {
+            digest.low_confidence.push((question.to_string(), confidence.unwrap_or_default()));
+        }
+        let failed = e["error"].as_bool().unwrap_or_default() || e["category"].as_str() == Some("no_context");
+        let count = poor.entry(chat_id).or_default();
+        if failed || low {
+            *count += 1;
+        }
+    }
+
+    let mut digests = digests
+        .into_values()
+        .map(|(mut digest, topics, poor)| {
+            let mut topics = topics.into_iter().collect::<Vec<(String, usize)>>();
+            topics.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            topics.truncate(TOP_TOPICS);
+            digest.top_topics = topics;
+
+            digest.conversations = poor.keys().filter(|c| !c.is_empty()).count();
+            digest.flagged_conversations = poor
+                .into_iter()
+                .filter(|(c, n)| !c.is_empty() && *n >= FLAG_AFTER)
+                .map(|(c, _)| chat_id::display_name(&c))
+                .collect();
+            digest.flagged_conversations.sort();
+
+            digest.low_confidence.sort_by(|a, b| a.1.total_cmp(&b.1));
+            digest.low_confidence.truncate(LOW_CONFIDENCE_LISTED);
+            digest
+        })
+        .collect::<Vec<Digest>>();
+    digests.sort_by(|a, b| a.collection.cmp(&b.collection));
+    digests
+}
+
+/// Sends a digest through the HTTP email API at `digest_email_endpoint`, which takes a
+/// `{"from", "to", "subject", "text"}` JSON body with `digest_email_api_key` as a bearer token.
+fn send(digest: &Digest, to: &[String]) -> Result<()> {
+    let endpoint = std::env::var("digest_email_endpoint").unwrap_or("".to_string());
+    if endpoint.is_empty() {
+        return Err(Error::InvalidInput("digest_email_endpoint is not set".to_string()));
+    }
+    let api_key = std::env::var("digest_email_api_key").unwrap_or("".to_string());
+    let authorization = format!("Bearer {api_key}");
+    let body = json!({
+        "from": std::env::var("digest_email_from").unwrap_or("chatbot@localhost".to_string()),
+        "to": to,
+        "subject": digest.subject(),
+        "text": digest.text(),
+    });
+
+    fetch::post_json(&endpoint, &[("Authorization", authorization.as_str())], &serde_json::to_vec(&body)?)?;
+    Ok(())
+}
+
+/// Compiles the digests of the last `digest_days` (default 1) and emails each to the recipients
+/// of its collection, for a scheduler hitting `?action=digest`. With `dry_run` nothing is sent.
+pub fn run(dry_run: bool) -> Value {
+    let days = setting("digest_days", 1u64);
+    let recipients = recipients();
+
+    let reports = compile(days)
+        .into_iter()
+        .map(|digest| {
+            let to = recipients.get(&digest.collection).cloned().unwrap_or_default();
+            let sent = match (dry_run, to.is_empty()) {
+                (false, false) =>
+                    match send(&digest, &to) {
+                        Ok(()) => json!(true),
+                        Err(e) => {
+                            log::error!("Cannot send the digest of {}: {}", digest.collection, e);
+                            json!(e.to_string())
+                        }
+                    }
+                _ => json!(false),
+            };
+            json!({ "digest": digest, "to": to, "sent": sent })
+        })
+        .collect::<Vec<Value>>();
+    json!({ "days": days, "digests": reports })
+}
@@ -0,0 +1,24 @@
+use crate::collection_config::CollectionConfig;
+
+/// The disclosure appended to answers from the knowledge base `collection_name`, or `None` when
+/// no footer is configured.
+///
+/// The text comes from the `disclosure_footer` of the collection config, falling back to the
+/// `disclosure_footer` setting, and `{source}` in it is replaced with the sources the answer drew
+/// on. An empty footer in the collection config turns the footer off for that collection.
+pub fn footer(collection_name: &str, sources: &[String]) -> Option<String> {
+    let template = match CollectionConfig::load(collection_name).disclosure_footer {
+        Some(f) => f,
+        None => std::env::var("disclosure_footer").unwrap_or("".to_string()),
+    };
+    if template.trim().is_empty() {
+        return None;
+    }
+
+    let source = match sources.is_empty() {
+        true => "the model's general knowledge".to_string(),
+        false => sources.join(", "),
+    };
+
+    Some(template.replace("{source}", &source))
+}
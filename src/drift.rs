@@ -0,0 +1,246 @@
+use rand::{ rngs::StdRng, Rng, SeedableRng };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+use vector_store_flows::*;
+
+use crate::{ analytics::now_secs, collection_config::CollectionConfig, deterministic, error::{ Error, Result }, fetch, guards, ingest, settings };
+
+const PROFILE_KEY: &str = "drift:corpus";
+const RECENT_KEY: &str = "drift:recent";
+const ALERT_KEY: &str = "drift:alert";
+/// How many recent questions the drift is measured over.
+const WINDOW: usize = 200;
+/// Drift is checked every this many questions, on top of `?action=drift_check`.
+const CHECK_EVERY: usize = 50;
+const MIN_SAMPLES: usize = 20;
+/// Random search vectors used to sample the knowledge base, 30 points each.
+const PROBES: usize = 10;
+
+/// Where the knowledge base sits in embedding space: the centroid of a sample of its chunks and
+/// how similar the chunks are to it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CorpusProfile {
+    centroid: Vec<f32>,
+    mean_similarity: f32,
+    std_similarity: f32,
+    points: usize,
+    updated_at: u64,
+}
+
+/// Similarities of recent questions to the corpus centroid.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Recent {
+    similarities: Vec<f32>,
+    since_check: usize,
+    /// Mean and standard deviation of the first full window of questions after profiling. Questions
+    /// are shorter than chunks and sit farther from the centroid even when on topic, so drift is
+    /// measured against them once known.
+    baseline: Option<(f32, f32)>,
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a
+        .iter()
+        .zip(b.iter())
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+fn mean_std(values: &[f32]) -> (f32, f32) {
+    let n = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+    (mean, var.sqrt())
+}
+
+/// Samples the knowledge base with random search vectors and stores its profile. Run it through
+/// `?action=drift_baseline` after (re)ingesting the corpus.
+pub async fn build_profile(collection_name: &str) -> Result<Value> {
+    let config = CollectionConfig::load(collection_name);
+
+    let mut rng = match deterministic::seed() {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut sampled: Vec<(u64, Option<Vec<f32>>, String)> = Vec::new();
+    for _ in 0..PROBES {
+        let probe = (0..config.vector_size)
+            .map(|_| rng.gen_range(-1.0f32..1.0))
+            .collect::<Vec<f32>>();
+        let p = PointsSearchParams { vector: probe, limit: 30 };
+        let points = search_points(collection_name, &p).await.map_err(|e|
+            Error::vector_store("search", collection_name, e)
+        )?;
+        for p in points {
+            let id = match p.id {
+                PointId::Num(i) => i,
+                _ => 0,
+            };
+            if sampled.iter().any(|(seen, _, _)| *seen == id) {
+                continue;
+            }
+            let text = p.payload
+                .as_ref()
+                .and_then(|m| m.get("text"))
+                .and_then(|t| t.as_str())
+                .unwrap_or_default()
+                .to_string();
+            sampled.push((id, p.vector, text));
+        }
+    }
+    if sampled.is_empty() {
+        return Err(Error::vector_store("sample", collection_name, "no points to profile"));
+    }
+
+    // the vector store may leave vectors out of search results, embed those chunks again
+    let missing = sampled
+        .iter()
+        .filter(|(_, v, _)| v.is_none())
+        .map(|(_, _, t)| t.clone())
+        .collect::<Vec<String>>();
+    let mut embedded = ingest::embed_texts(&config, &missing).await?.into_iter();
+    let vectors = sampled
+        .into_iter()
+        .filter_map(|(_, v, _)| v.or_else(|| embedded.next()))
+        .collect::<Vec<Vec<f32>>>();
+
+    let mut centroid = vec![0f32; vectors[0].len()];
+    for v in &vectors {
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt().max(f32::EPSILON);
+        for (c, x) in centroid.iter_mut().zip(v.iter()) {
+            *c += x / norm;
+        }
+    }
+    let similarities = vectors
+        .iter()
+        .map(|v| cosine(v, &centroid))
+        .collect::<Vec<f32>>();
+    let (mean_similarity, std_similarity) = mean_std(&similarities);
+
+    let profile = CorpusProfile {
+        // four decimals keep the profile well within the KV value limit
+        centroid: centroid
+            .iter()
+            .map(|c| (c / (vectors.len() as f32) * 10000.0).round() / 10000.0)
+            .collect(),
+        mean_similarity,
+        std_similarity,
+        points: vectors.len(),
+        updated_at: now_secs(),
+    };
+    guards::set_capped(PROFILE_KEY, serde_json::to_value(&profile).unwrap_or_default(), None);
+    guards::set_capped(RECENT_KEY, json!(Recent::default()), None);
+
+    Ok(
+        json!({
+            "points": profile.points,
+            "mean_similarity": profile.mean_similarity,
+            "std_similarity": profile.std_similarity,
+        })
+    )
+}
+
+/// Notes how close a question is to the corpus, checking for drift every [`CHECK_EVERY`] questions.
+/// Does nothing until a corpus profile is built.
+pub fn observe(question_vector: &[f32]) {
+    let profile = match get(PROFILE_KEY).and_then(|v| serde_json::from_value::<CorpusProfile>(v).ok()) {
+        Some(p) if p.centroid.len() == question_vector.len() => p,
+        _ => {
+            return;
+        }
+    };
+
+    let mut recent = get(RECENT_KEY)
+        .and_then(|v| serde_json::from_value::<Recent>(v).ok())
+        .unwrap_or_default();
+    recent.similarities.push((cosine(question_vector, &profile.centroid) * 1000.0).round() / 1000.0);
+    if recent.similarities.len() > WINDOW {
+        recent.similarities.remove(0);
+    }
+    recent.since_check += 1;
+    if recent.baseline.is_none() && recent.similarities.len() == WINDOW {
+        recent.baseline = Some(mean_std(&recent.similarities));
+    }
+
+    let due = recent.since_check >= CHECK_EVERY;
+    if due {
+        recent.since_check = 0;
+    }
+    guards::set_capped(RECENT_KEY, serde_json::to_value(&recent).unwrap_or_default(), None);
+
+    if due {
+        check();
+    }
+}
+
+/// Compares recent questions with the corpus profile and alerts when they drift away from it.
+///
+/// The drift is how far the mean similarity of recent questions to the corpus centroid has fallen
+/// below the reference, in its standard deviations. The reference is the first window of questions
+/// after profiling, or the corpus chunks themselves until that window is full. Alerts go to
+/// the log, the KV store (`drift:alert`) and `drift_alert_url` when set, once the drift against the
+/// question baseline exceeds `drift_threshold` (default 1.5).
+pub fn check() -> Value {
+    let profile = match get(PROFILE_KEY).and_then(|v| serde_json::from_value::<CorpusProfile>(v).ok()) {
+        Some(p) => p,
+        None => {
+            return json!({ "status": "no corpus profile, run ?action=drift_baseline first" });
+        }
+    };
+    let recent = get(RECENT_KEY)
+        .and_then(|v| serde_json::from_value::<Recent>(v).ok())
+        .unwrap_or_default();
+    if recent.similarities.len() < MIN_SAMPLES {
+        return json!({ "status": "not enough questions yet", "samples": recent.similarities.len() });
+    }
+
+    let threshold = settings::setting::<f32>("drift_threshold", 1.5);
+    let (recent_mean, _) = mean_std(&recent.similarities);
+    let (reference, (reference_mean, reference_std)) = match recent.baseline {
+        Some(b) => ("questions", b),
+        None => ("corpus", (profile.mean_similarity, profile.std_similarity)),
+    };
+    let spread = reference_std.max(0.001);
+    let drift = (reference_mean - recent_mean) / spread;
+    let floor = reference_mean - 2.0 * spread;
+    // questions never match chunks closely, so only alert against the question baseline
+    let alert = drift > threshold && recent.baseline.is_some();
+    let uncovered = recent.similarities
+        .iter()
+        .filter(|s| **s < floor)
+        .count();
+
+    let report = json!({
+        "status": "ok",
+        "drift": drift,
+        "threshold": threshold,
+        "alert": alert,
+        "samples": recent.similarities.len(),
+        "reference": reference,
+        "reference_mean_similarity": reference_mean,
+        "recent_mean_similarity": recent_mean,
+        "corpus_mean_similarity": profile.mean_similarity,
+        "corpus_std_similarity": profile.std_similarity,
+        "uncovered_share": (uncovered as f32) / (recent.similarities.len() as f32),
+        "checked_at": now_secs(),
+    });
+
+    if alert {
+        log::error!("Questions drifted away from the knowledge base: {}", report);
+        guards::set_capped(ALERT_KEY, report.clone(), None);
+
+        let url = std::env::var("drift_alert_url").unwrap_or("".to_string());
+        if !url.is_empty() {
+            if let Err(e) = fetch::post_json(&url, &[], report.to_string().as_bytes()) {
+                log::error!("Cannot send the drift alert: {}", e);
+            }
+        }
+    }
+
+    report
+}
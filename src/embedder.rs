@@ -0,0 +1,172 @@
+use std::future::Future;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{ collection_config::EmbeddingProvider, error::{ Error, Result }, fetch, retry, settings };
+
+/// Turns texts into vectors. Vectors of different embedders are not comparable, so whatever
+/// searches a collection must use the embedder it was written with.
+pub trait Embedder {
+    /// One vector per text, in the order of the texts.
+    fn embed(&self, texts: &[String]) -> impl Future<Output = Result<Vec<Vec<f32>>>>;
+}
+
+/// The provider of collections without settings of their own, from the `embedding_provider`
+/// setting: `open_ai` (the default), `llm_service` or `local`.
+pub fn default_provider() -> EmbeddingProvider {
+    match std::env::var("embedding_provider").unwrap_or_default().as_str() {
+        "llm_service" => EmbeddingProvider::LlmService,
+        "local" => EmbeddingProvider::Local,
+        "open_ai" | "" => EmbeddingProvider::OpenAI,
+        other => {
+            log::error!("Unknown embedding_provider {}, using open_ai", other);
+            EmbeddingProvider::OpenAI
+        }
+    }
+}
+
+/// The vector size of collections without settings of their own, `embedding_vector_size`
+/// (default 1536, the size of text-embedding-ada-002).
+pub fn default_vector_size() -> u64 {
+    settings::setting("embedding_vector_size", 1536)
+}
+
+/// OpenAI through flows.network, text-embedding-ada-002.
+pub struct OpenAIEmbedder;
+
+impl Embedder for OpenAIEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let openai = retry::openai_client();
+        let vectors = retry
+            ::call("embeddings", || {
+                openai.create_embeddings(openai_flows::embeddings::EmbeddingsInput::Vec(texts.to_vec()))
+            }).await
+            .map_err(|e| Error::Embedding(format!("OpenAI returned an error: {e}")))?;
+        checked(to_f32(vectors), texts)
+    }
+}
+
+/// An OpenAI compatible endpoint through the LLM service.
+pub struct LlmServiceEmbedder {
+    pub endpoint: String,
+    pub model: Option<String>,
+    pub api_key: String,
+}
+
+impl LlmServiceEmbedder {
+    /// `endpoint` unless `None`, else `llm_endpoint`, with `LLM_API_KEY`.
+    pub fn new(endpoint: Option<&str>, model: Option<&str>) -> Self {
+        Self {
+            endpoint: match endpoint {
+                Some(e) => e.to_string(),
+                None => std::env::var("llm_endpoint").unwrap_or("".to_string()),
+            },
+            model: model.map(String::from),
+            api_key: std::env::var("LLM_API_KEY").unwrap_or("".to_string()),
+        }
+    }
+}
+
+impl Embedder for LlmServiceEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let llm = retry::llm_client(&self.endpoint, &self.api_key);
+        let vectors = retry
+            ::call("embeddings", || {
+                llm.create_embeddings(
+                    self.model.as_deref(),
+                    llmservice_flows::embeddings::EmbeddingsInput::Vec(texts.to_vec())
+                )
+            }).await
+            .map_err(|e| Error::Embedding(format!("LLM service returned an error: {e}")))?;
+        checked(to_f32(vectors), texts)
+    }
+}
+
+/// An OpenAI style `/embeddings` endpoint called directly, e.g. a text-embeddings-inference or
+/// Ollama server next to the deployment.
+pub struct LocalEmbedder {
+    pub endpoint: String,
+    pub model: Option<String>,
+    pub api_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LocalEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LocalResponse {
+    data: Vec<LocalEmbedding>,
+}
+
+impl LocalEmbedder {
+    /// `endpoint` unless `None`, else `embedding_endpoint`, with `embedding_model` as the default
+    /// model and `embedding_api_key` sent as a bearer token when set.
+    pub fn new(endpoint: Option<&str>, model: Option<&str>) -> Self {
+        Self {
+            endpoint: match endpoint {
+                Some(e) => e.to_string(),
+                None => std::env::var("embedding_endpoint").unwrap_or("".to_string()),
+            },
+            model: model.map(String::from).or(std::env::var("embedding_model").ok()),
+            api_key: std::env::var("embedding_api_key").unwrap_or("".to_string()),
+        }
+    }
+
+    fn request(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if self.endpoint.is_empty() {
+            return Err(Error::Embedding("the local provider needs embedding_endpoint".to_string()));
+        }
+        let url = format!("{}/embeddings", self.endpoint.trim_end_matches('/'));
+        let authorization = format!("Bearer {}", self.api_key);
+        let mut headers = Vec::new();
+        if !self.api_key.is_empty() {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        let mut body = json!({ "input": texts });
+        if let Some(model) = &self.model {
+            body["model"] = json!(model);
+        }
+
+        let res = fetch::post_json(&url, &headers, &serde_json::to_vec(&body)?)?;
+        let mut data = serde_json::from_slice::<LocalResponse>(&res)?.data;
+        data.sort_by_key(|e| e.index);
+        Ok(
+            data
+                .into_iter()
+                .map(|e| e.embedding)
+                .collect()
+        )
+    }
+}
+
+impl Embedder for LocalEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let vectors = retry
+            ::call("embeddings", || async { self.request(texts).map_err(|e| e.to_string()) }).await
+            .map_err(|e| Error::Embedding(format!("the local endpoint returned an error: {e}")))?;
+        checked(vectors, texts)
+    }
+}
+
+fn to_f32(vectors: Vec<Vec<f64>>) -> Vec<Vec<f32>> {
+    vectors
+        .into_iter()
+        .map(|v|
+            v
+                .iter()
+                .map(|n| *n as f32)
+                .collect()
+        )
+        .collect()
+}
+
+fn checked(vectors: Vec<Vec<f32>>, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    match vectors.len() == texts.len() {
+        true => Ok(vectors),
+        false => Err(Error::Embedding(format!("got {} embeddings for {} texts", vectors.len(), texts.len()))),
+    }
+}
@@ -0,0 +1,111 @@
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use serde_json::json;
+use sha2::{ Digest, Sha256 };
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{ cache_metrics::{ self, CacheStats }, guards, settings };
+
+/// The name of the cache in the cache metrics.
+const CACHE: &str = "embedding";
+
+/// Whether vectors are cached, off with `embedding_cache` set to `off`.
+pub fn enabled() -> bool {
+    std::env::var("embedding_cache").unwrap_or_default() != "off"
+}
+
+/// The cache key of a text embedded by `embedder`, which names the provider, model and endpoint:
+/// vectors of different embedders are not interchangeable.
+fn key(embedder: &str, text: &str) -> String {
+    let hash = Sha256::digest(format!("{embedder}\n{text}").as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("embedding_cache:{hash}")
+}
+
+pub fn encode(vector: &[f32]) -> String {
+    let bytes = vector
+        .iter()
+        .flat_map(|f| f.to_le_bytes())
+        .collect::<Vec<u8>>();
+    STANDARD.encode(bytes)
+}
+
+pub fn decode(vector: &str) -> Option<Vec<f32>> {
+    let bytes = STANDARD.decode(vector).ok()?;
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    )
+}
+
+/// The cached vector of each text, `None` for the texts to embed. Entries without a readable
+/// vector are dropped, to be written again once the text is embedded.
+pub fn lookup(embedder: &str, texts: &[String]) -> Vec<Option<Vec<f32>>> {
+    let mut evicted = 0;
+    let found = texts
+        .iter()
+        .map(|t| {
+            let key = key(embedder, t);
+            let entry = get(&key)?;
+            let vector = entry["vector"]
+                .as_str()
+                .and_then(decode)
+                .filter(|v| !v.is_empty());
+            if vector.is_none() {
+                store_flows::del(&key);
+                evicted += 1;
+            }
+            vector
+        })
+        .collect::<Vec<Option<Vec<f32>>>>();
+    cache_metrics::evicted(CACHE, evicted);
+
+    let hits = found
+        .iter()
+        .filter(|v| v.is_some())
+        .count() as u64;
+    cache_metrics::lookups(CACHE, hits, (texts.len() as u64) - hits);
+    found
+}
+
+/// Caches the vector of a text for `embedding_cache_ttl_days` (default 7). Vectors too large for
+/// the KV store are not cached rather than truncated.
+pub fn store(embedder: &str, text: &str, vector: &[f32]) {
+    let days = settings::setting("embedding_cache_ttl_days", 7i64);
+    let entry = json!({ "vector": encode(vector) });
+    cache_metrics::stored(CACHE, entry.to_string().len());
+    guards::set_capped(
+        &key(embedder, text),
+        entry,
+        Some(Expire {
+            kind: ExpireKind::Ex,
+            value: days * 86400,
+        })
+    );
+}
+
+pub fn stats() -> CacheStats {
+    cache_metrics::stats(CACHE)
+}
+
+/// The counters with the hit rate, for `?action=embedding_cache`.
+pub fn report() -> serde_json::Value {
+    let stats = stats();
+    json!({
+        "enabled": enabled(),
+        "hits": stats.hits,
+        "misses": stats.misses,
+        "hit_rate": stats.hit_rate(),
+        "stores": stats.stores,
+        "bytes_stored": stats.bytes_stored,
+        "rejected": stats.rejected,
+        "evictions": stats.evictions,
+    })
+}
+
+pub fn reset_stats() {
+    cache_metrics::reset_cache(CACHE);
+}
@@ -0,0 +1,133 @@
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use chacha20poly1305::{ aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce };
+use rand::RngCore;
+
+/// Marks an encrypted value: `enc:v1:<key id>:<base64 of nonce and ciphertext>`.
+const PREFIX: &str = "enc:v1:";
+
+/// A key given as `<id>:<base64 of 32 bytes>`.
+struct MemoryKey {
+    id: String,
+    cipher: ChaCha20Poly1305,
+}
+
+fn parse_key(s: &str) -> Option<MemoryKey> {
+    let (id, key) = s.trim().split_once(':')?;
+    let bytes = STANDARD.decode(key.trim())
+        .map_err(|e| log::error!("Memory encryption key {} is not base64: {}", id, e))
+        .ok()?;
+    if bytes.len() != 32 || id.is_empty() || id.contains(':') {
+        log::error!("Memory encryption key {} must be a non-empty id and 32 bytes", id);
+        return None;
+    }
+    Some(MemoryKey {
+        id: id.to_string(),
+        cipher: ChaCha20Poly1305::new(Key::from_slice(&bytes)),
+    })
+}
+
+/// The `memory_encryption_key` setting.
+enum CurrentKey {
+    Unset,
+    /// Set but not a valid key: nothing is stored rather than stored in the clear.
+    Invalid,
+    Key(MemoryKey),
+}
+
+/// The key new values are encrypted with, from `memory_encryption_key`.
+fn current_key() -> CurrentKey {
+    match std::env::var("memory_encryption_key").ok().filter(|s| !s.trim().is_empty()) {
+        None => CurrentKey::Unset,
+        Some(s) =>
+            match parse_key(&s) {
+                Some(k) => CurrentKey::Key(k),
+                None => CurrentKey::Invalid,
+            }
+    }
+}
+
+/// Whether `memory_encryption_key` is set, valid or not. Data that would be sealed is then not
+/// to be sent anywhere in the clear either.
+pub fn enabled() -> bool {
+    !matches!(current_key(), CurrentKey::Unset)
+}
+
+/// The key a value was encrypted with, the current one or one of the comma separated
+/// `memory_encryption_previous_keys` kept to read values written before a rotation.
+fn key_by_id(id: &str) -> Option<MemoryKey> {
+    let current = match current_key() {
+        CurrentKey::Key(k) => Some(k),
+        _ => None,
+    };
+    current
+        .into_iter()
+        .chain(
+            std::env
+                ::var("memory_encryption_previous_keys")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .filter_map(parse_key)
+        )
+        .find(|k| k.id == id)
+}
+
+/// Encrypts the text with the current key, or returns it as it is when encryption is off. With an
+/// invalid `memory_encryption_key` the value is dropped, returned empty.
+pub fn seal(text: &str) -> String {
+    let key = match current_key() {
+        CurrentKey::Key(k) => k,
+        CurrentKey::Unset => {
+            return text.to_string();
+        }
+        CurrentKey::Invalid => {
+            // never fall back to storing the plain text
+            log::error!("memory_encryption_key is set but invalid, not storing the value");
+            return String::new();
+        }
+    };
+
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    match key.cipher.encrypt(Nonce::from_slice(&nonce), text.as_bytes()) {
+        Ok(ciphertext) => {
+            let mut sealed = nonce.to_vec();
+            sealed.extend(ciphertext);
+            format!("{PREFIX}{}:{}", key.id, STANDARD.encode(sealed))
+        }
+        Err(e) => {
+            // never fall back to storing the plain text
+            log::error!("Cannot encrypt with memory key {}: {}", key.id, e);
+            String::new()
+        }
+    }
+}
+
+/// Decrypts a value written by [`seal`]. Values stored before encryption was turned on are
+/// returned as they are, values whose key is gone or that were tampered with are `None`.
+pub fn open(stored: &str) -> Option<String> {
+    let rest = match stored.strip_prefix(PREFIX) {
+        Some(r) => r,
+        None => {
+            return Some(stored.to_string());
+        }
+    };
+
+    let (id, data) = rest.split_once(':')?;
+    let key = match key_by_id(id) {
+        Some(k) => k,
+        None => {
+            log::error!("Memory encryption key {} is not configured", id);
+            return None;
+        }
+    };
+    let bytes = STANDARD.decode(data).ok().filter(|b| b.len() > 12)?;
+    let (nonce, ciphertext) = bytes.split_at(12);
+    match key.cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(plain) => String::from_utf8(plain).ok(),
+        Err(_) => {
+            log::error!("Cannot decrypt a value with memory key {}", id);
+            None
+        }
+    }
+}
@@ -0,0 +1,38 @@
+/// Errors of the helpers of this crate. Each call site decides whether an error fails the request
+/// or only degrades the answer, e.g. answering without the chat history when the memory
+/// collection is unavailable.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("embedding failed: {0}")]
+    Embedding(String),
+    #[error("chat completion failed: {0}")]
+    Llm(String),
+    #[error("vector store {op} on collection {collection} failed: {message}")]
+    VectorStore {
+        op: &'static str,
+        collection: String,
+        message: String,
+    },
+    #[error("collection {0} does not exist")]
+    MissingCollection(String),
+    #[error("KV store failed: {0}")]
+    KvStore(String),
+    #[error("HTTP request failed: {0}")]
+    Http(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    pub fn vector_store(op: &'static str, collection: &str, message: impl ToString) -> Self {
+        Error::VectorStore {
+            op,
+            collection: collection.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,92 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+
+/// The events of an answer in streaming mode, sent as server-sent events whose `data` is the
+/// JSON of the event, tagged with its `type`.
+///
+/// The webhook runtime sends the response once the handler returns, so a client receives the
+/// events of an answer together; front ends can still drive their states from them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    RetrievalStarted {
+        conversation_id: String,
+        lane: String,
+    },
+    ContextReady {
+        sources: Vec<String>,
+        /// The best retrieval score, when the knowledge base was used.
+        confidence: Option<f32>,
+    },
+    /// A piece of the answer, the pieces concatenated make the whole answer.
+    Token {
+        text: String,
+    },
+    /// A source the answer drew on, with the knowledge base chunk when it is one.
+    Citation {
+        source: String,
+        chunk_id: Option<u64>,
+        kb_version: Option<u64>,
+    },
+    Done {
+        category: String,
+        /// The id to rate the answer by, see `?action=feedback`.
+        #[serde(default)]
+        message_id: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ChatEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatEvent::RetrievalStarted { .. } => "retrieval_started",
+            ChatEvent::ContextReady { .. } => "context_ready",
+            ChatEvent::Token { .. } => "token",
+            ChatEvent::Citation { .. } => "citation",
+            ChatEvent::Done { .. } => "done",
+            ChatEvent::Error { .. } => "error",
+        }
+    }
+
+    /// Formats the event as a server-sent event.
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name(), serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Splits the answer into word tokens, keeping the whitespace so they concatenate back.
+pub fn tokens(answer: &str) -> Vec<ChatEvent> {
+    answer
+        .split_inclusive(char::is_whitespace)
+        .map(|t| ChatEvent::Token { text: t.to_string() })
+        .collect()
+}
+
+/// One citation per source, then one per knowledge base chunk of the provenance.
+pub fn citations(sources: &[String], provenance: &Value) -> Vec<ChatEvent> {
+    let collection = provenance["collection"].as_str().unwrap_or_default();
+    let mut citations = sources
+        .iter()
+        .map(|s| ChatEvent::Citation { source: s.clone(), chunk_id: None, kb_version: None })
+        .collect::<Vec<ChatEvent>>();
+    for c in provenance["chunks"].as_array().into_iter().flatten() {
+        citations.push(ChatEvent::Citation {
+            source: c["source"].as_str().unwrap_or(collection).to_string(),
+            chunk_id: c["id"].as_u64(),
+            kb_version: c["kb_version"].as_u64(),
+        });
+    }
+    citations
+}
+
+/// Renders the events as a `text/event-stream` body.
+pub fn event_stream(events: &[ChatEvent]) -> String {
+    events
+        .iter()
+        .map(|e| e.to_sse())
+        .collect()
+}
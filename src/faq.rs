@@ -0,0 +1,179 @@
+use llmservice_flows::chat::ChatOptions;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{
+    analytics::{ self, now_secs },
+    collection_config::CollectionConfig,
+    deterministic,
+    drift::cosine,
+    error::{ Error, Result },
+    guards,
+    ingest,
+    ranking::Ranking,
+    retry,
+    search_collection,
+    CHAT_MODEL,
+};
+
+const FAQ_KEY: &str = "faq";
+/// Questions at least this similar are taken as the same question asked differently.
+const CLUSTER_SIMILARITY: f32 = 0.85;
+/// Clusters asked fewer times than this are not worth an FAQ entry.
+const MIN_ASKED: usize = 2;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct FaqItem {
+    question: String,
+    answer: String,
+    /// How many of the recent questions the entry covers.
+    asked: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Faq {
+    collection: String,
+    generated_at: u64,
+    items: Vec<FaqItem>,
+}
+
+/// Groups the questions greedily around the first question of each group, largest groups first.
+fn clusters(questions: &[String], vectors: &[Vec<f32>]) -> Vec<Vec<usize>> {
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for (i, v) in vectors.iter().enumerate() {
+        match clusters.iter_mut().find(|c| cosine(&vectors[c[0]], v) >= CLUSTER_SIMILARITY) {
+            Some(c) => c.push(i),
+            None => clusters.push(vec![i]),
+        }
+    }
+    // the most recent wording of a question leads its cluster
+    for c in clusters.iter_mut() {
+        c.reverse();
+    }
+    clusters.retain(|c| c.len() >= MIN_ASKED && !questions[c[0]].is_empty());
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.len()));
+    clusters
+}
+
+/// Writes a polished Q&A pair for a question cluster from the knowledge base, `None` when the
+/// knowledge base does not cover it.
+async fn write_item(wordings: &[&str], collection_name: &str) -> Result<Option<FaqItem>> {
+    let found = search_collection(wordings[0], collection_name, &Ranking::default()).await?;
+    if found.is_empty() {
+        return Ok(None);
+    }
+    let context = found
+        .iter()
+        .map(|(_, _, text)| text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    let sys_prompt =
+        "You're writing the FAQ page of a technical knowledge base. Only use the excerpts provided.";
+    let usr_prompt = format!(
+        "Users asked the following, in different words:\n{}\n\nExcerpts from the knowledge base:\n{context}\n\nWrite one FAQ entry for it. Reply with a JSON object with a `question` field holding the question as a clear, general sentence and an `answer` field holding a concise answer of at most two paragraphs in markdown. Reply with `{{}}` if the excerpts do not answer it.",
+        wordings
+            .iter()
+            .map(|w| format!("- {w}"))
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 2048,
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let llm_endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let llm_api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&llm_endpoint, &llm_api_key);
+    let reply = retry
+        ::call("faq", || llm.chat_completion("faq-writer", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+
+    let json = reply
+        .find('{')
+        .zip(reply.rfind('}'))
+        .map(|(start, end)| &reply[start..=end])
+        .unwrap_or("{}");
+    let item = serde_json::from_str::<FaqItem>(json)?;
+    match item.question.trim().is_empty() || item.answer.trim().is_empty() {
+        true => Ok(None),
+        false => Ok(Some(item)),
+    }
+}
+
+/// Builds the FAQ from the most asked recent questions and publishes it for `?action=faq`. Meant
+/// to be run now and then through `?action=faq_generate`, as it makes one LLM call per entry.
+pub async fn generate(collection_name: &str, max_items: usize) -> Result<Value> {
+    let questions = analytics::recent_questions();
+    if questions.is_empty() {
+        return Err(Error::InvalidInput("no questions have been recorded yet".to_string()));
+    }
+
+    let config = CollectionConfig::load(collection_name);
+    let vectors = ingest::embed_texts(&config, &questions).await?;
+
+    let mut items = Vec::new();
+    for cluster in clusters(&questions, &vectors) {
+        if items.len() >= max_items {
+            break;
+        }
+        let wordings = cluster
+            .iter()
+            .take(5)
+            .map(|i| questions[*i].as_str())
+            .collect::<Vec<&str>>();
+        match write_item(&wordings, collection_name).await {
+            Ok(Some(mut item)) => {
+                item.asked = cluster.len();
+                items.push(item);
+            }
+            Ok(None) => log::info!("The knowledge base does not answer the FAQ question: {}", wordings[0]),
+            Err(e) => log::error!("Cannot write the FAQ entry for {}: {}", wordings[0], e),
+        }
+    }
+
+    let faq = Faq {
+        collection: collection_name.to_string(),
+        generated_at: now_secs(),
+        items,
+    };
+    let value = serde_json::to_value(&faq)?;
+    guards::set_capped(FAQ_KEY, value.clone(), None);
+    log::info!("Published an FAQ of {} entries from {} questions", faq.items.len(), questions.len());
+    Ok(value)
+}
+
+/// The published FAQ, empty until [`generate`] has run.
+pub fn load() -> Value {
+    get(FAQ_KEY).unwrap_or(json!({ "items": [] }))
+}
+
+/// The questions of the first `n` entries of the published FAQ, the most asked first.
+pub fn top_questions(n: usize) -> Vec<String> {
+    serde_json::from_value::<Faq>(load())
+        .unwrap_or_default()
+        .items.into_iter()
+        .take(n)
+        .map(|item| item.question.trim().to_string())
+        .collect()
+}
+
+/// The published FAQ as a markdown page.
+pub fn markdown() -> String {
+    let faq = serde_json::from_value::<Faq>(load()).unwrap_or_default();
+    let mut page = String::from("# Frequently asked questions\n");
+    for item in &faq.items {
+        page.push_str(&format!("\n## {}\n\n{}\n", item.question.trim(), item.answer.trim()));
+    }
+    page
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ analytics::now_secs, encryption, guards, memory, settings::setting, trace, transcript };
+
+/// The oldest votes are dropped once a collection has this many.
+const MAX_VOTES: usize = 1000;
+const RATINGS_KEY: &str = "feedback:ratings";
+/// The oldest ratings are dropped once there are this many.
+const MAX_RATINGS: usize = 1000;
+
+/// A thumbs up or down on an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+/// What `?action=feedback` takes.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct RatingRequest {
+    /// The chat id of the logs, else `conversation` as the client names it.
+    pub chat_id: Option<String>,
+    pub conversation: Option<String>,
+    /// The id the answer came with, in `message_id` or the `x-message-id` header.
+    pub message_id: String,
+    pub rating: Rating,
+}
+
+/// A rated answer, kept for evaluation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rated {
+    chat_id: String,
+    message_id: String,
+    rating: Rating,
+    /// Sealed while stored, like the memory.
+    question: String,
+    answer: String,
+    at: u64,
+    /// The collection the answer was given from, empty for ratings from before it was recorded.
+    #[serde(default)]
+    collection: String,
+}
+
+/// One chunk found helpful, from `/helpful` after an answer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Vote {
+    id: u64,
+    chat_id: String,
+    at: u64,
+}
+
+/// The boosts computed from the votes by [`rollup`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Boosts {
+    updated_at: u64,
+    boosts: HashMap<u64, f32>,
+}
+
+fn votes_key(collection_name: &str) -> String {
+    format!("feedback:votes:{collection_name}")
+}
+
+fn boosts_key(collection_name: &str) -> String {
+    format!("feedback:boosts:{collection_name}")
+}
+
+fn load_votes(collection_name: &str) -> Vec<Vote> {
+    get(&votes_key(collection_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Counts a vote for every knowledge base chunk used in the last answer of the chat. A chat
+/// votes once per chunk.
+pub fn helpful(chat_id: &str, collection_name: &str) -> String {
+    let used = trace
+        ::load(chat_id)
+        .map(|t| {
+            t.candidates
+                .into_iter()
+                .filter(|c| c.outcome == trace::Outcome::Used)
+                .map(|c| c.id)
+                .collect::<Vec<u64>>()
+        })
+        .unwrap_or_default();
+    if used.is_empty() {
+        return "Thanks! The last answer did not use the knowledge base, so there is nothing to learn from.".to_string();
+    }
+
+    let mut votes = load_votes(collection_name);
+    for id in used {
+        if !votes.iter().any(|v| v.id == id && v.chat_id == chat_id) {
+            votes.push(Vote { id, chat_id: chat_id.to_string(), at: now_secs() });
+        }
+    }
+    if votes.len() > MAX_VOTES {
+        votes.drain(..votes.len() - MAX_VOTES);
+    }
+    guards::set_capped(&votes_key(collection_name), serde_json::to_value(&votes).unwrap_or_default(), None);
+    "Thanks! The passages behind this answer will be preferred in future answers.".to_string()
+}
+
+/// A new id for an answer, which it is rated by.
+pub fn message_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn load_ratings() -> Vec<Rated> {
+    get(RATINGS_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Rates the answer `message_id` of the chat in its memory, where an answer rated down is no
+/// longer recalled, and keeps the rated pair for evaluation. Rating again replaces the rating.
+/// `None` when the answer is not in the memory.
+pub fn rate(chat_id: &str, message_id: &str, rating: Rating) -> Option<Value> {
+    let chunks = memory::rate(chat_id, message_id, rating);
+    if chunks.is_empty() {
+        return None;
+    }
+    let (question, answer) = transcript::rejoin(&chunks);
+    let collection = chunks
+        .first()
+        .map(|c| c.collection.clone())
+        .unwrap_or_default();
+
+    let mut ratings = load_ratings();
+    ratings.retain(|r| !(r.chat_id == chat_id && r.message_id == message_id));
+    ratings.push(Rated {
+        chat_id: chat_id.to_string(),
+        message_id: message_id.to_string(),
+        rating,
+        question: encryption::seal(&question),
+        answer: encryption::seal(&answer),
+        at: now_secs(),
+        collection,
+    });
+    if ratings.len() > MAX_RATINGS {
+        ratings.drain(..ratings.len() - MAX_RATINGS);
+    }
+    guards::set_capped(RATINGS_KEY, serde_json::to_value(&ratings).unwrap_or_default(), None);
+    log::info!("Answer {} of {} rated {:?}", message_id, chat_id, rating);
+    Some(json!({ "chat_id": chat_id, "message_id": message_id, "rating": rating }))
+}
+
+/// The rated answers, newest first, only those rated `rating` when given, for
+/// `?action=feedback_export`.
+pub fn ratings(rating: Option<Rating>, limit: usize) -> Value {
+    let rated = load_ratings()
+        .into_iter()
+        .rev()
+        .filter(|r| rating.is_none_or(|rating| r.rating == rating))
+        .take(limit)
+        .map(|r| {
+            json!({
+                "chat_id": r.chat_id,
+                "message_id": r.message_id,
+                "rating": r.rating,
+                "question": encryption::open(&r.question),
+                "answer": encryption::open(&r.answer),
+                "at": r.at,
+                "collection": r.collection,
+            })
+        })
+        .collect::<Vec<Value>>();
+    json!(rated)
+}
+
+/// Deletes the votes and ratings given before `cutoff`, or forgets which chat gave them and, for
+/// ratings, the rated pair. Returns how many are (or with `dry_run` would be) changed.
+pub fn apply_retention(collection_name: &str, cutoff: u64, anonymize: bool, dry_run: bool) -> usize {
+    let mut votes = load_votes(collection_name);
+    let old = |v: &Vote| v.at < cutoff && !(anonymize && v.chat_id.is_empty());
+    let affected = votes
+        .iter()
+        .filter(|v| old(v))
+        .count();
+    let mut ratings = load_ratings();
+    let old_rating = |r: &Rated| r.at < cutoff && !(anonymize && r.chat_id.is_empty());
+    let affected_ratings = ratings
+        .iter()
+        .filter(|r| old_rating(r))
+        .count();
+    if dry_run {
+        return affected + affected_ratings;
+    }
+
+    if affected > 0 {
+        match anonymize {
+            true => {
+                for v in votes.iter_mut().filter(|v| v.at < cutoff) {
+                    v.chat_id.clear();
+                }
+            }
+            false => votes.retain(|v| v.at >= cutoff),
+        }
+        guards::set_capped(&votes_key(collection_name), serde_json::to_value(&votes).unwrap_or_default(), None);
+    }
+    if affected_ratings > 0 {
+        match anonymize {
+            true => {
+                for r in ratings.iter_mut().filter(|r| r.at < cutoff) {
+                    r.chat_id.clear();
+                    r.question.clear();
+                    r.answer.clear();
+                }
+            }
+            false => ratings.retain(|r| r.at >= cutoff),
+        }
+        guards::set_capped(RATINGS_KEY, serde_json::to_value(&ratings).unwrap_or_default(), None);
+    }
+    affected + affected_ratings
+}
+
+/// Turns the votes into per-chunk boosts, each vote decaying with a half-life of
+/// `feedback_half_life_days` (default 30). A vote is worth `feedback_boost` (default 0.01) and a
+/// chunk gets at most `feedback_max_boost` (default 0.05). Votes that have decayed to nothing are
+/// forgotten. Meant to be run daily by a scheduler hitting `?action=feedback_boosts`.
+pub fn rollup(collection_name: &str) -> Value {
+    let half_life = setting("feedback_half_life_days", 30.0f32).max(1.0) * 86400.0;
+    let per_vote = setting("feedback_boost", 0.01f32);
+    let max_boost = setting("feedback_max_boost", 0.05f32);
+    let now = now_secs();
+
+    let mut votes = load_votes(collection_name);
+    // after 8 half-lives a vote is worth less than 0.5% of a fresh one
+    votes.retain(|v| (now.saturating_sub(v.at) as f32) < half_life * 8.0);
+    guards::set_capped(&votes_key(collection_name), serde_json::to_value(&votes).unwrap_or_default(), None);
+
+    let mut weights: HashMap<u64, f32> = HashMap::new();
+    for v in &votes {
+        let age = now.saturating_sub(v.at) as f32;
+        *weights.entry(v.id).or_default() += (0.5f32).powf(age / half_life);
+    }
+    let boosts = Boosts {
+        updated_at: now,
+        boosts: weights
+            .into_iter()
+            .map(|(id, w)| (id, (w * per_vote).min(max_boost)))
+            .collect(),
+    };
+    guards::set_capped(&boosts_key(collection_name), serde_json::to_value(&boosts).unwrap_or_default(), None);
+
+    json!({
+        "collection": collection_name,
+        "votes": votes.len(),
+        "updated_at": boosts.updated_at,
+        "boosts": boosts.boosts,
+    })
+}
+
+/// The boost of every chunk of the collection found helpful, as of the last [`rollup`].
+pub fn boosts(collection_name: &str) -> HashMap<u64, f32> {
+    get(&boosts_key(collection_name))
+        .and_then(|v| serde_json::from_value::<Boosts>(v).ok())
+        .map(|b| b.boosts)
+        .unwrap_or_default()
+}
@@ -0,0 +1,56 @@
+use http_req::{ request::{ Method, Request }, uri::Uri };
+use std::time::Duration;
+
+use crate::{ error::{ Error, Result }, first_x_chars };
+
+/// The timeout of a request unless the caller sets its own.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Sends a GET request and returns the body of a successful response.
+pub fn get(url: &str, headers: &[(&str, &str)]) -> Result<Vec<u8>> {
+    send(Method::GET, url, headers, None, TIMEOUT)
+}
+
+/// Sends a GET request that gives up after `timeout`.
+pub fn get_within(url: &str, headers: &[(&str, &str)], timeout: Duration) -> Result<Vec<u8>> {
+    send(Method::GET, url, headers, None, timeout)
+}
+
+/// Sends a POST request with a JSON body and returns the body of a successful response.
+pub fn post_json(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Vec<u8>> {
+    let content_length = body.len().to_string();
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type", "application/json"));
+    headers.push(("Content-Length", &content_length));
+    send(Method::POST, url, &headers, Some(body), TIMEOUT)
+}
+
+fn send(method: Method, url: &str, headers: &[(&str, &str)], body: Option<&[u8]>, timeout: Duration) -> Result<Vec<u8>> {
+    let uri = Uri::try_from(url).map_err(|e| Error::InvalidInput(format!("invalid url {url}: {e}")))?;
+    let name = format!("{:?}", method);
+
+    let mut writer = Vec::new();
+    let mut request = Request::new(&uri);
+    request.method(method).timeout(Some(timeout));
+    for (k, v) in headers {
+        request.header(k, v);
+    }
+    if let Some(body) = body {
+        request.body(body);
+    }
+
+    let res = request.send(&mut writer).map_err(|e| Error::Http(format!("{name} {url} failed: {e}")))?;
+    match res.status_code().is_success() {
+        true => Ok(writer),
+        false =>
+            Err(
+                Error::Http(
+                    format!(
+                        "{name} {url} returned {}: {}",
+                        res.status_code(),
+                        first_x_chars(&String::from_utf8_lossy(&writer), 256)
+                    )
+                )
+            ),
+    }
+}
@@ -0,0 +1,306 @@
+use regex::{ Captures, Regex };
+
+use crate::{ error::{ Error, Result }, origin };
+
+/// How the Markdown of an answer is rendered for the channel it goes to, from the `x-channel`
+/// header, else from the platform adapter of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Profile {
+    /// The Markdown of the model as it is, for the web client.
+    #[default]
+    Markdown,
+    /// Slack mrkdwn: `*bold*`, `<url|text>` links and `&`, `<`, `>` escaped.
+    Slack,
+    /// Telegram MarkdownV2, with every reserved character escaped.
+    Telegram,
+    /// Text without markup.
+    Plain,
+    /// Text without markup, sent in SMS segments, see [`crate::message_parts`].
+    Sms,
+    /// An HTML fragment with paragraphs, lists, links and code.
+    Html,
+}
+
+impl Profile {
+    pub fn select(headers: &[(String, String)]) -> Result<Self> {
+        let channel = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("x-channel"))
+            .map(|(_, v)| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty());
+        if let Some(channel) = channel {
+            return match channel.as_str() {
+                "markdown" | "web" => Ok(Profile::Markdown),
+                "slack" => Ok(Profile::Slack),
+                "telegram" => Ok(Profile::Telegram),
+                "plain" => Ok(Profile::Plain),
+                "sms" => Ok(Profile::Sms),
+                "html" => Ok(Profile::Html),
+                _ =>
+                    Err(
+                        Error::InvalidInput(
+                            format!("unknown channel `{channel}`, use markdown, slack, telegram, plain, sms or html")
+                        )
+                    ),
+            };
+        }
+        Ok(match origin::current().platform.as_str() {
+            "slack" => Profile::Slack,
+            "telegram" => Profile::Telegram,
+            "sms" | "twilio" => Profile::Sms,
+            _ => Profile::Markdown,
+        })
+    }
+
+    /// Renders a Markdown reply for the channel. Code blocks and inline code are kept verbatim
+    /// wherever the channel has code formatting.
+    pub fn apply(&self, markdown: &str) -> String {
+        match self {
+            Profile::Markdown => markdown.to_string(),
+            Profile::Slack => slack(markdown),
+            Profile::Telegram => telegram(markdown),
+            Profile::Plain | Profile::Sms => plain(markdown),
+            Profile::Html => html(markdown),
+        }
+    }
+}
+
+/// The parts of a Markdown text: prose, inline code and fenced code blocks.
+enum Part<'a> {
+    Prose(&'a str),
+    Inline(&'a str),
+    Block {
+        lang: &'a str,
+        code: &'a str,
+    },
+}
+
+fn parts(markdown: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    for (n, piece) in markdown.split("```").enumerate() {
+        if n % 2 == 1 {
+            let (lang, code) = piece.split_once('\n').unwrap_or(("", piece));
+            parts.push(Part::Block { lang: lang.trim(), code: code.trim_end_matches('\n') });
+            continue;
+        }
+        for (m, piece) in piece.split('`').enumerate() {
+            match m % 2 {
+                1 => parts.push(Part::Inline(piece)),
+                _ if !piece.is_empty() => parts.push(Part::Prose(piece)),
+                _ => {}
+            }
+        }
+    }
+    parts
+}
+
+/// Bold text, links and headings of prose.
+fn inline_markup() -> Regex {
+    Regex::new(r"(?m)\*\*(?P<bold>.+?)\*\*|\[(?P<text>[^\]]+)\]\((?P<url>[^)\s]+)\)|^#{1,6}\s+(?P<heading>.+)$").unwrap()
+}
+
+/// Rewrites the markup of prose with `markup` and the text between with `text`.
+fn convert(prose: &str, markup: impl Fn(&Captures) -> String, text: impl Fn(&str) -> String) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in inline_markup().captures_iter(prose) {
+        let m = caps.get(0).unwrap();
+        out.push_str(&text(&prose[last..m.start()]));
+        out.push_str(&markup(&caps));
+        last = m.end();
+    }
+    out.push_str(&text(&prose[last..]));
+    out
+}
+
+fn slack_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// A `<url|text>` link: `|` would end the URL early, so it is percent-encoded, and `&`, `<` and
+/// `>` are escaped in both parts. Links other than web and mail links are left as their text.
+fn slack_link(url: &str, text: &str) -> String {
+    match safe_link(url) {
+        true => format!("<{}|{}>", slack_escape(&url.replace('|', "%7C")), slack_escape(text)),
+        false => slack_escape(text),
+    }
+}
+
+fn slack(markdown: &str) -> String {
+    parts(markdown)
+        .into_iter()
+        .map(|p| match p {
+            Part::Prose(s) =>
+                convert(
+                    s,
+                    |c| match (c.name("bold"), c.name("heading"), c.name("url")) {
+                        (Some(b), _, _) => format!("*{}*", slack_escape(b.as_str())),
+                        (_, Some(h), _) => format!("*{}*", slack_escape(h.as_str())),
+                        (_, _, Some(u)) => slack_link(u.as_str(), &c["text"]),
+                        _ => String::new(),
+                    },
+                    slack_escape
+                ),
+            Part::Inline(s) => format!("`{}`", slack_escape(s)),
+            // Slack has no syntax highlighting, the language line would show as code
+            Part::Block { code, .. } => format!("```\n{}\n```", slack_escape(code)),
+        })
+        .collect()
+}
+
+fn telegram_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Inside code only `` ` `` and `\` are escaped.
+fn telegram_escape_code(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+fn telegram(markdown: &str) -> String {
+    parts(markdown)
+        .into_iter()
+        .map(|p| match p {
+            Part::Prose(s) =>
+                convert(
+                    s,
+                    |c| match (c.name("bold"), c.name("heading"), c.name("url")) {
+                        (Some(b), _, _) => format!("*{}*", telegram_escape(b.as_str())),
+                        (_, Some(h), _) => format!("*{}*", telegram_escape(h.as_str())),
+                        (_, _, Some(u)) =>
+                            format!(
+                                "[{}]({})",
+                                telegram_escape(&c["text"]),
+                                u.as_str().replace('\\', "\\\\").replace(')', "\\)")
+                            ),
+                        _ => String::new(),
+                    },
+                    telegram_escape
+                ),
+            Part::Inline(s) => format!("`{}`", telegram_escape_code(s)),
+            Part::Block { lang, code } => format!("```{}\n{}\n```", lang, telegram_escape_code(code)),
+        })
+        .collect()
+}
+
+fn plain(markdown: &str) -> String {
+    let text = parts(markdown)
+        .into_iter()
+        .map(|p| match p {
+            Part::Prose(s) =>
+                convert(
+                    s,
+                    |c| match (c.name("bold"), c.name("heading"), c.name("url")) {
+                        (Some(b), _, _) => b.as_str().to_string(),
+                        (_, Some(h), _) => h.as_str().to_string(),
+                        (_, _, Some(u)) => format!("{} ({})", &c["text"], u.as_str()),
+                        _ => String::new(),
+                    },
+                    |t| t.to_string()
+                ),
+            Part::Inline(s) => s.to_string(),
+            Part::Block { code, .. } => format!("\n{code}\n"),
+        })
+        .collect::<String>();
+
+    // one line per paragraph, SMS has no room for blank lines
+    text.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Whether a link target is safe to put in an `href`: web and mail links only, never
+/// `javascript:` or `data:` ones.
+fn safe_link(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    ["http://", "https://", "mailto:"].iter().any(|scheme| url.starts_with(scheme))
+}
+
+fn html(markdown: &str) -> String {
+    // inline parts are rendered first, then the text is cut into paragraphs and lists
+    let mut blocks = Vec::new();
+    let mut text = String::new();
+    for p in parts(markdown) {
+        match p {
+            Part::Prose(s) =>
+                text.push_str(
+                    &convert(
+                        s,
+                        |c| match (c.name("bold"), c.name("heading"), c.name("url")) {
+                            (Some(b), _, _) => format!("<strong>{}</strong>", html_escape(b.as_str())),
+                            (_, Some(h), _) => format!("<h3>{}</h3>", html_escape(h.as_str())),
+                            (_, _, Some(u)) if safe_link(u.as_str()) =>
+                                format!("<a href=\"{}\">{}</a>", html_escape(u.as_str()), html_escape(&c["text"])),
+                            (_, _, Some(_)) => html_escape(&c["text"]),
+                            _ => String::new(),
+                        },
+                        html_escape
+                    )
+                ),
+            Part::Inline(s) => text.push_str(&format!("<code>{}</code>", html_escape(s))),
+            Part::Block { lang, code } => {
+                blocks.extend(paragraphs(&std::mem::take(&mut text)));
+                let class = match lang.is_empty() {
+                    true => String::new(),
+                    false => format!(" class=\"language-{}\"", html_escape(lang)),
+                };
+                blocks.push(format!("<pre><code{}>{}</code></pre>", class, html_escape(code)));
+            }
+        }
+    }
+    blocks.extend(paragraphs(&text));
+    blocks.join("\n")
+}
+
+fn paragraphs(text: &str) -> Vec<String> {
+    let bullet = Regex::new(r"^\s*(?:[-*]|\d+\.)\s+").unwrap();
+    text.split("\n\n")
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let lines = p.lines().collect::<Vec<&str>>();
+            if lines.iter().all(|l| bullet.is_match(l)) {
+                let items = lines
+                    .iter()
+                    .map(|l| format!("<li>{}</li>", bullet.replace(l, "")))
+                    .collect::<String>();
+                return format!("<ul>{items}</ul>");
+            }
+            if p.starts_with("<h3>") && p.ends_with("</h3>") {
+                return p.to_string();
+            }
+            format!("<p>{}</p>", lines.join("<br>"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slack_links_escape_url_and_label() {
+        assert_eq!(
+            slack_link("https://example.com/a?b=1&c=2|d", "a <b> & c"),
+            "<https://example.com/a?b=1&amp;c=2%7Cd|a &lt;b&gt; &amp; c>"
+        );
+    }
+
+    #[test]
+    fn unsafe_slack_links_are_text() {
+        assert_eq!(slack_link("javascript:alert(1)", "click <here>"), "click &lt;here&gt;");
+    }
+}
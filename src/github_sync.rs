@@ -0,0 +1,320 @@
+use std::collections::{ BTreeMap, HashSet };
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::{ del, get };
+
+use crate::{
+    bulk_delete,
+    error::{ Error, Result },
+    fetch,
+    guards,
+    ingest::{ self, Document, IngestOptions, Progress },
+    settings,
+    webhooks,
+};
+
+/// Changed files re-embedded per run, the others are picked up by the next runs.
+const MAX_FILES_PER_RUN: usize = 40;
+/// Extensions of the documentation files synced.
+const DOC_EXTENSIONS: [&str; 5] = [".md", ".mdx", ".markdown", ".txt", ".rst"];
+
+/// Where the documentation is read from, set with `github_docs_repo` (`owner/name`),
+/// `github_docs_branch` (default `main`) and `github_docs_path` (a folder of the repository,
+/// default the whole of it).
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    pub repo: String,
+    pub branch: String,
+    pub path: String,
+}
+
+impl Source {
+    pub fn configured() -> Result<Self> {
+        let repo = std::env::var("github_docs_repo").unwrap_or("".to_string());
+        if repo.split('/').filter(|p| !p.trim().is_empty()).count() != 2 {
+            return Err(Error::InvalidInput("set `github_docs_repo` to the `owner/name` of the repository".to_string()));
+        }
+        let branch = std::env::var("github_docs_branch").unwrap_or("main".to_string());
+        let path = std::env::var("github_docs_path").unwrap_or("".to_string());
+        Ok(Source {
+            repo: repo.trim().to_string(),
+            branch: branch.trim().to_string(),
+            path: path.trim().trim_matches('/').to_string(),
+        })
+    }
+
+    /// The link stored as the `source` of the chunks of a file.
+    fn link(&self, path: &str) -> String {
+        format!("https://github.com/{}/blob/{}/{}", self.repo, self.branch, path)
+    }
+
+    fn wanted(&self, path: &str) -> bool {
+        let lower = path.to_lowercase();
+        (self.path.is_empty() || path.starts_with(&format!("{}/", self.path))) &&
+            DOC_EXTENSIONS.iter().any(|ext| lower.ends_with(ext))
+    }
+}
+
+/// The GitHub API headers, with `GITHUB_TOKEN` for private repositories and a higher rate limit.
+fn headers(token: &str) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("User-Agent", "chat-with-text".to_string()), ("Accept", "application/vnd.github+json".to_string())];
+    if !token.is_empty() {
+        headers.push(("Authorization", format!("Bearer {token}")));
+    }
+    headers
+}
+
+fn github_get(url: &str) -> Result<Vec<u8>> {
+    let token = std::env::var("GITHUB_TOKEN").unwrap_or("".to_string());
+    let headers = headers(&token);
+    let headers = headers
+        .iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .collect::<Vec<(&str, &str)>>();
+    fetch::get(url, &headers)
+}
+
+#[derive(Deserialize)]
+struct Tree {
+    tree: Vec<TreeEntry>,
+    #[serde(default)]
+    truncated: bool,
+}
+
+#[derive(Deserialize)]
+struct TreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+    sha: String,
+}
+
+/// The documentation files of the branch with their blob hash, which changes with their content.
+fn list_files(source: &Source) -> Result<BTreeMap<String, String>> {
+    let url = format!("https://api.github.com/repos/{}/git/trees/{}?recursive=1", source.repo, source.branch);
+    let tree: Tree = serde_json::from_slice(&github_get(&url)?)?;
+    if tree.truncated {
+        log::warn!("The tree of {} is too large to be listed whole, set github_docs_path to the documentation folder", source.repo);
+    }
+    Ok(
+        tree.tree
+            .into_iter()
+            .filter(|e| e.kind == "blob" && source.wanted(&e.path))
+            .map(|e| (e.path, e.sha))
+            .collect()
+    )
+}
+
+/// Before the hashes were kept in parts, read once so the next sync does not start over.
+fn legacy_hashes_key(collection_name: &str) -> String {
+    format!("github_sync:{collection_name}")
+}
+
+fn parts_key(collection_name: &str) -> String {
+    format!("github_sync:{collection_name}:parts")
+}
+
+fn part_key(collection_name: &str, part: u64) -> String {
+    format!("github_sync:{collection_name}:{part}")
+}
+
+/// The blob hashes of the files in the collection, by path, as of the last sync. They are kept in
+/// parts of half the largest value of the KV store, a large repository does not fit in one.
+fn stored_hashes(collection_name: &str) -> BTreeMap<String, String> {
+    let Some(parts) = get(&parts_key(collection_name)).and_then(|v| v.as_u64()) else {
+        return get(&legacy_hashes_key(collection_name))
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or_default();
+    };
+    (0..parts)
+        .filter_map(|part| get(&part_key(collection_name, part)))
+        .filter_map(|v| serde_json::from_value::<BTreeMap<String, String>>(v).ok())
+        .flatten()
+        .collect()
+}
+
+fn save_hashes(collection_name: &str, hashes: &BTreeMap<String, String>) {
+    let max = guards::max_store_value_bytes() / 2;
+    let mut parts: Vec<BTreeMap<&String, &String>> = vec![BTreeMap::new()];
+    let mut size = 2;
+    for (path, sha) in hashes {
+        // the quotes, colon and comma around the pair
+        let entry = path.len() + sha.len() + 6;
+        if size + entry > max && parts.last().is_some_and(|p| !p.is_empty()) {
+            parts.push(BTreeMap::new());
+            size = 2;
+        }
+        if let Some(part) = parts.last_mut() {
+            part.insert(path, sha);
+        }
+        size += entry;
+    }
+
+    let before = get(&parts_key(collection_name))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    for (i, part) in parts.iter().enumerate() {
+        guards::set_capped(&part_key(collection_name, i as u64), json!(part), None);
+    }
+    for stale in (parts.len() as u64)..before {
+        del(&part_key(collection_name, stale));
+    }
+    guards::set_capped(&parts_key(collection_name), json!(parts.len()), None);
+    del(&legacy_hashes_key(collection_name));
+}
+
+/// The title of a file: its first heading, else its path.
+fn title_of(path: &str, text: &str) -> String {
+    text.lines()
+        .find_map(|l| l.trim_start().strip_prefix("# "))
+        .map(|h| h.trim().to_string())
+        .unwrap_or(path.to_string())
+}
+
+/// Brings the collection in line with the documentation of the repository: files added or
+/// changed since the last sync are fetched and re-embedded, and the points of files changed or
+/// removed are deleted. Run by the flow's schedule, see [`crate::scheduler`], and through
+/// `?action=github_sync`. With `dry_run`, only lists what would change.
+pub async fn sync(
+    collection_name: &str,
+    options: &IngestOptions,
+    dry_run: bool,
+    progress: &mut impl FnMut(Progress)
+) -> Result<Value> {
+    let source = Source::configured()?;
+    let files = list_files(&source)?;
+    let mut hashes = stored_hashes(collection_name);
+
+    let changed = files
+        .iter()
+        .filter(|(path, sha)| hashes.get(*path) != Some(*sha))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<String>>();
+    let removed = hashes
+        .keys()
+        .filter(|path| !files.contains_key(*path))
+        .cloned()
+        .collect::<Vec<String>>();
+    let (now, later) = changed.split_at(changed.len().min(MAX_FILES_PER_RUN));
+
+    if dry_run {
+        return Ok(
+            json!({
+                "collection": collection_name,
+                "source": source,
+                "dry_run": true,
+                "files": files.len(),
+                "changed": now,
+                "removed": removed,
+                "left_for_later": later.len(),
+            })
+        );
+    }
+
+    let mut documents = Vec::new();
+    let mut fetched = Vec::new();
+    for path in now {
+        let url = format!("https://raw.githubusercontent.com/{}/{}/{}", source.repo, source.branch, path);
+        match github_get(&url) {
+            Ok(body) => {
+                let text = String::from_utf8_lossy(&body).to_string();
+                documents.push(Document {
+                    title: Some(title_of(path, &text)),
+                    source: Some(source.link(path)),
+                    text,
+                    ..Default::default()
+                });
+                fetched.push(path.clone());
+            }
+            Err(e) => {
+                log::error!("Cannot fetch {} from {}: {}", path, source.repo, e);
+                progress(Progress::Error { source: source.link(path), message: e.to_string() });
+            }
+        }
+    }
+    documents.retain(|d| !d.text.trim().is_empty());
+
+    let chunks = ingest::chunk_documents(documents, options, progress);
+    let written = ingest::embed_and_upsert(collection_name, chunks, progress).await?;
+
+    // the points written above carry the new version, older ones of the same files are stale
+    let stale = fetched
+        .iter()
+        .chain(removed.iter())
+        .map(|path| source.link(path))
+        .collect::<HashSet<String>>();
+    let mut deleted = 0;
+    // with nothing written and nothing synced before, the collection may not exist
+    if !stale.is_empty() && (written > 0 || !hashes.is_empty()) {
+        let current = match written {
+            0 => None,
+            _ => ingest::kb_version(collection_name).map(|v| v.version),
+        };
+        deleted = bulk_delete::delete_where(collection_name, |payload| {
+            payload
+                .get("source")
+                .and_then(|v| v.as_str())
+                .is_some_and(|s| stale.contains(s)) &&
+                payload.get("kb_version").and_then(|v| v.as_u64()) != current
+        }).await?;
+    }
+
+    for path in &fetched {
+        hashes.insert(path.clone(), files[path].clone());
+    }
+    for path in &removed {
+        hashes.remove(path);
+    }
+    save_hashes(collection_name, &hashes);
+    log::info!(
+        "Synced {} from {}: {} files re-embedded into {} chunks, {} removed, {} stale points deleted",
+        collection_name,
+        source.repo,
+        fetched.len(),
+        written,
+        removed.len(),
+        deleted
+    );
+
+    progress(Progress::Done { written });
+    Ok(
+        json!({
+            "collection": collection_name,
+            "source": source,
+            "dry_run": false,
+            "files": files.len(),
+            "changed": fetched,
+            "removed": removed,
+            "left_for_later": later.len() + (now.len() - fetched.len()),
+            "written": written,
+            "deleted": deleted,
+            "kb_version": ingest::kb_version(collection_name),
+        })
+    )
+}
+
+/// Syncs the collection and, unless `dry_run`, announces the ingestion to the outbound webhooks.
+pub async fn run(collection_name: &str, options: &IngestOptions, dry_run: bool) -> Result<Value> {
+    let mut failed = false;
+    let mut progress = |e: Progress| {
+        log::info!("GitHub sync progress {}: {}", e.name(), e.data());
+        failed |= matches!(e, Progress::Error { .. });
+    };
+    let report = sync(collection_name, options, dry_run, &mut progress).await?;
+    if !dry_run {
+        webhooks::emit(webhooks::Event::IngestionCompleted {
+            collection: collection_name.to_string(),
+            written: report["written"].as_u64().unwrap_or_default() as usize,
+            kb_version: ingest::kb_version(collection_name).map(|v| v.version),
+            failed,
+        }).await;
+    }
+    Ok(report)
+}
+
+/// Minutes between the scheduled syncs, `github_sync_interval_minutes` (default `60`), `0` turning
+/// them off.
+pub fn interval_minutes() -> u64 {
+    settings::setting("github_sync_interval_minutes", 60)
+}
@@ -0,0 +1,147 @@
+use async_graphql::{ EmptyMutation, EmptySubscription, Json, Object, Request, Schema, SimpleObject };
+use serde_json::{ json, Value };
+use vector_store_flows::collection_info;
+
+use crate::{ analytics, audit, chat_id, conversation_id, conversation_prompt, error::Error, history, ingest, quarantine, trace::Trace };
+
+/// Deep queries are rejected rather than resolved, every field reads the KV store.
+const MAX_DEPTH: usize = 6;
+const MAX_COMPLEXITY: usize = 200;
+
+pub type BotSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub struct QueryRoot;
+
+/// A conversation, read from what the flow keeps of it in the KV store.
+pub struct Conversation {
+    chat_id: String,
+}
+
+#[Object]
+impl Conversation {
+    /// The chat id, the conversation name as used for its keys.
+    async fn id(&self) -> &str {
+        &self.chat_id
+    }
+
+    /// The `x-conversation-name` the chat id was made from, when known.
+    async fn name(&self) -> Option<String> {
+        chat_id::name_of(&self.chat_id)
+    }
+
+    /// The mirrored QA pairs, oldest first.
+    async fn history(&self) -> Vec<String> {
+        history::load(&self.chat_id)
+    }
+
+    /// The system prompt addendum of the conversation.
+    async fn prompt(&self) -> Option<String> {
+        conversation_prompt::load(&self.chat_id)
+    }
+
+    /// The post prompt addendum of the conversation.
+    async fn post_prompt(&self) -> Option<String> {
+        conversation_prompt::load_post(&self.chat_id)
+    }
+
+    /// How the last answer was put together, as shown by `/why`.
+    async fn last_trace(&self) -> Option<Trace> {
+        crate::trace::load(&self.chat_id)
+    }
+
+    /// The audit log entries of the conversation, newest first.
+    async fn answers(&self, #[graphql(default = 20)] limit: usize) -> Json<Vec<Value>> {
+        Json(audit::entries(limit, Some(&self.chat_id)))
+    }
+}
+
+/// Stats of a knowledge base collection.
+#[derive(SimpleObject)]
+pub struct KnowledgeBase {
+    collection: String,
+    /// `None` when the vector store cannot be reached.
+    points_count: Option<u64>,
+    /// The version bumped by every ingestion run, `None` when nothing was ingested through the flow.
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    quarantined: Vec<u64>,
+}
+
+#[Object]
+impl QueryRoot {
+    /// One conversation, by the name sent in `x-conversation-name`.
+    async fn conversation(&self, name: String) -> Conversation {
+        Conversation { chat_id: conversation_id(&name) }
+    }
+
+    /// The conversations of the latest audit log entries, most recent first.
+    async fn conversations(&self, #[graphql(default = 20)] limit: usize) -> Vec<Conversation> {
+        let mut chat_ids: Vec<String> = Vec::new();
+        for e in audit::entries(usize::MAX, None) {
+            let chat_id = e["chat_id"].as_str().unwrap_or_default();
+            if chat_ids.len() >= limit {
+                break;
+            }
+            if !chat_id.is_empty() && !chat_ids.iter().any(|c| c == chat_id) {
+                chat_ids.push(chat_id.to_string());
+            }
+        }
+        chat_ids
+            .into_iter()
+            .map(|chat_id| Conversation { chat_id })
+            .collect()
+    }
+
+    /// Stats of a collection, `collection_name` by default.
+    async fn knowledge_base(&self, collection: Option<String>) -> KnowledgeBase {
+        let collection = collection.unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+        let points_count = match collection_info(&collection).await {
+            Ok(info) => Some(info.points_count),
+            Err(e) => {
+                log::error!("{}", Error::vector_store("info", &collection, e));
+                None
+            }
+        };
+        let version = ingest::kb_version(&collection);
+        KnowledgeBase {
+            points_count,
+            version: version.map(|v| v.version),
+            updated_at: version.map(|v| v.updated_at),
+            quarantined: quarantine::load(&collection).into_iter().map(|q| q.id).collect(),
+            collection,
+        }
+    }
+
+    /// The daily series of `?action=analytics`.
+    async fn analytics(&self, #[graphql(default = 30)] days: u64) -> Json<Value> {
+        Json(analytics::report(days))
+    }
+
+    /// The latest audit log entries across conversations, newest first.
+    async fn answers(&self, #[graphql(default = 50)] limit: usize) -> Json<Vec<Value>> {
+        Json(audit::entries(limit, None))
+    }
+}
+
+pub fn schema() -> BotSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// Runs a `{"query": ..., "variables": ...}` body, or the `query` parameter of a GET. Errors of
+/// the query are in the `errors` of the response, as GraphQL clients expect.
+pub async fn execute(body: &[u8], query: Option<&str>) -> Value {
+    let request = match (body.is_empty(), query) {
+        (true, Some(q)) => Request::new(q),
+        _ =>
+            match serde_json::from_slice::<Request>(body) {
+                Ok(r) => r,
+                Err(e) => {
+                    return json!({ "errors": [{ "message": format!("Invalid GraphQL request: {e}") }] });
+                }
+            }
+    };
+    serde_json::to_value(schema().execute(request).await).unwrap_or_default()
+}
@@ -0,0 +1,61 @@
+use serde_json::json;
+use store_flows::get;
+
+use crate::{ commands, faq, guards, roles::Role };
+
+const EXAMPLES: usize = 3;
+const DEFAULT_GREETING: &str =
+    "Hi! I answer questions about Kubernetes from the knowledge base, and say where an answer came from.\n\n{examples}\n\n{commands}";
+
+fn key(chat_id: &str) -> String {
+    format!("greeted:{chat_id}")
+}
+
+/// Example questions from the FAQ, none before an FAQ is generated.
+fn examples() -> String {
+    let questions = faq::top_questions(EXAMPLES);
+    match questions.is_empty() {
+        true => String::new(),
+        false =>
+            format!(
+                "You could ask:\n{}",
+                questions
+                    .iter()
+                    .map(|q| format!("- {q}"))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+    }
+}
+
+fn commands() -> String {
+    format!(
+        "Commands:\n{}",
+        commands
+            ::available(Role::User)
+            .map(|c| format!("- `{}`: {}", c.usage(), c.summary))
+            .collect::<Vec<String>>()
+            .join("\n")
+    )
+}
+
+/// The greeting of a conversation seen for the first time, `None` after that. The `greeting`
+/// setting replaces the default text, with `{examples}` and `{commands}` filled in, and `off`
+/// turns the greeting off.
+pub fn take(chat_id: &str) -> Option<String> {
+    let template = std::env::var("greeting").unwrap_or(DEFAULT_GREETING.to_string());
+    if chat_id.is_empty() || template.trim() == "off" || get(&key(chat_id)).is_some() {
+        return None;
+    }
+    guards::set_capped(&key(chat_id), json!(true), None);
+
+    let greeting = template.replace("{examples}", &examples()).replace("{commands}", &commands());
+    // an FAQ-less deployment leaves an empty placeholder behind
+    Some(
+        greeting
+            .split("\n\n")
+            .filter(|p| !p.trim().is_empty())
+            .collect::<Vec<&str>>()
+            .join("\n\n")
+    )
+}
@@ -0,0 +1,118 @@
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use serde::Deserialize;
+
+use crate::{ deterministic, error::{ Error, Result }, first_x_chars, overrides, retry, settings, trace };
+
+/// Context shown to the verifier, in characters.
+const MAX_CONTEXT_CHARS: usize = 8000;
+const DEFAULT_DISCLAIMER: &str =
+    "_Parts of this answer could not be verified against the knowledge base, double-check them before relying on them._";
+
+/// What happens to an answer below the grounding threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    /// The answer is sent with `grounding_disclaimer` above it.
+    Disclaimer,
+    /// `no_answer_mesg` is sent instead.
+    NoAnswer,
+}
+
+/// Whether answers built on retrieved context are verified before they are sent, with
+/// `grounding_check` set to `true`.
+pub fn enabled() -> bool {
+    std::env::var("grounding_check").unwrap_or_default() == "true"
+}
+
+/// The share of supported claims below which an answer is not trusted, `grounding_threshold`
+/// (default `0.6`).
+fn threshold() -> f32 {
+    settings::setting("grounding_threshold", 0.6)
+}
+
+/// `grounding_action`: `disclaimer` (the default) or `no_answer`.
+fn action() -> Action {
+    match std::env::var("grounding_action").unwrap_or_default().as_str() {
+        "no_answer" => Action::NoAnswer,
+        _ => Action::Disclaimer,
+    }
+}
+
+#[derive(Deserialize)]
+struct Claim {
+    #[serde(default)]
+    supported: bool,
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    #[serde(default)]
+    claims: Vec<Claim>,
+}
+
+/// Asks the model to split the answer into its factual claims and tell which the context
+/// supports, returning the share of supported claims. An answer without claims is grounded.
+pub async fn check(context: &str, answer: &str, llm: &LLMServiceFlows<'_>, model: &str) -> Result<f32> {
+    let sys_prompt =
+        "You verify answers against the context they were written from. List every factual claim of the answer and whether the context supports it. Reply with JSON only, like {\"claims\": [{\"claim\": \"...\", \"supported\": true}]}.";
+    let usr_prompt = format!("Context:\n{}\n\nAnswer:\n{}", first_x_chars(context, MAX_CONTEXT_CHARS), answer);
+    let co = ChatOptions {
+        model: Some(model),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 4096,
+        max_tokens: Some(512),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+    let reply = retry
+        ::call("grounding", || llm.chat_completion("grounding-check", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+
+    // the model may wrap the JSON in text or a code block
+    let json = match (reply.find('{'), reply.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => reply.as_str(),
+    };
+    let verdict: Verdict = serde_json::from_str(json).map_err(|e| Error::Llm(format!("unreadable verification {reply:?}: {e}")))?;
+    let supported = verdict.claims
+        .iter()
+        .filter(|c| c.supported)
+        .count();
+    Ok(match verdict.claims.len() {
+        0 => 1.0,
+        n => (supported as f32) / (n as f32),
+    })
+}
+
+/// Verifies the answer against the context and, below the threshold, prepends the disclaimer
+/// (unless `disclaim` is false, e.g. for JSON answers) or replaces the answer with
+/// `no_answer_mesg`. The answer is sent as it is when it cannot be verified.
+pub async fn verify(
+    context: &str,
+    answer: String,
+    no_answer_mesg: &str,
+    disclaim: bool,
+    llm: &LLMServiceFlows<'_>,
+    model: &str
+) -> String {
+    let grounded = match check(context, &answer, llm, model).await {
+        Ok(g) => g,
+        Err(e) => {
+            log::error!("Cannot verify the grounding of the answer: {}", e);
+            return answer;
+        }
+    };
+    trace::grounding(grounded);
+    if grounded >= threshold() {
+        return answer;
+    }
+
+    log::warn!("Only {:.0}% of the claims of the answer are supported by the context", grounded * 100.0);
+    match action() {
+        Action::NoAnswer => no_answer_mesg.to_string(),
+        Action::Disclaimer if disclaim =>
+            format!("{}\n\n{}", overrides::setting("grounding_disclaimer", DEFAULT_DISCLAIMER), answer),
+        Action::Disclaimer => answer,
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use serde_json::Value;
+use store_flows::{ set, Expire };
+
+use crate::settings;
+
+static PAYLOAD_BYTES_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Total bytes of retrieved payload text one request may process, from `max_payload_bytes`.
+fn max_payload_bytes() -> usize {
+    settings::setting("max_payload_bytes", 64 * 1024)
+}
+
+/// Largest value written to the KV store, from `max_store_value_bytes`.
+pub fn max_store_value_bytes() -> usize {
+    settings::setting("max_store_value_bytes", 32 * 1024)
+}
+
+/// Cuts the text to at most `max` bytes without splitting a character.
+pub fn truncate_bytes(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Starts a new request with the full payload budget.
+pub fn reset_payload_budget() {
+    PAYLOAD_BYTES_USED.store(0, Ordering::Relaxed);
+}
+
+/// Charges retrieved payload text to the budget of the request. Returns the text, truncated to what
+/// is left of the budget, or `None` once the budget is used up.
+pub fn take_payload(text: &str) -> Option<String> {
+    let max = max_payload_bytes();
+    let used = PAYLOAD_BYTES_USED.load(Ordering::Relaxed);
+    if used >= max {
+        log::warn!("Payload budget of {} bytes used up, dropping a {} byte payload", max, text.len());
+        return None;
+    }
+
+    let taken = truncate_bytes(text, max - used);
+    if taken.len() < text.len() {
+        log::warn!("Truncated a {} byte payload to the {} bytes left of the budget", text.len(), taken.len());
+    }
+    PAYLOAD_BYTES_USED.store(used + taken.len(), Ordering::Relaxed);
+
+    Some(taken.to_string())
+}
+
+/// Writes to the KV store, shrinking values over [`max_store_value_bytes`]: strings are truncated
+/// and arrays lose their oldest (first) items. Other values that are too large are not written.
+pub fn set_capped(key: &str, value: Value, expire: Option<Expire>) {
+    let max = max_store_value_bytes();
+    let size = value.to_string().len();
+    if size <= max {
+        set(key, value, expire);
+        return;
+    }
+
+    let value = match value {
+        Value::String(s) => {
+            // leave room for the quotes and escapes of the serialized string
+            Value::String(truncate_bytes(&s, max.saturating_sub(size - s.len())).to_string())
+        }
+        Value::Array(mut items) => {
+            let mut size = size;
+            while !items.is_empty() && size > max {
+                // the item and the comma separating it
+                size -= items.remove(0).to_string().len() + 1;
+            }
+            Value::Array(items)
+        }
+        _ => {
+            log::warn!("Not writing {}: {} bytes is over the {} byte limit", key, size, max);
+            return;
+        }
+    };
+
+    log::warn!("Shrunk {} from {} to {} bytes to fit the {} byte limit", key, size, value.to_string().len(), max);
+    set(key, value, expire);
+}
@@ -0,0 +1,83 @@
+use serde_json::json;
+use store_flows::get;
+
+use crate::{ encryption, guards, retention, settings };
+
+/// How many QA pairs of a chat are mirrored, from `history_size`.
+fn history_size() -> usize {
+    settings::setting("history_size", 5)
+}
+
+fn key(chat_id: &str) -> String {
+    format!("history:{chat_id}")
+}
+
+/// The mirrored QA pairs of the chat, oldest first.
+pub fn load(chat_id: &str) -> Vec<String> {
+    get(&key(chat_id))
+        .and_then(|v| serde_json::from_value::<Vec<String>>(v).ok())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|qa| encryption::open(qa))
+        .collect()
+}
+
+/// Mirrors a QA pair into the KV store, keeping the latest [`history_size`] of the chat. The
+/// mirror stands in for the conversation memory when the vector store is unreachable.
+pub fn push(chat_id: &str, qa: &str) {
+    let size = history_size();
+    if size == 0 {
+        return;
+    }
+
+    let mut history = load(chat_id);
+    history.push(qa.to_string());
+    if history.len() > size {
+        history.drain(..history.len() - size);
+    }
+    // written back with the current key, which moves the mirror off a rotated key
+    let sealed = history
+        .iter()
+        .map(|qa| encryption::seal(qa))
+        .collect::<Vec<String>>();
+    guards::set_capped(&key(chat_id), json!(sealed), retention::expire(retention::Data::History));
+}
+
+/// Moves the mirrored QA pairs of the chat `from` before those of `into`, skipping the ones
+/// `into` has. The mirror keeps no times, so `from` is taken as the older conversation. Returns
+/// the pairs moved and the duplicates, with `dry_run` only counting them.
+pub fn merge(from: &str, into: &str, dry_run: bool) -> (usize, usize) {
+    let existing = load(into);
+    let (duplicates, mut merged): (Vec<String>, Vec<String>) = load(from)
+        .into_iter()
+        .partition(|qa| existing.contains(qa));
+    let moved = merged.len();
+    if dry_run {
+        return (moved, duplicates.len());
+    }
+
+    merged.extend(existing);
+    clear(into);
+    for qa in &merged {
+        push(into, qa);
+    }
+    clear(from);
+    (moved, duplicates.len())
+}
+
+/// Deletes the mirror of the chat, returning how many QA pairs it held, with `dry_run` only
+/// counting them. Run by the retention sweep.
+pub fn apply_retention(chat_id: &str, dry_run: bool) -> usize {
+    let held = get(&key(chat_id))
+        .and_then(|v| v.as_array().map(|a| a.len()))
+        .unwrap_or_default();
+    if !dry_run {
+        clear(chat_id);
+    }
+    held
+}
+
+/// Forgets the mirrored QA pairs of the chat, as `/new` does with the conversation memory.
+pub fn clear(chat_id: &str) {
+    store_flows::del(&key(chat_id));
+}
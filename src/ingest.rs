@@ -0,0 +1,563 @@
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use std::collections::HashMap;
+use store_flows::get;
+use vector_store_flows::*;
+
+use crate::{
+    analytics::now_secs,
+    chunking::{ self, ChunkerKind },
+    code_blocks,
+    collection_config::CollectionConfig,
+    error::{ Error, Result },
+    fetch,
+    guards,
+    keywords,
+    pdf::{ self, PagedDocument },
+    settings,
+    tokenizer,
+    web_search::html_to_text,
+};
+
+/// Most URLs fetched by one ingestion request.
+const MAX_URLS: usize = 50;
+
+/// A piece of source material ready to be embedded. `payload` is stored alongside the text.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    pub payload: Map<String, Value>,
+}
+
+impl Chunk {
+    pub fn new(text: String) -> Self {
+        Self { text, payload: Map::new() }
+    }
+
+    pub fn with(mut self, key: &str, value: Value) -> Self {
+        self.payload.insert(key.to_string(), value);
+        self
+    }
+}
+
+/// Options of an ingestion run, read from the query string of the request.
+#[derive(Debug, Clone)]
+pub struct IngestOptions {
+    pub chunker: ChunkerKind,
+    /// Characters per chunk, or tokens with [`ChunkerKind::Tokens`].
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    /// Chunk the material and report the result without embedding or writing anything.
+    pub preview: bool,
+    /// Split on Markdown headings first, `None` telling Markdown from its headings.
+    pub markdown: Option<bool>,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        Self {
+            chunker: ChunkerKind::Sentence,
+            chunk_size: 1500,
+            chunk_overlap: 150,
+            preview: false,
+            markdown: None,
+        }
+    }
+}
+
+impl IngestOptions {
+    /// Fails when `chunk_size` is below [`ChunkerKind::min_size`] or `chunk_overlap` is not
+    /// smaller than `chunk_size`.
+    pub fn from_query(qry: &HashMap<String, Value>) -> Result<Self> {
+        let number = |key: &str| {
+            qry.get(key).and_then(|v| {
+                v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse::<u64>().ok()))
+            })
+        };
+        let chunker = ChunkerKind::from_query(qry.get("chunker"));
+        let (size, overlap) = chunker.default_size();
+        let chunk_size = number("chunk_size").map(|n| n as usize).unwrap_or(size);
+        let chunk_overlap = number("chunk_overlap")
+            .map(|n| n as usize)
+            .unwrap_or(overlap);
+        chunking::check_size(chunk_size, chunk_overlap, chunker.min_size())?;
+
+        Ok(Self {
+            chunker,
+            chunk_size,
+            chunk_overlap,
+            preview: qry
+                .get("preview")
+                .map(|v| v.as_bool().unwrap_or(v.as_str() == Some("true")))
+                .unwrap_or(false),
+            markdown: qry
+                .get("markdown")
+                .and_then(|v| v.as_bool().or(v.as_str().and_then(|s| s.parse().ok()))),
+        })
+    }
+}
+
+/// The model whose tokens are priced, embeddings of any provider are billed close to it.
+const PRICED_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Describes what an ingestion run would write, with the estimated embedding cost priced from
+/// `embedding_cost_per_1k_tokens` (USD, defaults to the text-embedding-ada-002 price).
+pub fn preview_report(chunks: &[Chunk]) -> Value {
+    let price = settings::setting::<f64>("embedding_cost_per_1k_tokens", 0.0001);
+    let counts = tokenizer::Counter
+        ::for_model(PRICED_EMBEDDING_MODEL)
+        .count_each(
+            &chunks
+                .iter()
+                .map(|c| c.text.as_str())
+                .collect::<Vec<&str>>()
+        );
+    let tokens = counts.iter().sum::<usize>();
+
+    json!({
+        "total_chunks": chunks.len(),
+        "estimated_tokens": tokens,
+        "estimated_cost_usd": (tokens as f64) / 1000.0 * price,
+        "chunks": chunks.iter().zip(counts).map(|(c, tokens)| json!({
+            "text": c.text,
+            "chars": c.text.chars().count(),
+            "estimated_tokens": tokens,
+            "payload": c.payload,
+        })).collect::<Vec<Value>>(),
+    })
+}
+
+/// Progress of an ingestion run, reported as each stage completes.
+#[derive(Debug, Clone)]
+pub enum Progress {
+    Chunked { source: String, chunks: usize },
+    Embedded { count: usize },
+    Upserted { count: usize },
+    Error { source: String, message: String },
+    Done { written: usize },
+}
+
+impl Progress {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Progress::Chunked { .. } => "chunked",
+            Progress::Embedded { .. } => "embedded",
+            Progress::Upserted { .. } => "upserted",
+            Progress::Error { .. } => "error",
+            Progress::Done { .. } => "done",
+        }
+    }
+
+    pub fn data(&self) -> Value {
+        match self {
+            Progress::Chunked { source, chunks } => json!({"source": source, "chunks": chunks}),
+            Progress::Embedded { count } => json!({"count": count}),
+            Progress::Upserted { count } => json!({"count": count}),
+            Progress::Error { source, message } => json!({"source": source, "message": message}),
+            Progress::Done { written } => json!({"written": written}),
+        }
+    }
+
+    /// Formats the event as a server-sent event.
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name(), self.data())
+    }
+}
+
+/// Renders the events as a `text/event-stream` body.
+///
+/// The webhook runtime sends the response once the handler returns, so the client receives all
+/// events together at the end of the run; they are also logged as they happen.
+pub fn event_stream(events: &[Progress]) -> String {
+    events
+        .iter()
+        .map(|e| e.to_sse())
+        .collect()
+}
+
+const EMBEDDING_BATCH: usize = 16;
+
+/// The version of a collection's content, bumped by every ingestion run writing to it.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct KbVersion {
+    pub version: u64,
+    pub updated_at: u64,
+}
+
+fn version_key(collection_name: &str) -> String {
+    format!("kb_version:{collection_name}")
+}
+
+/// The current version of the collection, `None` when nothing was ingested through this flow.
+pub fn kb_version(collection_name: &str) -> Option<KbVersion> {
+    get(&version_key(collection_name)).and_then(|v| serde_json::from_value(v).ok())
+}
+
+/// Bumps the version of the collection, for ingestion runs and deletions.
+pub fn bump_version(collection_name: &str) -> KbVersion {
+    let version = KbVersion {
+        version: kb_version(collection_name).unwrap_or_default().version + 1,
+        updated_at: now_secs(),
+    };
+    guards::set_capped(&version_key(collection_name), serde_json::to_value(version).unwrap_or_default(), None);
+    version
+}
+
+fn last_id_key(collection_name: &str) -> String {
+    format!("last_point_id:{collection_name}")
+}
+
+/// The highest point id handed out in the collection. Ids are numbered from 1 as points are
+/// written, and after deletions the point count is below it, so it is kept in the KV store.
+pub fn last_id(collection_name: &str, points_count: u64) -> u64 {
+    get(&last_id_key(collection_name))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0)
+        .max(points_count)
+}
+
+/// Chunks a document, indexing its code blocks and YAML manifests as separate chunks flagged
+/// `kind=code` (with their `language`) and the prose around them as `kind=text` chunks.
+/// Markdown is split on its headings first, each chunk storing the `heading_path` of its section.
+pub fn chunk_document(text: &str, options: &IngestOptions) -> Vec<Chunk> {
+    if !options.markdown.unwrap_or(chunking::is_markdown(text)) {
+        return chunk_text(text, options);
+    }
+    chunking
+        ::markdown_sections(text)
+        .into_iter()
+        .flat_map(|section| {
+            let path = section.heading_path();
+            chunk_text(&section.text, options)
+                .into_iter()
+                .map(move |chunk| {
+                    match &path {
+                        Some(p) => chunk.with("heading_path", json!(p)),
+                        None => chunk,
+                    }
+                })
+        })
+        .collect()
+}
+
+fn chunk_text(text: &str, options: &IngestOptions) -> Vec<Chunk> {
+    let (prose, code) = code_blocks::extract_code(text);
+
+    let mut chunks = options.chunker
+        .build(options.chunk_size, options.chunk_overlap)
+        .chunk(&prose)
+        .into_iter()
+        .map(|t| Chunk::new(t).with("kind", json!("text")))
+        .collect::<Vec<Chunk>>();
+    let code_chunker = options.chunker.build(options.chunk_size, 0);
+    for block in code {
+        for t in code_chunker.chunk(&block.code) {
+            chunks.push(
+                Chunk::new(t)
+                    .with("kind", json!("code"))
+                    .with("language", json!(block.language))
+            );
+        }
+    }
+
+    chunks
+}
+
+/// A document posted to `?action=ingest`. `source`, `title` and `metadata` are stored with
+/// each of its chunks, along with the `chunk_index` of the chunk in the document.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Document {
+    pub text: String,
+    pub source: Option<String>,
+    pub title: Option<String>,
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DocumentsBody {
+    Many {
+        documents: Vec<Document>,
+    },
+    List(Vec<Document>),
+    // before `One`, which takes any object
+    Paged(PagedDocument),
+    One(Document),
+}
+
+/// Reads the documents of an ingestion request: a JSON document, a JSON array of documents,
+/// `{"documents": [...]}`, a document extracted page by page or a PDF file, split by page. Any
+/// other body is one document of raw text from `source`.
+pub fn parse_documents(body: &[u8], source: Option<&str>, title: Option<&str>) -> Result<Vec<Document>> {
+    if pdf::is_pdf(body) {
+        return pdf::extract(body, source, title);
+    }
+    let text = String::from_utf8_lossy(body);
+    let documents = match text.trim_start().starts_with(['{', '[']) {
+        true =>
+            match serde_json::from_str::<DocumentsBody>(&text)? {
+                DocumentsBody::Many { documents } => documents,
+                DocumentsBody::List(documents) => documents,
+                DocumentsBody::Paged(document) => document.into_documents(),
+                DocumentsBody::One(document) => vec![document],
+            }
+        false =>
+            vec![Document {
+                text: text.to_string(),
+                source: source.map(String::from),
+                title: title.map(String::from),
+                ..Default::default()
+            }],
+    };
+
+    if documents.iter().all(|d| d.text.trim().is_empty()) {
+        return Err(Error::InvalidInput("no document text to ingest".to_string()));
+    }
+    Ok(documents)
+}
+
+#[derive(Deserialize)]
+struct UrlsBody {
+    urls: Vec<String>,
+}
+
+/// The URLs of a `{"urls": [...]}` ingestion request, `None` for a request with documents.
+pub fn parse_urls(body: &[u8]) -> Option<Result<Vec<String>>> {
+    let urls = serde_json::from_slice::<UrlsBody>(body).ok()?.urls;
+    let urls = urls
+        .into_iter()
+        .map(|u| u.trim().to_string())
+        .filter(|u| !u.is_empty())
+        .collect::<Vec<String>>();
+    Some(match urls.len() {
+        0 => Err(Error::InvalidInput("`urls` is empty".to_string())),
+        n if n > MAX_URLS => Err(Error::InvalidInput(format!("{n} urls given, at most {MAX_URLS} are fetched at once"))),
+        _ => Ok(urls),
+    })
+}
+
+/// The main content of a page: its `<main>` or `<article>` element when it has one, else the page
+/// without its header, navigation, sidebars, forms and footer.
+fn main_content(html: &str) -> String {
+    let main = Regex::new(r"(?is)<main[^>]*>(.*)</main>").unwrap();
+    let article = Regex::new(r"(?is)<article[^>]*>(.*)</article>").unwrap();
+    let boilerplate = Regex::new(r"(?is)<header[^>]*>.*?</header>|<aside[^>]*>.*?</aside>|<form[^>]*>.*?</form>").unwrap();
+
+    let content = main
+        .captures(html)
+        .or_else(|| article.captures(html))
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str())
+        .unwrap_or(html);
+    html_to_text(&boilerplate.replace_all(content, " "))
+}
+
+/// Fetches a page as a document of its main content, with the URL as its `source` so answers
+/// cite it, and the page title as its `title`.
+pub fn fetch_page(url: &str) -> Result<Document> {
+    let body = fetch::get(url, &[])?;
+    let html = String::from_utf8_lossy(&body);
+    let title = Regex::new(r"(?is)<title[^>]*>(.*?)</title>")
+        .unwrap()
+        .captures(&html)
+        .and_then(|c| c.get(1))
+        .map(|t| html_to_text(t.as_str()))
+        .filter(|t| !t.is_empty());
+    let text = main_content(&html);
+    if text.trim().is_empty() {
+        return Err(Error::InvalidInput(format!("{url} has no text content")));
+    }
+    Ok(Document {
+        text,
+        source: Some(url.to_string()),
+        title,
+        ..Default::default()
+    })
+}
+
+/// Fetches the pages for [`chunk_documents`], reporting the ones that cannot be fetched to
+/// `progress` and going on with the others.
+pub fn fetch_pages(urls: &[String], progress: &mut impl FnMut(Progress)) -> Vec<Document> {
+    urls.iter()
+        .filter_map(|url| {
+            match fetch_page(url) {
+                Ok(d) => Some(d),
+                Err(e) => {
+                    log::error!("Cannot fetch {} to ingest it: {}", url, e);
+                    progress(Progress::Error { source: url.clone(), message: e.to_string() });
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Chunks the documents for [`embed_and_upsert`], reporting each document to `progress`.
+pub fn chunk_documents(
+    documents: Vec<Document>,
+    options: &IngestOptions,
+    progress: &mut impl FnMut(Progress)
+) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    for (i, doc) in documents.into_iter().enumerate() {
+        let source = doc.source.clone().unwrap_or(format!("document {}", i + 1));
+        let doc_chunks = chunk_document(&doc.text, options)
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut chunk)| {
+                chunk = chunk.with("chunk_index", json!(i));
+                for (k, v) in &doc.metadata {
+                    chunk.payload.entry(k.clone()).or_insert(v.clone());
+                }
+                if let Some(s) = &doc.source {
+                    chunk = chunk.with("source", json!(s));
+                }
+                if let Some(t) = &doc.title {
+                    chunk = chunk.with("title", json!(t));
+                }
+                chunk
+            })
+            .collect::<Vec<Chunk>>();
+        progress(Progress::Chunked { source, chunks: doc_chunks.len() });
+        chunks.extend(doc_chunks);
+    }
+    chunks
+}
+
+/// Embeds the texts in batches with the provider configured for the collection, returning one
+/// vector per text in the same order.
+pub async fn embed_texts(config: &CollectionConfig, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let mut vectors = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDING_BATCH) {
+        vectors.extend(config.embed(batch).await?);
+    }
+
+    Ok(vectors)
+}
+
+/// Embeds the chunks and upserts them into the collection, creating the collection when it does
+/// not exist yet. The collection's [`CollectionConfig`] decides the embedding provider and the
+/// vector size. The chunks are stamped with a new [`KbVersion`] of the collection and their
+/// ingestion time. Returns the number of points written.
+pub async fn embed_and_upsert(
+    collection_name: &str,
+    chunks: Vec<Chunk>,
+    progress: &mut impl FnMut(Progress)
+) -> Result<usize> {
+    if chunks.is_empty() {
+        return Ok(0);
+    }
+
+    let config = CollectionConfig::load(collection_name);
+    // ids freed by deletions are not handed out again, that would overwrite other points
+    let mut next_id = match collection_info(collection_name).await {
+        Ok(ci) => last_id(collection_name, ci.points_count) + 1,
+        Err(_) => {
+            let p = CollectionCreateParams { vector_size: config.vector_size };
+            create_collection(collection_name, &p).await.map_err(|e|
+                Error::vector_store("create", collection_name, e)
+            )?;
+            1
+        }
+    };
+
+    let version = bump_version(collection_name);
+    let mut written = 0;
+    for batch in chunks.chunks(EMBEDDING_BATCH) {
+        let texts = batch
+            .iter()
+            .map(|c| c.text.clone())
+            .collect::<Vec<String>>();
+        let vectors = embed_texts(&config, &texts).await?;
+        progress(Progress::Embedded { count: vectors.len() });
+
+        let points = batch
+            .iter()
+            .zip(vectors)
+            .map(|(chunk, vector)| {
+                let mut payload = chunk.payload.clone();
+                payload.insert("text".to_string(), Value::from(chunk.text.clone()));
+                payload.insert("keywords".to_string(), keywords::payload_field(&chunk.text));
+                payload.insert("kb_version".to_string(), Value::from(version.version));
+                payload.insert("ingested_at".to_string(), Value::from(now_secs()));
+                let p = Point {
+                    id: PointId::Num(next_id),
+                    vector,
+                    payload: Some(payload),
+                };
+                next_id += 1;
+                p
+            })
+            .collect::<Vec<Point>>();
+
+        upsert_points(collection_name, points).await.map_err(|e|
+            Error::vector_store("upsert", collection_name, e)
+        )?;
+        guards::set_capped(&last_id_key(collection_name), json!(next_id - 1), None);
+        written += batch.len();
+        progress(Progress::Upserted { count: batch.len() });
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_text_is_one_document() {
+        let documents = parse_documents(b"Some notes", Some("notes.txt"), Some("Notes")).unwrap();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].text, "Some notes");
+        assert_eq!(documents[0].source.as_deref(), Some("notes.txt"));
+        assert_eq!(documents[0].title.as_deref(), Some("Notes"));
+    }
+
+    #[test]
+    fn json_documents_in_every_shape() {
+        let one = parse_documents(br#"{"text": "a", "source": "s"}"#, None, None).unwrap();
+        assert_eq!(one[0].source.as_deref(), Some("s"));
+        let list = parse_documents(br#"[{"text": "a"}, {"text": "b"}]"#, None, None).unwrap();
+        assert_eq!(list.len(), 2);
+        let many = parse_documents(br#"{"documents": [{"text": "a"}, {"text": "b"}]}"#, None, None).unwrap();
+        assert_eq!(many[1].text, "b");
+    }
+
+    #[test]
+    fn documents_without_text_are_refused() {
+        assert!(parse_documents(b"  ", None, None).is_err());
+        assert!(parse_documents(br#"[{"text": ""}]"#, None, None).is_err());
+        assert!(parse_documents(b"{not json", None, None).is_err());
+    }
+
+    #[test]
+    fn urls_are_trimmed_and_bounded() {
+        let urls = parse_urls(br#"{"urls": [" https://a.example ", ""]}"#).unwrap().unwrap();
+        assert_eq!(urls, vec!["https://a.example"]);
+        assert!(parse_urls(br#"{"urls": []}"#).unwrap().is_err());
+        let many = (0..=MAX_URLS).map(|i| format!("https://{i}.example")).collect::<Vec<String>>();
+        let body = serde_json::to_vec(&json!({ "urls": many })).unwrap();
+        assert!(parse_urls(&body).unwrap().is_err());
+        assert!(parse_urls(br#"{"text": "a"}"#).is_none());
+    }
+
+    #[test]
+    fn options_are_bounded() {
+        let qry = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::from(*v)))
+                .collect::<HashMap<String, Value>>()
+        };
+        let options = IngestOptions::from_query(&qry(&[("chunk_size", "800"), ("chunk_overlap", "80")])).unwrap();
+        assert_eq!((options.chunk_size, options.chunk_overlap), (800, 80));
+        assert!(IngestOptions::from_query(&qry(&[("chunk_size", "10")])).is_err());
+        assert!(IngestOptions::from_query(&qry(&[("chunk_size", "200"), ("chunk_overlap", "200")])).is_err());
+        assert!(IngestOptions::from_query(&qry(&[("chunker", "tokens"), ("chunk_size", "50"), ("chunk_overlap", "10")])).is_ok());
+    }
+}
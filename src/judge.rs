@@ -0,0 +1,134 @@
+use llmservice_flows::chat::ChatOptions;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ error::{ Error, Result }, first_x_chars, guards, retry, settings, CHAT_MODEL };
+
+const RECENT_KEY: &str = "judge:recent";
+/// The latest judgements the rolling averages are over.
+const MAX_RECENT: usize = 200;
+/// Context shown to the judge, in characters.
+const MAX_CONTEXT_CHARS: usize = 6000;
+
+/// How an answer was judged, each score from 0 (worst) to 1 (best).
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Judgement {
+    pub at: u64,
+    /// Whether the answer only says what the context supports.
+    pub faithfulness: f32,
+    /// Whether the answer addresses the question.
+    pub relevance: f32,
+}
+
+/// The share of answers judged, from `judge_sample_percent` (0 to 100, `5` by default, `0` turns
+/// judging off).
+fn sample_percent() -> u32 {
+    settings::setting::<u32>("judge_sample_percent", 5)
+        .min(100)
+}
+
+/// Whether this answer is among the sampled ones.
+pub fn sampled() -> bool {
+    let percent = sample_percent();
+    percent > 0 && rand::random::<u32>() % 100 < percent
+}
+
+/// A score of 1 to 5 from the judge as 0 to 1.
+fn normalized(v: &Value) -> Option<f32> {
+    let score = v.as_f64()?.clamp(1.0, 5.0);
+    Some(((score - 1.0) / 4.0) as f32)
+}
+
+/// Asks the `judge_model` (the default chat model when not set) to score the faithfulness of the
+/// answer to the retrieved context and its relevance to the question.
+pub async fn judge(question: &str, context: &str, answer: &str) -> Result<Judgement> {
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+    let model = std::env::var("judge_model").unwrap_or(CHAT_MODEL.to_string());
+
+    let sys_prompt =
+        "You grade the answers of a question answering bot. Score faithfulness from 1 to 5: 5 when every claim of the answer is supported by the context, 1 when it is mostly unsupported or contradicts it. Score relevance from 1 to 5: 5 when the answer fully addresses the question, 1 when it does not address it. Reply with JSON only, like {\"faithfulness\": 4, \"relevance\": 5}.";
+    let usr_prompt = format!(
+        "Context:\n{}\n\nQuestion: {}\n\nAnswer:\n{}",
+        first_x_chars(context, MAX_CONTEXT_CHARS),
+        question,
+        answer
+    );
+    let co = ChatOptions {
+        model: Some(&model),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 4096,
+        max_tokens: Some(40),
+        temperature: Some(0.0),
+        ..Default::default()
+    };
+    let reply = retry
+        ::call("judge", || llm.chat_completion("answer-judge", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+
+    // the model may wrap the JSON in text or a code block
+    let json = match (reply.find('{'), reply.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => reply.as_str(),
+    };
+    let scores: Value = serde_json::from_str(json).map_err(|e| Error::Llm(format!("unreadable judgement {reply:?}: {e}")))?;
+    match (normalized(&scores["faithfulness"]), normalized(&scores["relevance"])) {
+        (Some(faithfulness), Some(relevance)) =>
+            Ok(Judgement { at: crate::analytics::now_secs(), faithfulness, relevance }),
+        _ => Err(Error::Llm(format!("judgement without both scores: {reply:?}"))),
+    }
+}
+
+fn recent() -> Vec<Judgement> {
+    get(RECENT_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Keeps the judgement among the latest for the rolling averages.
+pub fn record(judgement: Judgement) {
+    let mut recent = recent();
+    recent.push(judgement);
+    if recent.len() > MAX_RECENT {
+        recent.drain(..recent.len() - MAX_RECENT);
+    }
+    guards::set_capped(RECENT_KEY, serde_json::to_value(&recent).unwrap_or_default(), None);
+}
+
+/// The rolling averages of the latest judgements, for `?action=metrics` and `/metrics`.
+pub fn stats() -> Value {
+    let recent = recent();
+    let avg = |f: fn(&Judgement) -> f32| {
+        match recent.len() {
+            0 => Value::Null,
+            n => json!(recent.iter().map(f).sum::<f32>() / (n as f32)),
+        }
+    };
+    json!({
+        "sample_percent": sample_percent(),
+        "judged": recent.len(),
+        "since": recent.first().map(|j| j.at),
+        "avg_faithfulness": avg(|j| j.faithfulness),
+        "avg_relevance": avg(|j| j.relevance),
+    })
+}
+
+/// The averages in one line for the chat.
+pub fn summary() -> String {
+    let stats = stats();
+    match (stats["avg_faithfulness"].as_f64(), stats["avg_relevance"].as_f64()) {
+        (Some(f), Some(r)) =>
+            format!(
+                "- **answer judge**: faithfulness {:.0}%, relevance {:.0}% over the latest {} judged answers",
+                f * 100.0,
+                r * 100.0,
+                stats["judged"]
+            ),
+        _ => "- **answer judge**: no answer judged yet".to_string(),
+    }
+}
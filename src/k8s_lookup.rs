@@ -0,0 +1,206 @@
+use std::{ sync::OnceLock, time::Duration };
+
+use regex::Regex;
+use serde_json::{ json, Map, Value };
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{ fetch, first_x_chars, guards, settings };
+
+/// The definitions of the spec, parsed once per instance.
+static DEFINITIONS: OnceLock<Map<String, Value>> = OnceLock::new();
+
+/// Answers "what fields does this resource have" questions from the Kubernetes OpenAPI spec.
+///
+/// The spec is read from `<k8s_api_endpoint>/openapi/v2` with the read-only token in
+/// `k8s_api_token` when a cluster is configured, or from the static spec at `k8s_openapi_url`
+/// otherwise. Returns `None` when neither is set, the question is not about resource fields, or
+/// the resource is unknown. Answers, unknown resources included, are cached in the KV store for
+/// `k8s_lookup_cache_hours` (default 24), so the spec is only downloaded on a miss.
+pub fn lookup(question: &str) -> Option<String> {
+    let (kind, path) = resource_in_question(question)?;
+    let spec_url = spec_url()?;
+
+    let target = first_x_chars(&[vec![kind.clone()], path.clone()].concat().join("."), 200);
+    let cache_key = format!("k8s_lookup:{target}");
+    if let Some(cached) = get(&cache_key) {
+        return cached
+            .as_str()
+            .filter(|s| !s.is_empty())
+            .map(String::from);
+    }
+
+    let definitions = definitions(&spec_url)?;
+    let found = describe(definitions, &kind, path);
+    let hours = settings::setting("k8s_lookup_cache_hours", 24i64);
+    if hours > 0 {
+        guards::set_capped(
+            &cache_key,
+            json!(found.clone().unwrap_or_default()),
+            Some(Expire {
+                kind: ExpireKind::Ex,
+                value: hours * 3600,
+            })
+        );
+    }
+    found
+}
+
+fn spec_url() -> Option<String> {
+    let endpoint = std::env::var("k8s_api_endpoint").unwrap_or("".to_string());
+    let spec_url = match endpoint.is_empty() {
+        false => format!("{}/openapi/v2", endpoint.trim_end_matches('/')),
+        true => std::env::var("k8s_openapi_url").unwrap_or("".to_string()),
+    };
+    (!spec_url.is_empty()).then_some(spec_url)
+}
+
+/// The definitions of the spec, downloaded within `k8s_openapi_timeout_secs` (default 10) the
+/// first time they are needed.
+fn definitions(spec_url: &str) -> Option<&'static Map<String, Value>> {
+    if let Some(definitions) = DEFINITIONS.get() {
+        return Some(definitions);
+    }
+
+    let token = std::env::var("k8s_api_token").unwrap_or("".to_string());
+    let bearer = format!("Bearer {token}");
+    let headers = match token.is_empty() {
+        true => vec![("Accept", "application/json")],
+        false => vec![("Accept", "application/json"), ("Authorization", bearer.as_str())],
+    };
+    let timeout = settings::setting("k8s_openapi_timeout_secs", 10);
+    let spec = fetch
+        ::get_within(spec_url, &headers, Duration::from_secs(timeout))
+        .and_then(|b| Ok(serde_json::from_slice::<Value>(&b)?));
+    let mut spec = match spec {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Cannot load the Kubernetes OpenAPI spec: {}", e);
+            return None;
+        }
+    };
+    let definitions = match spec.get_mut("definitions").map(Value::take) {
+        Some(Value::Object(d)) => d,
+        _ => {
+            log::error!("The Kubernetes OpenAPI spec at {} has no definitions", spec_url);
+            return None;
+        }
+    };
+    Some(DEFINITIONS.get_or_init(|| definitions))
+}
+
+/// The fields of the resource `kind`, or of the field at `path` within it.
+fn describe(definitions: &Map<String, Value>, kind: &str, path: Vec<String>) -> Option<String> {
+    let (name, mut schema) = find_kind(definitions, kind)?;
+    let mut title = name.clone();
+    for field in path {
+        schema = resolve(definitions, schema.get("properties")?.get(&field)?)?;
+        title = format!("{title}.{field}");
+    }
+
+    let fields = describe_fields(schema);
+    if fields.is_empty() {
+        return None;
+    }
+    log::info!("Kubernetes API lookup found {} fields for {}", fields.len(), title);
+
+    Some(format!("[Kubernetes API: {title}] fields:\n{}", fields.join("\n")))
+}
+
+/// Extracts the resource kind and an optional field path (`deployment.spec.strategy`) from
+/// questions like "what fields does a Deployment have" or "kubectl explain pod.spec".
+fn resource_in_question(question: &str) -> Option<(String, Vec<String>)> {
+    let patterns = [
+        r"(?i)kubectl\s+explain\s+([a-z][a-z0-9.]*)",
+        r"(?i)what\s+fields\s+(?:does|do|can)\s+(?:an?\s+|the\s+)?([a-z][a-z0-9.]*)",
+        r"(?i)fields\s+(?:of|in|for)\s+(?:an?\s+|the\s+)?([a-z][a-z0-9.]*)",
+    ];
+
+    let target = patterns.iter().find_map(|p| {
+        Regex::new(p)
+            .unwrap()
+            .captures(question)
+            .map(|c| c[1].trim_end_matches('.').to_lowercase())
+    })?;
+
+    let mut parts = target.split('.').map(|s| s.to_string());
+    let kind = parts.next()?;
+    Some((kind, parts.collect()))
+}
+
+/// Matches a kind such as `deployment` or `deployments` against definition names like
+/// `io.k8s.api.apps.v1.Deployment`, preferring the stable API group.
+fn find_kind<'a>(definitions: &'a Map<String, Value>, kind: &str) -> Option<(String, &'a Value)> {
+    let singular = kind.strip_suffix('s').unwrap_or(kind);
+
+    definitions
+        .iter()
+        .filter(|(name, _)| {
+            let last = short_name(name).to_lowercase();
+            last == kind || last == singular
+        })
+        .min_by_key(|(name, _)| (name.contains("beta") || name.contains("alpha"), name.len()))
+        .map(|(name, schema)| (name.to_string(), schema))
+}
+
+fn resolve<'a>(definitions: &'a Map<String, Value>, schema: &'a Value) -> Option<&'a Value> {
+    let schema = match schema.get("items") {
+        Some(items) => items,
+        None => schema,
+    };
+    match schema.get("$ref").and_then(|r| r.as_str()) {
+        Some(r) => definitions.get(r.trim_start_matches("#/definitions/")),
+        None => Some(schema),
+    }
+}
+
+fn short_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+fn describe_fields(schema: &Value) -> Vec<String> {
+    let properties = match schema.get("properties").and_then(|p| p.as_object()) {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    let required = schema
+        .get("required")
+        .and_then(|r| r.as_array())
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect::<Vec<&str>>())
+        .unwrap_or_default();
+
+    properties
+        .iter()
+        .map(|(name, prop)| {
+            let type_name = match prop.get("type").and_then(|t| t.as_str()) {
+                Some("array") =>
+                    format!(
+                        "[]{}",
+                        prop
+                            .pointer("/items/$ref")
+                            .or(prop.pointer("/items/type"))
+                            .and_then(|t| t.as_str())
+                            .map(short_name)
+                            .unwrap_or("object")
+                    ),
+                Some(t) => t.to_string(),
+                None =>
+                    prop
+                        .get("$ref")
+                        .and_then(|r| r.as_str())
+                        .map(short_name)
+                        .unwrap_or("object")
+                        .to_string(),
+            };
+            let description = prop
+                .get("description")
+                .and_then(|d| d.as_str())
+                .and_then(|d| d.split(". ").next())
+                .unwrap_or_default();
+            let required = match required.contains(&name.as_str()) {
+                true => " (required)",
+                false => "",
+            };
+            format!("- {name} <{type_name}>{required}: {description}")
+        })
+        .collect()
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use serde_json::{ Map, Value };
+
+/// Words too common to tell chunks apart.
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "can", "do", "does", "for", "from", "how", "i",
+    "in", "is", "it", "my", "of", "on", "or", "that", "the", "this", "to", "what", "when", "where",
+    "which", "why", "with", "you",
+];
+/// The most frequent terms of a chunk kept in its `keywords` payload field.
+const MAX_PAYLOAD_TERMS: usize = 200;
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+/// The rank constant of reciprocal rank fusion.
+const RRF_K: f32 = 60.0;
+
+/// Splits the text into lowercase terms, keeping flags, versions, paths and dotted names whole,
+/// e.g. `--dry-run`, `v1.29`, `apps/v1`, `spec.replicas`. Each term comes with whether it is an
+/// exact term: one with digits or inner punctuation, or in camel case like `CrashLoopBackOff`.
+fn tokens(text: &str) -> Vec<(String, bool)> {
+    text.split(|c: char| !(c.is_alphanumeric() || "-_./".contains(c)))
+        .map(|t| t.trim_end_matches(['.', '/', '-']).trim_start_matches(['.', '/']))
+        .filter(|t| t.chars().any(|c| c.is_alphanumeric()))
+        .filter_map(|t| {
+            let term = t.to_lowercase();
+            if term.chars().count() < 2 || STOPWORDS.contains(&term.as_str()) {
+                return None;
+            }
+            let inner = t.trim_start_matches('-');
+            let exact =
+                t.chars().any(|c| c.is_ascii_digit()) ||
+                inner.contains(['-', '_', '.', '/']) ||
+                t.starts_with('-') ||
+                inner.chars().skip(1).any(|c| c.is_uppercase());
+            Some((term, exact))
+        })
+        .collect()
+}
+
+pub fn terms(text: &str) -> Vec<String> {
+    tokens(text)
+        .into_iter()
+        .map(|(t, _)| t)
+        .collect()
+}
+
+fn counts(text: &str) -> HashMap<String, f32> {
+    let mut counts = HashMap::new();
+    for t in terms(text) {
+        *counts.entry(t).or_insert(0.0) += 1.0;
+    }
+    counts
+}
+
+/// The term counts of a chunk, stored in its `keywords` payload field at ingestion so searches
+/// do not tokenize the chunks they get back.
+pub fn payload_field(text: &str) -> Value {
+    let mut counts = counts(text).into_iter().collect::<Vec<(String, f32)>>();
+    counts.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(MAX_PAYLOAD_TERMS);
+    Value::Object(
+        counts
+            .into_iter()
+            .map(|(t, n)| (t, Value::from(n as u64)))
+            .collect::<Map<String, Value>>()
+    )
+}
+
+/// The term counts of a returned chunk, from its `keywords` payload field or its text for chunks
+/// ingested before the field was written.
+pub fn chunk_counts(payload: Option<&Map<String, Value>>, text: &str) -> HashMap<String, f32> {
+    match payload.and_then(|m| m.get("keywords")).and_then(|k| k.as_object()) {
+        Some(k) =>
+            k
+                .iter()
+                .map(|(t, n)| (t.clone(), n.as_f64().unwrap_or_default() as f32))
+                .collect(),
+        None => counts(text),
+    }
+}
+
+/// The terms of a question matched against the chunks.
+#[derive(Debug, Clone, Default)]
+pub struct Keywords {
+    pub terms: Vec<String>,
+    /// Error codes, flags, versions and identifiers, which embeddings tend to blur.
+    pub exact: Vec<String>,
+}
+
+impl Keywords {
+    pub fn of_question(question: &str) -> Self {
+        let mut keywords = Self::default();
+        for (term, exact) in tokens(question) {
+            if exact && !keywords.exact.contains(&term) {
+                keywords.exact.push(term.clone());
+            }
+            if !keywords.terms.contains(&term) {
+                keywords.terms.push(term);
+            }
+        }
+        keywords
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Whether the chunk has every exact term of the question, false when it has none.
+    pub fn has_exact_terms(&self, chunk: &HashMap<String, f32>) -> bool {
+        !self.exact.is_empty() && self.exact.iter().all(|t| chunk.contains_key(t))
+    }
+
+    /// The BM25 score of each chunk. The vector store has no keyword search, so the document
+    /// frequencies are those of the chunks returned by the vector search rather than the corpus.
+    pub fn bm25(&self, chunks: &[HashMap<String, f32>]) -> Vec<f32> {
+        let n = chunks.len() as f32;
+        let lengths = chunks
+            .iter()
+            .map(|c| c.values().sum::<f32>())
+            .collect::<Vec<f32>>();
+        let avg_length = (lengths.iter().sum::<f32>() / n.max(1.0)).max(1.0);
+
+        let idf = self.terms
+            .iter()
+            .map(|t| {
+                let df = chunks
+                    .iter()
+                    .filter(|c| c.contains_key(t))
+                    .count() as f32;
+                (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+            })
+            .collect::<Vec<f32>>();
+
+        chunks
+            .iter()
+            .zip(lengths)
+            .map(|(c, length)| {
+                self.terms
+                    .iter()
+                    .zip(&idf)
+                    .map(|(t, idf)| {
+                        let tf = c.get(t).copied().unwrap_or_default();
+                        (idf * tf * (BM25_K1 + 1.0)) /
+                            (tf + BM25_K1 * (1.0 - BM25_B + (BM25_B * length) / avg_length))
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+/// The rank of each score among `scores`, 0 for the highest.
+pub fn ranks(scores: &[f32]) -> Vec<usize> {
+    let mut order = (0..scores.len()).collect::<Vec<usize>>();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    let mut ranks = vec![0; scores.len()];
+    for (rank, i) in order.into_iter().enumerate() {
+        ranks[i] = rank;
+    }
+    ranks
+}
+
+/// The reciprocal rank fusion score of a chunk from its rank in the vector search and, when it
+/// matches any term, in the keyword search.
+pub fn fuse(vector_rank: usize, keyword_rank: Option<usize>) -> f32 {
+    1.0 / (RRF_K + (vector_rank as f32) + 1.0) +
+        keyword_rank.map_or(0.0, |r| 1.0 / (RRF_K + (r as f32) + 1.0))
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::error::{ Error, Result };
+
+/// ISO 639-1 codes the bot answers in by default, with the names used in the prompt.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("vi", "Vietnamese"),
+    ("zh", "Chinese"),
+];
+
+/// The codes accepted for `respond_in`, from the comma separated `supported_languages` setting
+/// when it is set.
+fn supported() -> Vec<String> {
+    let setting = std::env::var("supported_languages").unwrap_or("".to_string());
+    match setting.trim().is_empty() {
+        true => LANGUAGES.iter().map(|(code, _)| code.to_string()).collect(),
+        false => setting
+            .split(',')
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect(),
+    }
+}
+
+/// The language requested with `?respond_in=<code>` or the `x-respond-in` header, the query
+/// parameter taking precedence. Unsupported codes are an error rather than silently ignored.
+pub fn respond_in(qry: &HashMap<String, Value>, headers: &[(String, String)]) -> Result<Option<String>> {
+    let code = qry
+        .get("respond_in")
+        .and_then(|v| v.as_str())
+        .or_else(|| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("x-respond-in"))
+                .map(|(_, v)| v.as_str())
+        })
+        .map(|c| c.trim().to_lowercase());
+
+    match code {
+        None => Ok(None),
+        Some(c) if c.is_empty() => Ok(None),
+        Some(c) if supported().contains(&c) => Ok(Some(c)),
+        Some(c) => Err(Error::InvalidInput(format!("unsupported language `{c}`, use one of {}", supported().join(", ")))),
+    }
+}
+
+/// The instruction added to the post prompt, naming the language when it is a known code.
+pub fn instruction(code: &str) -> String {
+    let name = LANGUAGES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or(code.to_string());
+    format!("Answer in {name} (language code `{code}`), whatever the language of the question.")
+}
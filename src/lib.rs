@@ -1,15 +1,110 @@
-use anyhow;
+use collection_config::CollectionConfig;
+use error::{ Error, Result };
 use flowsnet_platform_sdk::logger;
 use itertools::Itertools;
-use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
-use openai_flows::{ embeddings::EmbeddingsInput, OpenAIFlows };
-use regex::Regex;
+use llmservice_flows::chat::ChatOptions;
+use ranking::Ranking;
+use response_format::ResponseFormat;
+use schedule_flows::{ schedule_cron_job, schedule_handler };
+use serde::{ Deserialize, Serialize };
 use serde_json::{ json, Value };
-use std::collections::HashMap;
-use store_flows::{ get, set };
+use std::collections::{ BTreeMap, HashMap };
+use store_flows::{ del, get, set };
 use vector_store_flows::*;
 use webhook_flows::{ create_endpoint, request_handler, send_response };
 
+pub mod analytics;
+pub mod answer_shape;
+pub mod api_keys;
+pub mod audit;
+pub mod bootstrap;
+pub mod bulk_delete;
+pub mod cache_metrics;
+pub mod chat_id;
+pub mod chunking;
+pub mod citations;
+pub mod code_blocks;
+pub mod collection_config;
+pub mod commands;
+pub mod compression;
+pub mod consensus;
+pub mod continuation;
+pub mod dedup;
+pub mod deterministic;
+pub mod diagnostics;
+pub mod conversation_prompt;
+pub mod conversation_ttl;
+pub mod curation;
+pub mod digest;
+pub mod disclosure;
+pub mod encryption;
+pub mod drift;
+pub mod embedder;
+pub mod embedding_cache;
+pub mod error;
+pub mod events;
+pub mod faq;
+pub mod feedback;
+pub mod formatting;
+pub mod github_sync;
+pub mod graphql;
+pub mod greeting;
+pub mod grounding;
+pub mod guards;
+pub mod history;
+mod fetch;
+pub mod ingest;
+pub mod judge;
+pub mod k8s_lookup;
+pub mod keywords;
+pub mod language;
+pub mod loadtest;
+pub mod maintenance;
+pub mod memory;
+pub mod memory_guard;
+pub mod merge;
+pub mod message_parts;
+pub mod models;
+pub mod openapi;
+pub mod origin;
+pub mod paraphrase;
+pub mod pdf;
+pub mod overrides;
+pub mod priority;
+pub mod prompt_template;
+pub mod quarantine;
+pub mod ranking;
+pub mod rate_limit;
+pub mod releases;
+pub mod reminders;
+pub mod rerank;
+pub mod response_format;
+pub mod resumption;
+pub mod retention;
+pub mod retrieval;
+pub mod retry;
+pub mod rewrite;
+pub mod roles;
+pub mod routing;
+pub mod scheduler;
+pub mod sentiment;
+pub mod settings;
+pub mod short_query;
+pub mod similar;
+pub mod telemetry;
+pub mod tokenizer;
+pub mod trace;
+pub mod transcript;
+pub mod tuning;
+pub mod units;
+pub mod upsert_queue;
+pub mod web_search;
+pub mod webhooks;
+
+pub const CHAT_MODEL: &str = "mistralai/Mixtral-8x7B-Instruct-v0.1";
+/// What the knowledge base is about when `domain_description` is not set.
+pub const DEFAULT_DOMAIN_DESCRIPTION: &str = "This source material is a technical book on Kubernetes.";
+
 #[derive(Debug, Clone)]
 pub struct ContentSettings {
     initial_system_prompt: String,
@@ -18,6 +113,8 @@ pub struct ContentSettings {
     error_mesg: String,
     no_answer_mesg: String,
     collection_name: String,
+    /// What the knowledge base is about, questions too far from it are not searched for.
+    domain_description: String,
 }
 
 impl ContentSettings {
@@ -36,6 +133,7 @@ impl ContentSettings {
             error_mesg,
             no_answer_mesg,
             collection_name,
+            domain_description: DEFAULT_DOMAIN_DESCRIPTION.to_string(),
         }
     }
 
@@ -47,6 +145,12 @@ impl ContentSettings {
         self.system_prompt = self.initial_system_prompt.clone();
     }
 
+    /// Adds instructions to the system prompt that survive [`ContentSettings::reset`].
+    pub fn extend_system_prompt(&mut self, instructions: &str) {
+        self.initial_system_prompt = format!("{}\n{}", self.initial_system_prompt, instructions);
+        self.reset();
+    }
+
     pub fn post_prompt(&self) -> &str {
         &self.post_prompt
     }
@@ -62,148 +166,1348 @@ impl ContentSettings {
     pub fn collection_name(&self) -> &str {
         &self.collection_name
     }
+
+    pub fn domain_description(&self) -> &str {
+        &self.domain_description
+    }
+
+    /// Points the relevance check at another corpus.
+    pub fn set_domain_description(&mut self, description: &str) {
+        self.domain_description = description.to_string();
+    }
 }
 
 #[no_mangle]
 #[tokio::main(flavor = "current_thread")]
 pub async fn on_deploy() {
+    logger::init();
+    if let Err(e) = api_keys::check_settings() {
+        log::error!("Not deploying: {}", e);
+        return;
+    }
     create_endpoint().await;
+    schedule_cron_job(scheduler::cron(), "tick".to_string()).await;
+}
+
+#[schedule_handler]
+async fn scheduled(_body: Vec<u8>) {
+    logger::init();
+    scheduler::tick().await;
 }
 
 #[request_handler]
-async fn handler(headers: Vec<(String, String)>, _qry: HashMap<String, Value>, body: Vec<u8>) {
+async fn handler(headers: Vec<(String, String)>, subpath: String, qry: HashMap<String, Value>, body: Vec<u8>) {
     logger::init();
+    guards::reset_payload_budget();
+    deterministic::begin(&headers);
+    origin::begin(&headers);
+
+    // the one path served besides the webhook URL itself, for OpenAPI tooling
+    if subpath.trim_matches('/') == "openapi.json" {
+        reply_json(&openapi::spec());
+        return;
+    }
+
+    if let Some(action) = qry.get("action").and_then(|v| v.as_str()) {
+        handle_action(action, &headers, &qry, &body).await;
+        return;
+    }
+    telemetry::begin();
+
+    // every chat reply is rendered for the channel it goes to
+    let profile = match formatting::Profile::select(&headers) {
+        Ok(p) => p,
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    };
+
+    let maintenance = maintenance::Maintenance::load();
+    if maintenance.enabled {
+        reply(&profile.apply(&maintenance.message()));
+        return;
+    }
+
     let llm_endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    // `/set` overrides the prompts and messages of the settings
     let mut cs = ContentSettings {
-        initial_system_prompt: std::env::var("system_prompt").unwrap_or("".to_string()),
-        system_prompt: std::env::var("system_prompt").unwrap_or("".to_string()),
-        post_prompt: std::env::var("post_prompt").unwrap_or("".to_string()),
-        error_mesg: std::env::var("error_mesg").unwrap_or("".to_string()),
-        no_answer_mesg: std::env::var("no_answer_mesg").unwrap_or("No answer".to_string()),
+        initial_system_prompt: overrides::setting("system_prompt", ""),
+        system_prompt: overrides::setting("system_prompt", ""),
+        post_prompt: overrides::setting("post_prompt", ""),
+        error_mesg: overrides::setting("error_mesg", ""),
+        no_answer_mesg: overrides::setting("no_answer_mesg", "No answer"),
         collection_name: std::env::var("collection_name").unwrap_or("".to_string()),
+        domain_description: overrides::setting("domain_description", DEFAULT_DOMAIN_DESCRIPTION),
     };
 
     // log::info!("Headers -- {:?}", headers);
     let mut chat_id = "".to_string();
-    for header in headers {
+    for header in &headers {
         if header.0.eq_ignore_ascii_case("x-conversation-name") {
-            chat_id = first_x_chars(&alpha_numeric(&header.1), 48);
+            chat_id = conversation_id(&header.1);
+            chat_id::migrate(&header.1, &chat_id);
+            chat_id::remember_name(&chat_id, &header.1);
             break;
         }
     }
+    let caller = roles::Caller::of(&headers);
+    // keys can be disabled after deploying
+    if let Err(e) = api_keys::check_settings() {
+        log::error!("{}", e);
+        reply_error(503, "No API key is enabled, the bot cannot be used.");
+        return;
+    }
+    if api_keys::required() && !caller.authenticated {
+        reply_error(401, "An API key is required, send it in the x-api-key header.");
+        return;
+    }
+    // operators and admins are not limited, they may be running evals or load tests
+    if caller.role == roles::Role::User {
+        if let Err(retry_after) = rate_limit::check(&chat_id, &headers) {
+            log::warn!("Rate limited {}, retry in {}s", chat_id, retry_after);
+            reply_too_many(retry_after, &format!("Too many messages, please try again in {retry_after} seconds."));
+            return;
+        }
+    }
+    if caller.authenticated {
+        if let Err(retry_after) = api_keys::charge(&caller.name) {
+            log::warn!("The daily quota of {} is used up", caller.name);
+            reply_too_many(retry_after, "The daily quota of this API key is used up.");
+            return;
+        }
+    }
+    if let Some(addendum) = conversation_prompt::load(&chat_id) {
+        cs.extend_system_prompt(&addendum);
+    }
+    match conversation_prompt::post_prompt(&chat_id, &qry, &headers) {
+        Ok(Some(addendum)) => {
+            cs.post_prompt = format!("{}\n{}", cs.post_prompt, addendum);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    }
+    match collection_config::selected(&headers, &cs.collection_name, &chat_id) {
+        Ok(collection) => {
+            cs.collection_name = collection;
+        }
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    }
+    let shape = match answer_shape::AnswerShape::requested(&qry, &headers) {
+        Ok(shape) => shape,
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    };
+    if let Some(shape) = shape {
+        cs.post_prompt = format!("{}\n{}", cs.post_prompt, shape.instruction());
+    }
+    match language::respond_in(&qry, &headers) {
+        Ok(Some(code)) => {
+            cs.post_prompt = format!("{}\n{}", cs.post_prompt, language::instruction(&code));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    }
 
     let body_string = String::from_utf8(body).unwrap_or("".to_string());
     let mut text = body_string.as_str();
-    if text.eq_ignore_ascii_case("/new") {
-        set(&chat_id.to_string(), json!(true), None);
+    let clarified;
+    if commands::is_restart(text) {
+        set(&chat_id.to_string(), json!(true), conversation_ttl::expire(&chat_id));
         log::info!("Restarted converstion for {}", chat_id);
         return;
     }
-
-    let mut openai = OpenAIFlows::new();
-    openai.set_retry_times(3);
+    // the greeting would make the first answer of a conversation differ from the others
+    let greeting = match deterministic::enabled() {
+        true => None,
+        false => greeting::take(&chat_id),
+    };
+    if let (Some(g), true) = (&greeting, text.trim().is_empty()) {
+        reply(&profile.apply(g));
+        return;
+    }
 
     let llm_api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
-    let mut llm = LLMServiceFlows::new(&llm_endpoint);
-    llm.set_api_key(&llm_api_key);
+    let llm = retry::llm_client(&llm_endpoint, &llm_api_key);
 
-    let restart = match get(&chat_id.to_string()) {
+    if let Some(cmd) = commands::parse(text) {
+        let helpful = cmd == commands::Command::Helpful;
+        if let Err(denied) = caller.authorize(cmd.spec().name, cmd.spec().role, &chat_id) {
+            reply_error(403, &denied);
+            return;
+        }
+        reply(&profile.apply(&greeted(&greeting, commands::run(cmd, &chat_id, &cs, &llm, caller.role).await)));
+        analytics::record(&chat_id, analytics::Category::Command, None, false);
+        if helpful {
+            webhooks::emit(webhooks::Event::FeedbackReceived {
+                conversation_id: chat_id.clone(),
+                collection: cs.collection_name.clone(),
+                helpful: true,
+            }).await;
+        }
+        return;
+    }
+
+    // a conversation idle past its TTL starts over, with nothing left of what was stored about it
+    let expired = conversation_ttl::check(&chat_id);
+    let restart = expired || match get(&chat_id.to_string()) {
         Some(v) => v.as_bool().unwrap_or_default(),
         None => false,
     };
+    // a new question gives up on continuing the previous answer, remembered as it is after replying
+    let abandoned = continuation::take(&chat_id).filter(|_| !restart);
 
-    let mut user_prompt = String::new();
-
-    if let Err(_) = collection_info("ephemeral").await {
-        let _ = create_ephemeral_collection().await;
+    if restart {
+        sentiment::clear(&chat_id);
+    } else if sentiment::observe(&chat_id, text) {
+        cs.extend_system_prompt(sentiment::CAREFUL);
     }
 
+    let mut user_prompt = String::new();
+    let mut sources = Vec::new();
+    let mut category = analytics::Category::Restart;
+    let mut confidence = None;
+    let mut earlier = None;
+    let mut missing_collection = None;
+    let lane = priority::Lane::from_headers(&headers);
+    let retrieval = match retrieval::Retrieval::from_request(&qry, &headers) {
+        Ok(r) => r,
+        Err(e) => {
+            reply_error(400, &format!("Invalid retrieval override: {e}"));
+            return;
+        }
+    };
+    let format = match ResponseFormat::negotiate(&headers) {
+        Ok(f) => f,
+        Err(e) => {
+            reply_error(400, &e.to_string());
+            return;
+        }
+    };
+    log::debug!("Serving {} in the {} lane", chat_id, lane.name());
+    trace::start(text, lane.name());
+    let mut events = vec![events::ChatEvent::RetrievalStarted {
+        conversation_id: chat_id.clone(),
+        lane: lane.name().to_string(),
+    }];
+
     cs.reset();
-    
+
+    // a chat idle past the memory retention picks up from its summary, not from what is left
+    let resumed = match restart {
+        true => None,
+        false => resumption::check(&chat_id),
+    };
+    if restart || resumed.is_some() {
+        memory::clear(&chat_id);
+        history::clear(&chat_id);
+    }
+    let mut rag_content = String::new();
+    let mut history = String::new();
     if restart {
-        let _ = reset_ephemeral_collection().await;
+        resumption::clear(&chat_id);
     } else {
-        let mut rag_content = String::new();
 
-        let last_3_relevant_qa_pairs = match
-            is_relevant(text, "This source material is a technical book on Kubernetes.").await
-        {
-            true => {
-                let hypo_answer = create_hypothetical_answer(&text).await;
-                rag_content = match get_rag_content(text, &hypo_answer, &cs).await {
-                    Ok(content) => format!("Given the context: `{content}`"),
-                    Err(_) => String::new(),
-                };
-                last_3_relevant_qa_pairs(&hypo_answer, &chat_id).await
+        // the user's answer to a clarifying question completes the short question that prompted it
+        if let Some(short) = get(&format!("short_query:{chat_id}")).and_then(|v| v.as_str().map(String::from)) {
+            del(&format!("short_query:{chat_id}"));
+            clarified = format!("{short} {text}");
+            text = clarified.as_str();
+        }
+
+        let version = releases::version_in_question(text);
+        let mut query = text.to_string();
+        // a follow-up is searched for as the standalone question it stands for
+        let standalone = match version.is_none() && !lane.skips_optional_stages() {
+            true =>
+                rewrite::standalone(text, &chat_id).await.unwrap_or_else(|e| {
+                    log::error!("Searching for the follow-up as it is: {}", e);
+                    None
+                }),
+            false => None,
+        };
+        if let Some(standalone) = standalone {
+            log::info!("Rewrote the follow-up as: {}", standalone);
+            query = standalone;
+        } else if version.is_none() && short_query::is_short(text) {
+            match short_query::ShortQueryMode::from_env() {
+                short_query::ShortQueryMode::Clarify => {
+                    let topics = recall(text, &chat_id).await;
+                    if let Some(q) = short_query::clarifying_question(text, &topics, &chat_id, &llm).await {
+                        guards::set_capped(&format!("short_query:{chat_id}"), json!(text), None);
+                        reply(&profile.apply(&q));
+                        analytics::record(&chat_id, analytics::Category::Clarify, None, false);
+                        return;
+                    }
+                }
+                short_query::ShortQueryMode::Expand => {
+                    let topics = recall(text, &chat_id).await;
+                    query = short_query::expand(text, &topics);
+                    log::info!("Expanded short query to: {}", query);
+                }
+                short_query::ShortQueryMode::Off => {}
+            }
+        }
+        trace::query(&query);
+
+        // the note depends on what was asked before, not on the question
+        if !lane.skips_optional_stages() && !deterministic::enabled() {
+            earlier = similar::find(text, &chat_id).await.unwrap_or_else(|e| {
+                log::error!("Cannot look for similar earlier questions: {}", e);
+                None
+            });
+        }
+
+        let last_3_relevant_qa_pairs = match version {
+            Some(version) => {
+                let notes = releases::release_context(text, &version).await;
+                category = analytics::Category::ReleaseNotes;
+                if !notes.is_empty() {
+                    rag_content = format!("Given the release notes of Kubernetes {version}: `{notes}`");
+                    sources.push(format!("the Kubernetes {version} release notes"));
+                }
+                recall(text, &chat_id).await
+            }
+            None if retrieval.strategy == retrieval::Strategy::MemoryOnly => {
+                category = analytics::Category::MemoryOnly;
+                recall(&query, &chat_id).await
+            }
+            None => {
+                let relevant = is_relevant(
+                    &query,
+                    cs.domain_description(),
+                    retrieval.relevance_threshold()
+                ).await.unwrap_or_else(|e| {
+                    // when relevance cannot be told, let the score threshold of the search decide
+                    log::error!("Cannot tell if the question is relevant: {}", e);
+                    true
+                });
+                match relevant {
+                    true => {
+                        // collections weighted to the question search only need no hypothetical answer
+                        let hyde = retrieval
+                            .collections(&cs.collection_name)
+                            .iter()
+                            .any(|c| retrieval.hyde_weight(c) > 0.0);
+                        let hypo_answer = match lane.skips_optional_stages() || !hyde {
+                            true => query.clone(),
+                            false =>
+                                create_hypothetical_answer(&query).await.unwrap_or_else(|e| {
+                                    log::error!("Searching without a hypothetical answer: {}", e);
+                                    query.clone()
+                                }),
+                        };
+                        let (mut content, best_score) = get_rag_content(&query, &hypo_answer, &cs, lane, &retrieval).await.unwrap_or_else(|e| {
+                            log::error!("Cannot search the knowledge base: {}", e);
+                            if let Error::MissingCollection(c) = e {
+                                missing_collection = Some(c);
+                            }
+                            (String::new(), None)
+                        });
+                        confidence = best_score;
+                        category = analytics::Category::KnowledgeBase;
+                        match content.is_empty() {
+                            // the knowledge base has nothing above the score threshold, try the web instead
+                            true => {
+                                content = web_search::external_context(&query).await;
+                                category = match content.is_empty() {
+                                    true => analytics::Category::NoContext,
+                                    false => analytics::Category::WebSearch,
+                                };
+                                if !content.is_empty() {
+                                    sources.push("web search".to_string());
+                                }
+                            }
+                            false => sources.push(retrieval.collections(&cs.collection_name).join(", ")),
+                        }
+                        if let Some(api_fields) = k8s_lookup::lookup(&query) {
+                            content = format!("{api_fields}\n{content}");
+                            sources.push("the Kubernetes API reference".to_string());
+                        }
+                        if !content.is_empty() {
+                            rag_content = format!("Given the context: `{content}`");
+                        }
+                        recall(&hypo_answer, &chat_id).await
+                    }
+                    false => {
+                        category = analytics::Category::OffTopic;
+                        recall(&query, &chat_id).await
+                    }
+                }
             }
-            false => last_3_relevant_qa_pairs(&text, &chat_id).await,
         };
 
-        log::info!("last_3_relevant_qa_pairs: {}", last_3_relevant_qa_pairs.clone());
-        cs.update(last_3_relevant_qa_pairs.clone());
+        history = match &resumed {
+            Some(r) => r.context(),
+            None => {
+                log::info!("last_3_relevant_qa_pairs: {}", last_3_relevant_qa_pairs.clone());
+                // a long conversation keeps its earlier context through the summary
+                format!("{}{}", resumption::rolling_context(&chat_id), last_3_relevant_qa_pairs)
+            }
+        };
+    }
 
-        user_prompt = format!(
-            "{rag_content} Here is the question you're to reply now: `{text}`. Please provide a concise answer, stay truthful and factual."
-        );
+    // the templates shape the prompts, `{{system_prompt}}{{history}}` and so on by default
+    let vars = prompt_template::Vars {
+        system_prompt: &cs.system_prompt,
+        post_prompt: &cs.post_prompt,
+        history: &history,
+        context: &rag_content,
+        question: text,
+        collection: &cs.collection_name,
+    };
+    if !restart {
+        user_prompt = prompt_template::render(prompt_template::Part::User, &vars);
     }
+    let system_prompt = prompt_template::render(prompt_template::Part::System, &vars);
+    let post_prompt = prompt_template::render(prompt_template::Part::Post, &vars);
+    cs.system_prompt = system_prompt;
+    cs.post_prompt = post_prompt;
 
+    let chat_model = models::for_chat(&chat_id, lane, text);
+    trace::model(&chat_model);
     let co = ChatOptions {
-        model: Some("mistralai/Mixtral-8x7B-Instruct-v0.1"),
-        restart: restart,
+        model: Some(&chat_model),
+        // the model's own copy of the expired conversation is dropped too
+        restart: restart || resumed.is_some(),
         system_prompt: Some(cs.system_prompt.as_str()),
         post_prompt: Some(&cs.post_prompt),
+        max_tokens: Some(continuation::max_tokens()),
         token_limit: 2048,
+        temperature: deterministic::temperature(),
         ..Default::default()
     };
 
-    match llm.chat_completion(&chat_id.to_string(), &user_prompt, &co).await {
+    let provenance = audit::provenance(&cs.collection_name, trace::provenance());
+    let retrieval_scores = trace::used_scores();
+    events.push(events::ChatEvent::ContextReady { sources: sources.clone(), confidence });
+    let answer = match retry::call("chat", || llm.chat_completion(&chat_id, &user_prompt, &co)).await {
         Ok(r) => {
-            let qa_to_upsert = format!("{}\n {}", text, r.choice);
-            let qa_to_upsert = qa_to_upsert.chars().take(1500).collect::<String>();
-            let _ = upsert_text(qa_to_upsert.as_str()).await;
-
-            reply(&r.choice);
+            // varied temperatures would make deterministic answers differ
+            let samples = consensus::samples(&cs.collection_name);
+            let choice = match samples >= 2 && !lane.skips_optional_stages() && !deterministic::enabled() {
+                true => {
+                    let (choice, agreement) = consensus::vote(r.choice, samples, &user_prompt, &co, &llm, &cs.collection_name).await;
+                    if let Some(agreement) = agreement {
+                        trace::consensus(agreement);
+                    }
+                    choice
+                }
+                false => r.choice,
+            };
+            let choice = match shape {
+                Some(shape) => answer_shape::enforce(shape, choice, &llm, &chat_model).await,
+                None => choice,
+            };
+            // a disclaimer would make a JSON answer invalid
+            let choice = match grounding::enabled() && !rag_content.is_empty() && !lane.skips_optional_stages() {
+                true => {
+                    let disclaim = shape != Some(answer_shape::AnswerShape::Json);
+                    grounding::verify(&rag_content, choice, cs.no_answer_mesg(), disclaim, &llm, &chat_model).await
+                }
+                false => choice,
+            };
+            units::format(&choice, units::UnitStyle::for_chat(&chat_id))
         }
         Err(e) => {
-            reply(&cs.error_mesg);
+            match format {
+                ResponseFormat::EventStream => {
+                    events.push(events::ChatEvent::Error { message: cs.error_mesg.clone() });
+                    reply_event_stream(&events);
+                }
+                ResponseFormat::Json => reply_json(&json!({ "error": cs.error_mesg, "conversation_id": chat_id })),
+                ResponseFormat::Text => reply(&profile.apply(&cs.error_mesg)),
+            }
             log::error!("LLM returns error: {}", e);
+            telemetry::finish(telemetry::RequestRecord {
+                prompt_tokens: tokenizer::count_for(&chat_model, &user_prompt) as u64,
+                retrieval_scores: telemetry::scores(&retrieval_scores),
+                category: category.name().to_string(),
+                lane: lane.name().to_string(),
+                restart,
+                error: true,
+                ..Default::default()
+            });
+            analytics::record(&chat_id, category, confidence, true);
+            audit::record(&chat_id, text, category, confidence, &provenance, true);
             return;
         }
+    };
+
+    let message_id = feedback::message_id();
+    let truncated = continuation::is_truncated(&answer);
+    let shown = match &earlier {
+        Some(earlier) => format!("{}\n\n{}", similar::note(earlier, &chat_id), answer),
+        None => answer.clone(),
+    };
+    let shown = match truncated {
+        true => format!("{}\n\n{}", shown, continuation::HINT),
+        false => shown,
+    };
+    let shown = match &resumed {
+        Some(r) => format!("{}\n\n{}", r.note(), shown),
+        None => shown,
+    };
+    // users are not told about the setup, operators are told how to fix it
+    let shown = match &missing_collection {
+        Some(c) if caller.role >= roles::Role::Operator => format!("{}\n\n{}", bootstrap::note(c), shown),
+        _ => shown,
+    };
+    let shown = match format {
+        ResponseFormat::Json => shown,
+        _ => greeted(&greeting, shown),
+    };
+    let cited = citations::from_provenance(&provenance);
+    let shown = match citations::section(&cited) {
+        // clients asking for events or JSON get the citations as data
+        Some(section) if citations::enabled() && format == ResponseFormat::Text => format!("{}\n\n{}", shown, section),
+        _ => shown,
+    };
+    // clients asking for JSON render their own disclosure
+    let shown = match disclosure::footer(&cs.collection_name, &sources) {
+        Some(footer) if format != ResponseFormat::Json => format!("{}\n\n{}", shown, footer),
+        _ => shown,
+    };
+    let shown = profile.apply(&shown);
+    let parts = message_parts::for_channel(&shown, profile, &headers);
+    match format {
+        ResponseFormat::EventStream => {
+            events.extend(events::tokens(&shown));
+            events.extend(events::citations(&sources, &provenance));
+            events.push(events::ChatEvent::Done {
+                category: category.name().to_string(),
+                message_id: message_id.clone(),
+            });
+            reply_event_stream(&events);
+        }
+        ResponseFormat::Json => {
+            // the LLM service does not report usage, the tokens are counted here
+            let tokens_used = [cs.system_prompt.as_str(), &user_prompt, &cs.post_prompt, &answer]
+                .iter()
+                .map(|t| tokenizer::count_for(&chat_model, t))
+                .sum::<usize>();
+            reply_json(
+                &json!({
+                    "conversation_id": chat_id,
+                    "message_id": message_id,
+                    "answer": shown,
+                    "parts": parts,
+                    "sources": sources,
+                    "tokens_used": tokens_used,
+                    "retrieval_scores": retrieval_scores,
+                    "citations": cited,
+                    "provenance": provenance,
+                    "greeting": greeting,
+                })
+            )
+        }
+        ResponseFormat::Text => reply_parts(&parts, &message_id),
+    }
+
+    // the user has the answer, the bookkeeping below does not add to the response time
+    telemetry::finish(telemetry::RequestRecord {
+        prompt_tokens: [cs.system_prompt.as_str(), &user_prompt, &cs.post_prompt]
+            .iter()
+            .map(|t| tokenizer::count_for(&chat_model, t) as u64)
+            .sum(),
+        completion_tokens: tokenizer::count_for(&chat_model, &answer) as u64,
+        retrieval_scores: telemetry::scores(&retrieval_scores),
+        category: category.name().to_string(),
+        lane: lane.name().to_string(),
+        restart,
+        error: false,
+        ..Default::default()
+    });
+    if let Some(p) = abandoned {
+        remember(&chat_id, &p.question, &p.answer, &p.message_id, &p.collection).await;
+    }
+    match truncated {
+        true => {
+            let pending = continuation::Pending {
+                question: text.to_string(),
+                answer: answer.clone(),
+                parts: 1,
+                message_id: message_id.clone(),
+                collection: cs.collection_name.clone(),
+            };
+            continuation::save(&chat_id, &pending);
+        }
+        false => remember(&chat_id, text, &answer, &message_id, &cs.collection_name).await,
+    }
+    analytics::record(&chat_id, category, confidence, false);
+    audit::record(&chat_id, text, category, confidence, &provenance, false);
+    if !rag_content.is_empty() && !lane.skips_optional_stages() && judge::sampled() {
+        match judge::judge(text, &rag_content, &answer).await {
+            Ok(judgement) => {
+                audit::attach(&chat_id, "judge", serde_json::to_value(judgement).unwrap_or_default());
+                judge::record(judgement);
+            }
+            Err(e) => log::warn!("Cannot judge the answer to {}: {}", chat_id, e),
+        }
+    }
+    resumption::touch(&chat_id);
+    conversation_ttl::touch(&chat_id);
+    retention::touch(&chat_id);
+    // the trace of the previous answer is replaced below, a chat without one is new
+    if restart || trace::load(&chat_id).is_none() {
+        webhooks::emit(webhooks::Event::ConversationStarted { conversation_id: chat_id.clone() }).await;
+    }
+    trace::finish(&chat_id, category.name(), confidence);
+    webhooks::emit(webhooks::Event::MessageAnswered {
+        conversation_id: chat_id.clone(),
+        question: (!encryption::enabled()).then(|| text.to_string()),
+        category: category.name().to_string(),
+        confidence,
+        provenance: provenance.clone(),
+    }).await;
+    if
+        matches!(
+            category,
+            analytics::Category::KnowledgeBase | analytics::Category::WebSearch | analytics::Category::NoContext
+        )
+    {
+        analytics::record_question(text);
     }
 
     // A successful restart. The new message will NOT be a restart
     if restart {
         log::info!("Detected restart = true");
-        set(&chat_id.to_string(), json!(false), None);
+        del(&chat_id);
         cs.reset();
     }
 }
 
-fn alpha_numeric(s: &str) -> String {
-    let regex_pattern = Regex::new(r"[^a-zA-Z0-9]").unwrap();
-    regex_pattern.replace_all(s, "-").to_string()
+/// Saves a QA pair to the conversation memory and its KV mirror, queueing it when it cannot be
+/// embedded.
+async fn remember(chat_id: &str, question: &str, answer: &str, message_id: &str, collection: &str) {
+    // keep out what could mislead later answers
+    if let Some(reason) = memory_guard::rejection(question, answer) {
+        log::info!("Not saving the QA pair to the conversation memory: {}", reason);
+        return;
+    }
+    let qa_to_upsert = format!("{}\n {}", question, answer);
+    history::push(chat_id, &qa_to_upsert);
+    resumption::remembered(chat_id).await;
+    let pair = QaPair {
+        chat_id: chat_id.to_string(),
+        question: first_x_chars(question, 500),
+        text: qa_to_upsert,
+        at: analytics::now_secs(),
+        message_id: message_id.to_string(),
+        rating: None,
+        collection: collection.to_string(),
+    };
+
+    match memory::save(&pair).await {
+        // the embedding service is reachable again, catch up on the pairs that failed before
+        Ok(()) => {
+            upsert_queue::flush().await;
+        }
+        Err(e) => {
+            log::error!("Cannot save the QA pair to the conversation memory: {}", e);
+            upsert_queue::enqueue(pair);
+        }
+    }
+}
+
+/// Serves the operator routes selected with the `action` query parameter.
+async fn handle_action(
+    action: &str,
+    headers: &[(String, String)],
+    qry: &HashMap<String, Value>,
+    body: &[u8]
+) {
+    // without `admin_token` or API keys everyone is a user, so operator and admin routes are closed
+    if let Err(denied) = roles::Caller::of(headers).authorize(action, roles::required(action), "") {
+        reply_error(403, &denied);
+        return;
+    }
+
+    match action {
+        "ingest" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            let urls = match ingest::parse_urls(body).transpose() {
+                Ok(urls) => urls,
+                Err(e) => {
+                    reply_error(400, &format!("Invalid urls: {e}"));
+                    return;
+                }
+            };
+            let documents = match &urls {
+                Some(_) => Vec::new(),
+                None =>
+                    match
+                        ingest::parse_documents(
+                            body,
+                            qry.get("source").and_then(|v| v.as_str()),
+                            qry.get("title").and_then(|v| v.as_str())
+                        )
+                    {
+                        Ok(d) => d,
+                        Err(e) => {
+                            reply_error(400, &format!("Invalid documents: {e}"));
+                            return;
+                        }
+                    }
+            };
+            let options = match ingest::IngestOptions::from_query(qry) {
+                Ok(o) => o,
+                Err(e) => {
+                    reply_error(400, &e.to_string());
+                    return;
+                }
+            };
+            let mut events = Vec::new();
+            let mut progress = |e: ingest::Progress| {
+                log::info!("Ingestion progress {}: {}", e.name(), e.data());
+                events.push(e);
+            };
+            // pages are fetched one after the other, those that fail are reported and skipped
+            let documents = match &urls {
+                Some(urls) => ingest::fetch_pages(urls, &mut progress),
+                None => documents,
+            };
+
+            let chunks = ingest::chunk_documents(documents, &options, &mut progress);
+            if options.preview {
+                reply_json(&ingest::preview_report(&chunks));
+                return;
+            }
+
+            let written = match ingest::embed_and_upsert(&collection, chunks, &mut progress).await {
+                Ok(n) => n,
+                Err(e) => {
+                    log::error!("Cannot ingest into {}: {}", collection, e);
+                    progress(ingest::Progress::Error { source: collection.clone(), message: e.to_string() });
+                    0
+                }
+            };
+            progress(ingest::Progress::Done { written });
+            let failed = events.iter().any(|e| matches!(e, ingest::Progress::Error { .. }));
+            webhooks::emit(webhooks::Event::IngestionCompleted {
+                collection: collection.clone(),
+                written,
+                kb_version: ingest::kb_version(&collection).map(|v| v.version),
+                failed,
+            }).await;
+            match wants_event_stream(headers) {
+                true =>
+                    send_response(
+                        200,
+                        vec![(String::from("content-type"), String::from("text/event-stream"))],
+                        ingest::event_stream(&events).into_bytes()
+                    ),
+                false => {
+                    let report = json!({ "collection": collection, "written": written, "kb_version": ingest::kb_version(&collection) });
+                    match failed {
+                        true => reply_json_status(500, &report),
+                        false => reply_json(&report),
+                    }
+                }
+            }
+        }
+        "delete_points" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            let filter = match bulk_delete::DeleteFilter::from_request(qry, body) {
+                Ok(f) => f,
+                Err(e) => {
+                    reply_error(400, &format!("Invalid filter: {e}"));
+                    return;
+                }
+            };
+            // nothing is deleted unless asked for after seeing the count
+            let dry_run = qry.get("confirm").and_then(|v| v.as_str()) != Some("true");
+            match bulk_delete::run(&collection, &filter, dry_run).await {
+                Ok(report) => reply_json(&report),
+                Err(e) => {
+                    log::error!("Cannot delete points of {}: {}", collection, e);
+                    reply_error(500, &e.to_string());
+                }
+            }
+        }
+        "refresh_releases" => {
+            let options = match ingest::IngestOptions::from_query(qry) {
+                Ok(o) => o,
+                Err(e) => {
+                    reply_error(400, &e.to_string());
+                    return;
+                }
+            };
+            let mut events = Vec::new();
+            let mut progress = |e: ingest::Progress| {
+                log::info!("Ingestion progress {}: {}", e.name(), e.data());
+                events.push(e);
+            };
+
+            if options.preview {
+                let chunks = releases
+                    ::collect_release_notes(&options, &mut progress)
+                    .into_iter()
+                    .flat_map(|entry| entry.chunks)
+                    .collect::<Vec<ingest::Chunk>>();
+                reply_json(&ingest::preview_report(&chunks));
+                return;
+            }
+
+            let written = releases::refresh_release_notes(&options, &mut progress).await;
+            webhooks::emit(webhooks::Event::IngestionCompleted {
+                collection: releases::RELEASES_COLLECTION.to_string(),
+                written,
+                kb_version: ingest::kb_version(releases::RELEASES_COLLECTION).map(|v| v.version),
+                failed: events.iter().any(|e| matches!(e, ingest::Progress::Error { .. })),
+            }).await;
+            match wants_event_stream(headers) {
+                true =>
+                    send_response(
+                        200,
+                        vec![(String::from("content-type"), String::from("text/event-stream"))],
+                        ingest::event_stream(&events).into_bytes()
+                    ),
+                false => reply(&format!("Ingested {written} chunks of release notes")),
+            }
+        }
+        "github_sync" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            let options = match ingest::IngestOptions::from_query(qry) {
+                Ok(o) => o,
+                Err(e) => {
+                    reply_error(400, &e.to_string());
+                    return;
+                }
+            };
+            let dry_run = qry.get("dry_run").and_then(|v| v.as_str()) == Some("true");
+            match github_sync::run(&collection, &options, dry_run).await {
+                Ok(report) => reply_json(&report),
+                Err(e) => {
+                    log::error!("Cannot sync {} from GitHub: {}", collection, e);
+                    reply_error(500, &e.to_string());
+                }
+            }
+        }
+        "tune_retrieval" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            let apply = qry.get("apply").and_then(|v| v.as_str()) == Some("true");
+            match tuning::recommend_hyde_weight(&collection, body, apply).await {
+                Ok(report) => reply_json(&report),
+                Err(e) => {
+                    log::error!("HyDE weight recommendation failed: {}", e);
+                    reply_error(400, &e.to_string());
+                }
+            }
+        }
+        "tune_chunking" =>
+            match tuning::recommend_chunking(body).await {
+                Ok(report) => reply_json(&report),
+                Err(e) => {
+                    log::error!("Chunking recommendation failed: {}", e);
+                    reply_error(400, &e.to_string());
+                }
+            }
+        "loadtest" => {
+            let options = match body.is_empty() {
+                true => Ok(loadtest::LoadTestOptions::default()),
+                false => serde_json::from_slice::<loadtest::LoadTestOptions>(body),
+            };
+            match options {
+                Ok(options) =>
+                    match loadtest::run(&options).await {
+                        Ok(report) => reply_json(&report),
+                        Err(e) => reply_error(400, &e.to_string()),
+                    }
+                Err(e) => reply_error(400, &format!("Invalid load test options: {e}")),
+            }
+        }
+        "maintenance" => {
+            if !body.is_empty() {
+                match serde_json::from_slice::<maintenance::Maintenance>(body) {
+                    Ok(m) => {
+                        log::info!("Maintenance mode enabled: {}", m.enabled);
+                        m.save();
+                    }
+                    Err(e) => {
+                        reply_error(400, &format!("Invalid maintenance settings: {e}"));
+                        return;
+                    }
+                }
+            }
+            reply_json(&serde_json::to_value(maintenance::Maintenance::load()).unwrap_or_default());
+        }
+        "analytics" => {
+            let days = qry
+                .get("days")
+                .and_then(|v| v.as_str())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(30);
+            reply_json(&analytics::report(days));
+        }
+        "analytics_rollup" => reply_json(&analytics::rollup()),
+        "digest" => {
+            let dry_run = qry.get("dry_run").and_then(|v| v.as_str()) == Some("true");
+            reply_json(&digest::run(dry_run));
+        }
+        "audit" => {
+            let limit = qry
+                .get("limit")
+                .and_then(|v| v.as_str())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(50);
+            let chat_id = qry
+                .get("conversation")
+                .and_then(|v| v.as_str())
+                .map(conversation_id);
+            reply_json(&audit::recent(limit, chat_id.as_deref()));
+        }
+        "export" => {
+            // `chat_id` as logged, or `conversation` as the client names it
+            let chat_id = match (qry.get("chat_id").and_then(|v| v.as_str()), qry.get("conversation").and_then(|v| v.as_str())) {
+                (Some(id), _) => id.to_string(),
+                (None, Some(c)) => conversation_id(c),
+                (None, None) => {
+                    reply_error(400, "`chat_id` or `conversation` is required");
+                    return;
+                }
+            };
+            let transcript = transcript::assemble(&chat_id);
+            match qry.get("format").and_then(|v| v.as_str()) {
+                Some("markdown") => reply_markdown(&transcript.markdown()),
+                _ => reply_json(&serde_json::to_value(&transcript).unwrap_or_default()),
+            }
+        }
+        "faq_generate" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            let count = qry
+                .get("count")
+                .and_then(|v| v.as_str())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(10);
+            match faq::generate(&collection, count).await {
+                Ok(v) => reply_json(&v),
+                Err(e) => reply_error(500, &e.to_string()),
+            }
+        }
+        "faq" =>
+            match qry.get("format").and_then(|v| v.as_str()) {
+                Some("markdown") => reply_markdown(&faq::markdown()),
+                _ => reply_json(&faq::load()),
+            }
+        "openapi" => reply_json(&openapi::spec()),
+        "graphql" => {
+            let query = qry.get("query").and_then(|v| v.as_str());
+            reply_json(&graphql::execute(body, query).await);
+        }
+        "drift_baseline" => {
+            let collection = match qry.get("collection").and_then(|v| v.as_str()) {
+                Some(c) => c.to_string(),
+                None => std::env::var("collection_name").unwrap_or("".to_string()),
+            };
+            match drift::build_profile(&collection).await {
+                Ok(profile) => reply_json(&profile),
+                Err(e) => {
+                    log::error!("Cannot profile the knowledge base: {}", e);
+                    reply_error(500, &e.to_string());
+                }
+            }
+        }
+        "drift_check" => reply_json(&drift::check()),
+        "diag" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            reply_json(&serde_json::to_value(diagnostics::run(&collection).await).unwrap_or_default());
+        }
+        "flush_upserts" => reply_json(&upsert_queue::flush_report().await),
+        "retry_metrics" => reply_json(&retry::metrics()),
+        "metrics" => {
+            if qry.get("reset").and_then(|v| v.as_str()) == Some("true") {
+                cache_metrics::reset();
+            }
+            reply_json(&json!({ "caches": cache_metrics::report(), "judge": judge::stats() }))
+        }
+        "stats" => {
+            if qry.get("reset").and_then(|v| v.as_str()) == Some("true") {
+                telemetry::reset();
+            }
+            reply_json(&telemetry::stats())
+        }
+        "deliver_reminders" => reply_json(&reminders::deliver_due().await),
+        "embedding_cache" => {
+            if qry.get("reset").and_then(|v| v.as_str()) == Some("true") {
+                embedding_cache::reset_stats();
+            }
+            reply_json(&embedding_cache::report())
+        }
+        "conversation_prompt" => {
+            let chat_id = match qry.get("conversation").and_then(|v| v.as_str()) {
+                Some(c) => conversation_id(c),
+                None => {
+                    reply_error(400, "`conversation` is required");
+                    return;
+                }
+            };
+            if !body.is_empty() {
+                if let Err(e) = conversation_prompt::save(&chat_id, body) {
+                    reply_error(400, &format!("Invalid conversation prompt: {e}"));
+                    return;
+                }
+            }
+            reply_json(
+                &json!({
+                    "conversation": chat_id,
+                    "prompt": conversation_prompt::load(&chat_id),
+                    "post_prompt": conversation_prompt::load_post(&chat_id),
+                })
+            );
+        }
+        "conversation_ttl" => {
+            let chat_id = match qry.get("conversation").and_then(|v| v.as_str()) {
+                Some(c) => conversation_id(c),
+                None => {
+                    reply_error(400, "`conversation` is required");
+                    return;
+                }
+            };
+            if let Some(days) = qry.get("days").and_then(|v| v.as_str()) {
+                let days = match days {
+                    "default" => Ok(None),
+                    d => d.parse::<u64>().map(Some).map_err(|_| Error::InvalidInput("`days` must be a number or `default`".to_string())),
+                };
+                if let Err(e) = days.and_then(|d| conversation_ttl::set_days(&chat_id, d)) {
+                    reply_error(400, &format!("Invalid TTL: {e}"));
+                    return;
+                }
+            }
+            reply_json(&json!({ "conversation": chat_id, "ttl_days": conversation_ttl::days(&chat_id) }));
+        }
+        "merge_conversations" => {
+            let id = |name: &str| {
+                qry.get(name)
+                    .and_then(|v| v.as_str())
+                    .map(conversation_id)
+                    .unwrap_or_default()
+            };
+            let dry_run = qry.get("dry_run").and_then(|v| v.as_str()) == Some("true");
+            match merge::conversations(&id("from"), &id("into"), dry_run) {
+                Ok(report) => reply_json(&report),
+                Err(e) => reply_error(400, &e.to_string()),
+            }
+        }
+        "collection_config" => {
+            let collection = match qry.get("collection").and_then(|v| v.as_str()) {
+                Some(c) => c,
+                None => {
+                    reply_error(400, "`collection` is required");
+                    return;
+                }
+            };
+            if !body.is_empty() {
+                match serde_json::from_slice::<CollectionConfig>(body) {
+                    Ok(config) => config.save(collection),
+                    Err(e) => {
+                        reply_error(400, &format!("Invalid collection config: {e}"));
+                        return;
+                    }
+                }
+            }
+            reply_json(&serde_json::to_value(CollectionConfig::load(collection)).unwrap_or_default());
+        }
+        "feedback" => {
+            let request = match serde_json::from_slice::<feedback::RatingRequest>(body) {
+                Ok(r) => r,
+                Err(e) => {
+                    reply_error(400, &format!("Invalid feedback: {e}"));
+                    return;
+                }
+            };
+            let chat_id = match (request.chat_id, request.conversation) {
+                (Some(id), _) => id,
+                (None, Some(c)) => conversation_id(&c),
+                (None, None) => {
+                    reply_error(400, "`chat_id` or `conversation` is required");
+                    return;
+                }
+            };
+            match feedback::rate(&chat_id, &request.message_id, request.rating) {
+                Some(v) => reply_json(&v),
+                None => reply_error(404, "No remembered answer of the conversation has this message id"),
+            }
+        }
+        "feedback_export" => {
+            let rating = match qry.get("rating").and_then(|v| v.as_str()) {
+                Some("up") => Some(feedback::Rating::Up),
+                Some("down") => Some(feedback::Rating::Down),
+                _ => None,
+            };
+            let limit = qry
+                .get("limit")
+                .and_then(|v| v.as_str())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(100);
+            reply_json(&feedback::ratings(rating, limit));
+        }
+        "feedback_boosts" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            reply_json(&feedback::rollup(&collection));
+        }
+        "quarantine" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            match body.is_empty() {
+                true => reply_json(&quarantine::report(&collection)),
+                false =>
+                    match quarantine::update(&collection, body) {
+                        Ok(v) => reply_json(&v),
+                        Err(e) => reply_error(400, &format!("Invalid quarantine request: {e}")),
+                    }
+            }
+        }
+        "curation" => {
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(std::env::var("collection_name").unwrap_or("".to_string()));
+            match body.is_empty() {
+                true => reply_json(&curation::report(&collection)),
+                false =>
+                    match curation::decide(&collection, body, &roles::Caller::of(headers).name).await {
+                        Ok(v) => reply_json(&v),
+                        Err(e @ (Error::Json(_) | Error::InvalidInput(_))) =>
+                            reply_error(400, &format!("Invalid curation request: {e}")),
+                        Err(e) => {
+                            log::error!("Cannot promote answers into {}: {}", collection, e);
+                            reply_error(500, &e.to_string());
+                        }
+                    }
+            }
+        }
+        "api_keys" => {
+            if !body.is_empty() {
+                let update = match serde_json::from_slice::<api_keys::KeyUpdate>(body) {
+                    Ok(u) => u,
+                    Err(e) => {
+                        reply_error(400, &format!("Invalid API key update: {e}"));
+                        return;
+                    }
+                };
+                match api_keys::upsert(&update) {
+                    Ok(key) => reply_json(&key),
+                    Err(e) => reply_error(400, &e.to_string()),
+                }
+                return;
+            }
+            reply_json(&api_keys::list());
+        }
+        "retention_rules" => {
+            if !body.is_empty() {
+                if let Err(e) = retention::save_rules(body) {
+                    reply_error(400, &format!("Invalid retention rules: {e}"));
+                    return;
+                }
+            }
+            reply_json(&serde_json::to_value(retention::load_rules()).unwrap_or_default());
+        }
+        "retention_sweep" => {
+            let dry_run = qry.get("dry_run").and_then(|v| v.as_str()) == Some("true");
+            let collection = std::env::var("collection_name").unwrap_or("".to_string());
+            reply_json(&retention::sweep(&collection, dry_run));
+        }
+        "search" => {
+            let default = std::env::var("collection_name").unwrap_or("".to_string());
+            let collection = qry
+                .get("collection")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or(default.clone());
+            if !collection_config::allowed(&default).contains(&collection) {
+                reply_error(400, &format!("`{collection}` is not one of the allowed collections"));
+                return;
+            }
+            // plain text, or a JSON string from clients generated off the OpenAPI document
+            let query = serde_json
+                ::from_slice::<String>(body)
+                .unwrap_or_else(|_| String::from_utf8_lossy(body).to_string())
+                .trim()
+                .to_string();
+            if query.is_empty() {
+                reply_error(400, "The body is the text to search for.");
+                return;
+            }
+            let mut ranking = Ranking::for_question(&query);
+            ranking.top_k = qry
+                .get("limit")
+                .and_then(|v| v.as_str())
+                .and_then(|s| s.parse::<usize>().ok())
+                .map(|k| k.clamp(1, 20));
+            match search_collection(&query, &collection, &ranking).await {
+                Ok(found) =>
+                    reply_json(
+                        &json!({
+                            "collection": collection,
+                            "results": found
+                                .iter()
+                                .map(|(id, score, text)| json!({ "id": id, "score": score, "text": text }))
+                                .collect::<Vec<Value>>(),
+                        })
+                    ),
+                Err(e) => {
+                    log::error!("Cannot search {}: {}", collection, e);
+                    reply_error(500, &e.to_string());
+                }
+            }
+        }
+        "boost_rules" => {
+            if !body.is_empty() {
+                match serde_json::from_slice::<Vec<ranking::BoostRule>>(body) {
+                    Ok(rules) => ranking::save_boost_rules(&rules),
+                    Err(e) => {
+                        reply_error(400, &format!("Invalid boost rules: {e}"));
+                        return;
+                    }
+                }
+            }
+            reply_json(&serde_json::to_value(ranking::load_boost_rules()).unwrap_or_default());
+        }
+        _ => reply_error(400, &format!("Unknown action: {action}")),
+    }
+}
+
+/// The chat id of a conversation name, as used for its keys in the stores.
+fn conversation_id(name: &str) -> String {
+    chat_id::normalize(name)
 }
 
 fn first_x_chars(s: &str, x: usize) -> String {
     s.chars().take(x).collect()
 }
 
-fn reply(s: &str) {
+/// Puts the greeting of a conversation seen for the first time before the reply.
+fn greeted(greeting: &Option<String>, reply: String) -> String {
+    match greeting {
+        Some(g) => format!("{g}\n\n{reply}"),
+        None => reply,
+    }
+}
+
+fn wants_event_stream(headers: &[(String, String)]) -> bool {
+    headers
+        .iter()
+        .any(|(k, v)| k.eq_ignore_ascii_case("accept") && v.contains("text/event-stream"))
+}
+
+fn reply_error(status: u16, s: &str) {
     send_response(
-        200,
+        status,
         vec![(String::from("content-type"), String::from("text/html"))],
         s.as_bytes().to_vec()
     );
 }
 
-pub async fn create_hypothetical_answer(question: &str) -> String {
-    // let llm_endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
-    // let llm = LLMServiceFlows::new(&llm_endpoint);
+fn reply_too_many(retry_after: u64, s: &str) {
+    send_response(
+        429,
+        vec![
+            (String::from("content-type"), String::from("text/html")),
+            (String::from("retry-after"), retry_after.to_string())
+        ],
+        s.as_bytes().to_vec()
+    );
+}
+
+fn reply_json(v: &Value) {
+    reply_json_status(200, v);
+}
 
-    let openai = OpenAIFlows::new();
-    let sys_prompt_1 = format!(
-        "You're an assistant bot with expertise in all domains of human knowledge."
+fn reply_json_status(status: u16, v: &Value) {
+    send_response(
+        status,
+        vec![(String::from("content-type"), String::from("application/json"))],
+        serde_json::to_vec_pretty(v).unwrap_or_default()
+    );
+}
+
+/// Sends the events in one response, the webhook runtime cannot flush a response in parts.
+fn reply_event_stream(events: &[events::ChatEvent]) {
+    send_response(
+        200,
+        vec![(String::from("content-type"), String::from("text/event-stream"))],
+        events::event_stream(events).into_bytes()
+    );
+}
+
+fn reply_markdown(s: &str) {
+    send_response(
+        200,
+        vec![(String::from("content-type"), String::from("text/markdown; charset=utf-8"))],
+        s.as_bytes().to_vec()
+    );
+}
+
+/// Replies with the messages of a split answer, separated by [`message_parts::SEPARATOR`] and
+/// counted in `x-message-parts` for the adapter to send one by one. `x-message-id` is what the
+/// answer is rated by.
+fn reply_parts(parts: &[String], message_id: &str) {
+    send_response(
+        200,
+        vec![
+            (String::from("content-type"), String::from("text/html")),
+            (String::from("x-message-parts"), parts.len().to_string()),
+            (String::from("x-message-id"), message_id.to_string())
+        ],
+        parts.join(&message_parts::SEPARATOR.to_string()).into_bytes()
+    );
+}
+
+fn reply(s: &str) {
+    send_response(
+        200,
+        vec![(String::from("content-type"), String::from("text/html"))],
+        s.as_bytes().to_vec()
     );
+}
+
+pub async fn create_hypothetical_answer(question: &str) -> Result<String> {
+    let openai = retry::openai_client();
+    let sys_prompt_1 = "You're an assistant bot with expertise in all domains of human knowledge.";
 
     let usr_prompt_1 = format!(
         "You're preparing to answer questions about a specific source material, before ingesting the source material, you need to answer the question based on the knowledge you're trained on, here it is: `{question}`, please provide a concise answer in one paragraph, stay truthful and factual."
@@ -211,131 +1515,353 @@ pub async fn create_hypothetical_answer(question: &str) -> String {
     let co = openai_flows::chat::ChatOptions {
         model: openai_flows::chat::ChatModel::GPT4Turbo,
         restart: true,
-        system_prompt: Some(&sys_prompt_1),
+        system_prompt: Some(sys_prompt_1),
         max_tokens: Some(128),
+        temperature: deterministic::temperature(),
         ..Default::default()
     };
 
-    if let Ok(r) = openai.chat_completion("create-hypo-answer", &usr_prompt_1, &co).await {
-        return r.choice;
-    }
-
-    String::new()
+    retry
+        ::call("hypo_answer", || openai.chat_completion("create-hypo-answer", &usr_prompt_1, &co)).await
+        .map(|r| r.choice)
+        .map_err(Error::Llm)
 }
 
+/// Searches the collection for the points scoring above its threshold once `ranking` has
+/// applied code preference and boost rules.
 pub async fn search_collection(
     question: &str,
-    collection_name: &str
-) -> anyhow::Result<Vec<(u64, String)>> {
-    let mut openai = OpenAIFlows::new();
-    openai.set_retry_times(3);
+    collection_name: &str,
+    ranking: &Ranking
+) -> Result<Vec<(u64, f32, String)>> {
+    let config = CollectionConfig::load(collection_name);
+    let question_vector = config.embed_one(question).await?;
 
-    let question_vector = match
-        openai.create_embeddings(EmbeddingsInput::String(question.to_string())).await
-    {
-        Ok(r) => {
-            if r.len() < 1 {
-                log::error!("LLM returned no embedding for the question");
-                return Err(anyhow::anyhow!("LLM returned no embedding for the question"));
-            }
-            r[0]
-                .iter()
-                .map(|n| *n as f32)
-                .collect()
+    search_vector(question_vector, collection_name, &config, ranking).await
+}
+
+/// Searches the collection with an embedded question, see [`search_collection`]. In collections
+/// with keyword search, chunks are ordered by fusing their similarity rank with their keyword
+/// rank, and a chunk below the threshold is used when it has every exact term of the question.
+pub async fn search_vector(
+    question_vector: Vec<f32>,
+    collection_name: &str,
+    config: &CollectionConfig,
+    ranking: &Ranking
+) -> Result<Vec<(u64, f32, String)>> {
+    // the vector store cannot filter or boost on payload, so over-fetch and rank here
+    let quarantined = quarantine::ids(collection_name);
+    let helpful = feedback::boosts(collection_name);
+    let hybrid = config.keyword_search && !ranking.keywords.is_empty();
+    let top_k = ranking.top_k();
+    let limit = match (hybrid, ranking.reranks() || !quarantined.is_empty() || !helpful.is_empty()) {
+        (true, _) => (top_k * 6).max(30),
+        (false, true) => (top_k * 3).max(15),
+        (false, false) => top_k,
+    };
+    let threshold = ranking.threshold.unwrap_or(config.score_threshold);
+    let p = PointsSearchParams {
+        vector: question_vector,
+        limit: limit as u64,
+    };
+
+    let started = std::time::Instant::now();
+    let sp = match loadtest::search(&p) {
+        Some(sp) => Ok(sp),
+        None => search_points(collection_name, &p).await,
+    };
+    telemetry::record_call("search", started.elapsed());
+    let sp = match sp {
+        Ok(sp) => sp,
+        // a collection searched before anything was ingested into it
+        Err(e) if bootstrap::is_missing(&e.to_string()) => {
+            bootstrap::on_missing(collection_name).await?;
+            Vec::new()
         }
-        Err(_e) => {
-            log::error!("LLM returned an error: {}", _e);
-            return Err(anyhow::anyhow!("LLM returned no embedding for the question"));
+        Err(e) => {
+            return Err(Error::vector_store("search", collection_name, e));
         }
     };
+    let mut ranked = Vec::new();
+    for p in sp.iter() {
+        let p_text = match p.payload.as_ref().and_then(|m| m.get("text")).and_then(|t| t.as_str()) {
+            Some(t) => t.to_string(),
+            None => {
+                continue;
+            }
+        };
+        // the section the chunk is from, so the model can refer to it by name
+        let p_text = match p.payload.as_ref().and_then(|m| m.get("heading_path")).and_then(|h| h.as_str()) {
+            Some(path) => format!("[{path}]\n{p_text}"),
+            None => p_text,
+        };
+        log::debug!("Received vector score={} and text={}", p.score, first_x_chars(&p_text, 256));
+        let p_id = match p.id {
+            PointId::Num(i) => i,
+            _ => 0,
+        };
+        if quarantined.contains(&p_id) {
+            trace::candidate(p_id, p.score, None, p.payload.as_ref(), &p_text, trace::Outcome::Quarantined);
+            continue;
+        }
+        match ranking.score(p.score, p.payload.as_ref()) {
+            Some(score) => ranked.push((p, p_id, score + helpful.get(&p_id).copied().unwrap_or_default(), p_text)),
+            None => trace::candidate(p_id, p.score, None, p.payload.as_ref(), &p_text, trace::Outcome::Excluded),
+        }
+    }
 
-    let p = PointsSearchParams {
-        vector: question_vector,
-        limit: 5,
+    let chunk_terms = match hybrid {
+        true =>
+            ranked
+                .iter()
+                .map(|(p, _, _, text)| keywords::chunk_counts(p.payload.as_ref(), text))
+                .collect::<Vec<HashMap<String, f32>>>(),
+        false => Vec::new(),
     };
+    let keyword_scores = ranking.keywords.bm25(&chunk_terms);
+    let keyword_ranks = keywords::ranks(&keyword_scores);
+    let vector_ranks = keywords::ranks(
+        &ranked
+            .iter()
+            .map(|(_, _, score, _)| *score)
+            .collect::<Vec<f32>>()
+    );
+
     let mut rag_content = Vec::new();
+    for (i, (p, p_id, score, p_text)) in ranked.into_iter().enumerate() {
+        let keyword_rank = match hybrid && keyword_scores[i] > 0.0 {
+            true => Some(keyword_ranks[i]),
+            false => None,
+        };
+        let exact_match = keyword_rank.is_some_and(|r| r < top_k) && ranking.keywords.has_exact_terms(&chunk_terms[i]);
+        let outcome = match score > threshold || exact_match {
+            true => trace::Outcome::Used,
+            false => trace::Outcome::BelowThreshold,
+        };
+        trace::candidate(p_id, p.score, Some(score), p.payload.as_ref(), &p_text, outcome);
+        if outcome == trace::Outcome::Used {
+            let order = match hybrid {
+                true => keywords::fuse(vector_ranks[i], keyword_rank),
+                false => score,
+            };
+            rag_content.push((p_id, score, p_text, order));
+        }
+    }
+    trace::threshold(threshold);
 
-    match search_points(&collection_name, &p).await {
-        Ok(sp) => {
-            for p in sp.iter() {
-                log::debug!(
-                    "Received vector score={} and text={}",
-                    p.score,
-                    first_x_chars(
-                        p.payload.as_ref().unwrap().get("text").unwrap().as_str().unwrap(),
-                        256
-                    )
-                );
-                let p_text = p.payload.as_ref().unwrap().get("text").unwrap().as_str().unwrap();
-                let p_id = match p.id {
-                    PointId::Num(i) => i,
-                    _ => 0,
-                };
-                if p.score > 0.75 {
-                    rag_content.push((p_id, p_text.to_string()));
-                }
-            }
+    rag_content.sort_by(|a, b| b.3.total_cmp(&a.3));
+    let mut found = Vec::new();
+    for (i, (id, score, text, _)) in rag_content.into_iter().enumerate() {
+        if i >= top_k {
+            trace::mark_current(id, trace::Outcome::OutOfTop);
+            continue;
         }
-        Err(e) => {
-            log::error!("Vector search returns error: {}", e);
+        match guards::take_payload(&text) {
+            Some(text) => found.push((id, score, text)),
+            None => trace::mark_current(id, trace::Outcome::OverPayloadBudget),
         }
     }
-    Ok(rag_content)
+    Ok(found)
 }
 
 pub async fn get_rag_content(
     text: &str,
     hypo_answer: &str,
-    cs: &ContentSettings
-) -> anyhow::Result<String> {
-    let raw_found_vec = search_collection(&text, &cs.collection_name).await?;
-
-    let mut raw_found_combined = raw_found_vec.into_iter().collect::<HashMap<u64, String>>();
+    cs: &ContentSettings,
+    lane: priority::Lane,
+    retrieval: &retrieval::Retrieval
+) -> Result<(String, Option<f32>)> {
+    let ranking = match lane.skips_optional_stages() {
+        true => Ranking::default(),
+        false => Ranking::for_question(text),
+    };
+    let ranking = ranking.with_retrieval(retrieval);
+    let top_k = ranking.top_k();
+    let reranker = match lane.skips_optional_stages() {
+        true => None,
+        false => rerank::Reranker::from_env(),
+    };
+    let threshold = ranking.threshold;
+    let ranking = match &reranker {
+        Some(r) => r.widen(ranking),
+        None => ranking,
+    };
 
-    // use the additional source material found to update the context for answer generation
-    let found_vec = search_collection(&hypo_answer, &cs.collection_name).await?;
+    let paraphrases = match paraphrase::count() {
+        0 => Vec::new(),
+        _ if lane.skips_optional_stages() || retrieval.strategy == retrieval::Strategy::Vector => Vec::new(),
+        n =>
+            paraphrase::paraphrases(text, n).await.unwrap_or_else(|e| {
+                log::error!("Searching without paraphrases: {}", e);
+                Vec::new()
+            }),
+    };
 
-    for (id, text) in found_vec {
-        raw_found_combined.insert(id, text);
-    }
+    // point ids are only unique within a collection, ordered so chunks of equal score always come
+    // in the same order; the score as searched goes against the thresholds and into the
+    // confidence, the score weighted by its search only ranks the chunks
+    let mut raw_found_combined = BTreeMap::<(String, u64), (f32, f32, String)>::new();
+    for collection_name in retrieval.collections(&cs.collection_name) {
+        let config = CollectionConfig::load(&collection_name);
+        // without a hypothetical answer the question is searched whatever its weight
+        let (question_factor, hypo_factor) = match hypo_answer == text {
+            true => (1.0, 0.0),
+            false => retrieval::search_factors(retrieval.hyde_weight(&collection_name)),
+        };
+        let question_vector = config.embed_one(text).await?;
+        if collection_name == cs.collection_name {
+            drift::observe(&question_vector);
+        }
+        if question_factor > 0.0 {
+            trace::stage(Some("question"));
+            let raw_found_vec = search_vector(question_vector, &collection_name, &config, &ranking).await;
+            trace::stage(None);
 
-    let found_combined = raw_found_combined
-        .into_iter()
-        .map(|(_, v)| v)
-        .collect::<Vec<String>>()
-        .join("\n");
+            for (id, score, text) in raw_found_vec? {
+                raw_found_combined.insert((collection_name.clone(), id), (score, score * question_factor, text));
+            }
+        }
 
-    Ok(found_combined)
-}
+        // a chunk found by several wordings is used once, with its best score
+        let paraphrase_vectors = match paraphrases.is_empty() || question_factor == 0.0 {
+            true => Vec::new(),
+            false =>
+                config.embed(&paraphrases).await.unwrap_or_else(|e| {
+                    log::error!("Cannot embed the paraphrases: {}", e);
+                    Vec::new()
+                }),
+        };
+        for (i, vector) in paraphrase_vectors.into_iter().enumerate() {
+            let stage = format!("paraphrase {}", i + 1);
+            trace::stage(Some(&stage));
+            let found_vec = search_vector(vector, &collection_name, &config, &ranking).await;
+            trace::stage(None);
 
-pub async fn is_relevant(current_q: &str, previous_q: &str) -> bool {
-    use nalgebra::DVector;
+            for (id, score, text) in found_vec? {
+                let weighted = score * question_factor;
+                match raw_found_combined.get_mut(&(collection_name.clone(), id)) {
+                    Some(found) => {
+                        trace::mark(&stage, id, trace::Outcome::Duplicate);
+                        found.0 = found.0.max(score);
+                        found.1 = found.1.max(weighted);
+                    }
+                    None => {
+                        raw_found_combined.insert((collection_name.clone(), id), (score, weighted, text));
+                    }
+                }
+            }
+        }
 
-    let mut openai = OpenAIFlows::new();
-    openai.set_retry_times(3);
+        // use the additional source material found to update the context for answer generation
+        if hypo_factor > 0.0 {
+            trace::stage(Some("hypothetical answer"));
+            let found_vec = search_collection(hypo_answer, &collection_name, &ranking).await;
+            trace::stage(None);
 
-    let embedding_input = EmbeddingsInput::Vec(vec![current_q.to_string(), previous_q.to_string()]);
+            for (id, score, text) in found_vec? {
+                let weighted = score * hypo_factor;
+                match raw_found_combined.get_mut(&(collection_name.clone(), id)) {
+                    Some(found) => {
+                        trace::mark("hypothetical answer", id, trace::Outcome::Duplicate);
+                        found.0 = found.0.max(score);
+                        found.1 = found.1.max(weighted);
+                    }
+                    None => {
+                        raw_found_combined.insert((collection_name.clone(), id), (score, weighted, text));
+                    }
+                }
+            }
+        }
+    }
 
-    let (current_q_vector, previous_q_vector) = match
-        openai.create_embeddings(embedding_input).await
-    {
-        Ok(r) if r.len() >= 2 =>
-            r
-                .into_iter()
-                .map(|v|
-                    v
-                        .iter()
-                        .map(|&n| n as f32)
-                        .collect::<Vec<f32>>()
-                )
-                .take(2)
-                .collect_tuple()
-                .unwrap_or((Vec::<f32>::new(), Vec::<f32>::new())),
-        _ => {
-            log::error!("LLM returned an error");
-            return false;
+    if let Some(reranker) = &reranker {
+        let (keys, passages): (Vec<(String, u64)>, Vec<String>) = raw_found_combined
+            .iter()
+            .map(|(k, (_, _, text))| (k.clone(), text.clone()))
+            .unzip();
+        let (kept, dropped) = match reranker.rerank(text, &passages, top_k).await {
+            Ok(kept) => (kept, trace::Outcome::RerankedOut),
+            Err(e) => {
+                // the candidates were searched with a lower threshold, keep the ones passing the usual one
+                log::error!("Answering without reranking: {}", e);
+                let mut kept = (0..keys.len())
+                    .filter(|i| {
+                        let (collection, _) = &keys[*i];
+                        let threshold = threshold.unwrap_or(CollectionConfig::load(collection).score_threshold);
+                        raw_found_combined[&keys[*i]].0 > threshold
+                    })
+                    .collect::<Vec<usize>>();
+                kept.sort_by(|a, b| raw_found_combined[&keys[*b]].1.total_cmp(&raw_found_combined[&keys[*a]].1));
+                kept.truncate(top_k);
+                (kept, trace::Outcome::BelowThreshold)
+            }
+        };
+        for (i, key) in keys.iter().enumerate() {
+            if !kept.contains(&i) {
+                raw_found_combined.remove(key);
+                trace::mark_used(key.1, dropped);
+            }
         }
+    }
+
+    let best_score = raw_found_combined
+        .values()
+        .map(|(score, _, _)| *score)
+        .reduce(f32::max);
+    let mut found = raw_found_combined.into_iter().collect::<Vec<((String, u64), (f32, f32, String))>>();
+    // the best chunks make it into a capped context, whole
+    found.sort_by(|a, b| b.1.1.total_cmp(&a.1.1));
+    let duplicates = dedup::near_duplicates(
+        &found
+            .iter()
+            .map(|(_, (_, _, chunk))| chunk.as_str())
+            .collect::<Vec<&str>>()
+    );
+    for i in duplicates.iter().rev() {
+        let ((_, id), _) = found.remove(*i);
+        trace::mark_used(id, trace::Outcome::NearDuplicate);
+    }
+    // compressed first, so more chunks fit the budget
+    let chunks = found
+        .iter()
+        .map(|(_, (_, _, chunk))| chunk.clone())
+        .collect::<Vec<String>>();
+    let chunks = match lane.skips_optional_stages() {
+        true => chunks,
+        false => compression::compress(text, chunks).await,
     };
+    let mut budget = retrieval.max_context_chars().unwrap_or(usize::MAX);
+    let mut found_vec = Vec::with_capacity(found.len());
+    for (((_, id), _), chunk) in found.into_iter().zip(chunks) {
+        if chunk.is_empty() {
+            trace::mark_used(id, trace::Outcome::NothingRelevant);
+            continue;
+        }
+        let chars = chunk.chars().count();
+        match chars <= budget {
+            true => {
+                budget -= chars;
+                found_vec.push(chunk);
+            }
+            false => trace::mark_used(id, trace::Outcome::OverContextLimit),
+        }
+    }
+    let found_combined = found_vec.join("\n");
+
+    Ok((found_combined, best_score))
+}
+
+pub async fn is_relevant(current_q: &str, previous_q: &str, threshold: f32) -> Result<bool> {
+    use nalgebra::DVector;
+
+    // questions are compared like the memory compares them
+    let embedding_input = vec![current_q.to_string(), previous_q.to_string()];
+    let vectors = CollectionConfig::load(memory::MEMORY_CONFIG).embed(&embedding_input).await?;
+    let (current_q_vector, previous_q_vector) = vectors
+        .into_iter()
+        .take(2)
+        .collect_tuple()
+        .ok_or(Error::Embedding("expected 2 embeddings".to_string()))?;
 
     let q1 = DVector::from_vec(current_q_vector);
     let q2 = DVector::from_vec(previous_q_vector);
@@ -345,78 +1871,77 @@ pub async fn is_relevant(current_q: &str, previous_q: &str) -> bool {
     let tail = previous_q.chars().take(100).collect::<String>();
     log::debug!("similarity: {score} between {head} and {tail}");
 
-    score > 0.75
+    Ok(score > threshold)
 }
 
-pub async fn last_3_relevant_qa_pairs(question: &str, chat_id: &str) -> String {
-    let mut found_vec = search_collection(&question, "ephemeral").await.unwrap_or(Vec::new());
-
-    found_vec.sort_by(|a, b| a.0.cmp(&b.0));
-
-    found_vec
-        .into_iter()
-        .take(3)
-        .map(|(_, v)| v)
-        .collect::<Vec<String>>()
-        .join("\n")
-}
-
-pub async fn create_ephemeral_collection() {
-    let collection_name = "ephemeral";
-    let vector_size: u64 = 1536;
-
-    let p = CollectionCreateParams { vector_size: vector_size };
-    if let Err(e) = create_collection(collection_name, &p).await {
-        log::error!("Cannot create collection named: {} with error: {}", collection_name, e);
-        return;
+/// The chat history relevant to the question. When the memory cannot be searched, the latest QA
+/// pairs of the chat mirrored in the KV store are used instead.
+async fn recall(question: &str, chat_id: &str) -> String {
+    match last_3_relevant_qa_pairs(chat_id, question).await {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            log::error!("Answering with the mirrored chat history: {}", e);
+            let history = history::load(chat_id);
+            history[history.len().saturating_sub(3)..].join("\n")
+        }
     }
 }
 
-pub async fn reset_ephemeral_collection() {
-    let collection_name = "ephemeral";
-    let vector_size: u64 = 1536;
-
-    _ = delete_collection(collection_name).await;
-
-    let p = CollectionCreateParams { vector_size: vector_size };
-    if let Err(e) = create_collection(collection_name, &p).await {
-        log::error!("Cannot create collection named: {} with error: {}", collection_name, e);
-        return;
-    }
+pub async fn last_3_relevant_qa_pairs(chat_id: &str, question: &str) -> Result<String> {
+    Ok(
+        memory
+            ::recall(chat_id, question, 3).await?
+            .into_iter()
+            .map(|p| p.text)
+            .collect::<Vec<String>>()
+            .join("\n")
+    )
 }
 
-pub async fn upsert_text(text_to_upsert: &str) {
-    let mut points = Vec::<Point>::new();
-    let openai = OpenAIFlows::new();
-    let collection_name = "ephemeral";
-    let id = match collection_info(collection_name).await {
-        Ok(ci) => { ci.points_count + 1 }
-        Err(e) => {
-            log::error!("Cannot get collection stat {}", e);
-            return;
-        }
-    };
+/// A QA pair of the conversation memory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QaPair {
+    /// Empty for pairs saved before chats were recorded.
+    pub chat_id: String,
+    pub question: String,
+    /// The question and the answer, as given to the LLM when recalled.
+    pub text: String,
+    /// When the pair was stored, 0 for pairs stored before it was recorded.
+    pub at: u64,
+    /// The id the answer was sent with, empty for pairs stored before answers had ids.
+    pub message_id: String,
+    /// How the user rated the answer with `?action=feedback`.
+    pub rating: Option<feedback::Rating>,
+    /// The knowledge base collection the answer was given from, empty for pairs stored before it
+    /// was recorded.
+    pub collection: String,
+}
 
-    let input = EmbeddingsInput::String(text_to_upsert.to_string());
-    match openai.create_embeddings(input).await {
-        Ok(r) => {
-            let p = Point {
-                id: PointId::Num(id),
-                vector: r[0]
-                    .iter()
-                    .map(|n| *n as f32)
-                    .collect(),
-                payload: json!({"text": text_to_upsert}).as_object().map(|m| m.to_owned()),
-            };
-            points.push(p);
-        }
-        Err(e) => {
-            log::error!("OpenAI returned an error: {}", e);
+impl QaPair {
+    /// The pair with the question and the text encrypted for storage, see [`encryption::seal`].
+    pub fn sealed(&self) -> Self {
+        Self {
+            chat_id: self.chat_id.clone(),
+            question: encryption::seal(&self.question),
+            text: encryption::seal(&self.text),
+            at: self.at,
+            message_id: self.message_id.clone(),
+            rating: self.rating,
+            collection: self.collection.clone(),
         }
     }
 
-    if let Err(e) = upsert_points(collection_name, points).await {
-        log::error!("Cannot upsert into database! {}", e);
-        return;
+    /// The pair as read from storage, `None` when it cannot be decrypted.
+    pub fn opened(self) -> Option<Self> {
+        Some(Self {
+            question: encryption::open(&self.question)?,
+            text: encryption::open(&self.text)?,
+            chat_id: self.chat_id,
+            at: self.at,
+            message_id: self.message_id,
+            rating: self.rating,
+            collection: self.collection,
+        })
     }
 }
@@ -0,0 +1,366 @@
+use std::{ sync::Mutex, time::Instant };
+
+use rand::{ rngs::StdRng, seq::SliceRandom, Rng, SeedableRng };
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{ json, Map, Value };
+use vector_store_flows::{ PointId, PointsSearchParams, ScoredPoint };
+
+use crate::{
+    create_hypothetical_answer,
+    error::{ Error, Result },
+    get_rag_content,
+    guards,
+    priority::Lane,
+    prompt_template::{ self, Part, Vars },
+    retrieval::Retrieval,
+    ContentSettings,
+};
+
+const STAGES: [&str; 3] = ["retrieve", "prompt", "chat"];
+const VECTOR_SIZE: usize = 256;
+/// The collection the stubbed vector store answers for, its settings are the defaults.
+const COLLECTION: &str = "loadtest";
+
+pub const MAX_CONVERSATIONS: usize = 100;
+pub const MAX_TURNS: usize = 10;
+pub const MAX_CONCURRENCY: usize = 32;
+pub const MAX_CORPUS_CHUNKS: usize = 5000;
+pub const MAX_LATENCY_MS: f64 = 60_000.0;
+
+const TOPICS: &[&str] = &[
+    "pod", "deployment", "service", "ingress", "configmap", "secret", "namespace", "node",
+    "statefulset", "daemonset", "job", "cronjob", "volume", "persistentvolumeclaim", "rbac",
+    "serviceaccount", "helm", "kubectl", "scheduler", "etcd", "probe", "hpa", "network policy",
+];
+const QUESTIONS: &[&str] = &[
+    "What is a {}?",
+    "{}",
+    "How do I create a {} with kubectl?",
+    "Give me a manifest for a {} that runs nginx",
+    "Why would my {} be stuck in pending, and how can I find out what is wrong?",
+    "What changed for {} in Kubernetes 1.29?",
+    "Explain the difference between a {} and a deployment in detail, with the trade-offs of each",
+];
+
+/// Settings of a load test run, read from the JSON body of `?action=loadtest`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LoadTestOptions {
+    /// At most [`MAX_CONVERSATIONS`].
+    pub conversations: usize,
+    /// At most [`MAX_TURNS`].
+    pub turns: usize,
+    /// How many conversations the projection assumes are served at once, at most [`MAX_CONCURRENCY`].
+    pub concurrency: usize,
+    /// Chunks in the stubbed knowledge base, at most [`MAX_CORPUS_CHUNKS`].
+    pub corpus_chunks: usize,
+    /// Simulated latencies of the stubbed upstream calls, at most [`MAX_LATENCY_MS`].
+    pub embed_latency_ms: f64,
+    pub search_latency_ms: f64,
+    pub llm_latency_ms: f64,
+    pub seed: u64,
+}
+
+impl Default for LoadTestOptions {
+    fn default() -> Self {
+        Self {
+            conversations: 20,
+            turns: 3,
+            concurrency: 4,
+            corpus_chunks: 1000,
+            embed_latency_ms: 150.0,
+            search_latency_ms: 40.0,
+            llm_latency_ms: 2500.0,
+            seed: 42,
+        }
+    }
+}
+
+impl LoadTestOptions {
+    fn validate(&self) -> Result<()> {
+        let counts = [
+            ("conversations", self.conversations, MAX_CONVERSATIONS),
+            ("turns", self.turns, MAX_TURNS),
+            ("concurrency", self.concurrency, MAX_CONCURRENCY),
+            ("corpus_chunks", self.corpus_chunks, MAX_CORPUS_CHUNKS),
+        ];
+        for (name, value, max) in counts {
+            if !(1..=max).contains(&value) {
+                return Err(Error::InvalidInput(format!("`{name}` must be between 1 and {max}")));
+            }
+        }
+        let latencies = [
+            ("embed_latency_ms", self.embed_latency_ms),
+            ("search_latency_ms", self.search_latency_ms),
+            ("llm_latency_ms", self.llm_latency_ms),
+        ];
+        for (name, value) in latencies {
+            if !(0.0..=MAX_LATENCY_MS).contains(&value) {
+                return Err(Error::InvalidInput(format!("`{name}` must be between 0 and {MAX_LATENCY_MS}")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A vector store kept in memory, searched by brute force.
+struct MockVectorStore {
+    points: Vec<(u64, Vec<f32>, Map<String, Value>)>,
+}
+
+impl MockVectorStore {
+    fn search(&self, vector: &[f32], limit: usize) -> Vec<ScoredPoint> {
+        let mut scored = self.points
+            .iter()
+            .map(|(id, v, payload)| (*id, dot(vector, v), payload))
+            .collect::<Vec<(u64, f32, &Map<String, Value>)>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(id, score, payload)| ScoredPoint {
+                id: PointId::Num(id),
+                vector: None,
+                payload: Some(payload.clone()),
+                score,
+            })
+            .collect()
+    }
+
+    fn upsert(&mut self, vector: Vec<f32>, text: String) {
+        let id = (self.points.len() as u64) + 1;
+        let mut payload = Map::new();
+        payload.insert("text".to_string(), Value::String(text));
+        self.points.push((id, vector, payload));
+    }
+}
+
+/// The upstream calls answered in memory while a load test runs, with the latency they would
+/// have taken.
+struct Stub {
+    kb: MockVectorStore,
+    options: LoadTestOptions,
+    /// The latency of the stubbed calls since the last lap.
+    simulated_ms: f64,
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+
+fn with_stub<T>(f: impl FnOnce(&mut Stub) -> T) -> Option<T> {
+    STUB.lock().ok()?.as_mut().map(f)
+}
+
+/// The vectors of the texts while a load test runs, in place of the embedding provider.
+pub fn embed(texts: &[String]) -> Option<Vec<Vec<f32>>> {
+    with_stub(|s| {
+        s.simulated_ms += s.options.embed_latency_ms;
+        texts
+            .iter()
+            .map(|t| mock_embed(t))
+            .collect()
+    })
+}
+
+/// The points found while a load test runs, in place of the vector store.
+pub fn search(p: &PointsSearchParams) -> Option<Vec<ScoredPoint>> {
+    with_stub(|s| {
+        s.simulated_ms += s.options.search_latency_ms;
+        s.kb.search(&p.vector, p.limit as usize)
+    })
+}
+
+/// Whether a model call is stubbed by a running load test. It counts its latency and fails, so the
+/// optional model stages (hypothetical answer, paraphrases, reranking, compression) take the path
+/// they take when the model is down.
+pub fn stubs_model_call() -> bool {
+    with_stub(|s| {
+        s.simulated_ms += s.options.llm_latency_ms;
+    }).is_some()
+}
+
+/// A stand-in for the embedding providers: hashes the words of the text into a normalized
+/// bag-of-words vector, so similar texts still get similar vectors.
+fn mock_embed(text: &str) -> Vec<f32> {
+    let mut v = vec![0f32; VECTOR_SIZE];
+    for word in text.split_whitespace() {
+        let h = word
+            .to_lowercase()
+            .bytes()
+            .fold(2166136261u32, |h, b| (h ^ (b as u32)).wrapping_mul(16777619));
+        v[(h as usize) % VECTOR_SIZE] += 1.0;
+    }
+    let norm = dot(&v, &v).sqrt().max(f32::EPSILON);
+    v.iter().map(|x| x / norm).collect()
+}
+
+/// A stand-in for the chat model that answers with a few sentences about the prompt.
+fn mock_chat(prompt: &str) -> String {
+    let words = prompt.split_whitespace().take(60).collect::<Vec<&str>>().join(" ");
+    format!("Here is a concise answer based on the context. {words}")
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x * y)
+        .sum()
+}
+
+/// Fires synthetic conversations at the answer pipeline with the embedding, vector store and LLM
+/// calls stubbed, and reports stage latencies and projected throughput.
+///
+/// Retrieval runs through [`get_rag_content`] and the prompt through the prompt templates, as for a
+/// chat message, against an in-memory knowledge base searched with no score threshold. Stubbed
+/// calls add their configured latency to the stage instead of sleeping. The instance runs one
+/// request at a time, so throughput at `concurrency` is projected from the per-conversation times
+/// rather than measured.
+pub async fn run(options: &LoadTestOptions) -> Result<Value> {
+    options.validate()?;
+    let started = Instant::now();
+    let mut rng = StdRng::seed_from_u64(options.seed);
+
+    let mut kb = MockVectorStore { points: Vec::new() };
+    for _ in 0..options.corpus_chunks {
+        let text = (0..120)
+            .map(|_| *TOPICS.choose(&mut rng).unwrap_or(&"pod"))
+            .collect::<Vec<&str>>()
+            .join(" ");
+        kb.upsert(mock_embed(&text), text);
+    }
+    if let Ok(mut stub) = STUB.lock() {
+        *stub = Some(Stub { kb, options: options.clone(), simulated_ms: 0.0 });
+    }
+
+    let cs = ContentSettings::new(
+        String::new(),
+        String::new(),
+        String::new(),
+        String::new(),
+        "No answer".to_string(),
+        COLLECTION.to_string()
+    );
+    let retrieval = Retrieval {
+        threshold: Some(0.0),
+        ..Default::default()
+    };
+
+    let mut samples: Vec<Vec<f64>> = vec![Vec::new(); STAGES.len()];
+    let mut conversation_ms = Vec::new();
+    let mut cpu_ms = 0.0;
+    let mut failures = 0;
+
+    for _ in 0..options.conversations {
+        let mut total = 0.0;
+        for _ in 0..options.turns {
+            let template = QUESTIONS.choose(&mut rng).unwrap_or(&"{}");
+            let topic = TOPICS[rng.gen_range(0..TOPICS.len())];
+            let question = template.replace("{}", topic);
+            guards::reset_payload_budget();
+
+            let mut timings = [0f64; STAGES.len()];
+            let mut clock = Instant::now();
+            let mut lap = |stage: usize| {
+                let elapsed = clock.elapsed().as_secs_f64() * 1000.0;
+                let simulated = with_stub(|s| std::mem::take(&mut s.simulated_ms)).unwrap_or_default();
+                timings[stage] = elapsed + simulated;
+                clock = Instant::now();
+                elapsed
+            };
+
+            let hyde = retrieval
+                .collections(COLLECTION)
+                .iter()
+                .any(|c| retrieval.hyde_weight(c) > 0.0);
+            let hypo_answer = match hyde {
+                true => create_hypothetical_answer(&question).await.unwrap_or_else(|_| question.clone()),
+                false => question.clone(),
+            };
+            let content = match get_rag_content(&question, &hypo_answer, &cs, Lane::Normal, &retrieval).await {
+                Ok((content, _)) => content,
+                Err(e) => {
+                    log::warn!("Load test retrieval failed: {}", e);
+                    failures += 1;
+                    String::new()
+                }
+            };
+            let mut cpu = lap(0);
+
+            let context = format!("Given the context: `{content}`");
+            let vars = Vars {
+                system_prompt: "",
+                post_prompt: "",
+                history: "",
+                context: &context,
+                question: &question,
+                collection: COLLECTION,
+            };
+            let user_prompt = prompt_template::render(Part::User, &vars);
+            cpu += lap(1);
+
+            let answer = mock_chat(&user_prompt);
+            with_stub(|s| {
+                s.simulated_ms += s.options.llm_latency_ms;
+            });
+            cpu += lap(2);
+            if answer.is_empty() {
+                failures += 1;
+            }
+
+            for (stage, ms) in timings.iter().enumerate() {
+                samples[stage].push(*ms);
+            }
+            total += timings.iter().sum::<f64>();
+            cpu_ms += cpu;
+        }
+        conversation_ms.push(total);
+    }
+    if let Ok(mut stub) = STUB.lock() {
+        *stub = None;
+    }
+
+    // conversations go to the least loaded lane; CPU work cannot overlap, so it bounds the run
+    let mut lanes = vec![0f64; options.concurrency];
+    for ms in &conversation_ms {
+        if let Some(lane) = lanes.iter_mut().min_by(|a, b| a.total_cmp(b)) {
+            *lane += ms;
+        }
+    }
+    let makespan_ms = lanes.iter().cloned().fold(cpu_ms, f64::max);
+    let turns = options.conversations * options.turns;
+
+    let stages = STAGES.iter()
+        .zip(samples.iter_mut())
+        .map(|(name, ms)| (name.to_string(), summarize(ms)))
+        .collect::<Map<String, Value>>();
+
+    Ok(json!({
+        "conversations": options.conversations,
+        "turns": turns,
+        "concurrency": options.concurrency,
+        "failures": failures,
+        "elapsed_ms": started.elapsed().as_secs_f64() * 1000.0,
+        "cpu_ms": cpu_ms,
+        "projected_makespan_ms": makespan_ms,
+        "projected_turns_per_sec": match makespan_ms > 0.0 {
+            true => (turns as f64) / makespan_ms * 1000.0,
+            false => 0.0,
+        },
+        "stages": stages,
+    }))
+}
+
+fn summarize(ms: &mut [f64]) -> Value {
+    if ms.is_empty() {
+        return json!({});
+    }
+    ms.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| ms[(((ms.len() - 1) as f64) * p).round() as usize];
+
+    json!({
+        "mean_ms": ms.iter().sum::<f64>() / (ms.len() as f64),
+        "p50_ms": percentile(0.5),
+        "p95_ms": percentile(0.95),
+        "max_ms": ms[ms.len() - 1],
+    })
+}
@@ -0,0 +1,40 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use store_flows::get;
+
+use crate::guards;
+
+const MAINTENANCE_KEY: &str = "maintenance";
+
+/// The maintenance switch, stored in the KV store. While enabled, chat messages get `message`
+/// (or `maintenance_mesg`) back without any model or vector store calls.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Maintenance {
+    pub enabled: bool,
+    pub message: Option<String>,
+}
+
+impl Maintenance {
+    pub fn load() -> Self {
+        get(MAINTENANCE_KEY)
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        guards::set_capped(MAINTENANCE_KEY, serde_json::to_value(self).unwrap_or_default(), None);
+    }
+
+    pub fn message(&self) -> String {
+        match &self.message {
+            Some(m) if !m.is_empty() => m.clone(),
+            _ =>
+                std::env
+                    ::var("maintenance_mesg")
+                    .unwrap_or(
+                        "The assistant is down for maintenance. Please try again later.".to_string()
+                    ),
+        }
+    }
+}
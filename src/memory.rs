@@ -0,0 +1,243 @@
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use store_flows::{ del, get };
+
+use crate::{
+    chunking::{ self, Chunker },
+    collection_config::CollectionConfig,
+    drift::cosine,
+    embedding_cache::{ decode, encode },
+    error::Result,
+    feedback::Rating,
+    guards,
+    ingest,
+    retention,
+    settings,
+    QaPair,
+};
+
+/// The embedding settings and score threshold of the memory are those of this collection name,
+/// the shared collection the memory used to live in.
+pub const MEMORY_CONFIG: &str = "ephemeral";
+
+/// How many QA pair chunks a chat keeps, from `memory_size`.
+fn memory_size() -> u64 {
+    settings::optional("memory_size")
+        .filter(|n| *n > 0)
+        .unwrap_or(20)
+}
+
+/// A chunk of a QA pair with the embedding it is recalled by.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Entry {
+    pair: QaPair,
+    /// Base64 of the little-endian `f32`s, a fraction of the size of a JSON array.
+    vector: String,
+}
+
+/// The number of chunks a chat has stored, the slots being used round robin.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Index {
+    stored: u64,
+}
+
+// Every chat has its own keys, so no chat can recall what was said in another. The KV store
+// cannot list keys, so the slots are found through the index.
+fn index_key(chat_id: &str) -> String {
+    format!("memory:{chat_id}")
+}
+
+fn slot_key(chat_id: &str, slot: u64) -> String {
+    format!("memory:{chat_id}:{slot}")
+}
+
+fn load_index(chat_id: &str) -> Index {
+    get(&index_key(chat_id))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Stores a QA pair in the memory of its chat, the oldest chunks making room once the chat has
+/// [`memory_size`]. Long answers are split rather than cut, later chunks repeat the question.
+pub async fn save(pair: &QaPair) -> Result<()> {
+    let texts = chunking
+        ::memory_chunker()
+        .chunk(&pair.text)
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| match i {
+            0 => chunk,
+            _ => format!("{}\n {}", pair.question, chunk),
+        })
+        .collect::<Vec<String>>();
+    let vectors = ingest::embed_texts(&CollectionConfig::load(MEMORY_CONFIG), &texts).await?;
+
+    let mut index = load_index(&pair.chat_id);
+    for (text, vector) in texts.into_iter().zip(vectors) {
+        store(&mut index, &QaPair { text, ..pair.clone() }, &vector)?;
+    }
+    guards::set_capped(&index_key(&pair.chat_id), json!(index), retention::expire(retention::Data::Memory));
+    Ok(())
+}
+
+/// Writes a chunk to the next slot of its chat, the index being saved by the caller.
+fn store(index: &mut Index, pair: &QaPair, vector: &[f32]) -> Result<()> {
+    let entry = Entry {
+        pair: pair.sealed(),
+        vector: encode(vector),
+    };
+    let slot = index.stored % memory_size();
+    guards::set_capped(
+        &slot_key(&pair.chat_id, slot),
+        serde_json::to_value(entry)?,
+        retention::expire(retention::Data::Memory)
+    );
+    index.stored += 1;
+    Ok(())
+}
+
+/// The stored chunks of the chat with their vectors, oldest first, without the ones past the
+/// memory retention period.
+pub fn load(chat_id: &str) -> Vec<(QaPair, Vec<f32>)> {
+    let index = load_index(chat_id);
+    let size = memory_size();
+    let cutoff = retention::memory_cutoff().unwrap_or_default();
+
+    (index.stored.saturating_sub(size)..index.stored)
+        .filter_map(|n| {
+            let entry = serde_json::from_value::<Entry>(get(&slot_key(chat_id, n % size))?).ok()?;
+            let pair = entry.pair.opened()?;
+            let vector = decode(&entry.vector)?;
+            (pair.at == 0 || pair.at >= cutoff).then_some((pair, vector))
+        })
+        .collect()
+}
+
+/// Whether the chat has stored more chunks than it keeps, the oldest having been overwritten.
+pub fn overwritten(chat_id: &str) -> bool {
+    load_index(chat_id).stored > memory_size()
+}
+
+/// Rates the chunks of the answer `message_id` of the chat, returning them oldest first. Empty
+/// when the answer is not in the memory.
+pub fn rate(chat_id: &str, message_id: &str, rating: Rating) -> Vec<QaPair> {
+    let index = load_index(chat_id);
+    let size = memory_size();
+    let mut rated = Vec::new();
+    for n in index.stored.saturating_sub(size)..index.stored {
+        let key = slot_key(chat_id, n % size);
+        let Some(mut entry) = get(&key).and_then(|v| serde_json::from_value::<Entry>(v).ok()) else {
+            continue;
+        };
+        if entry.pair.message_id != message_id {
+            continue;
+        }
+        entry.pair.rating = Some(rating);
+        guards::set_capped(&key, serde_json::to_value(&entry).unwrap_or_default(), retention::expire(retention::Data::Memory));
+        rated.extend(entry.pair.opened());
+    }
+    rated
+}
+
+/// The chunks of the chat most similar to the question, above the memory threshold, with their
+/// similarity, best first. Chunks of answers rated down are left out.
+pub fn nearest(chat_id: &str, vector: &[f32], threshold: f32, n: usize) -> Vec<(QaPair, f32)> {
+    let mut scored = load(chat_id)
+        .into_iter()
+        // answers the user rated down are not repeated
+        .filter(|(pair, _)| pair.rating != Some(Rating::Down))
+        .map(|(pair, v)| {
+            let score = cosine(vector, &v);
+            (pair, score)
+        })
+        .filter(|(_, score)| *score > threshold)
+        .collect::<Vec<(QaPair, f32)>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(n);
+    scored
+}
+
+/// The `n` chunks of the chat most relevant to the question, in the order they were said.
+pub async fn recall(chat_id: &str, question: &str, n: usize) -> Result<Vec<QaPair>> {
+    let config = CollectionConfig::load(MEMORY_CONFIG);
+    let vector = config.embed_one(question).await?;
+
+    let mut found = nearest(chat_id, &vector, config.score_threshold, n)
+        .into_iter()
+        .map(|(pair, _)| pair)
+        .collect::<Vec<QaPair>>();
+    found.sort_by_key(|p| p.at);
+    Ok(found)
+}
+
+/// Moves the memory of the chat `from` into the chat `into`, in the order the chunks were said,
+/// the latest [`memory_size`] being kept. Chunks with the same question and text as one of `into`
+/// are dropped. Returns the chunks moved and the duplicates, with `dry_run` only counting them.
+pub fn merge(from: &str, into: &str, dry_run: bool) -> Result<(usize, usize)> {
+    let mut merged = load(into);
+    let mut moved = 0;
+    let mut duplicates = 0;
+    for (pair, vector) in load(from) {
+        match merged.iter().any(|(p, _)| p.question == pair.question && p.text == pair.text) {
+            true => {
+                duplicates += 1;
+            }
+            false => {
+                moved += 1;
+                merged.push((QaPair { chat_id: into.to_string(), ..pair }, vector));
+            }
+        }
+    }
+    if dry_run {
+        return Ok((moved, duplicates));
+    }
+
+    merged.sort_by_key(|(p, _)| p.at);
+    clear(into);
+    let mut index = Index::default();
+    for (pair, vector) in &merged {
+        store(&mut index, pair, vector)?;
+    }
+    guards::set_capped(&index_key(into), json!(index), retention::expire(retention::Data::Memory));
+    clear(from);
+    Ok((moved, duplicates))
+}
+
+/// Deletes the chunks of the chat stored before `cutoff`, returning how many, with `dry_run` only
+/// counting them. Run by the retention sweep.
+pub fn apply_retention(chat_id: &str, cutoff: u64, dry_run: bool) -> usize {
+    let index = load_index(chat_id);
+    let slots = (0..index.stored.min(memory_size()))
+        .filter_map(|slot| {
+            let entry = serde_json::from_value::<Entry>(get(&slot_key(chat_id, slot))?).ok()?;
+            Some((slot, entry.pair.at))
+        })
+        .collect::<Vec<(u64, u64)>>();
+    let old = slots
+        .iter()
+        .filter(|(_, at)| *at < cutoff)
+        .map(|(slot, _)| *slot)
+        .collect::<Vec<u64>>();
+    if dry_run || old.is_empty() {
+        return old.len();
+    }
+    if old.len() == slots.len() {
+        clear(chat_id);
+    } else {
+        for slot in &old {
+            del(&slot_key(chat_id, *slot));
+        }
+    }
+    old.len()
+}
+
+/// Forgets the memory of the chat, for `/new`.
+pub fn clear(chat_id: &str) {
+    let index = load_index(chat_id);
+    for slot in 0..index.stored.min(memory_size()) {
+        del(&slot_key(chat_id, slot));
+    }
+    del(&index_key(chat_id));
+}
@@ -0,0 +1,71 @@
+/// Phrases of an answer that declines the question, matched case-insensitively.
+const REFUSALS: &[&str] = &[
+    "i'm sorry, but",
+    "i am sorry, but",
+    "i can't help with",
+    "i cannot help with",
+    "i can't answer",
+    "i cannot answer",
+    "i'm unable to",
+    "i am unable to",
+    "as an ai",
+    "i don't have enough information",
+    "i do not have enough information",
+];
+
+/// Phrases of a question trying to plant instructions or facts for later retrieval.
+const INJECTIONS: &[&str] = &[
+    "ignore previous instructions",
+    "ignore all previous",
+    "ignore the above",
+    "disregard previous",
+    "system prompt",
+    "remember that",
+    "from now on",
+];
+
+/// Extra phrases from a comma separated setting, lowercased.
+fn phrases(setting: &str) -> Vec<String> {
+    std::env
+        ::var(setting)
+        .unwrap_or_default()
+        .split(',')
+        .map(|p| p.trim().to_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+fn contains_any(text: &str, builtin: &[&str], setting: &str) -> bool {
+    let text = text.to_lowercase();
+    builtin.iter().any(|p| text.contains(p)) || phrases(setting).iter().any(|p| text.contains(p.as_str()))
+}
+
+/// Why a QA pair must stay out of the shared conversation memory, `None` when it can be stored.
+/// Set `memory_guard` to `off` to store every pair.
+pub fn rejection(question: &str, answer: &str) -> Option<&'static str> {
+    if std::env::var("memory_guard").unwrap_or_default() == "off" {
+        return None;
+    }
+
+    let error_mesg = std::env::var("error_mesg").unwrap_or("".to_string());
+    let no_answer_mesg = std::env::var("no_answer_mesg").unwrap_or("No answer".to_string());
+    let answer = answer.trim();
+
+    if answer.is_empty() {
+        return Some("empty answer");
+    }
+    if question.trim_start().starts_with('/') {
+        return Some("command traffic");
+    }
+    if (!error_mesg.is_empty() && answer == error_mesg.trim()) || answer == no_answer_mesg.trim() {
+        return Some("error answer");
+    }
+    // refusals are short, a long answer that apologizes once is still an answer
+    if answer.chars().count() < 400 && contains_any(answer, REFUSALS, "memory_refusal_phrases") {
+        return Some("refused answer");
+    }
+    if contains_any(question, INJECTIONS, "memory_blocked_phrases") {
+        return Some("instructions in the question");
+    }
+    None
+}
@@ -0,0 +1,33 @@
+use serde_json::{ json, Value };
+
+use crate::{ audit, error::{ Error, Result }, history, memory };
+
+/// Merges the conversation `from` into `into`, for when a platform adapter gave one user two
+/// conversation ids: the memory, the history mirror and the audit log entries of `from` move to
+/// `into`, and `from` is left empty. With `dry_run` only the counts are reported.
+pub fn conversations(from: &str, into: &str, dry_run: bool) -> Result<Value> {
+    if from.is_empty() || into.is_empty() {
+        return Err(Error::InvalidInput("`from` and `into` are required".to_string()));
+    }
+    if from == into {
+        return Err(Error::InvalidInput("`from` and `into` are the same conversation".to_string()));
+    }
+
+    let (memory_moved, memory_duplicates) = memory::merge(from, into, dry_run)?;
+    let (history_moved, history_duplicates) = history::merge(from, into, dry_run);
+    let audit_moved = audit::reassign(from, into, dry_run);
+    if !dry_run {
+        log::info!("Merged conversation {} into {}", from, into);
+    }
+
+    Ok(
+        json!({
+            "from": from,
+            "into": into,
+            "dry_run": dry_run,
+            "memory": { "moved": memory_moved, "duplicates": memory_duplicates },
+            "history": { "moved": history_moved, "duplicates": history_duplicates },
+            "audit_entries": audit_moved,
+        })
+    )
+}
@@ -0,0 +1,208 @@
+use crate::{ formatting::Profile, settings };
+
+/// Room kept in every part for its `i/n ` indicator.
+const INDICATOR_CHARS: usize = 8;
+/// Parts are never cut shorter than this, whatever the limit.
+const MIN_PART_CHARS: usize = 40;
+/// Marks where one part ends and the next starts in a text reply.
+pub const SEPARATOR: char = '\u{1e}';
+
+/// The longest message of the channel: the `x-max-message-chars` header, else 4096 characters
+/// for Telegram and `sms_segment_chars` (default 160) for SMS. Other channels take answers of
+/// any length.
+pub fn limit(profile: Profile, headers: &[(String, String)]) -> Option<usize> {
+    let header = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("x-max-message-chars"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0);
+    if header.is_some() {
+        return header;
+    }
+    match profile {
+        Profile::Telegram => Some(4096),
+        Profile::Sms =>
+            Some(
+                settings::setting("sms_segment_chars", 160)
+            ),
+        _ => None,
+    }
+}
+
+/// Splits the answer into messages of at most `max` characters, each starting with `i/n ` when
+/// there are several. Code blocks are kept whole when they fit and otherwise split between
+/// lines with their fences repeated, prose is split between paragraphs, then lines, sentences
+/// and words.
+pub fn split(text: &str, max: Option<usize>) -> Vec<String> {
+    number(pieces(text, max))
+}
+
+/// The longest SMS answer, all its messages together, from `sms_max_chars` (default 320).
+fn sms_max_chars() -> usize {
+    settings::setting("sms_max_chars", 320)
+}
+
+/// Splits the answer into the messages of the channel, see [`split`]. SMS answers are then cut
+/// to `sms_max_chars` in all, ending on a word with `…`, so long answers do not run up the cost
+/// of many segments.
+pub fn for_channel(text: &str, profile: Profile, headers: &[(String, String)]) -> Vec<String> {
+    let pieces = pieces(text, limit(profile, headers));
+    match profile {
+        Profile::Sms => number(cap(pieces, sms_max_chars())),
+        _ => number(pieces),
+    }
+}
+
+/// The text in pieces of at most `max` characters, less the room of the indicator when it takes
+/// several.
+fn pieces(text: &str, max: Option<usize>) -> Vec<String> {
+    let max = match max {
+        Some(m) if text.chars().count() > m => m.max(MIN_PART_CHARS),
+        _ => {
+            return vec![text.to_string()];
+        }
+    };
+    let budget = max - INDICATOR_CHARS;
+
+    let mut parts: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for unit in units(text)
+        .into_iter()
+        .flat_map(|u| fit(&u, budget)) {
+        let joined = match current.is_empty() {
+            true => unit.clone(),
+            false => format!("{current}\n\n{unit}"),
+        };
+        match joined.chars().count() <= budget {
+            true => {
+                current = joined;
+            }
+            false => {
+                parts.push(std::mem::take(&mut current));
+                current = unit;
+            }
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Starts every part with its `i/n ` indicator when there are several.
+fn number(parts: Vec<String>) -> Vec<String> {
+    let n = parts.len();
+    match n {
+        1 => parts,
+        _ =>
+            parts
+                .into_iter()
+                .enumerate()
+                .map(|(i, p)| format!("{}/{} {}", i + 1, n, p))
+                .collect(),
+    }
+}
+
+/// The first parts, up to `max_chars` in all, the part reaching it cut at a word.
+fn cap(parts: Vec<String>, max_chars: usize) -> Vec<String> {
+    let mut kept: Vec<String> = Vec::new();
+    let mut total = 0;
+    for part in parts {
+        let len = part.chars().count();
+        if total + len <= max_chars {
+            total += len;
+            kept.push(part);
+            continue;
+        }
+        let room = max_chars - total;
+        match kept.last_mut() {
+            // too little room left for a part of its own
+            Some(last) if room < MIN_PART_CHARS => last.push('…'),
+            _ => kept.push(truncate(&part, room.max(MIN_PART_CHARS))),
+        }
+        break;
+    }
+    kept
+}
+
+/// The text cut to `max_chars`, on a whole word when there is one, ending with `…`.
+fn truncate(text: &str, max_chars: usize) -> String {
+    let cut = text.chars().take(max_chars.saturating_sub(1)).collect::<String>();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(i) if i > max_chars / 2 => cut[..i].to_string(),
+        _ => cut,
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// The paragraphs and fenced code blocks of the text, in order.
+fn units(text: &str) -> Vec<String> {
+    let mut units = Vec::new();
+    for (n, piece) in text.split("```").enumerate() {
+        match n % 2 {
+            1 => units.push(format!("```{piece}```")),
+            _ =>
+                units.extend(
+                    piece
+                        .split("\n\n")
+                        .map(|p| p.trim())
+                        .filter(|p| !p.is_empty())
+                        .map(String::from)
+                ),
+        }
+    }
+    units
+}
+
+/// A unit as it is when it fits the budget, else cut into pieces that do.
+fn fit(unit: &str, budget: usize) -> Vec<String> {
+    if unit.chars().count() <= budget {
+        return vec![unit.to_string()];
+    }
+    match unit.strip_prefix("```").and_then(|u| u.strip_suffix("```")) {
+        Some(block) => {
+            let (lang, code) = block.split_once('\n').unwrap_or(("", block));
+            // every piece is fenced again so it renders as code on its own
+            let fence = format!("```{lang}\n");
+            let inner = budget.saturating_sub(fence.chars().count() + 4).max(1);
+            cut(code.trim_end_matches('\n'), inner, &["\n"])
+                .into_iter()
+                .map(|c| format!("{fence}{c}\n```"))
+                .collect()
+        }
+        None => cut(unit, budget, &["\n", ". ", " "]),
+    }
+}
+
+/// Cuts the text at the first separator that gives pieces within `max`, falling back to the
+/// next separator for pieces still too long and to a hard cut when none is left.
+fn cut(text: &str, max: usize, separators: &[&str]) -> Vec<String> {
+    if text.chars().count() <= max {
+        return vec![text.to_string()];
+    }
+    let Some((separator, rest)) = separators.split_first() else {
+        return text
+            .chars()
+            .collect::<Vec<char>>()
+            .chunks(max)
+            .map(|c| c.iter().collect())
+            .collect();
+    };
+
+    let mut pieces: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for piece in text.split_inclusive(separator) {
+        if current.chars().count() + piece.chars().count() > max && !current.is_empty() {
+            pieces.push(std::mem::take(&mut current));
+        }
+        current.push_str(piece);
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+        .into_iter()
+        .flat_map(|p| cut(p.trim_end(), max, rest))
+        .filter(|p| !p.trim().is_empty())
+        .collect()
+}
@@ -0,0 +1,64 @@
+use serde_json::json;
+use store_flows::{ del, get };
+
+use crate::{ guards, priority::Lane, routing, CHAT_MODEL };
+
+/// The chat models a conversation can be pinned to: the `allowed_models` setting (comma
+/// separated), else the default, fast and routed chat models.
+pub fn allowed() -> Vec<String> {
+    let setting = std::env::var("allowed_models").unwrap_or("".to_string());
+    let mut models = setting
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .collect::<Vec<String>>();
+    if models.is_empty() {
+        models.push(CHAT_MODEL.to_string());
+        for setting in ["fast_chat_model", "simple_chat_model", "complex_chat_model"] {
+            let model = std::env::var(setting).unwrap_or("".to_string());
+            if !model.is_empty() && !models.contains(&model) {
+                models.push(model);
+            }
+        }
+    }
+    models
+}
+
+fn key(chat_id: &str) -> String {
+    format!("model:{chat_id}")
+}
+
+/// The model the chat was pinned to with `/model`, unless it is no longer allowed.
+pub fn pinned(chat_id: &str) -> Option<String> {
+    let model = get(&key(chat_id)).and_then(|v| v.as_str().map(String::from))?;
+    match allowed().contains(&model) {
+        true => Some(model),
+        false => {
+            log::warn!("{} is pinned to {}, which is no longer allowed", chat_id, model);
+            None
+        }
+    }
+}
+
+pub fn pin(chat_id: &str, model: &str) {
+    guards::set_capped(&key(chat_id), json!(model), None);
+}
+
+pub fn unpin(chat_id: &str) {
+    del(&key(chat_id));
+}
+
+/// The model answering the question: the pinned one, so the tone does not change
+/// mid-conversation, else the fast model of the high priority lane, else the model of the
+/// question's tier when routing is on, else the default.
+pub fn for_chat(chat_id: &str, lane: Lane, question: &str) -> String {
+    if let Some(model) = pinned(chat_id) {
+        return model;
+    }
+    if lane == Lane::Normal && routing::enabled() {
+        let tier = routing::classify(question);
+        log::debug!("Routing a {} question", tier.name());
+        return tier.chat_model();
+    }
+    lane.chat_model()
+}
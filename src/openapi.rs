@@ -0,0 +1,561 @@
+use schemars::{ gen::{ SchemaGenerator, SchemaSettings }, JsonSchema };
+use serde_json::{ json, Map, Value };
+
+use crate::{
+    api_keys::KeyUpdate,
+    bulk_delete::DeleteFilter,
+    citations::Citation,
+    collection_config::CollectionConfig,
+    curation::CurationRequest,
+    events::ChatEvent,
+    faq::Faq,
+    feedback::RatingRequest,
+    ingest::{ Document, KbVersion },
+    loadtest::LoadTestOptions,
+    maintenance::Maintenance,
+    pdf::PagedDocument,
+    quarantine::QuarantineRequest,
+    ranking::BoostRule,
+    retention::RetentionRule,
+    retrieval::Retrieval,
+    roles::{ self, Role },
+    transcript::Transcript,
+};
+
+/// One operation of the API. The flow has a single webhook URL, so every operation is the root
+/// path with its `action` query parameter, one value of its enum.
+struct Operation {
+    action: &'static str,
+    summary: &'static str,
+    request: Option<Value>,
+    response: Value,
+}
+
+fn object() -> Value {
+    json!({ "type": "object" })
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap_or_default()
+}
+
+fn operations(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![
+        Operation {
+            action: "ingest",
+            summary: "Chunk, embed and upsert documents, or the pages at `urls`, into a collection",
+            request: Some(
+                json!({
+                    "oneOf": [
+                        schema::<Document>(gen),
+                        schema::<Vec<Document>>(gen),
+                        schema::<PagedDocument>(gen),
+                        {
+                            "type": "object",
+                            "properties": { "urls": { "type": "array", "items": { "type": "string" } } },
+                            "required": ["urls"],
+                        },
+                    ],
+                })
+            ),
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "collection": { "type": "string" },
+                    "written": { "type": "integer" },
+                    "kb_version": schema::<Option<KbVersion>>(gen),
+                },
+            }),
+        },
+        Operation {
+            action: "delete_points",
+            summary: "Count the points of `collection` matching a payload filter, deleting them with `confirm=true`",
+            request: Some(schema::<DeleteFilter>(gen)),
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "collection": { "type": "string" },
+                    "dry_run": { "type": "boolean" },
+                    "scanned": { "type": "integer" },
+                    "matched": { "type": "integer" },
+                    "deleted": { "type": "integer" },
+                    "ids": { "type": "array", "items": { "type": "integer" } },
+                    "kb_version": schema::<Option<KbVersion>>(gen),
+                },
+            }),
+        },
+        Operation {
+            action: "github_sync",
+            summary: "Re-embed the documentation files of `github_docs_repo` changed since the last sync into `collection`, listing them only with `dry_run=true`",
+            request: None,
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "collection": { "type": "string" },
+                    "dry_run": { "type": "boolean" },
+                    "files": { "type": "integer" },
+                    "changed": { "type": "array", "items": { "type": "string" } },
+                    "removed": { "type": "array", "items": { "type": "string" } },
+                    "left_for_later": { "type": "integer" },
+                    "written": { "type": "integer" },
+                    "deleted": { "type": "integer" },
+                    "kb_version": schema::<Option<KbVersion>>(gen),
+                },
+            }),
+        },
+        Operation {
+            action: "tune_retrieval",
+            summary: "Score weights of the hypothetical answer search of `collection` on seed questions, storing the best with `apply=true`",
+            request: Some(object()),
+            response: object(),
+        },
+        Operation {
+            action: "faq",
+            summary: "The published FAQ, as markdown with `format=markdown`",
+            request: None,
+            response: schema::<Faq>(gen),
+        },
+        Operation {
+            action: "feedback",
+            summary: "Rate an answer up or down by the `message_id` it came with",
+            request: Some(schema::<RatingRequest>(gen)),
+            response: object(),
+        },
+        Operation {
+            action: "feedback_export",
+            summary: "The rated answers, newest first, only those of `rating=up|down` when given",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "curation",
+            summary: "Promote upvoted answers of `collection` into it as verified answers, or reject them, list the candidates with an empty body",
+            request: Some(schema::<CurationRequest>(gen)),
+            response: object(),
+        },
+        Operation {
+            action: "faq_generate",
+            summary: "Regenerate the FAQ from the recent questions",
+            request: None,
+            response: schema::<Faq>(gen),
+        },
+        Operation {
+            action: "audit",
+            summary: "The latest answered questions with their provenance",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "export",
+            summary: "The QA history of conversation `chat_id` (or `conversation`), as markdown with `format=markdown`",
+            request: None,
+            response: schema::<Transcript>(gen),
+        },
+        Operation {
+            action: "analytics",
+            summary: "Answer categories and confidence over time",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "quarantine",
+            summary: "Quarantine or release knowledge base chunks, list them with an empty body",
+            request: Some(schema::<QuarantineRequest>(gen)),
+            response: object(),
+        },
+        Operation {
+            action: "retention_rules",
+            summary: "Replace the retention rules, list them with an empty body",
+            request: Some(schema::<Vec<RetentionRule>>(gen)),
+            response: schema::<Vec<RetentionRule>>(gen),
+        },
+        Operation {
+            action: "retention_sweep",
+            summary: "Apply the retention rules and clear the conversations idle past their TTL, only report with `dry_run=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "conversation_ttl",
+            summary: "Set the days `conversation` is kept after its last message with `days`, `default` for `conversation_ttl_days`",
+            request: None,
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "conversation": { "type": "string" },
+                    "ttl_days": { "type": "integer" },
+                },
+            }),
+        },
+        Operation {
+            action: "collection_config",
+            summary: "Set the embedding and retrieval settings of a collection, read them with an empty body",
+            request: Some(schema::<CollectionConfig>(gen)),
+            response: schema::<CollectionConfig>(gen),
+        },
+        Operation {
+            action: "maintenance",
+            summary: "Turn maintenance mode on or off, read it with an empty body",
+            request: Some(schema::<Maintenance>(gen)),
+            response: schema::<Maintenance>(gen),
+        },
+        Operation {
+            action: "merge_conversations",
+            summary: "Merge the memory, history and audit entries of conversation `from` into `into`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "refresh_releases",
+            summary: "Ingest the release notes of the tracked repositories, only chunk them with `preview=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "tune_chunking",
+            summary: "Recommend chunk sizes for sample documents and questions",
+            request: Some(object()),
+            response: object(),
+        },
+        Operation {
+            action: "loadtest",
+            summary: "Run the retrieval and prompt pipeline against stubbed upstream services and project the throughput",
+            request: Some(schema::<LoadTestOptions>(gen)),
+            response: object(),
+        },
+        Operation {
+            action: "analytics_rollup",
+            summary: "Roll the answer analytics up into daily totals",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "digest",
+            summary: "Send the digest of unanswered and badly rated questions, only build it with `dry_run=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "drift_baseline",
+            summary: "Profile `collection` as the baseline questions are compared against",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "drift_check",
+            summary: "How far the recent questions have drifted from the knowledge base baseline",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "diag",
+            summary: "Check the settings and the upstream services the flow depends on for `collection`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "flush_upserts",
+            summary: "Write the queued upserts to the vector store",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "retry_metrics",
+            summary: "Retries and failures of the upstream calls per retry policy",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "metrics",
+            summary: "Cache hits, misses and evictions and the answer judge stats, reset with `reset=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "stats",
+            summary: "Latency of the stages of the answers, reset with `reset=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "deliver_reminders",
+            summary: "Send the due reminders as `reminder.due` webhook events",
+            request: None,
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "delivered": { "type": "integer" },
+                    "retrying": { "type": "integer" },
+                    "dropped": { "type": "integer" },
+                    "pending": { "type": "integer" },
+                },
+            }),
+        },
+        Operation {
+            action: "embedding_cache",
+            summary: "The embedding cache statistics, reset with `reset=true`",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "conversation_prompt",
+            summary: "Set the `prompt` and `post_prompt` of `conversation`, read them with an empty body",
+            request: Some(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "prompt": { "type": ["string", "null"] },
+                        "post_prompt": { "type": ["string", "null"] },
+                    },
+                })
+            ),
+            response: object(),
+        },
+        Operation {
+            action: "feedback_boosts",
+            summary: "Turn the `/helpful` votes on the chunks of `collection` into score boosts",
+            request: None,
+            response: object(),
+        },
+        Operation {
+            action: "api_keys",
+            summary: "Add an API key or change the role, quota or state of one, list them with an empty body",
+            request: Some(schema::<KeyUpdate>(gen)),
+            response: object(),
+        },
+        Operation {
+            action: "boost_rules",
+            summary: "Replace the score boost rules, list them with an empty body",
+            request: Some(schema::<Vec<BoostRule>>(gen)),
+            response: schema::<Vec<BoostRule>>(gen),
+        },
+        Operation {
+            action: "search",
+            summary: "Search `collection` for the text of the body as a chat question would, without answering, up to `limit` chunks",
+            request: Some(json!({ "type": "string" })),
+            response: json!({
+                "type": "object",
+                "properties": {
+                    "collection": { "type": "string" },
+                    "results": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "id": { "type": "integer" },
+                                "score": { "type": "number" },
+                                "text": { "type": "string" },
+                            },
+                        },
+                    },
+                },
+            }),
+        },
+        Operation {
+            action: "graphql",
+            summary: "Read conversations, knowledge base stats, traces and analytics with a GraphQL query",
+            request: Some(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string" },
+                        "operationName": { "type": "string" },
+                        "variables": { "type": "object" },
+                    },
+                    "required": ["query"],
+                })
+            ),
+            response: object(),
+        },
+        Operation {
+            action: "openapi",
+            summary: "This document",
+            request: None,
+            response: object(),
+        }
+    ]
+}
+
+/// What an operation takes, returns and needs, under `x-actions`: OpenAPI cannot tell operations
+/// apart by a query parameter, so these ride along the `action` enum of the root path.
+fn action_entry(op: &Operation) -> Value {
+    let mut entry = json!({
+        "summary": op.summary,
+        "x-required-role": roles::required(op.action),
+        "response": op.response,
+    });
+    if let Some(request) = &op.request {
+        entry["request"] = request.clone();
+    }
+    entry
+}
+
+/// The `action` query parameter, every operation listed with its role in the description. Chat
+/// messages are posted without it.
+fn action_parameter(ops: &[Operation], required: bool) -> Value {
+    let lead = match required {
+        true => "The operation to run:",
+        false => "Runs the operation instead of answering:",
+    };
+    let description = ops
+        .iter()
+        .map(|op| format!("- `{}` ({}): {}", op.action, role_name(roles::required(op.action)), op.summary))
+        .fold(lead.to_string(), |d, line| d + "\n" + &line);
+    json!({
+        "name": "action",
+        "in": "query",
+        "required": required,
+        "description": description,
+        "schema": { "enum": ops.iter().map(|op| op.action).collect::<Vec<&str>>() },
+    })
+}
+
+fn role_name(role: Role) -> String {
+    serde_json::to_value(role)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+fn action_requests(ops: &[Operation]) -> Value {
+    json!({ "oneOf": ops.iter().filter_map(|op| op.request.clone()).collect::<Vec<Value>>() })
+}
+
+fn action_responses(ops: &[Operation]) -> Value {
+    json!({ "oneOf": ops.iter().map(|op| op.response.clone()).collect::<Vec<Value>>() })
+}
+
+/// Reading operations, those taking a body are posted.
+fn action_item(ops: &[Operation]) -> Value {
+    json!({
+        "operationId": "action",
+        "summary": "Run the operation of `action`",
+        "parameters": [action_parameter(ops, true)],
+        // the user operations need no credential
+        "security": [{}, { "adminToken": [] }, { "apiKey": [] }],
+        "responses": {
+            "200": {
+                "description": "The result of the operation, see `x-actions`",
+                "content": { "application/json": { "schema": action_responses(ops) } },
+            },
+            "400": { "description": "Unknown action or invalid request" },
+            "403": { "description": "The caller does not hold the role the operation needs" },
+        },
+    })
+}
+
+/// Posting asks a question, or runs the operation of `action` with its JSON body.
+fn chat_item(gen: &mut SchemaGenerator, ops: &[Operation]) -> Value {
+    let action = action_parameter(ops, false);
+    let answer = json!({
+        "type": "object",
+        "properties": {
+            "conversation_id": { "type": "string" },
+            "answer": { "type": "string" },
+            "sources": { "type": "array", "items": { "type": "string" } },
+            "tokens_used": { "type": "integer" },
+            "retrieval_scores": { "type": "array", "items": { "type": "object" } },
+            "provenance": { "type": "object" },
+            "citations": schema::<Vec<Citation>>(gen),
+        },
+    });
+    json!({
+        "get": action_item(ops),
+        "post": {
+            "operationId": "chat",
+            "summary": "Ask a question, or run an operation with `action`",
+            "security": [{}, { "adminToken": [] }, { "apiKey": [] }],
+            "parameters": [
+                action,
+                { "name": "x-conversation-name", "in": "header", "schema": { "type": "string" } },
+                { "name": "x-collection-name", "in": "header", "description": "One of `allowed_collections`", "schema": { "type": "string" } },
+                { "name": "respond_in", "in": "query", "schema": { "type": "string" } },
+                { "name": "x-retrieval", "in": "header", "description": "JSON retrieval overrides", "schema": schema::<Retrieval>(gen) },
+                { "name": "x-retrieval-limit", "in": "header", "schema": { "type": "integer", "minimum": 1, "maximum": 20 } },
+                { "name": "x-score-threshold", "in": "header", "schema": { "type": "number", "minimum": 0, "maximum": 1 } },
+                { "name": "x-max-context-chars", "in": "header", "schema": { "type": "integer", "minimum": 1 } },
+                { "name": "x-response-format", "in": "header", "schema": { "enum": ["text", "json", "events"] } },
+                { "name": "x-deterministic", "in": "header", "description": "`true` for reproducible answers", "schema": { "type": "boolean" } },
+                { "name": "x-platform", "in": "header", "description": "The platform adapter, e.g. `slack`", "schema": { "type": "string", "maxLength": 32 } },
+                { "name": "x-client-version", "in": "header", "schema": { "type": "string", "maxLength": 32 } },
+                { "name": "x-max-message-chars", "in": "header", "description": "Splits longer answers into several messages", "schema": { "type": "integer", "minimum": 40 } },
+                { "name": "x-channel", "in": "header", "description": "How the answer is rendered, the platform's profile by default", "schema": { "enum": ["markdown", "slack", "telegram", "plain", "sms", "html"] } },
+            ],
+            "requestBody": {
+                "content": {
+                    "text/plain": { "schema": { "type": "string" } },
+                    "application/json": { "schema": action_requests(ops) },
+                },
+            },
+            "responses": {
+                "200": {
+                    "description": "The answer, or the result of the operation",
+                    "content": {
+                        "text/html": { "schema": { "type": "string" } },
+                        "application/json": {
+                            "schema": {
+                                "oneOf": [answer, action_responses(ops)],
+                            },
+                        },
+                        "text/event-stream": { "schema": schema::<ChatEvent>(gen) },
+                    },
+                },
+                "400": { "description": "Unknown action or invalid request" },
+                "401": { "description": "`require_api_key` is on and no valid API key was sent" },
+                "403": { "description": "The caller does not hold the role the operation needs" },
+                "429": {
+                    "description": "Too many messages from the conversation or address, or the daily quota of the API key is used up; retry after `retry-after` seconds",
+                    "headers": { "retry-after": { "schema": { "type": "integer" } } },
+                },
+            },
+        },
+    })
+}
+
+/// The OpenAPI 3.1 document of the HTTP API, with the schemas derived from the request and
+/// response types.
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::draft2019_09()
+        .with(|s| {
+            s.definitions_path = "#/components/schemas/".to_string();
+        })
+        .into_generator();
+
+    let ops = operations(&mut gen);
+    let root = chat_item(&mut gen, &ops);
+    let actions = ops
+        .iter()
+        .map(|op| (op.action.to_string(), action_entry(op)))
+        .collect::<Map<String, Value>>();
+
+    let mut paths = Map::new();
+    paths.insert("/".to_string(), root);
+    paths.insert(
+        "/openapi.json".to_string(),
+        json!({
+            "get": {
+                "operationId": "openapi_json",
+                "summary": "This document",
+                "security": [],
+                "responses": { "200": { "description": "OK", "content": { "application/json": { "schema": object() } } } },
+            },
+        })
+    );
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "RAG chatbot",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "x-actions": actions,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "adminToken": { "type": "apiKey", "in": "header", "name": "x-admin-token" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "x-api-key" },
+            },
+        },
+    })
+}
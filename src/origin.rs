@@ -0,0 +1,69 @@
+use std::sync::Mutex;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::first_x_chars;
+
+/// Where a request came from, recorded with its audit log entry and counted in the analytics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Origin {
+    /// The platform adapter from `x-platform`, e.g. `slack` or `discord`, else `web` for browsers
+    /// and `unknown`.
+    pub platform: String,
+    pub user_agent: Option<String>,
+    /// The `x-client-version` header.
+    pub client_version: Option<String>,
+}
+
+static CURRENT: Mutex<Option<Origin>> = Mutex::new(None);
+
+impl Origin {
+    pub fn of(headers: &[(String, String)]) -> Self {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim())
+                .filter(|v| !v.is_empty())
+        };
+        let user_agent = header("user-agent").map(|ua| first_x_chars(ua, 200));
+        // used as an analytics key, so kept short and plain
+        let platform = match header("x-platform") {
+            Some(p) =>
+                first_x_chars(
+                    &p
+                        .to_lowercase()
+                        .chars()
+                        .filter(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c))
+                        .collect::<String>(),
+                    32
+                ),
+            None if user_agent.as_deref().is_some_and(|ua| ua.starts_with("Mozilla/")) => "web".to_string(),
+            None => "unknown".to_string(),
+        };
+        Self {
+            platform: match platform.is_empty() {
+                true => "unknown".to_string(),
+                false => platform,
+            },
+            user_agent,
+            client_version: header("x-client-version").map(|v| first_x_chars(v, 32)),
+        }
+    }
+}
+
+/// Records the origin of the request being served.
+pub fn begin(headers: &[(String, String)]) {
+    if let Ok(mut o) = CURRENT.lock() {
+        *o = Some(Origin::of(headers));
+    }
+}
+
+/// The origin of the request being served.
+pub fn current() -> Origin {
+    CURRENT.lock()
+        .ok()
+        .and_then(|o| o.clone())
+        .unwrap_or_default()
+}
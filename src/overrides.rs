@@ -0,0 +1,52 @@
+use serde_json::json;
+use store_flows::{ del, get };
+
+use crate::{ error::{ Error, Result }, guards };
+
+/// The settings admins can change from the chat with `/set`, without redeploying the flow.
+pub const SETTABLE: &[&str] = &[
+    "system_prompt",
+    "post_prompt",
+    "error_mesg",
+    "no_answer_mesg",
+    "domain_description",
+    "system_template",
+    "user_template",
+    "post_template",
+    "grounding_disclaimer",
+];
+/// Longest value accepted, in characters.
+const MAX_CHARS: usize = 4000;
+
+fn key(name: &str) -> String {
+    format!("setting:{name}")
+}
+
+/// The value set with `/set`, `None` when the setting is not overridden.
+pub fn get_override(name: &str) -> Option<String> {
+    get(&key(name)).and_then(|v| v.as_str().map(String::from))
+}
+
+/// The value set with `/set`, else the env var, else `default`.
+pub fn setting(name: &str, default: &str) -> String {
+    get_override(name).unwrap_or(std::env::var(name).unwrap_or(default.to_string()))
+}
+
+/// Overrides a settable setting, `default` removing the override.
+pub fn set(name: &str, value: &str) -> Result<()> {
+    if !SETTABLE.contains(&name) {
+        return Err(Error::InvalidInput(format!("`{name}` cannot be set, only {}", SETTABLE.join(", "))));
+    }
+    if value.chars().count() > MAX_CHARS {
+        return Err(Error::InvalidInput(format!("the value is longer than {MAX_CHARS} characters")));
+    }
+
+    match value {
+        "" | "default" => {
+            del(&key(name));
+        }
+        value => guards::set_capped(&key(name), json!(value), None),
+    }
+    log::info!("Setting {} overridden from the chat", name);
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use llmservice_flows::chat::ChatOptions;
+
+use crate::{ deterministic, error::{ Error, Result }, first_x_chars, retry, settings, CHAT_MODEL };
+
+/// The most paraphrases searched besides the question.
+const MAX_PARAPHRASES: usize = 3;
+
+/// How many paraphrases of the question are searched, from `query_paraphrases` (0 to 3, 0 by
+/// default which leaves multi-query retrieval off).
+pub fn count() -> usize {
+    settings::setting::<usize>("query_paraphrases", 0)
+        .min(MAX_PARAPHRASES)
+}
+
+/// Asks the LLM for `n` rewordings of the question, so chunks worded differently from the user
+/// are found too. The question itself is not among them.
+pub async fn paraphrases(question: &str, n: usize) -> Result<Vec<String>> {
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+
+    let sys_prompt =
+        "You rewrite search queries. Reword the question in different words a document answering it might use, keeping its meaning, names, versions and error messages. Reply with one rewording per line and nothing else.";
+    let usr_prompt = format!("Give {n} rewordings of: `{question}`");
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 1024,
+        max_tokens: Some(256),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let reply = retry
+        ::call("paraphrase", || llm.chat_completion("paraphrase", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+    let paraphrases = reply
+        .lines()
+        // the model may number or bullet the lines
+        .map(|l| l.trim().trim_start_matches(|c: char| c.is_ascii_digit() || "-*.) ".contains(c)).trim_matches(['`', '"']))
+        .filter(|l| !l.is_empty() && !l.eq_ignore_ascii_case(question.trim()))
+        .map(|l| first_x_chars(l, 300))
+        .take(n)
+        .collect::<Vec<String>>();
+    log::debug!("Paraphrased the question as {:?}", paraphrases);
+    Ok(paraphrases)
+}
@@ -0,0 +1,84 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{ json, Map, Value };
+
+use crate::{ error::{ Error, Result }, ingest::Document };
+
+/// A page of a book-style document, as extracted from its PDF.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Page {
+    /// The page number, the position in `pages` (from 1) when left out.
+    #[serde(default)]
+    pub page: Option<u64>,
+    pub text: String,
+}
+
+/// A document already extracted page by page, e.g. `{"source": "k8s-book.pdf", "pages": [{"page": 1, "text": "..."}]}`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PagedDocument {
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+    pub pages: Vec<Page>,
+}
+
+/// Whether the body is a PDF file.
+pub fn is_pdf(body: &[u8]) -> bool {
+    body.starts_with(b"%PDF-")
+}
+
+impl PagedDocument {
+    /// One document per page with text, stored with its `page` number. The title names the page,
+    /// so the Sources section of an answer tells the pages it drew on apart.
+    pub fn into_documents(self) -> Vec<Document> {
+        let name = self.title.clone().or(self.source.clone());
+        self.pages
+            .into_iter()
+            .enumerate()
+            .filter(|(_, p)| !p.text.trim().is_empty())
+            .map(|(i, p)| {
+                let page = p.page.unwrap_or((i as u64) + 1);
+                let mut metadata = self.metadata.clone();
+                metadata.insert("page".to_string(), json!(page));
+                Document {
+                    text: p.text,
+                    source: self.source.clone(),
+                    title: Some(match &name {
+                        Some(name) => format!("{name}, page {page}"),
+                        None => format!("page {page}"),
+                    }),
+                    metadata,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Extracts the text of a PDF page by page. Scanned pages without a text layer come out empty
+/// and are skipped.
+#[cfg(feature = "pdf")]
+pub fn extract(body: &[u8], source: Option<&str>, title: Option<&str>) -> Result<Vec<Document>> {
+    let pages = pdf_extract::extract_text_from_mem_by_pages(body).map_err(|e| Error::InvalidInput(format!("cannot read the PDF: {e}")))?;
+    let documents = (PagedDocument {
+        source: source.map(String::from),
+        title: title.map(String::from),
+        metadata: Map::new(),
+        pages: pages
+            .into_iter()
+            .map(|text| Page { page: None, text })
+            .collect(),
+    }).into_documents();
+    match documents.is_empty() {
+        true => Err(Error::InvalidInput("the PDF has no text layer to ingest".to_string())),
+        false => Ok(documents),
+    }
+}
+
+/// Without the `pdf` feature PDF files are refused, pages extracted elsewhere are still ingested.
+#[cfg(not(feature = "pdf"))]
+pub fn extract(_body: &[u8], _source: Option<&str>, _title: Option<&str>) -> Result<Vec<Document>> {
+    Err(Error::InvalidInput("this build cannot read PDF files, post the pages extracted instead".to_string()))
+}
@@ -0,0 +1,58 @@
+use crate::CHAT_MODEL;
+
+/// The lane a chat request is served in. High priority requests skip the optional retrieval
+/// stages and use the fastest model, normal ones get the full pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Lane {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Lane {
+    /// Reads the lane from the `x-priority: high` header, which is only trusted together with an
+    /// `x-priority-key` header matching the `priority_key` setting.
+    pub fn from_headers(headers: &[(String, String)]) -> Self {
+        let priority_key = std::env::var("priority_key").unwrap_or("".to_string());
+        if priority_key.is_empty() {
+            return Lane::Normal;
+        }
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim())
+        };
+        match (header("x-priority"), header("x-priority-key")) {
+            (Some(p), Some(k)) if p.eq_ignore_ascii_case("high") && k == priority_key => Lane::High,
+            (Some(_), _) => {
+                log::warn!("Ignoring x-priority without a valid x-priority-key");
+                Lane::Normal
+            }
+            _ => Lane::Normal,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lane::Normal => "normal",
+            Lane::High => "high",
+        }
+    }
+
+    /// Whether the hypothetical answer search and the re-ranking by boost rules and code
+    /// preference are skipped.
+    pub fn skips_optional_stages(&self) -> bool {
+        *self == Lane::High
+    }
+
+    /// The chat model of the lane, `fast_chat_model` for high priority when set.
+    pub fn chat_model(&self) -> String {
+        let fast = std::env::var("fast_chat_model").unwrap_or("".to_string());
+        match self {
+            Lane::High if !fast.is_empty() => fast,
+            _ => CHAT_MODEL.to_string(),
+        }
+    }
+}
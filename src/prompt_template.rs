@@ -0,0 +1,88 @@
+use crate::overrides;
+
+/// The system prompt followed by the earlier QA pairs, as it was built before templates.
+const DEFAULT_SYSTEM: &str = "{{system_prompt}}{{history}}";
+const DEFAULT_USER: &str =
+    "{{context}} Here is the question you're to reply now: `{{question}}`. Please provide a concise answer, stay truthful and factual.";
+const DEFAULT_POST: &str = "{{post_prompt}}";
+
+/// The prompts sent to the model, each rendered from its own template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    System,
+    User,
+    Post,
+}
+
+impl Part {
+    /// The `system_template`, `user_template` or `post_template` setting, which `/set` overrides.
+    fn template(&self) -> String {
+        match self {
+            Part::System => overrides::setting("system_template", DEFAULT_SYSTEM),
+            Part::User => overrides::setting("user_template", DEFAULT_USER),
+            Part::Post => overrides::setting("post_template", DEFAULT_POST),
+        }
+    }
+}
+
+/// What the placeholders of the templates stand for.
+#[derive(Debug, Clone, Default)]
+pub struct Vars<'a> {
+    /// `{{system_prompt}}`, with the instructions of the conversation.
+    pub system_prompt: &'a str,
+    /// `{{post_prompt}}`, with the language instruction.
+    pub post_prompt: &'a str,
+    /// `{{history}}`, the earlier QA pairs relevant to the question after the running summary of a
+    /// long conversation, or the summary of a resumed conversation.
+    pub history: &'a str,
+    /// `{{context}}`, the retrieved context with its introduction, e.g. ``Given the context: `...` ``,
+    /// empty when nothing was retrieved.
+    pub context: &'a str,
+    /// `{{question}}`.
+    pub question: &'a str,
+    /// `{{collection}}`, the collection searched.
+    pub collection: &'a str,
+}
+
+impl Vars<'_> {
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "system_prompt" => Some(self.system_prompt),
+            "post_prompt" => Some(self.post_prompt),
+            "history" => Some(self.history),
+            "context" => Some(self.context),
+            "question" => Some(self.question),
+            "collection" => Some(self.collection),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces the `{{name}}` placeholders of `template`. Unknown placeholders are left as they are,
+/// so a typo shows in the prompt rather than silently dropping text.
+pub fn render_template(template: &str, vars: &Vars) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        rendered.push_str(&rest[..start]);
+        match vars.get(name) {
+            Some(value) => rendered.push_str(value),
+            None => {
+                log::warn!("Unknown placeholder {{{{{}}}}} in a prompt template", name);
+                rendered.push_str(&rest[start..start + len + 4]);
+            }
+        }
+        rest = &rest[start + len + 4..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The prompt of `part`, rendered from its template.
+pub fn render(part: Part, vars: &Vars) -> String {
+    render_template(&part.template(), vars)
+}
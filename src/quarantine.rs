@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ analytics::now_secs, error::Result, guards };
+
+/// A point kept out of retrieval, e.g. an incorrect passage reported through feedback.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Quarantined {
+    pub id: u64,
+    pub reason: String,
+    pub at: u64,
+}
+
+/// Body of `?action=quarantine`, points to quarantine and points to release.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct QuarantineRequest {
+    pub ids: Vec<u64>,
+    pub reason: String,
+    pub release: Vec<u64>,
+}
+
+// The vector store can neither update the payload of a stored point nor filter a search, so
+// the flags are kept in the KV store and applied to the search results.
+fn key(collection_name: &str) -> String {
+    format!("quarantine:{collection_name}")
+}
+
+pub fn load(collection_name: &str) -> Vec<Quarantined> {
+    get(&key(collection_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// The ids of the points of the collection retrieval must skip.
+pub fn ids(collection_name: &str) -> HashSet<u64> {
+    load(collection_name)
+        .into_iter()
+        .map(|q| q.id)
+        .collect()
+}
+
+/// Quarantines and releases points as asked, taking effect on the next search.
+pub fn update(collection_name: &str, body: &[u8]) -> Result<Value> {
+    let req: QuarantineRequest = serde_json::from_slice(body)?;
+
+    let mut list = load(collection_name);
+    list.retain(|q| !req.release.contains(&q.id) && !req.ids.contains(&q.id));
+    for id in &req.ids {
+        list.push(Quarantined {
+            id: *id,
+            reason: req.reason.clone(),
+            at: now_secs(),
+        });
+    }
+    guards::set_capped(&key(collection_name), serde_json::to_value(&list)?, None);
+    log::info!(
+        "Quarantined {} and released {} points of {}, {} in quarantine",
+        req.ids.len(),
+        req.release.len(),
+        collection_name,
+        list.len()
+    );
+
+    Ok(report(collection_name))
+}
+
+pub fn report(collection_name: &str) -> Value {
+    json!({ "collection": collection_name, "quarantined": load(collection_name) })
+}
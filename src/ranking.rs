@@ -0,0 +1,144 @@
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+use store_flows::get;
+
+use crate::{ code_blocks::{ self, CodeRetrieval }, guards, keywords::Keywords, retrieval::{ self, Retrieval } };
+
+const BOOST_RULES_KEY: &str = "boost_rules";
+
+/// An operator-defined rule: when the question matches `pattern`, points whose payload `field`
+/// matches `matches` get `boost` added to their score. Both patterns are regexes; array fields
+/// such as `tags` match when any element does.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BoostRule {
+    pub pattern: String,
+    pub field: String,
+    pub matches: String,
+    pub boost: f32,
+}
+
+/// Loads the boost rules from the KV store, falling back to the `boost_rules` env var.
+pub fn load_boost_rules() -> Vec<BoostRule> {
+    let stored = get(BOOST_RULES_KEY).or_else(|| {
+        std::env
+            ::var("boost_rules")
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+    });
+
+    match stored {
+        Some(v) =>
+            serde_json::from_value(v).unwrap_or_else(|e| {
+                log::error!("Invalid boost rules: {}", e);
+                Vec::new()
+            }),
+        None => Vec::new(),
+    }
+}
+
+pub fn save_boost_rules(rules: &[BoostRule]) {
+    guards::set_capped(BOOST_RULES_KEY, serde_json::to_value(rules).unwrap_or_default(), None);
+}
+
+/// A boost rule whose question pattern matched, ready to be applied to payloads.
+#[derive(Debug, Clone)]
+pub struct ActiveBoost {
+    field: String,
+    matches: Regex,
+    boost: f32,
+}
+
+/// How retrieved points are scored for one question, decided once from the user's question and
+/// applied to every search made for it (including the one with the hypothetical answer).
+#[derive(Debug, Clone, Default)]
+pub struct Ranking {
+    pub code: Option<CodeRetrieval>,
+    pub boosts: Vec<ActiveBoost>,
+    /// How many points a search keeps, [`retrieval::default_limit`] when unset.
+    pub top_k: Option<usize>,
+    /// Replaces the score threshold of the collection.
+    pub threshold: Option<f32>,
+    /// The terms matched against the chunks of collections with keyword search.
+    pub keywords: Keywords,
+}
+
+impl Ranking {
+    pub fn for_question(question: &str) -> Self {
+        let boosts = load_boost_rules()
+            .into_iter()
+            .filter_map(|rule| {
+                let pattern = Regex::new(&rule.pattern)
+                    .map_err(|e| log::error!("Invalid boost rule pattern {}: {}", rule.pattern, e))
+                    .ok()?;
+                let matches = Regex::new(&rule.matches)
+                    .map_err(|e| log::error!("Invalid boost rule match {}: {}", rule.matches, e))
+                    .ok()?;
+                pattern.is_match(question).then_some(ActiveBoost {
+                    field: rule.field,
+                    matches,
+                    boost: rule.boost,
+                })
+            })
+            .collect::<Vec<ActiveBoost>>();
+        if !boosts.is_empty() {
+            log::debug!("{} boost rules apply to the question", boosts.len());
+        }
+
+        Self {
+            code: code_blocks::is_code_request(question).then(CodeRetrieval::from_env),
+            boosts,
+            keywords: Keywords::of_question(question),
+            ..Default::default()
+        }
+    }
+
+    /// Applies the `k` and `threshold` of a per-request retrieval override.
+    pub fn with_retrieval(mut self, retrieval: &Retrieval) -> Self {
+        self.top_k = retrieval.k;
+        self.threshold = retrieval.threshold;
+        self
+    }
+
+    pub fn top_k(&self) -> usize {
+        self.top_k.unwrap_or_else(retrieval::default_limit)
+    }
+
+    /// The score of a point after boosts, or `None` when the point must not be used.
+    pub fn score(&self, score: f32, payload: Option<&Map<String, Value>>) -> Option<f32> {
+        let field = |name: &str| payload.and_then(|m| m.get(name));
+
+        let is_code = field("kind").and_then(|k| k.as_str()) == Some("code");
+        let mut score = match self.code {
+            Some(CodeRetrieval::Exclusive) if !is_code => {
+                return None;
+            }
+            Some(CodeRetrieval::Boost(boost)) if is_code => score + boost,
+            _ => score,
+        };
+
+        for b in &self.boosts {
+            let hit = match field(&b.field) {
+                Some(Value::String(s)) => b.matches.is_match(s),
+                Some(Value::Array(items)) =>
+                    items
+                        .iter()
+                        .filter_map(|i| i.as_str())
+                        .any(|s| b.matches.is_match(s)),
+                Some(v) => b.matches.is_match(&v.to_string()),
+                None => false,
+            };
+            if hit {
+                score += b.boost;
+            }
+        }
+
+        Some(score)
+    }
+
+    /// Whether the ranking needs more candidates than a plain similarity search.
+    pub fn reranks(&self) -> bool {
+        self.code.is_some() || !self.boosts.is_empty()
+    }
+}
@@ -0,0 +1,60 @@
+use serde_json::json;
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{ analytics::now_secs, guards, settings::setting };
+
+/// The length of the sliding window, `rate_limit_window_secs` (default 60).
+fn window_secs() -> u64 {
+    setting("rate_limit_window_secs", 60).max(1)
+}
+
+/// The client address from `x-real-ip`, else the last `x-forwarded-for` hop. Both are set by the
+/// proxy in front of the flow, the earlier hops come from the client and can be forged.
+pub fn client_ip(headers: &[(String, String)]) -> Option<String> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    header("x-real-ip")
+        .filter(|ip| !ip.trim().is_empty())
+        .or(header("x-forwarded-for").and_then(|v| v.rsplit(',').next()))
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+}
+
+/// Counts a request against the limit of `key`, unless `limit` requests were already counted
+/// within the window. Returns the seconds until the oldest of them leaves the window when over.
+fn hit(key: &str, limit: u64) -> Result<(), u64> {
+    if limit == 0 {
+        return Ok(());
+    }
+    let now = now_secs();
+    let window = window_secs();
+    let mut times = get(key)
+        .and_then(|v| serde_json::from_value::<Vec<u64>>(v).ok())
+        .unwrap_or_default();
+    times.retain(|t| *t + window > now);
+
+    if (times.len() as u64) >= limit {
+        let oldest = times.iter().min().copied().unwrap_or(now);
+        return Err((oldest + window).saturating_sub(now).max(1));
+    }
+    times.push(now);
+    guards::set_capped(key, json!(times), Some(Expire { kind: ExpireKind::Ex, value: window as i64 }));
+    Ok(())
+}
+
+/// Checks a chat message against `rate_limit_per_chat` (default 20) requests of the chat and
+/// `rate_limit_per_ip` (default 60) requests of the client address per window, `0` turning a
+/// limit off. Returns the seconds to wait when either is reached.
+pub fn check(chat_id: &str, headers: &[(String, String)]) -> Result<(), u64> {
+    if !chat_id.is_empty() {
+        hit(&format!("rate_limit:chat:{chat_id}"), setting("rate_limit_per_chat", 20))?;
+    }
+    if let Some(ip) = client_ip(headers) {
+        hit(&format!("rate_limit:ip:{ip}"), setting("rate_limit_per_ip", 60))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use regex::Regex;
+use serde_json::{ json, Value };
+use store_flows::get;
+use vector_store_flows::*;
+
+use crate::{ collection_config::CollectionConfig, fetch, guards, ingest::{ self, Chunk, IngestOptions, Progress }, web_search::html_to_text };
+
+/// The collection holding Kubernetes release notes, kept apart from the book.
+pub const RELEASES_COLLECTION: &str = "releases";
+
+const SEEN_KEY: &str = "releases:seen";
+const MAX_SEEN: usize = 500;
+
+/// An entry of a release notes feed, or a whole page when the url is not a feed.
+#[derive(Debug)]
+struct ReleaseNote {
+    title: String,
+    link: String,
+    body: String,
+}
+
+/// The chunks of one release notes entry, tagged with the Kubernetes minor version it is about.
+#[derive(Debug)]
+pub struct ReleaseChunks {
+    pub link: String,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Fetches every feed or page listed in `release_notes_urls` (comma separated) and chunks the
+/// entries that have not been ingested before. Nothing is written.
+pub fn collect_release_notes(
+    options: &IngestOptions,
+    progress: &mut impl FnMut(Progress)
+) -> Vec<ReleaseChunks> {
+    let urls = std::env::var("release_notes_urls").unwrap_or("".to_string());
+    let seen = seen_links();
+
+    let mut collected = Vec::new();
+    for url in urls.split(',').map(|u| u.trim()).filter(|u| !u.is_empty()) {
+        let notes = match fetch::get(url, &[]) {
+            Ok(body) => parse_notes(url, &String::from_utf8_lossy(&body)),
+            Err(e) => {
+                log::error!("Cannot fetch release notes from {}: {}", url, e);
+                progress(Progress::Error { source: url.to_string(), message: e.to_string() });
+                continue;
+            }
+        };
+
+        for note in notes {
+            if seen.contains(&note.link) {
+                continue;
+            }
+            let version = version_of(&format!("{} {}", note.title, note.link)).or_else(||
+                version_of(&note.body)
+            );
+
+            let chunks = ingest
+                ::chunk_document(&note.body, options)
+                .into_iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    chunk
+                        .with("chunk_index", json!(i))
+                        .with("source", json!(note.link))
+                        .with("title", json!(note.title))
+                        .with("version", json!(version))
+                })
+                .collect::<Vec<Chunk>>();
+            progress(Progress::Chunked { source: note.link.clone(), chunks: chunks.len() });
+
+            collected.push(ReleaseChunks { link: note.link, chunks });
+        }
+    }
+
+    collected
+}
+
+/// Ingests the new release notes entries into the `releases` collection. Meant to be triggered
+/// on a schedule through `?action=refresh_releases`. Every stage is reported to `progress`,
+/// ending with [`Progress::Done`] carrying the number of chunks written.
+pub async fn refresh_release_notes(
+    options: &IngestOptions,
+    progress: &mut impl FnMut(Progress)
+) -> usize {
+    let mut seen = seen_links();
+
+    let mut written = 0;
+    for entry in collect_release_notes(options, progress) {
+        match ingest::embed_and_upsert(RELEASES_COLLECTION, entry.chunks, progress).await {
+            Ok(n) => {
+                log::info!("Ingested {} chunks of release notes from {}", n, entry.link);
+                written += n;
+                seen.push(entry.link);
+            }
+            Err(e) => {
+                log::error!("Cannot ingest release notes from {}: {}", entry.link, e);
+                progress(Progress::Error { source: entry.link, message: e.to_string() });
+            }
+        }
+    }
+
+    if seen.len() > MAX_SEEN {
+        seen.drain(..seen.len() - MAX_SEEN);
+    }
+    guards::set_capped(SEEN_KEY, json!(seen), None);
+
+    progress(Progress::Done { written });
+    written
+}
+
+fn seen_links() -> Vec<String> {
+    match get(SEEN_KEY) {
+        Some(Value::Array(v)) =>
+            v
+                .into_iter()
+                .filter_map(|s| s.as_str().map(|s| s.to_string()))
+                .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Splits an RSS or Atom feed into its entries. Anything else is treated as a single page.
+fn parse_notes(url: &str, body: &str) -> Vec<ReleaseNote> {
+    let entry = Regex::new(r"(?is)<item[\s>].*?</item>|<entry[\s>].*?</entry>").unwrap();
+    let entries = entry
+        .find_iter(body)
+        .map(|m| m.as_str())
+        .collect::<Vec<&str>>();
+
+    if entries.is_empty() {
+        return vec![ReleaseNote {
+            title: tag_text(body, "title").unwrap_or_default(),
+            link: url.to_string(),
+            body: html_to_text(body),
+        }];
+    }
+
+    let href = Regex::new(r#"(?is)<link[^>]*href="([^"]+)""#).unwrap();
+    entries
+        .into_iter()
+        .map(|e| {
+            let link = tag_text(e, "link")
+                .filter(|l| !l.is_empty())
+                .or_else(|| href.captures(e).map(|c| c[1].to_string()))
+                .unwrap_or(url.to_string());
+            let body = ["content:encoded", "content", "description", "summary"]
+                .iter()
+                .find_map(|t| tag_text(e, t))
+                .unwrap_or_default();
+            ReleaseNote {
+                title: tag_text(e, "title").unwrap_or_default(),
+                link,
+                body: html_to_text(&body),
+            }
+        })
+        .filter(|n| !n.body.is_empty())
+        .collect()
+}
+
+fn tag_text(xml: &str, tag: &str) -> Option<String> {
+    let re = Regex::new(&format!(r"(?is)<{tag}(?:\s[^>]*)?>(.*?)</{tag}>")).unwrap();
+    let inner = re.captures(xml)?[1].trim().to_string();
+    let inner = inner
+        .strip_prefix("<![CDATA[")
+        .and_then(|s| s.strip_suffix("]]>"))
+        .map(|s| s.to_string())
+        .unwrap_or(inner);
+
+    Some(inner.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+}
+
+/// Finds the first Kubernetes minor version (`1.29`) mentioned in the text.
+fn version_of(text: &str) -> Option<String> {
+    let re = Regex::new(r"\bv?(1\.\d{1,2})(?:\.\d+)?\b").unwrap();
+    re.captures(text).map(|c| c[1].to_string())
+}
+
+/// Returns the version asked about when the question is a "what's new in 1.x" style question.
+pub fn version_in_question(question: &str) -> Option<String> {
+    let re = Regex::new(
+        r"(?i)(what'?s\s+new|what\s+is\s+new|what\s+changed|changes|changelog|release\s+notes?|new\s+features?|deprecat\w*|removed)"
+    ).unwrap();
+    match re.is_match(question) {
+        true => version_of(question),
+        false => None,
+    }
+}
+
+/// Searches the `releases` collection and keeps only the chunks tagged with `version`.
+pub async fn release_context(question: &str, version: &str) -> String {
+    let vector = match CollectionConfig::load(RELEASES_COLLECTION).embed_one(question).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Cannot embed the question: {}", e);
+            return String::new();
+        }
+    };
+
+    // the vector store cannot filter on payload, so over-fetch and filter here
+    let p = PointsSearchParams {
+        vector,
+        limit: 30,
+    };
+    let points = match search_points(RELEASES_COLLECTION, &p).await {
+        Ok(sp) => sp,
+        Err(e) => {
+            log::error!("Vector search returns error: {}", e);
+            return String::new();
+        }
+    };
+
+    points
+        .iter()
+        .filter_map(|p| p.payload.as_ref())
+        .filter(|payload| payload.get("version").and_then(|v| v.as_str()) == Some(version))
+        .filter_map(|payload| payload.get("text").and_then(|t| t.as_str()))
+        .take(5)
+        .filter_map(guards::take_payload)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
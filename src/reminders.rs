@@ -0,0 +1,252 @@
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::{ del, get };
+
+use crate::{
+    analytics::now_secs,
+    chat_id,
+    encryption,
+    error::{ Error, Result },
+    first_x_chars,
+    guards,
+    origin,
+    webhooks,
+};
+
+/// The due reminders of every chat, soonest first, for the scheduler to deliver.
+const DUE_KEY: &str = "reminders:due";
+/// Pending reminders a chat may have.
+const MAX_PER_CHAT: usize = 10;
+const MAX_NOTE_CHARS: usize = 500;
+const MIN_DELAY_SECS: u64 = 60;
+const MAX_DELAY_SECS: u64 = 90 * 86400;
+/// Deliveries a due reminder gets before it is dropped, one per run.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// A message to send back into the conversation later, set with `/remind`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Reminder {
+    /// Numbers the pending reminders of the chat, for `/remind cancel <id>`.
+    pub id: u64,
+    pub due_at: u64,
+    /// Sealed while stored.
+    pub note: String,
+    /// The platform adapter the reminder was set from, which should deliver it.
+    pub platform: String,
+}
+
+/// Where a due reminder is kept, one entry of [`DUE_KEY`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Due {
+    chat_id: String,
+    id: u64,
+    due_at: u64,
+    /// Failed deliveries so far.
+    #[serde(default)]
+    attempts: u32,
+}
+
+fn key(chat_id: &str) -> String {
+    format!("reminders:{chat_id}")
+}
+
+fn load(chat_id: &str) -> Vec<Reminder> {
+    get(&key(chat_id))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save(chat_id: &str, reminders: &[Reminder]) {
+    match reminders.is_empty() {
+        true => {
+            del(&key(chat_id));
+        }
+        false => guards::set_capped(&key(chat_id), serde_json::to_value(reminders).unwrap_or_default(), None),
+    }
+}
+
+fn load_due() -> Vec<Due> {
+    get(DUE_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_due(due: &[Due]) {
+    guards::set_capped(DUE_KEY, serde_json::to_value(due).unwrap_or_default(), None);
+}
+
+/// Parses durations such as `30m`, `2h`, `1h30m`, `3d` or `1w` into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.trim().to_ascii_lowercase().chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            's' | 'm' | 'h' | 'd' | 'w' => {
+                let n = number.parse::<u64>().ok()?;
+                number.clear();
+                let unit = match c {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    _ => 7 * 86400,
+                };
+                total = total.checked_add(n.checked_mul(unit)?)?;
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    (number.is_empty() && total > 0).then_some(total)
+}
+
+/// A delay in the largest two units, e.g. `2d 3h` or `45m`.
+pub fn in_words(secs: u64) -> String {
+    let mut left = secs;
+    let mut parts = Vec::new();
+    for (name, size) in [("w", 7 * 86400), ("d", 86400), ("h", 3600), ("m", 60)] {
+        let n = left / size;
+        left %= size;
+        if n > 0 && parts.len() < 2 {
+            parts.push(format!("{n}{name}"));
+        }
+    }
+    match parts.is_empty() {
+        true => "less than a minute".to_string(),
+        false => parts.join(" "),
+    }
+}
+
+/// Schedules the note to come back into the conversation after `delay` seconds.
+pub fn schedule(chat_id: &str, delay: u64, note: &str) -> Result<Reminder> {
+    if chat_id.is_empty() {
+        return Err(Error::InvalidInput("reminders need a conversation".to_string()));
+    }
+    if !(MIN_DELAY_SECS..=MAX_DELAY_SECS).contains(&delay) {
+        return Err(Error::InvalidInput("a reminder is due in 1 minute to 90 days".to_string()));
+    }
+    let note = first_x_chars(note.trim(), MAX_NOTE_CHARS);
+    if note.is_empty() {
+        return Err(Error::InvalidInput("the reminder has no note".to_string()));
+    }
+    let mut reminders = load(chat_id);
+    if reminders.len() >= MAX_PER_CHAT {
+        return Err(Error::InvalidInput(format!("this conversation already has {MAX_PER_CHAT} pending reminders")));
+    }
+
+    let reminder = Reminder {
+        id: reminders
+            .iter()
+            .map(|r| r.id)
+            .max()
+            .unwrap_or_default() + 1,
+        due_at: now_secs() + delay,
+        note: encryption::seal(&note),
+        platform: origin::current().platform,
+    };
+    reminders.push(reminder.clone());
+    save(chat_id, &reminders);
+
+    let mut due = load_due();
+    due.push(Due { chat_id: chat_id.to_string(), id: reminder.id, due_at: reminder.due_at, attempts: 0 });
+    due.sort_by_key(|d| d.due_at);
+    save_due(&due);
+
+    Ok(Reminder { note, ..reminder })
+}
+
+/// The pending reminders of the chat with their notes, soonest first.
+pub fn pending(chat_id: &str) -> Vec<Reminder> {
+    let mut reminders = load(chat_id)
+        .into_iter()
+        .map(|r| Reminder { note: encryption::open(&r.note).unwrap_or_default(), ..r })
+        .collect::<Vec<Reminder>>();
+    reminders.sort_by_key(|r| r.due_at);
+    reminders
+}
+
+/// Cancels a pending reminder, `false` when the chat has none with the id.
+pub fn cancel(chat_id: &str, id: u64) -> bool {
+    let mut reminders = load(chat_id);
+    let before = reminders.len();
+    reminders.retain(|r| r.id != id);
+    if reminders.len() == before {
+        return false;
+    }
+    save(chat_id, &reminders);
+    let mut due = load_due();
+    due.retain(|d| !(d.chat_id == chat_id && d.id == id));
+    save_due(&due);
+    true
+}
+
+/// Cancels every pending reminder of the chat.
+pub fn clear(chat_id: &str) {
+    del(&key(chat_id));
+    let mut due = load_due();
+    due.retain(|d| d.chat_id != chat_id);
+    save_due(&due);
+}
+
+/// Sends the reminders that are due as `reminder.due` webhook events, for the platform adapter
+/// subscribed to them to post into their conversations. Run on every tick of the flow's schedule
+/// and by `?action=deliver_reminders`; reminders are delivered late when it runs late. A reminder
+/// no subscriber received is tried again on the next run, up to [`MAX_ATTEMPTS`] times.
+pub async fn deliver_due() -> Value {
+    let now = now_secs();
+    let (due, later): (Vec<Due>, Vec<Due>) = load_due()
+        .into_iter()
+        .partition(|d| d.due_at <= now);
+    if due.is_empty() {
+        return json!({ "delivered": 0, "retrying": 0, "dropped": 0, "pending": later.len() });
+    }
+    // taken off the list first, so an overlapping run does not deliver them twice
+    save_due(&later);
+
+    let mut delivered = 0;
+    let mut dropped = 0;
+    let mut retry = Vec::new();
+    for d in due {
+        let mut reminders = load(&d.chat_id);
+        let Some(i) = reminders.iter().position(|r| r.id == d.id && r.due_at == d.due_at) else {
+            continue;
+        };
+        let reminder = reminders[i].clone();
+        let note = encryption::open(&reminder.note).unwrap_or_default();
+        let received = webhooks::deliver(webhooks::Event::ReminderDue {
+            conversation_id: d.chat_id.clone(),
+            conversation_name: chat_id::display_name(&d.chat_id),
+            platform: reminder.platform,
+            message: format!("Reminder: {note}"),
+            due_at: reminder.due_at,
+        }).await;
+
+        let attempts = d.attempts + 1;
+        if received || attempts >= MAX_ATTEMPTS {
+            reminders.remove(i);
+            save(&d.chat_id, &reminders);
+        }
+        if received {
+            delivered += 1;
+        } else if attempts >= MAX_ATTEMPTS {
+            log::error!("Dropped reminder {} of {} after {} failed deliveries", d.id, d.chat_id, attempts);
+            dropped += 1;
+        } else {
+            retry.push(Due { attempts, ..d });
+        }
+    }
+
+    let retrying = retry.len();
+    if !retry.is_empty() {
+        // reminders scheduled while delivering are on the list by now
+        let mut due = load_due();
+        due.extend(retry);
+        due.sort_by_key(|d| d.due_at);
+        save_due(&due);
+    }
+    log::info!("Delivered {} reminders, {} to retry, {} dropped", delivered, retrying, dropped);
+    json!({ "delivered": delivered, "retrying": retrying, "dropped": dropped, "pending": later.len() })
+}
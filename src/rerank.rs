@@ -0,0 +1,151 @@
+use llmservice_flows::chat::ChatOptions;
+use serde::Deserialize;
+use serde_json::{ json, Value };
+
+use crate::{ deterministic, error::{ Error, Result }, fetch, first_x_chars, ranking::Ranking, retry, settings::setting, CHAT_MODEL };
+
+/// Passages are cut to this many characters in the rerank prompt.
+const PASSAGE_CHARS: usize = 800;
+
+/// Who scores the retrieved chunks against the question, from the `rerank` setting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reranker {
+    /// The chat model rates every passage from 0 to 10.
+    Llm,
+    /// A Cohere/Jina style `/rerank` endpoint, `rerank_endpoint`.
+    Endpoint(String),
+}
+
+/// One result of a rerank endpoint.
+#[derive(Debug, Deserialize)]
+struct Scored {
+    index: usize,
+    relevance_score: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointResponse {
+    results: Vec<Scored>,
+}
+
+impl Reranker {
+    /// `llm` or `endpoint` (with `rerank_endpoint` set), anything else leaves reranking off.
+    pub fn from_env() -> Option<Self> {
+        match std::env::var("rerank").unwrap_or_default().as_str() {
+            "llm" => Some(Reranker::Llm),
+            "endpoint" =>
+                match std::env::var("rerank_endpoint") {
+                    Ok(url) if !url.is_empty() => Some(Reranker::Endpoint(url)),
+                    _ => {
+                        log::error!("rerank is `endpoint` but rerank_endpoint is not set");
+                        None
+                    }
+                }
+            _ => None,
+        }
+    }
+
+    /// Lets more and weaker candidates through the searches, the reranker picks among them: up
+    /// to `rerank_candidates` (default 15) above `rerank_candidate_threshold` (default 0.6).
+    pub fn widen(&self, mut ranking: Ranking) -> Ranking {
+        let candidates = setting("rerank_candidates", 15usize);
+        let threshold = setting("rerank_candidate_threshold", 0.6f32);
+        ranking.top_k = Some(candidates.max(ranking.top_k()));
+        ranking.threshold = Some(ranking.threshold.map_or(threshold, |t| t.min(threshold)));
+        ranking
+    }
+
+    /// The relevance of each passage to the question, from 0 to 1.
+    pub async fn scores(&self, question: &str, passages: &[String]) -> Result<Vec<f32>> {
+        match self {
+            Reranker::Llm => llm_scores(question, passages).await,
+            Reranker::Endpoint(url) => endpoint_scores(url, question, passages),
+        }
+    }
+
+    /// Keeps the `top_k` passages the reranker finds most relevant, dropping those scored below
+    /// `rerank_min_score` (default 0.5). Returns the indexes kept, best first.
+    pub async fn rerank(&self, question: &str, passages: &[String], top_k: usize) -> Result<Vec<usize>> {
+        let scores = self.scores(question, passages).await?;
+        let min_score = setting("rerank_min_score", 0.5f32);
+
+        let mut kept = (0..passages.len())
+            .filter(|i| scores.get(*i).is_some_and(|s| *s >= min_score))
+            .collect::<Vec<usize>>();
+        kept.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+        kept.truncate(top_k);
+        log::debug!("Reranking kept {} of {} passages", kept.len(), passages.len());
+        Ok(kept)
+    }
+}
+
+async fn llm_scores(question: &str, passages: &[String]) -> Result<Vec<f32>> {
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+
+    let sys_prompt =
+        "You're a relevance judge for a search engine. Rate how well each passage answers the question, from 0 (unrelated) to 10 (fully answers it). Reply with a JSON array of the ratings in the order of the passages and nothing else.";
+    let listed = passages
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("[{}] {}", i + 1, first_x_chars(p, PASSAGE_CHARS).replace('\n', " ")))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let usr_prompt = format!("Question: `{question}`\n\nPassages:\n{listed}");
+
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 4096,
+        max_tokens: Some(128),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let reply = retry
+        ::call("rerank", || llm.chat_completion("rerank", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+    // the model may wrap the array in prose or a code fence
+    let array = match (reply.find('['), reply.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => {
+            return Err(Error::Llm(format!("no ratings in the rerank reply: {}", first_x_chars(&reply, 200))));
+        }
+    };
+    let ratings = serde_json::from_str::<Vec<f32>>(array)?;
+    if ratings.len() != passages.len() {
+        return Err(Error::Llm(format!("got {} ratings for {} passages", ratings.len(), passages.len())));
+    }
+    Ok(
+        ratings
+            .into_iter()
+            .map(|r| r.clamp(0.0, 10.0) / 10.0)
+            .collect()
+    )
+}
+
+fn endpoint_scores(url: &str, question: &str, passages: &[String]) -> Result<Vec<f32>> {
+    let api_key = std::env::var("rerank_api_key").unwrap_or("".to_string());
+    let authorization = format!("Bearer {api_key}");
+    let mut headers = Vec::new();
+    if !api_key.is_empty() {
+        headers.push(("Authorization", authorization.as_str()));
+    }
+    let mut body = json!({ "query": question, "documents": passages, "top_n": passages.len() });
+    if let Ok(model) = std::env::var("rerank_model") {
+        body["model"] = Value::from(model);
+    }
+
+    let res = fetch::post_json(url, &headers, &serde_json::to_vec(&body)?)?;
+    let res = serde_json::from_slice::<EndpointResponse>(&res)?;
+    let mut scores = vec![0.0; passages.len()];
+    for r in res.results {
+        if let Some(s) = scores.get_mut(r.index) {
+            *s = r.relevance_score;
+        }
+    }
+    Ok(scores)
+}
@@ -0,0 +1,41 @@
+use crate::error::{ Error, Result };
+
+/// How an answer is sent back, from the `x-response-format` header (`text`, `json` or `events`),
+/// else from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ResponseFormat {
+    /// The answer with its footers, as HTML text.
+    #[default]
+    Text,
+    /// The answer and its sources, scores and usage as one JSON object. The client renders its
+    /// own disclosure and citations.
+    Json,
+    /// The events of the answer, see [`crate::events::ChatEvent`].
+    EventStream,
+}
+
+impl ResponseFormat {
+    pub fn negotiate(headers: &[(String, String)]) -> Result<Self> {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim())
+        };
+
+        if let Some(format) = header("x-response-format") {
+            return match format.to_ascii_lowercase().as_str() {
+                "text" | "html" => Ok(ResponseFormat::Text),
+                "json" => Ok(ResponseFormat::Json),
+                "events" | "event-stream" => Ok(ResponseFormat::EventStream),
+                _ => Err(Error::InvalidInput(format!("unknown response format `{format}`, use text, json or events"))),
+            };
+        }
+        let accept = header("accept").unwrap_or_default();
+        match (accept.contains("text/event-stream"), accept.contains("application/json")) {
+            (true, _) => Ok(ResponseFormat::EventStream),
+            (false, true) => Ok(ResponseFormat::Json),
+            (false, false) => Ok(ResponseFormat::Text),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use llmservice_flows::chat::ChatOptions;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use store_flows::{ del, get, Expire, ExpireKind };
+
+use crate::{
+    analytics::now_secs,
+    deterministic,
+    encryption,
+    error::{ Error, Result },
+    first_x_chars,
+    guards,
+    history,
+    retention,
+    retry,
+    settings,
+    CHAT_MODEL,
+};
+
+const MAX_SUMMARY_CHARS: usize = 2000;
+
+/// A running summary of a conversation, which outlives its memory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Summary {
+    /// Sealed while stored.
+    text: String,
+    /// QA pairs remembered since the summary was last updated.
+    pending: u64,
+    /// QA pairs remembered since the conversation started.
+    turns: u64,
+    updated_at: u64,
+}
+
+/// A conversation picked up again after its memory expired.
+#[derive(Debug, Clone)]
+pub struct Resumption {
+    pub idle_days: u64,
+    /// What the conversation was about, `None` when it ended before a summary was made.
+    pub summary: Option<String>,
+}
+
+impl Resumption {
+    /// The note shown above the first answer after the break.
+    pub fn note(&self) -> String {
+        let days = match self.idle_days {
+            1 => "1 day".to_string(),
+            n => format!("{n} days"),
+        };
+        match self.summary.is_some() {
+            true => format!("_Resuming after {days}; earlier context may be summarized._"),
+            false => format!("_Resuming after {days}; earlier context has expired._"),
+        }
+    }
+
+    /// The summary in place of the expired QA pairs, for the system prompt.
+    pub fn context(&self) -> String {
+        match &self.summary {
+            Some(s) => format!("\nSummary of the conversation so far: {s}"),
+            None => String::new(),
+        }
+    }
+}
+
+fn summary_key(chat_id: &str) -> String {
+    format!("conversation_summary:{chat_id}")
+}
+
+fn active_key(chat_id: &str) -> String {
+    format!("last_active:{chat_id}")
+}
+
+/// The summary and last activity are kept `conversation_summary_days` (default 180) after the
+/// last message, well past the memory.
+fn expire() -> Option<Expire> {
+    let days = settings::setting("conversation_summary_days", 180i64);
+    Some(Expire { kind: ExpireKind::Ex, value: days * 86400 })
+}
+
+/// Turns after which the summary is given to the model with every question, besides the QA pairs
+/// relevant to it, from `summary_after_turns` (default 10). `0` gives it only when resuming.
+fn after_turns() -> u64 {
+    settings::setting("summary_after_turns", 10)
+}
+
+/// How many QA pairs are folded into the summary at once, `conversation_summary_every` (default
+/// 5). `0` turns summaries off.
+fn every() -> u64 {
+    settings::setting("conversation_summary_every", 5)
+}
+
+fn load(chat_id: &str) -> Summary {
+    get(&summary_key(chat_id))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Whether the chat comes back after being idle longer than the memory retention period. Without
+/// a memory retention rule the memory never expires and nothing is resumed.
+pub fn check(chat_id: &str) -> Option<Resumption> {
+    let cutoff = retention::memory_cutoff()?;
+    let last_active = get(&active_key(chat_id)).and_then(|v| v.as_u64())?;
+    if last_active >= cutoff {
+        return None;
+    }
+
+    let summary = load(chat_id);
+    let text = encryption::open(&summary.text).filter(|t| !t.is_empty());
+    Some(Resumption { idle_days: now_secs().saturating_sub(last_active) / 86400, summary: text })
+}
+
+/// The summary of the chat, whether or not its memory expired.
+pub fn summary(chat_id: &str) -> Option<String> {
+    encryption::open(&load(chat_id).text).filter(|t| !t.is_empty())
+}
+
+/// The rolling summary of a conversation past [`after_turns`], for the system prompt, so what was
+/// said long ago is not lost to the few QA pairs recalled. Empty for shorter conversations.
+pub fn rolling_context(chat_id: &str) -> String {
+    let after = after_turns();
+    let summary = load(chat_id);
+    if after == 0 || summary.turns <= after {
+        return String::new();
+    }
+    match encryption::open(&summary.text).filter(|t| !t.is_empty()) {
+        Some(text) => format!("\nSummary of the earlier conversation: {text}\n"),
+        None => String::new(),
+    }
+}
+
+/// Records that the chat was answered now.
+pub fn touch(chat_id: &str) {
+    guards::set_capped(&active_key(chat_id), json!(now_secs()), expire());
+}
+
+/// Counts a remembered QA pair, folding the latest ones into the summary every
+/// [`every`] pairs.
+pub async fn remembered(chat_id: &str) {
+    let every = every();
+    if every == 0 {
+        return;
+    }
+    let mut summary = load(chat_id);
+    summary.pending += 1;
+    summary.turns += 1;
+    if summary.pending >= every {
+        let previous = encryption::open(&summary.text).unwrap_or_default();
+        let recent = history::load(chat_id);
+        let recent = &recent[recent.len().saturating_sub(summary.pending as usize)..];
+        match summarize(&previous, recent).await {
+            Ok(text) => {
+                summary = Summary { text: encryption::seal(&text), pending: 0, updated_at: now_secs(), ..summary };
+            }
+            // tried again with the next pair
+            Err(e) => log::error!("Cannot update the summary of {}: {}", chat_id, e),
+        }
+    }
+    guards::set_capped(&summary_key(chat_id), serde_json::to_value(&summary).unwrap_or_default(), expire());
+}
+
+async fn summarize(previous: &str, recent: &[String]) -> Result<String> {
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+
+    let sys_prompt =
+        "You keep a short summary of a support conversation: the user's goal, their setup and versions, what was tried and what is still open. Reply with the updated summary in at most 150 words and nothing else.";
+    let usr_prompt = format!(
+        "Summary so far: `{}`\n\nNew exchanges:\n{}",
+        match previous.is_empty() {
+            true => "none",
+            false => previous,
+        },
+        recent.join("\n---\n")
+    );
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 2048,
+        max_tokens: Some(300),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let reply = retry
+        ::call("summary", || llm.chat_completion("summary", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+    Ok(first_x_chars(reply.trim(), MAX_SUMMARY_CHARS))
+}
+
+/// Forgets the summary and activity of the chat.
+pub fn clear(chat_id: &str) {
+    del(&summary_key(chat_id));
+    del(&active_key(chat_id));
+}
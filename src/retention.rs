@@ -0,0 +1,203 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::{ get, Expire, ExpireKind };
+
+use crate::{
+    analytics::now_secs,
+    audit,
+    conversation_ttl,
+    error::{ Error, Result },
+    feedback,
+    guards,
+    history,
+    memory,
+    trace,
+};
+
+const RULES_KEY: &str = "retention_rules";
+/// The chats with data under a memory, history or traces rule and when each was last answered,
+/// least recently active first, for the sweep: the KV store cannot list keys. Kept under the size
+/// limit of the KV store by dropping the least recently active, whose data still expires on its
+/// own.
+const CHATS_KEY: &str = "retention:chats";
+
+/// The data a retention rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Data {
+    /// The QA pairs of the conversation memory.
+    Memory,
+    /// The KV mirror of the latest QA pairs of each chat.
+    History,
+    /// The retrieval traces behind `/why`.
+    Traces,
+    AuditLog,
+    FeedbackVotes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Delete,
+    /// Drops who asked and what, keeping the rest of the entry for the statistics.
+    Anonymize,
+}
+
+/// e.g. `{"data": "audit_log", "action": "anonymize", "after_days": 90}`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RetentionRule {
+    pub data: Data,
+    pub action: Action,
+    pub after_days: u64,
+}
+
+impl RetentionRule {
+    fn cutoff(&self) -> u64 {
+        now_secs().saturating_sub(self.after_days * 86400)
+    }
+}
+
+/// The rules stored with `?action=retention_rules`, else the `retention_rules` setting.
+pub fn load_rules() -> Vec<RetentionRule> {
+    let stored = get(RULES_KEY).or_else(|| {
+        std::env
+            ::var("retention_rules")
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+    });
+
+    match stored {
+        Some(v) =>
+            serde_json::from_value(v).unwrap_or_else(|e| {
+                log::error!("Invalid retention rules: {}", e);
+                Vec::new()
+            }),
+        None => Vec::new(),
+    }
+}
+
+pub fn save_rules(body: &[u8]) -> Result<Vec<RetentionRule>> {
+    let rules: Vec<RetentionRule> = serde_json::from_slice(body)?;
+    for rule in &rules {
+        if rule.after_days == 0 {
+            return Err(Error::InvalidInput("`after_days` must be at least 1".to_string()));
+        }
+        let anonymizable = matches!(rule.data, Data::AuditLog | Data::FeedbackVotes);
+        if rule.action == Action::Anonymize && !anonymizable {
+            return Err(Error::InvalidInput(format!("{:?} can only be deleted", rule.data)));
+        }
+    }
+    guards::set_capped(RULES_KEY, serde_json::to_value(&rules)?, None);
+    Ok(rules)
+}
+
+fn rule_for(data: Data) -> Option<RetentionRule> {
+    load_rules()
+        .into_iter()
+        .filter(|r| r.data == data && r.action == Action::Delete)
+        .min_by_key(|r| r.after_days)
+}
+
+/// The expiry of a KV value written for `data`, so the KV store deletes it once the retention
+/// period has passed without a newer write.
+pub fn expire(data: Data) -> Option<Expire> {
+    rule_for(data).map(|r| Expire {
+        kind: ExpireKind::Ex,
+        value: (r.after_days * 86400) as i64,
+    })
+}
+
+/// Whether `data` is kept per chat, so its chats are tracked for the sweep.
+fn per_chat(data: Data) -> bool {
+    matches!(data, Data::Memory | Data::History | Data::Traces)
+}
+
+fn chats() -> Vec<(String, u64)> {
+    get(CHATS_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_chats(chats: &[(String, u64)]) {
+    guards::set_capped(CHATS_KEY, json!(chats), None);
+}
+
+/// Records that the chat was answered now, for the sweep of its memory, history and trace. Chats
+/// are not tracked while no rule applies to those.
+pub fn touch(chat_id: &str) {
+    if !load_rules().iter().any(|r| per_chat(r.data) && r.action == Action::Delete) {
+        return;
+    }
+    let mut chats = chats();
+    chats.retain(|(c, _)| c != chat_id);
+    chats.push((chat_id.to_string(), now_secs()));
+    save_chats(&chats);
+}
+
+/// Deletes the per-chat data of the tracked chats past the rule, returning how much there was.
+/// Memory chunks are dated, the history mirror and the trace are dated by the last answer of
+/// their chat.
+fn apply_per_chat(rule: &RetentionRule, chats: &[(String, u64)], dry_run: bool) -> usize {
+    let cutoff = rule.cutoff();
+    chats
+        .iter()
+        .map(|(chat_id, at)| match rule.data {
+            Data::Memory => memory::apply_retention(chat_id, cutoff, dry_run),
+            Data::History if *at < cutoff => history::apply_retention(chat_id, dry_run),
+            Data::Traces if *at < cutoff => trace::apply_retention(chat_id, dry_run),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// QA pairs stored before this Unix time are past the memory retention period.
+pub fn memory_cutoff() -> Option<u64> {
+    rule_for(Data::Memory).map(|r| r.cutoff())
+}
+
+/// Applies the rules and clears the conversations idle past their TTL, or with `dry_run` only
+/// reports what they would change. Meant to be run daily by a scheduler hitting
+/// `?action=retention_sweep`.
+pub fn sweep(collection_name: &str, dry_run: bool) -> Value {
+    let rules = load_rules();
+    let chats = chats();
+    let report = rules
+        .iter()
+        .map(|rule| {
+            let cutoff = rule.cutoff();
+            let anonymize = rule.action == Action::Anonymize;
+            let (affected, enforced) = match rule.data {
+                Data::AuditLog => (audit::apply_retention(cutoff, anonymize, dry_run), "sweep"),
+                Data::FeedbackVotes =>
+                    (feedback::apply_retention(collection_name, cutoff, anonymize, dry_run), "sweep"),
+                // chats gone from the list are left to the expiry set on write
+                Data::Memory | Data::History | Data::Traces =>
+                    (apply_per_chat(rule, &chats, dry_run), "sweep and expiry set on write"),
+            };
+            json!({
+                "data": rule.data,
+                "action": rule.action,
+                "after_days": rule.after_days,
+                "affected": affected,
+                "enforced_by": enforced,
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    if !dry_run {
+        // chats idle past every per-chat rule have nothing left to sweep
+        let oldest = rules
+            .iter()
+            .filter(|r| per_chat(r.data) && r.action == Action::Delete)
+            .map(|r| r.cutoff())
+            .min();
+        let kept = chats
+            .into_iter()
+            .filter(|(_, at)| oldest.is_some_and(|cutoff| *at >= cutoff))
+            .collect::<Vec<(String, u64)>>();
+        save_chats(&kept);
+        log::info!("Retention sweep done: {}", Value::from(report.clone()));
+    }
+    json!({ "dry_run": dry_run, "rules": report, "conversations": conversation_ttl::sweep(dry_run) })
+}
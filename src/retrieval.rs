@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{ collection_config::{ self, CollectionConfig }, error::{ Error, Result }, settings::optional };
+
+const MAX_K: usize = 20;
+const MAX_COLLECTIONS: usize = 5;
+/// Both searches count the same.
+pub const DEFAULT_HYDE_WEIGHT: f32 = 0.5;
+
+/// How many chunks a search keeps unless overridden, `retrieval_limit` or 5.
+pub fn default_limit() -> usize {
+    optional("retrieval_limit")
+        .filter(|k| (1..=MAX_K).contains(k))
+        .unwrap_or(5)
+}
+
+/// The factors the scores of the question search and of the hypothetical answer search are
+/// multiplied by to rank the chunks found, for a weight of the hypothetical answer search from 0 to 1: at `0.5` both keep
+/// their scores, towards `0` the hypothetical answer scores shrink and towards `1` the question
+/// ones, a factor of 0 skipping that search.
+pub fn search_factors(hyde_weight: f32) -> (f32, f32) {
+    let w = hyde_weight.clamp(0.0, 1.0);
+    ((2.0 * (1.0 - w)).min(1.0), (2.0 * w).min(1.0))
+}
+
+/// The score threshold of collections without their own and of the relevance check of the
+/// question, `score_threshold` or 0.75.
+pub fn default_score_threshold() -> f32 {
+    optional("score_threshold")
+        .filter(|t| (0.0..=1.0).contains(t))
+        .unwrap_or(0.75)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// The question and a hypothetical answer are both searched, the default pipeline.
+    #[default]
+    Hybrid,
+    /// Only the question is searched.
+    Vector,
+    /// The knowledge base is not searched, the answer draws on the conversation memory only.
+    MemoryOnly,
+}
+
+/// How the knowledge base is searched for one request, given by advanced clients and the eval
+/// harness as a JSON object in the `retrieval` query parameter or the `x-retrieval` header.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retrieval {
+    pub strategy: Strategy,
+    /// How many chunks each search keeps, [`default_limit`] when unset.
+    pub k: Option<usize>,
+    /// Replaces the score threshold of the collections.
+    pub threshold: Option<f32>,
+    /// Searched instead of `collection_name`.
+    pub collections: Vec<String>,
+    /// Caps the knowledge base context given to the model, `max_context_chars` when unset.
+    pub max_context_chars: Option<usize>,
+    /// Replaces the `hyde_weight` of the collections, see [`search_factors`].
+    pub hyde_weight: Option<f32>,
+}
+
+impl Retrieval {
+    pub fn from_request(qry: &HashMap<String, Value>, headers: &[(String, String)]) -> Result<Self> {
+        let raw = match qry.get("retrieval") {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(v) => Some(v.to_string()),
+            None =>
+                headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case("x-retrieval"))
+                    .map(|(_, v)| v.clone()),
+        };
+        let mut retrieval = match raw {
+            Some(raw) if !raw.trim().is_empty() => serde_json::from_str::<Retrieval>(&raw)?,
+            _ => Self::default(),
+        };
+
+        // single parameters in their own headers, for clients not sending JSON
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim())
+        };
+        let parse = |name: &str| -> Result<Option<f64>> {
+            match header(name) {
+                Some(v) => v.parse().map(Some).map_err(|_| Error::InvalidInput(format!("`{name}` must be a number"))),
+                None => Ok(None),
+            }
+        };
+        if let Some(k) = parse("x-retrieval-limit")? {
+            retrieval.k = Some(k as usize);
+        }
+        if let Some(t) = parse("x-score-threshold")? {
+            retrieval.threshold = Some(t as f32);
+        }
+        if let Some(c) = parse("x-max-context-chars")? {
+            retrieval.max_context_chars = Some(c as usize);
+        }
+        if retrieval.is_default() {
+            return Ok(retrieval);
+        }
+
+        if retrieval.k.is_some_and(|k| k == 0 || k > MAX_K) {
+            return Err(Error::InvalidInput(format!("`k` must be between 1 and {MAX_K}")));
+        }
+        if retrieval.threshold.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+            return Err(Error::InvalidInput("`threshold` must be between 0 and 1".to_string()));
+        }
+        if retrieval.hyde_weight.is_some_and(|w| !(0.0..=1.0).contains(&w)) {
+            return Err(Error::InvalidInput("`hyde_weight` must be between 0 and 1".to_string()));
+        }
+        if retrieval.max_context_chars == Some(0) {
+            return Err(Error::InvalidInput("`max_context_chars` must be positive".to_string()));
+        }
+        if retrieval.collections.len() > MAX_COLLECTIONS {
+            return Err(Error::InvalidInput(format!("at most {MAX_COLLECTIONS} `collections` can be searched")));
+        }
+        if retrieval.collections.iter().any(|c| c.is_empty() || c == "ephemeral") {
+            return Err(Error::InvalidInput("`collections` must name knowledge base collections".to_string()));
+        }
+        // checked before anything is searched, a missing collection may be created on its search
+        let allowed = collection_config::allowed(&std::env::var("collection_name").unwrap_or("".to_string()));
+        if let Some(c) = retrieval.collections.iter().find(|c| !allowed.contains(c)) {
+            return Err(Error::InvalidInput(format!("the collection `{c}` is not in `allowed_collections`")));
+        }
+        log::info!("Retrieval overridden for the request: {:?}", retrieval);
+        Ok(retrieval)
+    }
+
+    fn is_default(&self) -> bool {
+        self.strategy == Strategy::Hybrid &&
+            self.k.is_none() &&
+            self.threshold.is_none() &&
+            self.collections.is_empty() &&
+            self.max_context_chars.is_none() &&
+            self.hyde_weight.is_none()
+    }
+
+    /// The weight of the hypothetical answer search in the collection: the override, else the
+    /// collection's `hyde_weight`, else [`DEFAULT_HYDE_WEIGHT`]. The `vector` strategy searches the
+    /// question only.
+    pub fn hyde_weight(&self, collection_name: &str) -> f32 {
+        match self.strategy {
+            Strategy::Vector => 0.0,
+            _ =>
+                self.hyde_weight
+                    .or_else(|| CollectionConfig::load(collection_name).hyde_weight)
+                    .unwrap_or(DEFAULT_HYDE_WEIGHT)
+                    .clamp(0.0, 1.0),
+        }
+    }
+
+    /// The score threshold of the relevance check of the question.
+    pub fn relevance_threshold(&self) -> f32 {
+        self.threshold.unwrap_or_else(default_score_threshold)
+    }
+
+    /// The most characters of knowledge base context, unlimited unless overridden or set with
+    /// `max_context_chars`.
+    pub fn max_context_chars(&self) -> Option<usize> {
+        self.max_context_chars.or_else(|| optional("max_context_chars").filter(|c| *c > 0))
+    }
+
+    /// The collections to search, `default` unless overridden.
+    pub fn collections(&self, default: &str) -> Vec<String> {
+        match self.collections.is_empty() {
+            true => vec![default.to_string()],
+            false => self.collections.clone(),
+        }
+    }
+}
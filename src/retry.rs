@@ -0,0 +1,177 @@
+use std::{ future::Future, time::{ Duration, Instant } };
+
+use llmservice_flows::LLMServiceFlows;
+use openai_flows::OpenAIFlows;
+use rand::Rng;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use store_flows::get;
+
+use crate::{ analytics::now_secs, guards, loadtest, settings, telemetry };
+
+const METRICS_KEY: &str = "retry_metrics";
+
+/// How a model call is retried: up to `attempts` retries after the first try, waiting
+/// `base_delay_ms` doubled on every retry up to `max_delay_ms`, with up to 25% random jitter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 8000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The policy of a call: the `retry_attempts`, `retry_base_delay_ms` and `retry_max_delay_ms`
+    /// settings, overridden by the entry for `call` in the `retry_overrides` JSON object, e.g.
+    /// `{"hypo_answer": {"attempts": 0}}`.
+    pub fn for_call(call: &str) -> Self {
+        let setting = settings::optional::<u64>;
+        let defaults = Self::default();
+        let mut policy = Self {
+            attempts: setting("retry_attempts").map(|n| n as u32).unwrap_or(defaults.attempts),
+            base_delay_ms: setting("retry_base_delay_ms").unwrap_or(defaults.base_delay_ms),
+            max_delay_ms: setting("retry_max_delay_ms").unwrap_or(defaults.max_delay_ms),
+        };
+
+        let overrides = std::env
+            ::var("retry_overrides")
+            .ok()
+            .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+            .and_then(|v| v.get(call).and_then(|o| o.as_object()).cloned());
+        if let Some(o) = overrides {
+            if let Some(n) = o.get("attempts").and_then(|n| n.as_u64()) {
+                policy.attempts = n as u32;
+            }
+            if let Some(n) = o.get("base_delay_ms").and_then(|n| n.as_u64()) {
+                policy.base_delay_ms = n;
+            }
+            if let Some(n) = o.get("max_delay_ms").and_then(|n| n.as_u64()) {
+                policy.max_delay_ms = n;
+            }
+        }
+
+        policy
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_delay_ms
+            .saturating_mul(1u64 << retry.min(16))
+            .min(self.max_delay_ms);
+        let jitter = rand::thread_rng().gen_range(0..=backoff / 4);
+        Duration::from_millis(backoff + jitter)
+    }
+}
+
+/// An OpenAI client without the platform's own retries, which `call` replaces.
+pub fn openai_client() -> OpenAIFlows {
+    let mut openai = OpenAIFlows::new();
+    openai.set_retry_times(0);
+    openai
+}
+
+/// An LLM service client without the platform's own retries, which `call` replaces.
+pub fn llm_client<'a>(endpoint: &'a str, api_key: &'a str) -> LLMServiceFlows<'a> {
+    let mut llm = LLMServiceFlows::new(endpoint);
+    llm.set_api_key(api_key);
+    llm.set_retry_times(0);
+    llm
+}
+
+/// Runs a model call under the policy of `call`, retrying every error with backoff. Calls that
+/// needed retries or gave up are counted in the retry metrics, and every call is timed for the
+/// request telemetry.
+pub async fn call<T, F, Fut>(call: &str, f: F) -> Result<T, String>
+    where F: FnMut() -> Fut, Fut: Future<Output = Result<T, String>>
+{
+    if loadtest::stubs_model_call() {
+        return Err(format!("{call} is stubbed by the load test"));
+    }
+    let started = Instant::now();
+    let result = call_with_retries(call, f).await;
+    telemetry::record_call(call, started.elapsed());
+    result
+}
+
+/// Runs a call once, timed like [`call`], for calls on the request path that must not wait out
+/// a backoff.
+pub async fn call_once<T, F, Fut>(call: &str, f: F) -> Result<T, String>
+    where F: FnOnce() -> Fut, Fut: Future<Output = Result<T, String>>
+{
+    let started = Instant::now();
+    let result = f().await;
+    telemetry::record_call(call, started.elapsed());
+    result
+}
+
+async fn call_with_retries<T, F, Fut>(call: &str, mut f: F) -> Result<T, String>
+    where F: FnMut() -> Fut, Fut: Future<Output = Result<T, String>>
+{
+    let policy = RetryPolicy::for_call(call);
+
+    let mut retry = 0;
+    loop {
+        match f().await {
+            Ok(r) => {
+                if retry > 0 {
+                    record(call, retry, None);
+                }
+                return Ok(r);
+            }
+            Err(e) if retry < policy.attempts => {
+                let delay = policy.delay(retry);
+                log::warn!("{} failed ({}), retrying in {}ms", call, e, delay.as_millis());
+                std::thread::sleep(delay);
+                retry += 1;
+            }
+            Err(e) => {
+                log::error!("{} failed after {} retries: {}", call, retry, e);
+                record(call, retry, Some(&e));
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Adds one call to the metrics of `call`: `retried` calls that succeeded after retrying,
+/// `retries` made in total, calls that `gave_up` and the last error.
+fn record(call: &str, retries: u32, error: Option<&str>) {
+    let mut metrics = get(METRICS_KEY)
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default();
+    let mut entry = metrics
+        .get(call)
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default();
+
+    let count = |m: &Map<String, Value>, k: &str| m.get(k).and_then(|v| v.as_u64()).unwrap_or_default();
+    entry.insert("retries".to_string(), json!(count(&entry, "retries") + (retries as u64)));
+    match error {
+        Some(e) => {
+            entry.insert("gave_up".to_string(), json!(count(&entry, "gave_up") + 1));
+            entry.insert("last_error".to_string(), json!(guards::truncate_bytes(e, 256)));
+            entry.insert("last_error_at".to_string(), json!(now_secs()));
+        }
+        None => {
+            entry.insert("retried".to_string(), json!(count(&entry, "retried") + 1));
+        }
+    }
+    metrics.insert(call.to_string(), Value::Object(entry));
+
+    guards::set_capped(METRICS_KEY, Value::Object(metrics), None);
+}
+
+/// The retry metrics of every call, for `?action=retry_metrics`.
+pub fn metrics() -> Value {
+    get(METRICS_KEY).unwrap_or(json!({}))
+}
@@ -0,0 +1,85 @@
+use llmservice_flows::chat::ChatOptions;
+
+use crate::{ deterministic, error::{ Error, Result }, first_x_chars, history, retry, CHAT_MODEL };
+
+/// The latest QA pairs shown to the model to resolve the follow-up.
+const TURNS: usize = 2;
+/// Pronouns that, leading the question, point back at something said before.
+const LEADING: &[&str] = &["it", "its", "this", "that", "these", "those", "they", "them", "their", "same"];
+/// Openings of a question that lean on an earlier turn.
+const OPENINGS: &[&str] = &[
+    "what about",
+    "how about",
+    "and ",
+    "why not",
+    "what if",
+    "the first",
+    "the second",
+    "the third",
+    "the last",
+    "the former",
+    "the latter",
+    "the other",
+    "the same",
+];
+
+/// Whether follow-up questions are rewritten into standalone ones before retrieval, from
+/// `rewrite_followups` (`true` by default).
+pub fn enabled() -> bool {
+    std::env::var("rewrite_followups").unwrap_or_default() != "false"
+}
+
+/// Whether the question leans on an earlier turn: it starts with a pronoun, or like "what about"
+/// or "the second". A pronoun further in, like "how do I install it", is left to the search.
+fn looks_like_follow_up(question: &str) -> bool {
+    let lower = question.trim().to_lowercase();
+    let first = lower.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default();
+    LEADING.contains(&first) || OPENINGS.iter().any(|p| lower.starts_with(p))
+}
+
+/// The follow-up question rewritten into a standalone one with the referents of the last turns of
+/// the chat, for the search. `None` when there is no earlier turn, the question does not start like
+/// a follow-up or the model keeps it as it is.
+pub async fn standalone(question: &str, chat_id: &str) -> Result<Option<String>> {
+    if !enabled() || !looks_like_follow_up(question) {
+        return Ok(None);
+    }
+    let turns = history::load(chat_id);
+    if turns.is_empty() {
+        return Ok(None);
+    }
+    let earlier = turns[turns.len().saturating_sub(TURNS)..]
+        .iter()
+        .map(|qa| first_x_chars(qa, 1200))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    let endpoint = std::env::var("llm_endpoint").unwrap_or("".to_string());
+    let api_key = std::env::var("LLM_API_KEY").unwrap_or("".to_string());
+    let llm = retry::llm_client(&endpoint, &api_key);
+    let sys_prompt =
+        "You rewrite follow-up questions for a search engine. Using the earlier conversation, replace pronouns and references like \"the second option\" with what they stand for, so the question can be understood on its own. Keep names, versions and error messages. If the question already stands alone, repeat it unchanged. Reply with the question only.";
+    let usr_prompt = format!("Earlier conversation:\n{earlier}\n\nFollow-up question: `{question}`");
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 2048,
+        max_tokens: Some(128),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let reply = retry
+        ::call("rewrite", || llm.chat_completion("rewrite-question", &usr_prompt, &co)).await
+        .map_err(Error::Llm)?
+        .choice;
+    let rewritten = first_x_chars(reply.lines().next().unwrap_or_default().trim().trim_matches(['`', '"']), 500);
+    match rewritten.is_empty() || rewritten.eq_ignore_ascii_case(question.trim()) {
+        true => Ok(None),
+        false => {
+            log::debug!("Rewrote the follow-up {:?} as {:?}", question, rewritten);
+            Ok(Some(rewritten))
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ api_keys, audit };
+
+/// What a caller may do, each role including the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Asks questions and runs the chat commands.
+    User,
+    /// Watches and curates: analytics, the audit log, the FAQ, quarantine, maintenance mode.
+    Operator,
+    /// Changes what the bot answers from and how: ingestion, collection settings, retention.
+    Admin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Who sent a request and the role they hold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub name: String,
+    pub role: Role,
+    /// Whether the caller sent the admin token or an enabled API key.
+    pub authenticated: bool,
+}
+
+impl Caller {
+    /// The `admin_token` in `x-admin-token` makes an admin, an enabled API key in `x-api-key` the
+    /// role it is bound to, anyone else is a user.
+    pub fn of(headers: &[(String, String)]) -> Self {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+
+        let admin_token = std::env::var("admin_token").unwrap_or("".to_string());
+        if !admin_token.is_empty() && header("x-admin-token").is_some_and(|t| api_keys::same_secret(&admin_token, t)) {
+            return Caller { name: "admin_token".to_string(), role: Role::Admin, authenticated: true };
+        }
+        if let Some(key) = header("x-api-key").filter(|k| !k.is_empty()) {
+            match api_keys::find(key) {
+                Some(k) => {
+                    return Caller { name: k.name, role: k.role, authenticated: true };
+                }
+                None => log::warn!("Unknown or disabled API key, serving the request as a user"),
+            }
+        }
+        Caller { name: "anonymous".to_string(), role: Role::User, authenticated: false }
+    }
+
+    /// Checks the caller holds `required` for `what`, a command or an action. A refusal is
+    /// audited and comes back as the message to reply with a 403.
+    pub fn authorize(&self, what: &str, required: Role, chat_id: &str) -> Result<(), String> {
+        if self.role >= required {
+            return Ok(());
+        }
+        log::warn!("{} ({}) was refused {}, which needs {}", self.name, self.role.name(), what, required.name());
+        audit::denied(chat_id, &self.name, self.role, what, required);
+        Err(format!("Forbidden: `{}` requires the {} role.", what, required.name()))
+    }
+}
+
+/// The role each `?action=` route requires. Routes not listed require [`Role::Admin`], so a new
+/// route is closed until it is given a role here.
+const ACTION_ROLES: &[(&str, Role)] = &[
+    // the published FAQ is meant for end users, the API description for client generators, and
+    // users rate the answers they got
+    ("faq", Role::User),
+    ("openapi", Role::User),
+    ("feedback", Role::User),
+    ("analytics", Role::Operator),
+    ("analytics_rollup", Role::Operator),
+    ("deliver_reminders", Role::Operator),
+    ("audit", Role::Operator),
+    ("digest", Role::Operator),
+    ("drift_check", Role::Operator),
+    ("embedding_cache", Role::Operator),
+    ("metrics", Role::Operator),
+    ("export", Role::Operator),
+    ("feedback_export", Role::Operator),
+    ("faq_generate", Role::Operator),
+    ("flush_upserts", Role::Operator),
+    ("graphql", Role::Operator),
+    ("maintenance", Role::Operator),
+    ("quarantine", Role::Operator),
+    ("retry_metrics", Role::Operator),
+    ("search", Role::Operator),
+    ("stats", Role::Operator),
+];
+
+pub fn required(action: &str) -> Role {
+    ACTION_ROLES.iter()
+        .find(|(a, _)| *a == action)
+        .map(|(_, r)| *r)
+        .unwrap_or(Role::Admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_include_the_ones_below() {
+        assert!(Role::Admin > Role::Operator);
+        assert!(Role::Operator > Role::User);
+    }
+
+    #[test]
+    fn unlisted_actions_require_admin() {
+        assert_eq!(required("faq"), Role::User);
+        assert_eq!(required("audit"), Role::Operator);
+        assert_eq!(required("ingest"), Role::Admin);
+        assert_eq!(required("a_new_route"), Role::Admin);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::CHAT_MODEL;
+
+/// Words that ask for reasoning rather than a fact.
+const REASONING_MARKERS: &[&str] = &[
+    "why",
+    "explain",
+    "compare",
+    "difference",
+    "versus",
+    "vs",
+    "trade-off",
+    "tradeoff",
+    "pros and cons",
+    "step by step",
+    "troubleshoot",
+    "debug",
+    "design",
+    "should i",
+    "what happens if",
+    "what if",
+    "best way",
+];
+/// Questions longer than this many words are taken as multi-step.
+const LONG_QUESTION_WORDS: usize = 30;
+
+/// How hard a question is, deciding the model that answers it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    /// A short factual question, answered by `simple_chat_model`.
+    Simple,
+    /// Multi-step reasoning, comparisons, troubleshooting or several questions at once, answered
+    /// by `complex_chat_model`.
+    Complex,
+}
+
+impl Tier {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tier::Simple => "simple",
+            Tier::Complex => "complex",
+        }
+    }
+
+    /// The model of the tier, the default chat model when its setting is unset.
+    pub fn chat_model(&self) -> String {
+        let setting = match self {
+            Tier::Simple => "simple_chat_model",
+            Tier::Complex => "complex_chat_model",
+        };
+        match std::env::var(setting).unwrap_or("".to_string()) {
+            m if m.is_empty() => CHAT_MODEL.to_string(),
+            m => m,
+        }
+    }
+}
+
+/// Whether questions are routed by complexity, with `model_routing` set to `complexity`.
+pub fn enabled() -> bool {
+    std::env::var("model_routing").unwrap_or_default() == "complexity"
+}
+
+/// Classifies the question without a model call: long questions, several questions, code and
+/// reasoning words make it complex.
+pub fn classify(question: &str) -> Tier {
+    let lower = question.to_lowercase();
+    let words = lower
+        .split(|c: char| !c.is_alphanumeric() && c != '-')
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>();
+
+    let long = words.len() > LONG_QUESTION_WORDS;
+    let several = question.matches('?').count() > 1;
+    let code = question.contains("```") || question.lines().count() > 3;
+    let reasoning = REASONING_MARKERS.iter().any(|m| {
+        match m.contains(' ') {
+            true => lower.contains(m),
+            false => words.contains(m),
+        }
+    });
+    match long || several || code || reasoning {
+        true => Tier::Complex,
+        false => Tier::Simple,
+    }
+}
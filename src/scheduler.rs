@@ -0,0 +1,46 @@
+use serde_json::json;
+use store_flows::get;
+
+use crate::{ analytics::now_secs, github_sync, guards, ingest::IngestOptions, reminders, webhooks };
+
+/// The cron of the flow's schedule, `schedule_cron` (default every 5 minutes). Every tick runs
+/// the jobs whose own interval has passed.
+pub fn cron() -> String {
+    std::env::var("schedule_cron").unwrap_or("*/5 * * * *".to_string())
+}
+
+fn last_run_key(job: &str) -> String {
+    format!("scheduler:{job}")
+}
+
+/// Whether the job last ran at least `every_secs` ago, taking the run when it did.
+fn due(job: &str, every_secs: u64) -> bool {
+    let last = get(&last_run_key(job))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    let now = now_secs();
+    if now.saturating_sub(last) < every_secs {
+        return false;
+    }
+    guards::set_capped(&last_run_key(job), json!(now), None);
+    true
+}
+
+/// Runs the jobs that are due, on every tick of the schedule.
+pub async fn tick() {
+    // delivery logs what it sent
+    reminders::deliver_due().await;
+    let report = webhooks::deliver_pending().await;
+    if report["delivered"].as_u64().unwrap_or_default() + report["dropped"].as_u64().unwrap_or_default() > 0 {
+        log::info!("Retried the pending webhook deliveries: {}", report);
+    }
+
+    let interval = github_sync::interval_minutes();
+    if interval > 0 && github_sync::Source::configured().is_ok() && due("github_sync", interval * 60) {
+        let collection = std::env::var("collection_name").unwrap_or("".to_string());
+        match github_sync::run(&collection, &IngestOptions::default(), false).await {
+            Ok(report) => log::info!("Scheduled GitHub sync of {}: {}", collection, report),
+            Err(e) => log::error!("Scheduled GitHub sync of {} failed: {}", collection, e),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use store_flows::{ del, get, Expire, ExpireKind };
+
+use crate::{ analytics::{ self, now_secs }, guards, settings };
+
+/// Messages of a chat whose scores are kept.
+const KEPT: usize = 10;
+/// The latest messages averaged for the trend.
+const TREND: usize = 3;
+const FLAGGED_KEY: &str = "sentiment:flagged";
+/// Flagged conversations kept for review.
+const MAX_FLAGGED: usize = 200;
+
+// Words such as "failed" or "broken" describe the problems users ask about, not the bot, so
+// only complaints about the answers count.
+const NEGATIVE: &[&str] = &[
+    "wrong", "useless", "frustrated", "frustrating", "annoying", "annoyed", "terrible", "awful",
+    "hate", "ridiculous", "waste", "stupid", "nonsense", "incorrect", "ugh", "wtf", "confusing",
+    "confused", "worse", "unhelpful",
+];
+const NEGATIVE_PHRASES: &[&str] = &[
+    "your answer doesn't work", "your answer does not work", "that didn't help", "that did not help",
+    "that's not", "not what i asked", "i already", "you already", "makes no sense", "not helpful",
+];
+const POSITIVE: &[&str] = &[
+    "thanks", "thank", "great", "perfect", "helpful", "awesome", "nice", "solved", "works", "worked",
+    "excellent", "appreciate",
+];
+
+/// The scores of the latest messages of a chat, from -1 (angry) to 1 (pleased).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Mood {
+    scores: Vec<f32>,
+    /// Whether the conversation was flagged since the scores started, so it is flagged once.
+    flagged: bool,
+}
+
+/// A conversation flagged for review, its user getting frustrated.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Flagged {
+    pub chat_id: String,
+    pub at: u64,
+    /// The average score of the latest messages when it was flagged.
+    pub trend: f32,
+}
+
+fn key(chat_id: &str) -> String {
+    format!("sentiment:{chat_id}")
+}
+
+/// How negative the latest messages have to be on average to adjust the prompt and flag the
+/// conversation, from `frustration_threshold` (`-0.3` by default, `-1` or below turns it off).
+fn threshold() -> f32 {
+    settings::setting("frustration_threshold", -0.3)
+}
+
+/// The sentiment of a message from its words, its punctuation and shouting, from -1 to 1.
+pub fn score(text: &str) -> f32 {
+    let lower = text.to_lowercase();
+    let words = lower
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>();
+    let mut score = 0.0;
+    score -= words
+        .iter()
+        .filter(|w| NEGATIVE.contains(w))
+        .count() as f32;
+    score -= NEGATIVE_PHRASES.iter()
+        .filter(|p| lower.contains(*p))
+        .count() as f32;
+    score += words
+        .iter()
+        .filter(|w| POSITIVE.contains(w))
+        .count() as f32;
+    if text.contains("!!") || text.contains("??") || text.contains("?!") {
+        score -= 1.0;
+    }
+    let letters = text
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .collect::<Vec<char>>();
+    if letters.len() >= 8 && letters.iter().all(|c| c.is_uppercase()) {
+        score -= 1.0;
+    }
+    (score / 3.0).clamp(-1.0, 1.0)
+}
+
+/// Scores the message into the mood of the chat and tells whether the user is getting
+/// frustrated: the latest messages, at least two, average at or below the threshold and the last
+/// is negative.
+/// The conversation is flagged for review the first time.
+pub fn observe(chat_id: &str, text: &str) -> bool {
+    let mut mood = get(&key(chat_id))
+        .and_then(|v| serde_json::from_value::<Mood>(v).ok())
+        .unwrap_or_default();
+    let score = score(text);
+    mood.scores.push(score);
+    if mood.scores.len() > KEPT {
+        mood.scores.drain(..mood.scores.len() - KEPT);
+    }
+    let latest = &mood.scores[mood.scores.len().saturating_sub(TREND)..];
+    let trend = latest.iter().sum::<f32>() / (latest.len() as f32);
+    let frustrated = threshold() > -1.0 && latest.len() >= 2 && score < 0.0 && trend <= threshold();
+
+    if frustrated && !mood.flagged {
+        mood.flagged = true;
+        flag(chat_id, trend);
+    }
+    guards::set_capped(
+        &key(chat_id),
+        serde_json::to_value(&mood).unwrap_or_default(),
+        Some(Expire { kind: ExpireKind::Ex, value: 30 * 86400 })
+    );
+    frustrated
+}
+
+fn flag(chat_id: &str, trend: f32) {
+    log::info!("Flagged {} for review, the user seems frustrated ({:.2})", chat_id, trend);
+    analytics::record_frustration();
+    let mut list = flagged();
+    list.retain(|f| f.chat_id != chat_id);
+    list.push(Flagged { chat_id: chat_id.to_string(), at: now_secs(), trend });
+    if list.len() > MAX_FLAGGED {
+        list.drain(..list.len() - MAX_FLAGGED);
+    }
+    guards::set_capped(FLAGGED_KEY, json!(list), None);
+}
+
+/// The conversations flagged for review, oldest first.
+pub fn flagged() -> Vec<Flagged> {
+    get(FLAGGED_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// The instruction added to the system prompt while the user is frustrated.
+pub const CAREFUL: &str =
+    "The user seems frustrated with the previous answers. Briefly acknowledge it and apologize if an earlier answer was wrong, then be especially careful and precise: check the answer against the context, say plainly what you are not sure of, and give concrete steps the user can verify.";
+
+/// Starts the mood of the chat over, e.g. on `/new`. A flag stays for review.
+pub fn clear(chat_id: &str) {
+    del(&key(chat_id));
+}
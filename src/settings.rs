@@ -0,0 +1,14 @@
+use std::str::FromStr;
+
+/// The env var `name` parsed, `None` when unset or unparsable.
+pub fn optional<T: FromStr>(name: &str) -> Option<T> {
+    std::env
+        ::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+}
+
+/// The env var `name` parsed, else `default`.
+pub fn setting<T: FromStr>(name: &str, default: T) -> T {
+    optional(name).unwrap_or(default)
+}
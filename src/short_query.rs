@@ -0,0 +1,114 @@
+use llmservice_flows::{ chat::ChatOptions, LLMServiceFlows };
+use serde_json::Value;
+use store_flows::get;
+
+use crate::{ deterministic, retry, CHAT_MODEL };
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "about", "at", "be", "by", "can", "do", "does", "for", "from", "how",
+    "i", "in", "is", "it", "me", "my", "of", "on", "or", "please", "tell", "the", "this", "that",
+    "to", "what", "whats", "when", "where", "which", "who", "why", "with", "you", "your",
+];
+
+/// What to do with questions of 1-3 meaningful words, from `short_query_mode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShortQueryMode {
+    /// Reply with one clarifying question instead of searching.
+    Clarify,
+    /// Expand the query with the glossary and the conversation topics before searching.
+    Expand,
+    Off,
+}
+
+impl ShortQueryMode {
+    pub fn from_env() -> Self {
+        match std::env::var("short_query_mode").unwrap_or_default().as_str() {
+            "clarify" => ShortQueryMode::Clarify,
+            "off" => ShortQueryMode::Off,
+            _ => ShortQueryMode::Expand,
+        }
+    }
+}
+
+/// The words of the question that carry meaning, lowercased.
+pub fn content_words(question: &str) -> Vec<String> {
+    question
+        .split(|c: char| !c.is_alphanumeric() && c != '-' && c != '.')
+        .map(|w| w.trim_matches('.').to_lowercase())
+        .filter(|w| !w.is_empty() && !STOPWORDS.contains(&w.as_str()))
+        .collect()
+}
+
+pub fn is_short(question: &str) -> bool {
+    let words = content_words(question).len();
+    (1..=3).contains(&words)
+}
+
+/// Expands a short query with glossary definitions of its words and the topics of the
+/// conversation so far. The glossary is a JSON object of term to expansion, stored in the KV store
+/// under `glossary` or given in the `glossary` env var.
+pub fn expand(question: &str, conversation_topics: &str) -> String {
+    let glossary = get("glossary")
+        .or_else(|| {
+            std::env
+                ::var("glossary")
+                .ok()
+                .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        })
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default();
+
+    let definitions = content_words(question)
+        .iter()
+        .filter_map(|w| {
+            glossary
+                .iter()
+                .find(|(term, _)| term.to_lowercase() == *w)
+                .and_then(|(term, expansion)| Some(format!("{term}: {}", expansion.as_str()?)))
+        })
+        .collect::<Vec<String>>();
+
+    let mut expanded = question.to_string();
+    if !definitions.is_empty() {
+        expanded = format!("{expanded} ({})", definitions.join("; "));
+    }
+    if !conversation_topics.is_empty() {
+        let topics = conversation_topics.chars().take(300).collect::<String>();
+        expanded = format!("{expanded}, in the context of: {topics}");
+    }
+
+    expanded
+}
+
+/// Asks the LLM for one short clarifying question about an underspecified question.
+pub async fn clarifying_question(
+    question: &str,
+    conversation_topics: &str,
+    chat_id: &str,
+    llm: &LLMServiceFlows<'_>
+) -> Option<String> {
+    let sys_prompt =
+        "You're an assistant for a technical book on Kubernetes. The user sent a very short message. Reply with exactly one short clarifying question that helps you understand what they want to know, and nothing else.";
+    let usr_prompt = format!(
+        "Recent conversation: `{conversation_topics}`\nThe user's message: `{question}`"
+    );
+
+    let co = ChatOptions {
+        model: Some(CHAT_MODEL),
+        restart: true,
+        system_prompt: Some(sys_prompt),
+        token_limit: 512,
+        max_tokens: Some(64),
+        temperature: deterministic::temperature(),
+        ..Default::default()
+    };
+
+    let conversation = format!("clarify-{chat_id}");
+    match retry::call("clarify", || llm.chat_completion(&conversation, &usr_prompt, &co)).await {
+        Ok(r) => Some(r.choice),
+        Err(e) => {
+            log::error!("LLM returns error when asking a clarifying question: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{ collection_config::CollectionConfig, error::Result, first_x_chars, memory, settings, QaPair };
+
+/// Where earlier questions are looked for, from `similar_questions`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// Only the questions of the same chat.
+    Chat,
+    /// The questions of every chat. Each chat now keeps its own memory, so this searches the
+    /// chat's questions only, like [`Scope::Chat`].
+    Global,
+    Off,
+}
+
+impl Scope {
+    pub fn from_env() -> Self {
+        match std::env::var("similar_questions").unwrap_or_default().as_str() {
+            "global" => {
+                log::warn!("similar_questions is `global` but chats no longer share a memory, only the chat is searched");
+                Scope::Global
+            }
+            "off" => Scope::Off,
+            _ => Scope::Chat,
+        }
+    }
+}
+
+/// How similar an earlier question must be, from `similar_question_threshold`. Higher than the
+/// memory threshold, the point is to spot the same question asked again.
+fn threshold() -> f32 {
+    settings::setting("similar_question_threshold", 0.92)
+}
+
+/// Finds an earlier question of the conversation memory close to this one.
+pub async fn find(question: &str, chat_id: &str) -> Result<Option<QaPair>> {
+    let scope = Scope::from_env();
+    if scope == Scope::Off {
+        return Ok(None);
+    }
+
+    let vector = CollectionConfig::load(memory::MEMORY_CONFIG).embed_one(question).await?;
+    // the threshold is exclusive in the memory, close enough for spotting a repeat
+    Ok(
+        memory
+            ::nearest(chat_id, &vector, threshold(), 10)
+            .into_iter()
+            .map(|(pair, _)| pair)
+            .find(|pair| !pair.question.is_empty())
+    )
+}
+
+/// The note put before the answer, quoting the earlier question and the start of its answer.
+pub fn note(earlier: &QaPair, chat_id: &str) -> String {
+    let answer = earlier.text
+        .strip_prefix(&earlier.question)
+        .unwrap_or(&earlier.text)
+        .trim();
+    let excerpt = first_x_chars(&answer.replace('\n', " "), 200);
+    let ellipsis = match excerpt.len() < answer.len() {
+        true => "…",
+        false => "",
+    };
+    let who = match earlier.chat_id == chat_id {
+        true => "You asked something similar before",
+        false => "A similar question was asked before",
+    };
+    format!("> {who}: \"{}\"\n> {excerpt}{ellipsis}", earlier.question.trim())
+}
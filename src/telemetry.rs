@@ -0,0 +1,234 @@
+use std::{ collections::BTreeMap, sync::Mutex, time::{ Duration, Instant } };
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ analytics::now_secs, fetch, guards };
+
+const AGGREGATE_KEY: &str = "telemetry:aggregate";
+const RECENT_KEY: &str = "telemetry:recent";
+/// The latest request records kept for percentiles.
+const MAX_RECENT: usize = 200;
+
+/// The time spent in one stage of a request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StageTime {
+    pub calls: u64,
+    pub ms: u64,
+}
+
+/// The metrics of one chat request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestRecord {
+    pub at: u64,
+    pub total_ms: u64,
+    /// `embedding`, `search` and `llm`, the model calls of every kind added up in `llm`.
+    pub stages: BTreeMap<String, StageTime>,
+    /// Counted with the tokenizer of the chat model.
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub retrieval_scores: Vec<f32>,
+    pub category: String,
+    pub lane: String,
+    pub restart: bool,
+    pub error: bool,
+}
+
+struct Current {
+    started: Instant,
+    stages: BTreeMap<String, StageTime>,
+}
+
+static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+
+/// Starts timing a request.
+pub fn begin() {
+    if let Ok(mut c) = CURRENT.lock() {
+        *c = Some(Current { started: Instant::now(), stages: BTreeMap::new() });
+    }
+}
+
+/// The stage a named model call counts in, `None` for calls made outside the request's answer.
+fn stage_of(call: &str) -> Option<&'static str> {
+    match call {
+        "embeddings" => Some("embedding"),
+        "search" => Some("search"),
+        "webhook" => None,
+        _ => Some("llm"),
+    }
+}
+
+/// Adds the time of a call to its stage, see [`crate::retry::call`].
+pub fn record_call(call: &str, elapsed: Duration) {
+    let Some(stage) = stage_of(call) else {
+        return;
+    };
+    if let Ok(mut c) = CURRENT.lock() {
+        if let Some(c) = c.as_mut() {
+            let s = c.stages.entry(stage.to_string()).or_default();
+            s.calls += 1;
+            s.ms += elapsed.as_millis() as u64;
+        }
+    }
+}
+
+/// Stores the record of the request: folded into the aggregates, kept among the latest records
+/// and posted to `telemetry_webhook` when set.
+pub fn finish(mut record: RequestRecord) {
+    let current = CURRENT.lock().ok().and_then(|mut c| c.take());
+    let Some(current) = current else {
+        return;
+    };
+    record.at = now_secs();
+    record.total_ms = current.started.elapsed().as_millis() as u64;
+    record.stages = current.stages;
+
+    let mut aggregate = get(AGGREGATE_KEY)
+        .and_then(|v| serde_json::from_value::<Aggregate>(v).ok())
+        .unwrap_or_default();
+    aggregate.add(&record);
+    guards::set_capped(AGGREGATE_KEY, serde_json::to_value(&aggregate).unwrap_or_default(), None);
+
+    let mut recent = recent();
+    recent.push(record.clone());
+    if recent.len() > MAX_RECENT {
+        recent.drain(..recent.len() - MAX_RECENT);
+    }
+    guards::set_capped(RECENT_KEY, serde_json::to_value(&recent).unwrap_or_default(), None);
+
+    let webhook = std::env::var("telemetry_webhook").unwrap_or("".to_string());
+    if !webhook.is_empty() {
+        let body = serde_json::to_vec(&record).unwrap_or_default();
+        if let Err(e) = fetch::post_json(&webhook, &[], &body) {
+            log::warn!("Cannot post the request metrics: {}", e);
+        }
+    }
+}
+
+/// The scores of the chunks used for the answer, from [`crate::trace::used_scores`]: the
+/// reranked score when there is one, else the similarity.
+pub fn scores(used: &Value) -> Vec<f32> {
+    used.as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|c| c["score"].as_f64().or(c["similarity"].as_f64()))
+                .map(|s| s as f32)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn recent() -> Vec<RequestRecord> {
+    get(RECENT_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// The totals of every request since the counters were reset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Aggregate {
+    since: u64,
+    requests: u64,
+    errors: u64,
+    restarts: u64,
+    total_ms: u64,
+    stages: BTreeMap<String, StageTime>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// The best retrieval score of the requests that had one, summed.
+    best_score_sum: f64,
+    scored_requests: u64,
+}
+
+impl Aggregate {
+    fn add(&mut self, r: &RequestRecord) {
+        if self.requests == 0 {
+            self.since = r.at;
+        }
+        self.requests += 1;
+        self.errors += r.error as u64;
+        self.restarts += r.restart as u64;
+        self.total_ms += r.total_ms;
+        for (name, t) in &r.stages {
+            let s = self.stages.entry(name.clone()).or_default();
+            s.calls += t.calls;
+            s.ms += t.ms;
+        }
+        self.prompt_tokens += r.prompt_tokens;
+        self.completion_tokens += r.completion_tokens;
+        if let Some(best) = r.retrieval_scores.iter().copied().reduce(f32::max) {
+            self.best_score_sum += best as f64;
+            self.scored_requests += 1;
+        }
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let i = ((sorted.len() as f64) * p).ceil() as usize;
+    sorted.get(i.saturating_sub(1).min(sorted.len() - 1)).copied()
+}
+
+/// The aggregates with averages, and latency percentiles of the latest requests, for
+/// `?action=stats`.
+pub fn stats() -> Value {
+    let a = get(AGGREGATE_KEY)
+        .and_then(|v| serde_json::from_value::<Aggregate>(v).ok())
+        .unwrap_or_default();
+    let avg = |sum: f64, n: u64| {
+        match n {
+            0 => Value::Null,
+            n => json!(sum / (n as f64)),
+        }
+    };
+    let stages = a.stages
+        .iter()
+        .map(|(name, s)| {
+            (
+                name.clone(),
+                json!({
+                    "calls": s.calls,
+                    "avg_ms_per_request": avg(s.ms as f64, a.requests),
+                    "avg_ms_per_call": avg(s.ms as f64, s.calls),
+                }),
+            )
+        })
+        .collect::<serde_json::Map<String, Value>>();
+
+    let recent = recent();
+    let mut latencies = recent
+        .iter()
+        .map(|r| r.total_ms)
+        .collect::<Vec<u64>>();
+    latencies.sort();
+
+    json!({
+        "since": a.since,
+        "requests": a.requests,
+        "errors": a.errors,
+        "restarts": a.restarts,
+        "avg_latency_ms": avg(a.total_ms as f64, a.requests),
+        "stages": stages,
+        "avg_prompt_tokens": avg(a.prompt_tokens as f64, a.requests),
+        "avg_completion_tokens": avg(a.completion_tokens as f64, a.requests),
+        "avg_best_score": avg(a.best_score_sum, a.scored_requests),
+        "recent": {
+            "requests": recent.len(),
+            "p50_ms": percentile(&latencies, 0.5),
+            "p95_ms": percentile(&latencies, 0.95),
+            "p99_ms": percentile(&latencies, 0.99),
+        },
+    })
+}
+
+/// Starts the aggregates and the latest records over.
+pub fn reset() {
+    store_flows::del(AGGREGATE_KEY);
+    store_flows::del(RECENT_KEY);
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tiktoken_rs::{ tokenizer::{ get_tokenizer, Tokenizer }, CoreBPE };
+
+use crate::CHAT_MODEL;
+
+/// The BPE tables bundled in `tiktoken-rs`, so counting needs no download at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Bpe(Tokenizer),
+    /// About four characters per token, the estimate used before exact counts.
+    Chars,
+}
+
+impl Encoding {
+    /// The `tokenizer` setting (`o200k_base`, `cl100k_base`, `p50k_base`, `r50k_base` or
+    /// `chars`), else the encoding of the model. Models without an OpenAI encoding, like the
+    /// default Mixtral, are counted with `cl100k_base`, which comes closest to their own.
+    fn for_model(model: &str) -> Self {
+        match std::env::var("tokenizer").unwrap_or_default().as_str() {
+            "o200k_base" => Encoding::Bpe(Tokenizer::O200kBase),
+            "cl100k_base" => Encoding::Bpe(Tokenizer::Cl100kBase),
+            "p50k_base" => Encoding::Bpe(Tokenizer::P50kBase),
+            "r50k_base" => Encoding::Bpe(Tokenizer::R50kBase),
+            "chars" => Encoding::Chars,
+            _ => Encoding::Bpe(get_tokenizer(model).unwrap_or(Tokenizer::Cl100kBase)),
+        }
+    }
+
+    fn bpe(&self) -> Option<Arc<Mutex<CoreBPE>>> {
+        match self {
+            Encoding::Bpe(Tokenizer::O200kBase) => Some(tiktoken_rs::o200k_base_singleton()),
+            Encoding::Bpe(Tokenizer::P50kBase) => Some(tiktoken_rs::p50k_base_singleton()),
+            Encoding::Bpe(Tokenizer::P50kEdit) => Some(tiktoken_rs::p50k_edit_singleton()),
+            Encoding::Bpe(Tokenizer::R50kBase | Tokenizer::Gpt2) => Some(tiktoken_rs::r50k_base_singleton()),
+            Encoding::Bpe(Tokenizer::Cl100kBase) => Some(tiktoken_rs::cl100k_base_singleton()),
+            Encoding::Chars => None,
+        }
+    }
+}
+
+/// The encoding of a model resolved once, for counting many texts, e.g. every word of a document.
+pub struct Counter {
+    bpe: Option<Arc<Mutex<CoreBPE>>>,
+}
+
+impl Counter {
+    pub fn for_model(model: &str) -> Self {
+        Self { bpe: Encoding::for_model(model).bpe() }
+    }
+
+    /// The tokens of each text, in order, taking the lock of the encoding once.
+    pub fn count_each<S: AsRef<str>>(&self, texts: &[S]) -> Vec<usize> {
+        match self.bpe.as_ref().map(|b| b.lock()) {
+            Some(bpe) =>
+                texts
+                    .iter()
+                    .map(|t| bpe.encode_with_special_tokens(t.as_ref()).len())
+                    .collect(),
+            None =>
+                texts
+                    .iter()
+                    .map(|t| t.as_ref().chars().count().div_ceil(4))
+                    .collect(),
+        }
+    }
+}
+
+/// The encoding of the default chat model.
+pub fn counter() -> Counter {
+    Counter::for_model(CHAT_MODEL)
+}
+
+/// The tokens of `text` for `model`.
+pub fn count_for(model: &str, text: &str) -> usize {
+    Counter::for_model(model).count_each(&[text])[0]
+}
+
+/// The tokens of `text` for the default chat model.
+pub fn count(text: &str) -> usize {
+    count_for(CHAT_MODEL, text)
+}
@@ -0,0 +1,409 @@
+use std::sync::Mutex;
+
+use async_graphql::{ Enum, SimpleObject };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use store_flows::{ del, get };
+
+use crate::{ consensus::Consensus, encryption, first_x_chars, guards, retention };
+
+/// What happened to a chunk returned by the vector store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Used,
+    /// Excluded by the ranking, e.g. prose when only code was asked for.
+    Excluded,
+    Quarantined,
+    BelowThreshold,
+    /// Above the threshold but not among the best `k`.
+    OutOfTop,
+    OverPayloadBudget,
+    /// Also found by the other search, the chunk is used once.
+    Duplicate,
+    /// Above the threshold but scored low by the reranker.
+    RerankedOut,
+    /// Did not fit in the `max_context_chars` of the context.
+    OverContextLimit,
+    /// Repeats the text of a better scored chunk with another id.
+    NearDuplicate,
+    /// Nothing in it helps answer the question, according to the compression model.
+    NothingRelevant,
+}
+
+impl Outcome {
+    fn describe(&self) -> &'static str {
+        match self {
+            Outcome::Used => "used",
+            Outcome::Excluded => "excluded by the ranking rules",
+            Outcome::Quarantined => "quarantined",
+            Outcome::BelowThreshold => "below the score threshold",
+            Outcome::OutOfTop => "not among the best results",
+            Outcome::OverPayloadBudget => "over the payload budget",
+            Outcome::Duplicate => "also found by the other search",
+            Outcome::RerankedOut => "scored low by the reranker",
+            Outcome::OverContextLimit => "over the context size limit",
+            Outcome::NearDuplicate => "a near duplicate of a better chunk",
+            Outcome::NothingRelevant => "found irrelevant by the compression model",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, SimpleObject)]
+pub struct Candidate {
+    /// `question` or `hypothetical answer`, the search that returned the chunk.
+    pub search: String,
+    pub id: u64,
+    /// The similarity given by the vector store.
+    pub similarity: f32,
+    /// The score after boosts, compared with the threshold.
+    pub score: Option<f32>,
+    pub source: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The position of the chunk in its document, for chunks ingested with it.
+    #[serde(default)]
+    pub chunk_index: Option<u64>,
+    /// The knowledge base version the chunk was ingested in, when known.
+    #[serde(default)]
+    pub kb_version: Option<u64>,
+    #[serde(default)]
+    pub ingested_at: Option<u64>,
+    pub preview: String,
+    pub outcome: Outcome,
+}
+
+/// How the last answer of a chat was put together, shown by `/why`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema, SimpleObject)]
+#[serde(default)]
+pub struct Trace {
+    pub question: String,
+    /// The query searched when it differs from the question, e.g. an expanded short question.
+    pub query: Option<String>,
+    pub lane: String,
+    /// The chat model that answered.
+    pub model: Option<String>,
+    pub category: String,
+    pub confidence: Option<f32>,
+    pub threshold: Option<f32>,
+    pub candidates: Vec<Candidate>,
+    /// How the answer was voted on, for collections sampling several answers.
+    pub consensus: Option<Consensus>,
+    /// The share of the claims of the answer the context supports, for verified answers.
+    pub grounding: Option<f32>,
+    /// The search being traced, chunks found outside of one (e.g. the memory) are not recorded.
+    #[serde(skip)]
+    #[graphql(skip)]
+    stage: Option<String>,
+}
+
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+fn key(chat_id: &str) -> String {
+    format!("trace:{chat_id}")
+}
+
+fn with_trace(f: impl FnOnce(&mut Trace)) {
+    if let Ok(mut t) = TRACE.lock() {
+        if let Some(trace) = t.as_mut() {
+            f(trace);
+        }
+    }
+}
+
+/// Starts tracing the answer to a question, nothing is recorded before.
+pub fn start(question: &str, lane: &str) {
+    if let Ok(mut t) = TRACE.lock() {
+        *t = Some(Trace {
+            question: question.to_string(),
+            lane: lane.to_string(),
+            ..Default::default()
+        });
+    }
+}
+
+/// Names the knowledge base search the next chunks come from, `None` once it is done.
+pub fn stage(search: Option<&str>) {
+    with_trace(|t| {
+        t.stage = search.map(String::from);
+    });
+}
+
+pub fn model(model: &str) {
+    with_trace(|t| {
+        t.model = Some(model.to_string());
+    });
+}
+
+pub fn consensus(consensus: Consensus) {
+    with_trace(|t| {
+        t.consensus = Some(consensus);
+    });
+}
+
+pub fn grounding(grounding: f32) {
+    with_trace(|t| {
+        t.grounding = Some(grounding);
+    });
+}
+
+pub fn query(query: &str) {
+    with_trace(|t| {
+        t.query = (query != t.question).then(|| query.to_string());
+    });
+}
+
+/// Records the score threshold of the current search.
+pub fn threshold(threshold: f32) {
+    with_trace(|t| {
+        if t.stage.is_some() {
+            t.threshold = Some(threshold);
+        }
+    });
+}
+
+/// Records a chunk returned by the current search.
+pub fn candidate(
+    id: u64,
+    similarity: f32,
+    score: Option<f32>,
+    payload: Option<&Map<String, Value>>,
+    text: &str,
+    outcome: Outcome
+) {
+    with_trace(|t| {
+        let search = match &t.stage {
+            Some(s) => s.clone(),
+            None => {
+                return;
+            }
+        };
+        let source = ["source", "title"]
+            .iter()
+            .find_map(|f| payload.and_then(|m| m.get(*f)).and_then(|v| v.as_str()))
+            .map(String::from);
+        let number = |f: &str| payload.and_then(|m| m.get(f)).and_then(|v| v.as_u64());
+        t.candidates.push(Candidate {
+            search,
+            id,
+            similarity,
+            score,
+            source,
+            title: payload
+                .and_then(|m| m.get("title"))
+                .and_then(|v| v.as_str())
+                .map(String::from),
+            chunk_index: number("chunk_index"),
+            kb_version: number("kb_version"),
+            ingested_at: number("ingested_at"),
+            preview: first_x_chars(text, 80),
+            outcome,
+        });
+    });
+}
+
+/// Changes the outcome of the chunk last recorded for the search.
+pub fn mark(search: &str, id: u64, outcome: Outcome) {
+    with_trace(|t| {
+        if let Some(c) = t.candidates.iter_mut().rev().find(|c| c.search == search && c.id == id) {
+            c.outcome = outcome;
+        }
+    });
+}
+
+/// Changes the outcome of every used chunk with the id, whichever search found it.
+pub fn mark_used(id: u64, outcome: Outcome) {
+    with_trace(|t| {
+        for c in t.candidates.iter_mut().filter(|c| c.id == id && c.outcome == Outcome::Used) {
+            c.outcome = outcome;
+        }
+    });
+}
+
+/// Changes the outcome of a chunk of the current search.
+pub fn mark_current(id: u64, outcome: Outcome) {
+    let search = match TRACE.lock() {
+        Ok(t) => t.as_ref().and_then(|t| t.stage.clone()),
+        Err(_) => None,
+    };
+    if let Some(search) = search {
+        mark(&search, id, outcome);
+    }
+}
+
+impl Trace {
+    /// The knowledge base chunks used, with the version they were ingested in.
+    pub fn provenance(&self) -> Value {
+        Value::from(
+            self.candidates
+                .iter()
+                .filter(|c| c.outcome == Outcome::Used)
+                .map(|c| {
+                    json!({
+                        "id": c.id,
+                        "source": c.source,
+                        "title": c.title,
+                        "chunk_index": c.chunk_index,
+                        "kb_version": c.kb_version,
+                        "ingested_at": c.ingested_at,
+                    })
+                })
+                .collect::<Vec<Value>>()
+        )
+    }
+}
+
+/// The knowledge base chunks the answer is built on so far, with the version they were ingested in.
+pub fn provenance() -> Value {
+    match TRACE.lock() {
+        Ok(t) =>
+            t
+                .as_ref()
+                .map(|t| t.provenance())
+                .unwrap_or(Value::from(Vec::<Value>::new())),
+        Err(_) => Value::from(Vec::<Value>::new()),
+    }
+}
+
+/// Forgets the trace of the last answer of the chat.
+pub fn clear(chat_id: &str) {
+    del(&key(chat_id));
+}
+
+/// Deletes the trace of the chat, returning whether there was one, with `dry_run` only checking.
+/// Run by the retention sweep.
+pub fn apply_retention(chat_id: &str, dry_run: bool) -> usize {
+    let held = get(&key(chat_id)).is_some() as usize;
+    if !dry_run {
+        clear(chat_id);
+    }
+    held
+}
+
+/// The scores of the knowledge base chunks the answer is built on so far, best first.
+pub fn used_scores() -> Value {
+    let mut used = match TRACE.lock() {
+        Ok(t) =>
+            t
+                .as_ref()
+                .map(|t| {
+                    t.candidates
+                        .iter()
+                        .filter(|c| c.outcome == Outcome::Used)
+                        .cloned()
+                        .collect::<Vec<Candidate>>()
+                })
+                .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    used.sort_by(|a, b| b.score.unwrap_or(b.similarity).total_cmp(&a.score.unwrap_or(a.similarity)));
+    Value::from(
+        used
+            .iter()
+            .map(|c| json!({ "id": c.id, "source": c.source, "similarity": c.similarity, "score": c.score }))
+            .collect::<Vec<Value>>()
+    )
+}
+
+/// Stores the trace as the one of the last answer of the chat and stops tracing.
+pub fn finish(chat_id: &str, category: &str, confidence: Option<f32>) {
+    let trace = match TRACE.lock() {
+        Ok(mut t) => t.take(),
+        Err(_) => None,
+    };
+    if let Some(mut trace) = trace {
+        trace.category = category.to_string();
+        trace.confidence = confidence;
+        // the question is the user's own text, encrypted like the memory
+        trace.question = encryption::seal(&trace.question);
+        trace.query = trace.query.map(|q| encryption::seal(&q));
+        guards::set_capped(
+            &key(chat_id),
+            serde_json::to_value(&trace).unwrap_or_default(),
+            retention::expire(retention::Data::Traces)
+        );
+    }
+}
+
+/// The trace of the last answer of the chat, with its question decrypted.
+pub fn load(chat_id: &str) -> Option<Trace> {
+    let trace = get(&key(chat_id)).and_then(|v| serde_json::from_value::<Trace>(v).ok())?;
+    Some(Trace {
+        question: encryption::open(&trace.question).unwrap_or_default(),
+        query: trace.query.as_deref().and_then(encryption::open),
+        ..trace
+    })
+}
+
+/// Explains the last answer of the chat for `/why`.
+pub fn explain(chat_id: &str) -> String {
+    let trace = match load(chat_id) {
+        Some(t) => t,
+        None => {
+            return "There is no previous answer to explain.".to_string();
+        }
+    };
+
+    let mut lines = vec![format!("**Question:** {}", trace.question)];
+    if let Some(q) = &trace.query {
+        lines.push(format!("**Searched as:** {q}"));
+    }
+    lines.push(format!("**Answered from:** {} ({} lane)", trace.category.replace('_', " "), trace.lane));
+    if let Some(m) = &trace.model {
+        lines.push(format!("**Model:** {m}"));
+    }
+    if let Some(g) = trace.grounding {
+        lines.push(format!("**Grounding:** {:.0}% of the claims supported by the context", g * 100.0));
+    }
+    if let Some(c) = &trace.consensus {
+        lines.push(format!("**Consensus:** {} of {} sampled answers agree ({:.0}%)", c.agreeing, c.samples, c.agreement * 100.0));
+    }
+    lines.push(
+        match (trace.confidence, trace.threshold) {
+            (Some(c), Some(t)) => format!("**Confidence:** {c:.3} (threshold {t:.3})"),
+            (Some(c), None) => format!("**Confidence:** {c:.3}"),
+            _ => "**Confidence:** none, no chunk of the knowledge base was used".to_string(),
+        }
+    );
+
+    if trace.candidates.is_empty() {
+        lines.push("\nThe knowledge base was not searched for this answer.".to_string());
+        return lines.join("\n");
+    }
+
+    for (title, used) in [("Chunks used", true), ("Chunks discarded", false)] {
+        let chunks = trace.candidates
+            .iter()
+            .filter(|c| (c.outcome == Outcome::Used) == used)
+            .collect::<Vec<&Candidate>>();
+        if chunks.is_empty() {
+            continue;
+        }
+        lines.push(format!("\n**{title}:**"));
+        for c in chunks {
+            let score = match c.score {
+                Some(s) if (s - c.similarity).abs() > f32::EPSILON => format!("{s:.3}, similarity {:.3}", c.similarity),
+                _ => format!("{:.3}", c.similarity),
+            };
+            let reason = match used {
+                true => String::new(),
+                false => format!(", {}", c.outcome.describe()),
+            };
+            let version = match c.kb_version {
+                Some(v) => format!(", version {v}"),
+                None => String::new(),
+            };
+            lines.push(
+                format!(
+                    "- #{} ({}{version}) score {score}, from the {} search{reason}: {}…",
+                    c.id,
+                    c.source.as_deref().unwrap_or("unknown source"),
+                    c.search,
+                    c.preview.replace('\n', " ")
+                )
+            );
+        }
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,177 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{ analytics::{ date_of_day, now_secs }, feedback::Rating, history, memory, resumption, QaPair };
+
+/// One question and its answer.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+    /// When the pair was stored (Unix seconds), `None` for pairs only in the KV mirror.
+    pub at: Option<u64>,
+    /// The beginning of the answer may have been overwritten in the memory before the rest of it.
+    pub partial: bool,
+    /// The id the answer was sent with, `None` for pairs only in the KV mirror or stored before
+    /// answers had ids.
+    pub message_id: Option<String>,
+    pub rating: Option<Rating>,
+}
+
+/// The QA history of a conversation, for `?action=export`.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Transcript {
+    pub conversation_id: String,
+    pub exported_at: u64,
+    /// What the conversation was about before the oldest turn, when a summary was kept.
+    pub earlier_summary: Option<String>,
+    pub turns: Vec<Turn>,
+}
+
+/// Joins two consecutive chunks of a QA pair, dropping the words the second repeats from the end
+/// of the first.
+fn join_overlapping(first: &str, second: &str) -> String {
+    let overlap = second
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(second.len()))
+        .filter(|i| *i > 0 && (*i == second.len() || second[*i..].starts_with(char::is_whitespace)))
+        .rfind(|i| first.ends_with(&second[..*i]));
+    match overlap {
+        Some(i) => format!("{}{}", first, &second[i..]),
+        None => format!("{} {}", first, second),
+    }
+}
+
+/// Splits a stored QA text, `question\n answer`, on the first separator after the start of the
+/// question, which is stored cut to 500 characters.
+fn split_pair(question: &str, text: &str) -> (String, String) {
+    let from = match text.starts_with(question) {
+        true => question.len(),
+        false => 0,
+    };
+    match text[from..].find("\n ") {
+        Some(i) => (text[..from + i].to_string(), text[from + i + 2..].to_string()),
+        None => (question.to_string(), text.to_string()),
+    }
+}
+
+/// The question and the answer of the chunks of one QA pair, oldest first.
+pub fn rejoin(chunks: &[QaPair]) -> (String, String) {
+    let Some(first) = chunks.first() else {
+        return (String::new(), String::new());
+    };
+    let prefix = format!("{}\n ", first.question);
+    let text = chunks[1..].iter().fold(first.text.clone(), |text, c| {
+        join_overlapping(&text, c.text.strip_prefix(&prefix).unwrap_or(&c.text))
+    });
+    split_pair(&first.question, &text)
+}
+
+/// Puts the QA pairs of the chat back together from the chunks of the conversation memory, then
+/// adds the pairs of the KV mirror the memory does not have, e.g. those still queued for
+/// embedding. Pairs past the memory retention period are gone, only the summary is left of them.
+pub fn assemble(chat_id: &str) -> Transcript {
+    // the chunks of a pair share its message id, question and time
+    let mut groups: Vec<Vec<QaPair>> = Vec::new();
+    for (pair, _) in memory::load(chat_id) {
+        match groups.last_mut() {
+            Some(group) if
+                group[0].message_id == pair.message_id &&
+                group[0].at == pair.at &&
+                group[0].question == pair.question
+            => group.push(pair),
+            _ => groups.push(vec![pair]),
+        }
+    }
+
+    // only the oldest pair can have lost chunks to newer ones
+    let overwritten = memory::overwritten(chat_id);
+    let mut turns = groups
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunks)| {
+            let (question, answer) = rejoin(&chunks);
+            Turn {
+                question,
+                answer,
+                at: (chunks[0].at > 0).then_some(chunks[0].at),
+                partial: i == 0 && overwritten,
+                message_id: Some(chunks[0].message_id.clone()).filter(|id| !id.is_empty()),
+                rating: chunks[0].rating,
+            }
+        })
+        .collect::<Vec<Turn>>();
+
+    for qa in history::load(chat_id) {
+        let (question, answer) = qa.split_once("\n ").unwrap_or((qa.as_str(), ""));
+        let remembered = turns
+            .iter_mut()
+            .rev()
+            .find(|t| question.starts_with(&t.question) && answer.ends_with(t.answer.trim_end()));
+        match remembered {
+            // the mirror keeps the pair whole
+            Some(t) => {
+                t.question = question.to_string();
+                t.answer = answer.to_string();
+                t.partial = false;
+            }
+            None =>
+                turns.push(Turn {
+                    question: question.to_string(),
+                    answer: answer.to_string(),
+                    at: None,
+                    partial: false,
+                    message_id: None,
+                    rating: None,
+                }),
+        }
+    }
+
+    Transcript {
+        conversation_id: chat_id.to_string(),
+        exported_at: now_secs(),
+        earlier_summary: resumption::summary(chat_id),
+        turns,
+    }
+}
+
+fn time_of(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    format!("{} {:02}:{:02} UTC", date_of_day(days), secs / 3600, (secs % 3600) / 60)
+}
+
+impl Transcript {
+    /// The transcript as a markdown document for support staff.
+    pub fn markdown(&self) -> String {
+        let mut md = format!(
+            "# Conversation {}\n\nExported {}, {} exchanges.\n",
+            self.conversation_id,
+            time_of(self.exported_at),
+            self.turns.len()
+        );
+        if let Some(summary) = &self.earlier_summary {
+            md.push_str(&format!("\n## Earlier in the conversation\n\n{}\n", summary));
+        }
+        for (i, turn) in self.turns.iter().enumerate() {
+            let when = match turn.at {
+                Some(at) => format!(" ({})", time_of(at)),
+                None => String::new(),
+            };
+            let answer = match turn.partial {
+                true => format!("…{}", turn.answer),
+                false => turn.answer.clone(),
+            };
+            let rating = match turn.rating {
+                Some(Rating::Up) => " (rated up)",
+                Some(Rating::Down) => " (rated down)",
+                None => "",
+            };
+            md.push_str(
+                &format!("\n## {}{}\n\n**Question:** {}\n\n**Answer{}:**\n\n{}\n", i + 1, when, turn.question, rating, answer)
+            );
+        }
+        md
+    }
+}
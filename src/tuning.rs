@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+
+use serde_json::{ json, Value };
+
+use crate::{
+    chunking::{ self, Chunker, SentenceBoundary },
+    collection_config::CollectionConfig,
+    create_hypothetical_answer,
+    error::{ Error, Result },
+    ingest::{ self, IngestOptions },
+    ranking::Ranking,
+    retrieval,
+    search_vector,
+    tokenizer,
+};
+
+/// Chunk size and overlap pairs tried when the request does not list its own.
+const DEFAULT_CANDIDATES: [(usize, usize); 6] = [
+    (500, 50),
+    (800, 100),
+    (1000, 100),
+    (1500, 150),
+    (2000, 200),
+    (3000, 300),
+];
+const MAX_SAMPLE_CHARS: usize = 60_000;
+/// Most candidates of a chunking run, each embeds the whole sample.
+const MAX_CANDIDATES: usize = 10;
+/// The largest `k` of a tuning run.
+const MAX_K: usize = 20;
+/// Weights of the hypothetical answer search tried when the request does not list its own.
+const DEFAULT_HYDE_WEIGHTS: [f32; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+/// Most seed questions of a retrieval tuning run, each costs a hypothetical answer.
+const MAX_SEED_QUESTIONS: usize = 50;
+
+/// A seed question with a snippet of the corpus that answers it.
+#[derive(Debug, Clone)]
+pub struct SeedQuestion {
+    pub question: String,
+    pub expected: String,
+}
+
+/// Measured retrieval quality of one chunking setting.
+#[derive(Debug, Clone)]
+pub struct TrialResult {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub chunks: usize,
+    pub estimated_tokens: usize,
+    pub hits: usize,
+    pub hit_rate: f64,
+}
+
+/// The `k` of the request, `default` when left out.
+fn top_k_of(req: &Value, default: usize) -> Result<usize> {
+    let k = req
+        .get("k")
+        .and_then(|k| k.as_u64())
+        .map(|k| k as usize)
+        .unwrap_or(default);
+    match (1..=MAX_K).contains(&k) {
+        true => Ok(k),
+        false => Err(Error::InvalidInput(format!("`k` must be between 1 and {MAX_K}"))),
+    }
+}
+
+fn seed_questions(req: &Value) -> Result<Vec<SeedQuestion>> {
+    let questions = req
+        .get("questions")
+        .and_then(|q| q.as_array())
+        .map(|q| {
+            q.iter()
+                .filter_map(|q| {
+                    Some(SeedQuestion {
+                        question: q.get("question")?.as_str()?.to_string(),
+                        expected: q.get("expected")?.as_str()?.to_string(),
+                    })
+                })
+                .collect::<Vec<SeedQuestion>>()
+        })
+        .unwrap_or_default();
+    match questions.is_empty() {
+        true => Err(Error::InvalidInput("`questions` needs at least one question with an expected passage".to_string())),
+        false => Ok(questions),
+    }
+}
+
+/// Recommends chunking settings for the corpus.
+///
+/// The request body is
+/// `{"corpus": "...", "questions": [{"question": "...", "expected": "..."}], "candidates": [[1000, 100]], "k": 3}`
+/// where `expected` is a passage of the corpus that answers the question. Each candidate chunks
+/// the corpus sample, embeds it in memory and counts the questions whose top `k` chunks contain
+/// the expected passage, embedding with the provider configured for `collection_name`. Nothing is
+/// written to the vector store. Up to 10 candidates of at least [`chunking::MIN_CHUNK_CHARS`]
+/// characters are tried, with `k` up to 20 and the first 50 questions.
+pub async fn recommend_chunking(body: &[u8]) -> Result<Value> {
+    let req: Value = serde_json::from_slice(body)?;
+
+    let corpus = req
+        .get("corpus")
+        .and_then(|c| c.as_str())
+        .ok_or(Error::InvalidInput("`corpus` is required".to_string()))?;
+    let mut questions = seed_questions(&req)?;
+    questions.truncate(MAX_SEED_QUESTIONS);
+    let candidates = req
+        .get("candidates")
+        .and_then(|c| c.as_array())
+        .map(|c| {
+            c.iter()
+                .filter_map(|pair| {
+                    Some((pair.get(0)?.as_u64()? as usize, pair.get(1)?.as_u64()? as usize))
+                })
+                .collect::<Vec<(usize, usize)>>()
+        })
+        .filter(|c| !c.is_empty())
+        .unwrap_or(DEFAULT_CANDIDATES.to_vec());
+    if candidates.len() > MAX_CANDIDATES {
+        return Err(Error::InvalidInput(format!("at most {MAX_CANDIDATES} `candidates` can be tried at once")));
+    }
+    for (chunk_size, chunk_overlap) in &candidates {
+        chunking::check_size(*chunk_size, *chunk_overlap, chunking::MIN_CHUNK_CHARS)?;
+    }
+    let k = top_k_of(&req, 3)?;
+
+    let collection_name = std::env::var("collection_name").unwrap_or("".to_string());
+    let config = CollectionConfig::load(&collection_name);
+
+    let sample = sample_corpus(corpus, &questions);
+    let question_vectors = ingest::embed_texts(
+        &config,
+        &questions
+            .iter()
+            .map(|q| q.question.clone())
+            .collect::<Vec<String>>()
+    ).await?;
+
+    let mut results = Vec::new();
+    for (chunk_size, chunk_overlap) in candidates {
+        let chunks = SentenceBoundary { size: chunk_size, overlap: chunk_overlap }.chunk(&sample);
+        let chunk_vectors = ingest::embed_texts(&config, &chunks).await?;
+
+        let hits = questions
+            .iter()
+            .zip(question_vectors.iter())
+            .filter(|(q, qv)| {
+                top_k(qv, &chunk_vectors, k)
+                    .into_iter()
+                    .any(|i| contains_passage(&chunks[i], &q.expected))
+            })
+            .count();
+
+        log::info!("Chunking {}/{} hits {} of {} questions", chunk_size, chunk_overlap, hits, questions.len());
+        results.push(TrialResult {
+            chunk_size,
+            chunk_overlap,
+            estimated_tokens: tokenizer::counter().count_each(&chunks).iter().sum(),
+            chunks: chunks.len(),
+            hits,
+            hit_rate: (hits as f64) / (questions.len() as f64),
+        });
+    }
+
+    Ok(report(&results))
+}
+
+/// Recommends the weight of the hypothetical answer search of the collection from the eval
+/// harness's seed questions.
+///
+/// The request body is
+/// `{"questions": [{"question": "...", "expected": "..."}], "weights": [0, 0.5, 1], "k": 5}` where
+/// `expected` is a passage of the collection that answers the question. Each question and its
+/// hypothetical answer are searched once, then every weight merges the two result lists as
+/// retrieval does and counts the questions whose top `k` chunks contain the expected passage.
+/// With `apply`, the best weight becomes the collection's `hyde_weight`.
+pub async fn recommend_hyde_weight(collection_name: &str, body: &[u8], apply: bool) -> Result<Value> {
+    let req: Value = serde_json::from_slice(body)?;
+    let mut questions = seed_questions(&req)?;
+    questions.truncate(MAX_SEED_QUESTIONS);
+    let weights = req
+        .get("weights")
+        .and_then(|w| w.as_array())
+        .map(|w| {
+            w.iter()
+                .filter_map(|w| w.as_f64())
+                .map(|w| w as f32)
+                .filter(|w| (0.0..=1.0).contains(w))
+                .collect::<Vec<f32>>()
+        })
+        .filter(|w| !w.is_empty())
+        .unwrap_or(DEFAULT_HYDE_WEIGHTS.to_vec());
+    let k = top_k_of(&req, retrieval::default_limit())?;
+
+    let mut config = CollectionConfig::load(collection_name);
+    // both searches, unweighted, for every question
+    let mut searched = Vec::with_capacity(questions.len());
+    for q in &questions {
+        let ranking = Ranking::for_question(&q.question);
+        let ranking = Ranking { top_k: Some(k), ..ranking };
+        let hypo_answer = create_hypothetical_answer(&q.question).await?;
+        let vectors = config.embed(&[q.question.clone(), hypo_answer]).await?;
+        let mut found = Vec::new();
+        for vector in vectors {
+            found.push(search_vector(vector, collection_name, &config, &ranking).await?);
+        }
+        searched.push(found);
+    }
+
+    let mut trials = Vec::new();
+    for weight in &weights {
+        let (question_factor, hypo_factor) = retrieval::search_factors(*weight);
+        let hits = questions
+            .iter()
+            .zip(searched.iter())
+            .filter(|(q, found)| {
+                // the best weighted score of each chunk, as retrieval merges them
+                let mut merged = BTreeMap::<u64, (f32, &str)>::new();
+                for (factor, results) in [question_factor, hypo_factor].iter().zip(found.iter()) {
+                    if *factor == 0.0 {
+                        continue;
+                    }
+                    for (id, score, text) in results {
+                        let entry = merged.entry(*id).or_insert((f32::MIN, text.as_str()));
+                        entry.0 = entry.0.max(score * factor);
+                    }
+                }
+                let mut ranked = merged.into_values().collect::<Vec<(f32, &str)>>();
+                ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+                ranked
+                    .iter()
+                    .take(k)
+                    .any(|(_, text)| contains_passage(text, &q.expected))
+            })
+            .count();
+        log::info!("HyDE weight {} hits {} of {} questions in {}", weight, hits, questions.len(), collection_name);
+        trials.push((*weight, hits, (hits as f64) / (questions.len() as f64)));
+    }
+
+    // the best hit rate wins, ties going to 0, which saves the hypothetical answer, then to the
+    // weight closest to the default
+    let best = trials
+        .iter()
+        .max_by(|a, b| {
+            a.1
+                .cmp(&b.1)
+                .then((a.0 == 0.0).cmp(&(b.0 == 0.0)))
+                .then(
+                    (b.0 - retrieval::DEFAULT_HYDE_WEIGHT).abs().total_cmp(&(a.0 - retrieval::DEFAULT_HYDE_WEIGHT).abs())
+                )
+        })
+        .copied();
+    let current = config.hyde_weight.unwrap_or(retrieval::DEFAULT_HYDE_WEIGHT);
+    if let (Some((weight, _, _)), true) = (best, apply) {
+        config.hyde_weight = Some(weight);
+        config.save(collection_name);
+        log::info!("Set the HyDE weight of {} to {}", collection_name, weight);
+    }
+
+    Ok(
+        json!({
+            "collection": collection_name,
+            "recommended": best.map(|(weight, _, hit_rate)| json!({
+                "hyde_weight": weight,
+                "hit_rate": hit_rate,
+                "hit_rate_change_vs_current": trials
+                    .iter()
+                    .find(|t| (t.0 - current).abs() < f32::EPSILON)
+                    .map(|c| hit_rate - c.2),
+            })),
+            "current": current,
+            "applied": apply && best.is_some(),
+            "trials": trials.iter().map(|(weight, hits, hit_rate)| json!({
+                "hyde_weight": weight,
+                "hits": hits,
+                "hit_rate": hit_rate,
+            })).collect::<Vec<Value>>(),
+        })
+    )
+}
+
+/// Keeps the corpus within [`MAX_SAMPLE_CHARS`], preferring the neighbourhood of the expected
+/// passages so every seed question stays answerable.
+fn sample_corpus(corpus: &str, questions: &[SeedQuestion]) -> String {
+    if corpus.chars().count() <= MAX_SAMPLE_CHARS {
+        return corpus.to_string();
+    }
+
+    let window = MAX_SAMPLE_CHARS / (questions.len() + 1);
+    let (lower, char_at) = lowercase_with_positions(corpus);
+    let mut sample = vec![corpus.chars().take(window).collect::<String>()];
+    for q in questions {
+        if let Some(pos) = lower.find(&q.expected.to_lowercase()) {
+            let start = char_at[pos].saturating_sub(window / 2);
+            sample.push(corpus.chars().skip(start).take(window).collect());
+        }
+    }
+
+    sample.join("\n\n")
+}
+
+/// The text lowercased, with the index of the character of the text every byte of it comes from.
+/// Lowercasing can change the length of a character, e.g. `İ`, so positions in the lowercased
+/// text do not carry over to the text.
+fn lowercase_with_positions(text: &str) -> (String, Vec<usize>) {
+    let mut lower = String::with_capacity(text.len());
+    let mut char_at = Vec::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        for l in c.to_lowercase() {
+            lower.push(l);
+            char_at.extend(std::iter::repeat_n(i, l.len_utf8()));
+        }
+    }
+    (lower, char_at)
+}
+
+fn top_k(query: &[f32], vectors: &[Vec<f32>], k: usize) -> Vec<usize> {
+    let mut scored = vectors
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            (
+                i,
+                query
+                    .iter()
+                    .zip(v.iter())
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>(),
+            )
+        })
+        .collect::<Vec<(usize, f32)>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    scored
+        .into_iter()
+        .take(k)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn contains_passage(chunk: &str, expected: &str) -> bool {
+    let normalize = |s: &str| s.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase();
+    normalize(chunk).contains(&normalize(expected))
+}
+
+fn report(results: &[TrialResult]) -> Value {
+    let current = IngestOptions::default();
+    let baseline = results
+        .iter()
+        .find(|r| r.chunk_size == current.chunk_size && r.chunk_overlap == current.chunk_overlap);
+
+    // highest hit rate wins, fewer embedded tokens breaks ties
+    let best = results.iter().max_by(|a, b| {
+        a.hit_rate.total_cmp(&b.hit_rate).then(b.estimated_tokens.cmp(&a.estimated_tokens))
+    });
+
+    json!({
+        "recommended": best.map(|b| json!({
+            "chunk_size": b.chunk_size,
+            "chunk_overlap": b.chunk_overlap,
+            "hit_rate": b.hit_rate,
+            "hit_rate_change_vs_current": baseline.map(|c| b.hit_rate - c.hit_rate),
+        })),
+        "current": { "chunk_size": current.chunk_size, "chunk_overlap": current.chunk_overlap },
+        "trials": results.iter().map(|r| json!({
+            "chunk_size": r.chunk_size,
+            "chunk_overlap": r.chunk_overlap,
+            "chunks": r.chunks,
+            "estimated_tokens": r.estimated_tokens,
+            "hits": r.hits,
+            "hit_rate": r.hit_rate,
+        })).collect::<Vec<Value>>(),
+    })
+}
@@ -0,0 +1,127 @@
+use regex::{ Captures, Regex };
+use serde_json::json;
+use store_flows::get;
+
+use crate::guards;
+
+/// How resource quantities in answers are written, from `/units` or the `unit_style` setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitStyle {
+    /// Kubernetes notation without converting values: `512Mi`, `1G`, `500m`.
+    Kubernetes,
+    /// Memory converted to decimal units (`536.87MB`) and CPU to cores (`0.5 CPU`).
+    Decimal,
+    /// Memory converted to binary units (`488.28Mi`), CPU in millicores.
+    Binary,
+    Off,
+}
+
+impl UnitStyle {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "kubernetes" | "k8s" => Some(UnitStyle::Kubernetes),
+            "decimal" => Some(UnitStyle::Decimal),
+            "binary" => Some(UnitStyle::Binary),
+            "off" => Some(UnitStyle::Off),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnitStyle::Kubernetes => "kubernetes",
+            UnitStyle::Decimal => "decimal",
+            UnitStyle::Binary => "binary",
+            UnitStyle::Off => "off",
+        }
+    }
+
+    /// The style chosen in the chat with `/units`, else the `unit_style` setting.
+    pub fn for_chat(chat_id: &str) -> Self {
+        get(&key(chat_id))
+            .and_then(|v| v.as_str().and_then(UnitStyle::parse))
+            .or_else(|| UnitStyle::parse(&std::env::var("unit_style").unwrap_or("".to_string())))
+            .unwrap_or(UnitStyle::Kubernetes)
+    }
+
+    pub fn save_for_chat(&self, chat_id: &str) {
+        guards::set_capped(&key(chat_id), json!(self.name()), None);
+    }
+}
+
+fn key(chat_id: &str) -> String {
+    format!("units:{chat_id}")
+}
+
+/// Rewrites the memory and CPU quantities of an answer in the given style. Code blocks and inline
+/// code are left alone so manifests stay valid.
+pub fn format(answer: &str, style: UnitStyle) -> String {
+    if style == UnitStyle::Off {
+        return answer.to_string();
+    }
+
+    let code = Regex::new(r"(?s)```.*?```|`[^`\n]*`").unwrap();
+    let mut out = String::with_capacity(answer.len());
+    let mut last = 0;
+    for m in code.find_iter(answer) {
+        out.push_str(&format_prose(&answer[last..m.start()], style));
+        out.push_str(m.as_str());
+        last = m.end();
+    }
+    out.push_str(&format_prose(&answer[last..], style));
+    out
+}
+
+fn format_prose(text: &str, style: UnitStyle) -> String {
+    let memory = Regex::new(r"\b(\d+(?:\.\d+)?) ?([kKMGT])(i?)(B?)\b").unwrap();
+    let cpu = Regex::new(r"\b(\d+(?:\.\d+)?) ?(?:millicores?|millicpus?|mCPU)\b").unwrap();
+
+    let text = memory.replace_all(text, |c: &Captures| {
+        let (binary, bytes) = (!c[3].is_empty(), !c[4].is_empty());
+        // a bare `5M` is as likely to be a count as a quantity
+        if !binary && !bytes {
+            return c[0].to_string();
+        }
+        let value: f64 = c[1].parse().unwrap_or_default();
+        let prefix = c[2].to_uppercase();
+        let power = match prefix.as_str() {
+            "K" => 1,
+            "M" => 2,
+            "G" => 3,
+            _ => 4,
+        };
+        let factor = (1024f64 / 1000f64).powi(power);
+
+        match (style, binary) {
+            (UnitStyle::Decimal, true) => format!("{}{}B", number(value * factor), decimal_prefix(&prefix)),
+            (UnitStyle::Decimal, false) => format!("{}{}B", &c[1], decimal_prefix(&prefix)),
+            (UnitStyle::Binary, false) => format!("{}{}i", number(value / factor), prefix),
+            (_, true) => format!("{}{}i", &c[1], prefix),
+            (_, false) => format!("{}{}", &c[1], decimal_prefix(&prefix)),
+        }
+    });
+
+    cpu.replace_all(&text, |c: &Captures| {
+        match style {
+            UnitStyle::Decimal => {
+                let millis: f64 = c[1].parse().unwrap_or_default();
+                format!("{} CPU", number(millis / 1000.0))
+            }
+            _ => format!("{}m", &c[1]),
+        }
+    }).to_string()
+}
+
+/// Kilo is a lowercase `k` in both SI and Kubernetes notation.
+fn decimal_prefix(prefix: &str) -> &str {
+    match prefix {
+        "K" => "k",
+        p => p,
+    }
+}
+
+/// At most two decimals, without trailing zeros.
+fn number(value: f64) -> String {
+    let s = format!("{:.2}", value);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
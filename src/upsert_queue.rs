@@ -0,0 +1,72 @@
+use serde_json::{ json, Value };
+use store_flows::get;
+
+use crate::{ guards, memory, QaPair };
+
+const QUEUE_KEY: &str = "upsert_queue";
+/// The oldest QA pairs are dropped once the queue holds this many.
+const MAX_QUEUED: usize = 200;
+
+fn load() -> Vec<QaPair> {
+    get(QUEUE_KEY)
+        .and_then(|v| v.as_array().cloned())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|v| {
+            match v {
+                // queued before the chat was kept with the pair
+                Value::String(text) => Some(QaPair { text, ..Default::default() }),
+                v => serde_json::from_value::<QaPair>(v).ok()?.opened(),
+            }
+        })
+        .collect()
+}
+
+fn save(queue: &[QaPair]) {
+    let sealed = queue
+        .iter()
+        .map(|p| p.sealed())
+        .collect::<Vec<QaPair>>();
+    guards::set_capped(QUEUE_KEY, json!(sealed), None);
+}
+
+/// Keeps a QA pair that could not be written to the conversation memory for a later flush.
+pub fn enqueue(pair: QaPair) {
+    let mut queue = load();
+    queue.push(pair);
+    if queue.len() > MAX_QUEUED {
+        log::warn!("Upsert queue is full, dropping {} of the oldest QA pairs", queue.len() - MAX_QUEUED);
+        queue.drain(..queue.len() - MAX_QUEUED);
+    }
+    log::info!("Queued a QA pair for a later upsert, {} waiting", queue.len());
+    save(&queue);
+}
+
+/// Retries the queued QA pairs in order, stopping at the first failure so the embedding service
+/// is not hammered while it is down. Returns the number written and the number still waiting.
+pub async fn flush() -> (usize, usize) {
+    let queue = load();
+    if queue.is_empty() {
+        return (0, 0);
+    }
+
+    let mut written = 0;
+    for pair in &queue {
+        if let Err(e) = memory::save(pair).await {
+            log::error!("Flushing the upsert queue stopped: {}", e);
+            break;
+        }
+        written += 1;
+    }
+
+    let remaining = &queue[written..];
+    save(remaining);
+    log::info!("Flushed {} queued QA pairs, {} waiting", written, remaining.len());
+    (written, remaining.len())
+}
+
+/// The flush result for `?action=flush_upserts`.
+pub async fn flush_report() -> Value {
+    let (written, remaining) = flush().await;
+    json!({ "written": written, "remaining": remaining })
+}
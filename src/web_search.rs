@@ -0,0 +1,202 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{ collection_config::CollectionConfig, error::Result, fetch, first_x_chars, settings };
+
+/// A hit returned by the configured web search API.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+/// Searches the web when `web_search_endpoint` is configured and returns the passages most
+/// similar to the question, each labeled with the page it came from. Returns an empty string
+/// when the fallback is disabled or nothing useful was found.
+///
+/// The endpoint is called as `GET <web_search_endpoint>?q=<question>&count=<n>` with the key
+/// from `web_search_api_key` sent in the `web_search_key_header` header (a bearer token in
+/// `Authorization` by default). Bing, Brave and most self-hosted search proxies fit this shape.
+pub async fn external_context(question: &str) -> String {
+    let endpoint = std::env::var("web_search_endpoint").unwrap_or("".to_string());
+    if endpoint.is_empty() {
+        return String::new();
+    }
+
+    let hits = match search(&endpoint, question) {
+        Ok(hits) => hits,
+        Err(e) => {
+            log::error!("Web search returns error: {}", e);
+            return String::new();
+        }
+    };
+    log::info!("Web search found {} results for the question", hits.len());
+
+    let mut labeled = Vec::new();
+    for hit in hits {
+        let passage = match best_passage(question, &hit).await {
+            Some(p) => p,
+            None => hit.snippet.clone(),
+        };
+        if passage.is_empty() {
+            continue;
+        }
+        labeled.push(format!("[external source: {} ({})] {}", hit.title, hit.url, passage));
+    }
+
+    labeled.join("\n")
+}
+
+fn search(endpoint: &str, question: &str) -> Result<Vec<SearchHit>> {
+    let api_key = std::env::var("web_search_api_key").unwrap_or("".to_string());
+    let key_header = std::env::var("web_search_key_header").unwrap_or("".to_string());
+    let count = settings::setting::<usize>("web_search_results", 3);
+
+    let separator = match endpoint.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    let url = format!("{endpoint}{separator}q={}&count={count}", urlencoding::encode(question));
+
+    let bearer = format!("Bearer {api_key}");
+    let headers = match (api_key.is_empty(), key_header.is_empty()) {
+        (true, _) => vec![],
+        (false, true) => vec![("Authorization", bearer.as_str())],
+        (false, false) => vec![(key_header.as_str(), api_key.as_str())],
+    };
+
+    let body = fetch::get(&url, &headers)?;
+    let json: Value = serde_json::from_slice(&body)?;
+
+    Ok(parse_hits(&json).into_iter().take(count).collect())
+}
+
+/// Reads result lists from the response shapes of the common search APIs.
+fn parse_hits(json: &Value) -> Vec<SearchHit> {
+    let results = ["/webPages/value", "/web/results", "/organic_results", "/results", "/items"]
+        .iter()
+        .find_map(|path| json.pointer(path).and_then(|v| v.as_array()));
+
+    let field = |item: &Value, names: &[&str]| {
+        names
+            .iter()
+            .find_map(|n| item.get(*n).and_then(|v| v.as_str()))
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    results
+        .map(|items| {
+            items
+                .iter()
+                .map(|item| SearchHit {
+                    title: field(item, &["title", "name"]),
+                    url: field(item, &["url", "link"]),
+                    snippet: field(item, &["snippet", "description", "content"]),
+                })
+                .filter(|hit| !hit.url.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Fetches the page behind `hit`, chunks its text and returns the chunk closest to the question.
+async fn best_passage(question: &str, hit: &SearchHit) -> Option<String> {
+    let html = match fetch::get(&hit.url, &[]) {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => {
+            log::debug!("Cannot fetch {}: {}", hit.url, e);
+            return None;
+        }
+    };
+
+    let chunks = html_to_text(&html)
+        .chars()
+        .collect::<Vec<char>>()
+        .chunks(1000)
+        .take(20)
+        .map(|c| c.iter().collect::<String>())
+        .collect::<Vec<String>>();
+    if chunks.is_empty() {
+        return None;
+    }
+
+    let mut input = vec![question.to_string()];
+    input.extend(chunks.iter().cloned());
+    let vectors = match CollectionConfig::default().embed(&input).await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("No embeddings for the chunks of {}: {}", hit.url, e);
+            return None;
+        }
+    };
+
+    let dot = |a: &Vec<f32>, b: &Vec<f32>| a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
+    let question_vector = &vectors[0];
+    vectors[1..]
+        .iter()
+        .zip(chunks)
+        .max_by(|a, b| dot(question_vector, a.0).total_cmp(&dot(question_vector, b.0)))
+        .map(|(_, chunk)| first_x_chars(&chunk, 1000))
+}
+
+/// Strips scripts, styles and tags from an HTML page and collapses the whitespace left behind.
+pub fn html_to_text(html: &str) -> String {
+    let blocks = Regex::new(
+        r"(?is)<script[^>]*>.*?</script>|<style[^>]*>.*?</style>|<noscript[^>]*>.*?</noscript>|<head[^>]*>.*?</head>|<nav[^>]*>.*?</nav>|<footer[^>]*>.*?</footer>"
+    ).unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let spaces = Regex::new(r"\s+").unwrap();
+
+    let text = blocks.replace_all(html, " ");
+    let text = tags.replace_all(&text, " ");
+    // in one pass, so a decoded `&` never starts another entity
+    let entities = Regex::new(r"(?i)&(nbsp|amp|lt|gt|quot|apos|#[0-9]{1,7}|#x[0-9a-f]{1,6});").unwrap();
+    let text = entities.replace_all(&text, |c: &regex::Captures| {
+        let entity = c[1].to_ascii_lowercase();
+        let decoded = match entity.as_str() {
+            "nbsp" => Some(' '),
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ =>
+                match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity[1..].parse().ok(),
+                }.and_then(char::from_u32),
+        };
+        decoded.map(String::from).unwrap_or(c[0].to_string())
+    });
+
+    spaces.replace_all(&text, " ").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_and_scripts_are_dropped() {
+        let html = "<html><head><title>T</title></head><body><script>alert(1)</script><p>Hello <b>world</b></p></body></html>";
+        assert_eq!(html_to_text(html), "Hello world");
+    }
+
+    #[test]
+    fn entities_are_decoded_once() {
+        assert_eq!(html_to_text("a &amp;lt; b"), "a &lt; b");
+        assert_eq!(html_to_text("x&nbsp;&lt;y&gt; &quot;z&quot;"), "x <y> \"z\"");
+    }
+
+    #[test]
+    fn numeric_entities_are_decoded() {
+        assert_eq!(html_to_text("it&#39;s &#x27;quoted&#X27;"), "it's 'quoted'");
+    }
+
+    #[test]
+    fn unknown_and_invalid_entities_are_kept() {
+        assert_eq!(html_to_text("&foo; &#xD800;"), "&foo; &#xD800;");
+    }
+}
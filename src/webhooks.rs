@@ -0,0 +1,263 @@
+use hmac::{ Hmac, Mac };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha2::Sha256;
+use store_flows::get;
+
+use crate::{ analytics::now_secs, fetch, guards, retry };
+
+/// Deliveries that failed on the request path, retried by the scheduler.
+const PENDING_KEY: &str = "webhooks:pending";
+/// Oldest pending deliveries are dropped past this many.
+const MAX_PENDING: usize = 50;
+/// Scheduler runs a pending delivery is tried in before it is dropped.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// A URL the events are posted to, from the `webhook_subscribers` JSON array.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct Subscriber {
+    url: String,
+    /// The event names sent to the subscriber, every event when empty.
+    events: Vec<String>,
+    /// Signs the body of the requests in the `x-webhook-signature` header when set.
+    secret: Option<String>,
+}
+
+/// What happened in the bot, posted to the subscribers as a JSON body tagged with its `event`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    /// The first answer of a chat, or the first after `/new`.
+    #[serde(rename = "conversation.started")]
+    ConversationStarted {
+        conversation_id: String,
+    },
+    /// The question is left out when memory encryption is on, it is not sent anywhere in the clear.
+    #[serde(rename = "message.answered")]
+    MessageAnswered {
+        conversation_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        question: Option<String>,
+        category: String,
+        confidence: Option<f32>,
+        provenance: Value,
+    },
+    #[serde(rename = "feedback.received")]
+    FeedbackReceived {
+        conversation_id: String,
+        collection: String,
+        helpful: bool,
+    },
+    #[serde(rename = "ingestion.completed")]
+    IngestionCompleted {
+        collection: String,
+        written: usize,
+        kb_version: Option<u64>,
+        failed: bool,
+    },
+    /// A reminder set with `/remind` is due, for the platform adapter to post `message` into the
+    /// conversation.
+    #[serde(rename = "reminder.due")]
+    ReminderDue {
+        conversation_id: String,
+        conversation_name: String,
+        platform: String,
+        message: String,
+        due_at: u64,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ConversationStarted { .. } => "conversation.started",
+            Event::MessageAnswered { .. } => "message.answered",
+            Event::FeedbackReceived { .. } => "feedback.received",
+            Event::IngestionCompleted { .. } => "ingestion.completed",
+            Event::ReminderDue { .. } => "reminder.due",
+        }
+    }
+}
+
+fn subscribers() -> Vec<Subscriber> {
+    let setting = std::env::var("webhook_subscribers").unwrap_or("".to_string());
+    if setting.trim().is_empty() {
+        return Vec::new();
+    }
+    serde_json::from_str(&setting).unwrap_or_else(|e| {
+        log::error!("Invalid webhook_subscribers: {}", e);
+        Vec::new()
+    })
+}
+
+/// The hex HMAC-SHA256 of the body, sent as `sha256=<hex>` for subscribers to verify the sender.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// A delivery that failed on the request path, waiting for the scheduler.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Pending {
+    url: String,
+    event: String,
+    body: String,
+    attempts: u32,
+}
+
+fn load_pending() -> Vec<Pending> {
+    get(PENDING_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_pending(pending: &[Pending]) {
+    let start = pending.len().saturating_sub(MAX_PENDING);
+    guards::set_capped(PENDING_KEY, serde_json::to_value(&pending[start..]).unwrap_or_default(), None);
+}
+
+/// Posts a body to one subscriber, once or under the `webhook` retry policy.
+async fn post(s: &Subscriber, event: &str, body: &[u8], retried: bool) -> bool {
+    let signature = s.secret.as_deref().map(|secret| format!("sha256={}", signature(secret, body)));
+    let mut headers = vec![("x-webhook-event", event)];
+    if let Some(signature) = &signature {
+        headers.push(("x-webhook-signature", signature));
+    }
+    let send = || async { fetch::post_json(&s.url, &headers, body).map_err(|e| e.to_string()) };
+    let sent = match retried {
+        true => retry::call("webhook", send).await,
+        false => retry::call_once("webhook", send).await,
+    };
+    match sent {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("Cannot deliver {} to {}: {}", event, s.url, e);
+            false
+        }
+    }
+}
+
+fn subscribed_to(event: &Event) -> Vec<Subscriber> {
+    subscribers()
+        .into_iter()
+        .filter(|s| s.events.is_empty() || s.events.iter().any(|e| e == event.name()))
+        .collect()
+}
+
+fn body_of(event: &Event) -> Vec<u8> {
+    let mut payload = serde_json::to_value(event).unwrap_or_default();
+    payload["at"] = json!(now_secs());
+    serde_json::to_vec(&payload).unwrap_or_default()
+}
+
+/// Posts the event to its subscribers from the request path: each is tried once, so a dead
+/// subscriber does not hold up the reply, and a failed delivery is queued for the scheduler, see
+/// [`deliver_pending`]. Returns whether any subscriber received it.
+pub async fn emit(event: Event) -> bool {
+    let subscribers = subscribed_to(&event);
+    if subscribers.is_empty() {
+        return false;
+    }
+    let body = body_of(&event);
+
+    let mut received = false;
+    let mut failed = Vec::new();
+    for s in subscribers {
+        match post(&s, event.name(), &body, false).await {
+            true => {
+                received = true;
+            }
+            false =>
+                failed.push(Pending {
+                    url: s.url,
+                    event: event.name().to_string(),
+                    body: String::from_utf8_lossy(&body).to_string(),
+                    attempts: 0,
+                }),
+        }
+    }
+    if !failed.is_empty() {
+        let mut pending = load_pending();
+        pending.extend(failed);
+        save_pending(&pending);
+    }
+    received
+}
+
+/// Posts the event to its subscribers from the scheduler, retrying each under the `webhook` retry
+/// policy, for callers that keep what was not received themselves. Returns whether any
+/// subscriber received it.
+pub async fn deliver(event: Event) -> bool {
+    let body = body_of(&event);
+    let mut received = false;
+    for s in subscribed_to(&event) {
+        received |= post(&s, event.name(), &body, true).await;
+    }
+    received
+}
+
+/// Retries the deliveries that failed on the request path under the `webhook` retry policy, run on
+/// every tick of the schedule. A delivery is dropped after [`MAX_ATTEMPTS`] runs, or when its
+/// subscriber is no longer configured.
+pub async fn deliver_pending() -> Value {
+    let pending = load_pending();
+    if pending.is_empty() {
+        return json!({ "delivered": 0, "retrying": 0, "dropped": 0 });
+    }
+    // taken off the list first, so an overlapping run does not deliver them twice
+    save_pending(&[]);
+
+    let subscribers = subscribers();
+    let (mut delivered, mut dropped) = (0, 0);
+    let mut retry = Vec::new();
+    for p in pending {
+        let Some(s) = subscribers.iter().find(|s| s.url == p.url) else {
+            dropped += 1;
+            continue;
+        };
+        let attempts = p.attempts + 1;
+        if post(s, &p.event, p.body.as_bytes(), true).await {
+            delivered += 1;
+        } else if attempts >= MAX_ATTEMPTS {
+            log::error!("Dropped {} to {} after {} failed runs", p.event, p.url, attempts);
+            dropped += 1;
+        } else {
+            retry.push(Pending { attempts, ..p });
+        }
+    }
+
+    let retrying = retry.len();
+    if !retry.is_empty() {
+        // deliveries that failed while retrying are on the list by now
+        let mut pending = load_pending();
+        pending.extend(retry);
+        save_pending(&pending);
+    }
+    json!({ "delivered": delivered, "retrying": retrying, "dropped": dropped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_is_the_hex_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signature_depends_on_secret_and_body() {
+        assert_ne!(signature("a", b"body"), signature("b", b"body"));
+        assert_ne!(signature("a", b"body"), signature("a", b"body2"));
+    }
+}